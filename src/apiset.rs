@@ -0,0 +1,446 @@
+//! The API set schema, which redirects imports of virtual DLLs such as
+//! `api-ms-win-core-synch-l1-2-0.dll` to the DLL implementing them.
+//!
+//! The schema is the `.apiset` section of `apisetschema.dll`. Every API set
+//! has a default host and optionally other hosts for specific importers,
+//! so that the default host itself can import the API set from another
+//! DLL.
+//!
+//! Three versions of the schema are read:
+//!
+//! - 2, of Windows 7 and 8. Names are stored without their `api-` prefix.
+//! - 4, of Windows 8.1. The same, with flags added to every structure.
+//! - 6, of Windows 10 and later. Names are stored in full, and are matched
+//!   up to their last hyphen, which ignores the minor version.
+//!
+//! All offsets are from the start of the schema.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use open_image;
+use json;
+use pe::{self, PeFile};
+
+/// Name of the section holding the schema
+const APISET_SECTION: [u8; 8] = *b".apiset\0";
+
+/// Largest schema we will read, real ones are a few hundred KiB
+const MAX_SCHEMA_SIZE: u32 = 16 * 1024 * 1024;
+
+/// A host of an API set
+#[derive(Clone)]
+pub struct Host {
+    /// Module this host is used for, `None` for the default host
+    pub importer: Option<String>,
+
+    /// DLL implementing the API set, eg. `kernelbase.dll`
+    pub host: String,
+}
+
+/// An API set and its hosts
+#[derive(Clone)]
+pub struct ApiSet {
+    /// Name of the API set as stored, without `.dll`, eg.
+    /// `api-ms-win-core-synch-l1-2-0` or `MS-Win-Core-Synch-L1-1-0` for
+    /// schemas before version 6
+    pub name: String,
+
+    /// The default host first, if there is one, then hosts for specific
+    /// importers. API sets without hosts are not implemented at all.
+    pub hosts: Vec<Host>,
+}
+
+/// A parsed API set schema
+pub struct Schema {
+    pub version: u32,
+    pub sets: Vec<ApiSet>,
+
+    /// Indices into `sets` by what they are matched by
+    index: HashMap<String, usize>,
+}
+
+/// Check whether `name` is that of an API set rather than of a real DLL
+pub fn is_api_set(name: &str) -> bool
+{
+    let name = name.to_lowercase();
+    name.starts_with("api-") || name.starts_with("ext-")
+}
+
+/// Get what API sets are matched by in a `version` schema: the name
+/// lowercased, without `.dll` and without the `api-` or `ext-` prefix older
+/// schemas leave out. Version 6 only matches up to the last hyphen.
+fn match_key(name: &str, version: u32) -> String
+{
+    let name = name.to_lowercase();
+    let name = name.strip_suffix(".dll").unwrap_or(&name);
+    let name = if is_api_set(name) { &name[4..] } else { name };
+    match name.rfind('-') {
+        Some(end) if version >= 6 => name[..end].to_string(),
+        _ => name.to_string(),
+    }
+}
+
+fn le32(data: &[u8], offset: usize) -> Result<u32, Box<dyn Error>>
+{
+    match data.get(offset..offset.saturating_add(4)) {
+        Some(bytes) => Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2],
+                                              bytes[3]])),
+        None => Err("API set schema truncated".into()),
+    }
+}
+
+/// Get the UTF-16 string of `len` bytes at `offset`
+fn string(data: &[u8], offset: u32, len: u32) -> Result<String, Box<dyn Error>>
+{
+    let bytes = data.get(offset as usize..(offset as usize)
+                         .saturating_add(len as usize))
+        .ok_or("API set schema string out of range")?;
+    let chars: Vec<u16> = bytes.chunks_exact(2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        .collect();
+    Ok(String::from_utf16_lossy(&chars))
+}
+
+/// Read `count` value entries of `size` bytes from `offset`. Entries are
+/// `<importer offset> <importer length> <host offset> <host length>`, after
+/// flags for `size` 20.
+fn hosts(data: &[u8], offset: u32, count: u32, size: usize) ->
+    Result<Vec<Host>, Box<dyn Error>>
+{
+    let mut hosts = Vec::new();
+    for ii in 0..count {
+        let value = (offset as usize).saturating_add(ii as usize * size)
+            .saturating_add(size - 16);
+        let importer = string(data, le32(data, value)?,
+                              le32(data, value + 4)?)?;
+        let host = string(data, le32(data, value + 8)?,
+                          le32(data, value + 12)?)?;
+        hosts.push(Host {
+            importer: if importer.is_empty() { None } else {
+                Some(importer)
+            },
+            host,
+        });
+    }
+    Ok(hosts)
+}
+
+/// Parse a version 2 schema
+fn parse_v2(data: &[u8]) -> Result<Vec<ApiSet>, Box<dyn Error>>
+{
+    let count = le32(data, 4)?;
+
+    let mut sets = Vec::new();
+    for ii in 0..count {
+        /* API_SET_NAMESPACE_ENTRY_V2, pointing at an API_SET_VALUE_ARRAY_V2
+         * of a count and API_SET_VALUE_ENTRY_V2s */
+        let entry = 8usize.saturating_add(ii as usize * 12);
+        let name = string(data, le32(data, entry)?, le32(data, entry + 4)?)?;
+        let values = le32(data, entry + 8)?;
+        sets.push(ApiSet {
+            name,
+            hosts: hosts(data, values.saturating_add(4),
+                         le32(data, values as usize)?, 16)?,
+        });
+    }
+    Ok(sets)
+}
+
+/// Parse a version 4 schema
+fn parse_v4(data: &[u8]) -> Result<Vec<ApiSet>, Box<dyn Error>>
+{
+    let count = le32(data, 12)?;
+
+    let mut sets = Vec::new();
+    for ii in 0..count {
+        /* API_SET_NAMESPACE_ENTRY_V4, pointing at an API_SET_VALUE_ARRAY_V4
+         * of flags, a count and API_SET_VALUE_ENTRY_V4s */
+        let entry = 16usize.saturating_add(ii as usize * 24);
+        let name = string(data, le32(data, entry + 4)?,
+                          le32(data, entry + 8)?)?;
+        let values = le32(data, entry + 20)?;
+        sets.push(ApiSet {
+            name,
+            hosts: hosts(data, values.saturating_add(8),
+                         le32(data, (values as usize).saturating_add(4))?,
+                         20)?,
+        });
+    }
+    Ok(sets)
+}
+
+/// Parse a version 6 schema
+fn parse_v6(data: &[u8]) -> Result<Vec<ApiSet>, Box<dyn Error>>
+{
+    let (count, entries) = (le32(data, 12)?, le32(data, 16)?);
+
+    let mut sets = Vec::new();
+    for ii in 0..count {
+        /* API_SET_NAMESPACE_ENTRY, pointing at API_SET_VALUE_ENTRYs */
+        let entry = (entries as usize).saturating_add(ii as usize * 24);
+        let name = string(data, le32(data, entry + 4)?,
+                          le32(data, entry + 8)?)?;
+        sets.push(ApiSet {
+            name,
+            hosts: hosts(data, le32(data, entry + 16)?,
+                         le32(data, entry + 20)?, 20)?,
+        });
+    }
+    Ok(sets)
+}
+
+impl Schema {
+    /// Parse the contents of the `.apiset` section
+    pub fn parse(data: &[u8]) -> Result<Schema, Box<dyn Error>>
+    {
+        let version = le32(data, 0)?;
+        let sets = match version {
+            2 => parse_v2(data)?,
+            4 => parse_v4(data)?,
+            6 => parse_v6(data)?,
+            _ => return Err(format!("Unsupported API set schema version {}",
+                                    version).into()),
+        };
+        Ok(Schema::new(version, sets))
+    }
+
+    /// Build a `version` schema out of its API sets
+    pub fn new(version: u32, sets: Vec<ApiSet>) -> Schema
+    {
+        let index = sets.iter().enumerate()
+            .map(|(ii, x)| (match_key(&x.name, version), ii)).collect();
+        Schema { version, sets, index }
+    }
+
+    /// Get the DLL an import of the API set `name` by `importer` is
+    /// redirected to. Unknown API sets and those without a host give
+    /// `None`.
+    pub fn resolve(&self, name: &str, importer: &str) -> Option<&str>
+    {
+        if !is_api_set(name) {
+            return None;
+        }
+        let key = match_key(name, self.version);
+        let set = &self.sets[*self.index.get(&key)?];
+
+        let host = set.hosts.iter()
+            .find(|x| x.importer.as_ref()
+                  .is_some_and(|x| x.eq_ignore_ascii_case(importer)))
+            .or_else(|| set.hosts.first())?;
+        if host.host.is_empty() { None } else { Some(&host.host) }
+    }
+
+    /// Format this as a JSON object, with every API set and its hosts
+    pub fn json(&self) -> String
+    {
+        let sets: Vec<String> = self.sets.iter().map(|set| {
+            let hosts: Vec<String> = set.hosts.iter().map(|host| {
+                let importer = host.importer.as_ref()
+                    .map_or("null".into(), |x| json::quote(x));
+                json::object(&[
+                    ("importer", importer),
+                    ("host",     json::quote(&host.host)),
+                ])
+            }).collect();
+            json::object(&[
+                ("name",  json::quote(&set.name)),
+                ("hosts", json::array(&hosts)),
+            ])
+        }).collect();
+
+        json::object(&[
+            ("version", self.version.to_string()),
+            ("sets",    json::array(&sets)),
+        ])
+    }
+}
+
+/// Read the API set schema out of `apisetschema.dll`
+pub fn read<R: Read + Seek>(image: &PeFile, fd: &mut R) ->
+    Result<Schema, Box<dyn Error>>
+{
+    let section = image.sections.iter()
+        .find(|x| {x.name} == APISET_SECTION)
+        .ok_or("No .apiset section present")?;
+
+    let (vaddr, vsize, raw_size) =
+        (section.vaddr, section.vsize, section.raw_data_size);
+    let size = if vsize == 0 { raw_size } else { vsize.min(raw_size) };
+    if size > MAX_SCHEMA_SIZE {
+        return Err("API set schema too large".into());
+    }
+    Schema::parse(&image.read_rva(fd, vaddr, size)?)
+}
+
+/// Read the API set schema out of the `apisetschema.dll` at `path`
+pub fn load(path: &Path) -> Result<Schema, Box<dyn Error>>
+{
+    let mut image = open_image(path)?;
+    let parsed = pe::parse(&mut image.stream);
+    read(&parsed, &mut image.stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put32(data: &mut [u8], offset: usize, val: usize)
+    {
+        data[offset..offset + 4].copy_from_slice(&(val as u32).to_le_bytes());
+    }
+
+    /// Append `text` as UTF-16, giving its offset and length in bytes
+    fn string(data: &mut Vec<u8>, text: &str) -> (usize, usize)
+    {
+        let offset = data.len();
+        data.extend(text.encode_utf16().flat_map(|x| x.to_le_bytes()));
+        (offset, data.len() - offset)
+    }
+
+    /// Build a `version` schema of API sets with their (importer, host)
+    /// pairs
+    fn schema(version: u32, sets: &[(&str, &[(&str, &str)])]) -> Vec<u8>
+    {
+        let (header, entry_size, value_size) = match version {
+            2 => (8, 12, 16),
+            4 => (16, 24, 20),
+            _ => (28, 24, 20),
+        };
+        let mut data = vec![0u8; header + sets.len() * entry_size];
+        put32(&mut data, 0, version as usize);
+        put32(&mut data, if version == 2 { 4 } else { 12 }, sets.len());
+        put32(&mut data, 16, header);
+
+        for (ii, &(name, hosts)) in sets.iter().enumerate() {
+            let entry = header + ii * entry_size;
+            let (offset, len) = string(&mut data, name);
+            let name_field = if version == 2 { entry } else { entry + 4 };
+            put32(&mut data, name_field, offset);
+            put32(&mut data, name_field + 4, len);
+
+            /* The value array, with its count in front of it before
+             * version 6 */
+            let array = data.len();
+            let values = match version {
+                2 => {
+                    put32(&mut data, entry + 8, array);
+                    array + 4
+                }
+                4 => {
+                    put32(&mut data, entry + 20, array);
+                    array + 8
+                }
+                _ => {
+                    put32(&mut data, entry + 16, array);
+                    put32(&mut data, entry + 20, hosts.len());
+                    array
+                }
+            };
+            data.resize(values + hosts.len() * value_size, 0);
+            if version != 6 {
+                put32(&mut data, values - 4, hosts.len());
+            }
+
+            for (jj, &(importer, host)) in hosts.iter().enumerate() {
+                let value = values + jj * value_size + value_size - 16;
+                let (offset, len) = string(&mut data, importer);
+                put32(&mut data, value, offset);
+                put32(&mut data, value + 4, len);
+                let (offset, len) = string(&mut data, host);
+                put32(&mut data, value + 8, offset);
+                put32(&mut data, value + 12, len);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn resolve_v6()
+    {
+        let data = schema(6, &[
+            ("api-ms-win-core-synch-l1-2-0",
+             &[("", "kernel32.dll"), ("kernel32.dll", "kernelbase.dll")]),
+            ("ext-ms-win-gdi-draw-l1-1-0", &[("", "gdi32full.dll")]),
+            ("api-ms-win-unimplemented-l1-1-0", &[]),
+            ("api-ms-win-empty-l1-1-0", &[("", "")]),
+        ]);
+        let schema = Schema::parse(&data).unwrap();
+        assert_eq!((schema.version, schema.sets.len()), (6, 4));
+        assert_eq!(schema.sets[0].hosts[1].importer.as_deref(),
+                   Some("kernel32.dll"));
+
+        let resolve = |name: &str, importer: &str| {
+            schema.resolve(name, importer).map(|x| x.to_string())
+        };
+        assert_eq!(resolve("api-ms-win-core-synch-l1-2-0.dll", "a.dll")
+                   .unwrap(), "kernel32.dll");
+        assert_eq!(resolve("API-MS-Win-Core-Synch-L1-2-1.dll",
+                           "KERNEL32.DLL").unwrap(), "kernelbase.dll");
+        assert_eq!(resolve("ext-ms-win-gdi-draw-l1-1-3", "a.dll").unwrap(),
+                   "gdi32full.dll");
+        assert!(resolve("api-ms-win-core-synch-l2-1-0.dll", "a.dll")
+                .is_none());
+        assert!(resolve("api-ms-win-unimplemented-l1-1-0.dll", "a.dll")
+                .is_none());
+        assert!(resolve("api-ms-win-empty-l1-1-0.dll", "a.dll").is_none());
+        assert!(resolve("kernel32.dll", "a.dll").is_none());
+
+        assert!(schema.json().starts_with(
+            "{\"version\":6,\"sets\":[{\"name\":\
+             \"api-ms-win-core-synch-l1-2-0\",\"hosts\":[{\"importer\":null,\
+             \"host\":\"kernel32.dll\"},"));
+    }
+
+    #[test]
+    fn resolve_v2_and_v4()
+    {
+        for &version in &[2, 4] {
+            let data = schema(version, &[
+                ("MS-Win-Core-Synch-L1-1-0",
+                 &[("", "kernel32.dll"), ("kernel32.dll", "kernelbase.dll")]),
+                ("MS-Win-Core-Heap-L1-2-0", &[("", "kernelbase.dll")]),
+            ]);
+            let schema = Schema::parse(&data).unwrap();
+            assert_eq!(schema.version, version);
+            assert_eq!(schema.sets[1].name, "MS-Win-Core-Heap-L1-2-0");
+
+            /* Names are stored without their prefix and match exactly */
+            assert_eq!(schema.resolve("api-ms-win-core-synch-l1-1-0.dll",
+                                      "a.dll"), Some("kernel32.dll"));
+            assert_eq!(schema.resolve("api-ms-win-core-synch-l1-1-0.dll",
+                                      "Kernel32.dll"),
+                       Some("kernelbase.dll"));
+            assert_eq!(schema.resolve("API-MS-WIN-CORE-HEAP-L1-2-0", "a.dll"),
+                       Some("kernelbase.dll"));
+            assert!(schema.resolve("api-ms-win-core-synch-l1-1-1.dll",
+                                   "a.dll").is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_schemas()
+    {
+        assert!(Schema::parse(&schema(5, &[])).is_err());
+        assert!(Schema::parse(&[]).is_err());
+        for &version in &[2, 4, 6] {
+            let data = schema(version, &[
+                ("api-ms-win-core-synch-l1-2-0",
+                 &[("", "kernel32.dll"), ("kernel32.dll", "kernelbase.dll")]),
+            ]);
+            for len in 0..data.len() {
+                assert!(Schema::parse(&data[..len]).is_err());
+            }
+            let mut seed = 1u32;
+            for _ in 0..10000 {
+                let mut data = data.clone();
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let pos = (seed >> 8) as usize % data.len();
+                data[pos] = (seed >> 24) as u8;
+                let _ = Schema::parse(&data);
+            }
+        }
+    }
+}
@@ -0,0 +1,603 @@
+//! Authenticode signatures of PE images.
+//!
+//! The security directory points at WIN_CERTIFICATE entries appended to the
+//! image (by file offset, it is never mapped). Authenticode signatures are
+//! PKCS#7 SignedData whose content is an SpcIndirectDataContent holding the
+//! digest of the image, and whose signer carries a counter-signature from a
+//! timestamping authority.
+//!
+//! Everything is checked offline: the digest of the image is recomputed and
+//! compared with the signed one, but neither the RSA signature nor the
+//! certificate chain is verified. A signer name is what the signature claims
+//! and not proof of who signed it.
+
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+
+use sha1::Sha1;
+use sha2::{Sha256, Sha384, Sha512};
+use sha2::digest::DynDigest;
+
+use der::{self, Tlv, Reader};
+use pe::{PeFile, OptionalHeader};
+
+/// Index of the security directory among the data directories
+const SECURITY_DIRECTORY: usize = 4;
+
+/// WIN_CERTIFICATE type of PKCS#7 SignedData
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 2;
+
+/// Largest certificate table we will read
+const MAX_CERTIFICATE_TABLE: u32 = 16 * 1024 * 1024;
+
+const OID_SIGNED_DATA:        &str = "1.2.840.113549.1.7.2";
+const OID_SPC_INDIRECT_DATA:  &str = "1.3.6.1.4.1.311.2.1.4";
+const OID_SIGNING_TIME:       &str = "1.2.840.113549.1.9.5";
+const OID_COUNTER_SIGNATURE:  &str = "1.2.840.113549.1.9.6";
+const OID_RFC3161_TIMESTAMP:  &str = "1.3.6.1.4.1.311.3.3.1";
+const OID_TST_INFO:           &str = "1.2.840.113549.1.9.16.1.4";
+
+/// A certificate embedded in a signature
+#[derive(Clone)]
+pub struct Certificate {
+    pub subject: String,
+    pub issuer:  String,
+
+    /// Attributes of `subject`, most specific first
+    pub subject_attributes: Vec<(String, String)>,
+
+    /// Serial number in hex
+    pub serial: String,
+}
+
+/// A decoded Authenticode signature
+#[derive(Clone)]
+pub struct Signature {
+    /// Number of WIN_CERTIFICATE entries. Only the first PKCS#7 one is
+    /// decoded.
+    pub entries: usize,
+
+    /// `SHA1`, `SHA256`, `SHA384`, `SHA512`, or the dotted OID of any other
+    /// digest algorithm
+    pub digest_algorithm: String,
+
+    /// Digest of the image as signed
+    pub signed_digest: Vec<u8>,
+
+    /// Digest of the image as it is, if the digest algorithm is supported
+    pub computed_digest: Option<Vec<u8>>,
+
+    /// Certificate of the signer, if it is embedded
+    pub signer: Option<Certificate>,
+
+    /// When the image was signed, from the timestamp counter-signature, as
+    /// `YYYY-MM-DD HH:MM:SS UTC`
+    pub timestamp: Option<String>,
+
+    /// Every embedded certificate
+    pub certificates: Vec<Certificate>,
+}
+
+impl Signature {
+    /// Whether the image still has the digest it was signed with
+    pub fn digest_matches(&self) -> bool
+    {
+        self.computed_digest.as_ref() == Some(&self.signed_digest)
+    }
+}
+
+/// Computes the digest of an image
+type Hasher = Box<dyn DynDigest>;
+
+/// Get the name of a digest algorithm and a hasher for it, if it is one we
+/// support
+fn digest_algorithm(oid: &str) -> (String, Option<Hasher>)
+{
+    let (name, hasher): (&str, Hasher) = match oid {
+        "1.3.14.3.2.26"          => ("SHA1",   Box::new(Sha1::default())),
+        "2.16.840.1.101.3.4.2.1" => ("SHA256", Box::new(Sha256::default())),
+        "2.16.840.1.101.3.4.2.2" => ("SHA384", Box::new(Sha384::default())),
+        "2.16.840.1.101.3.4.2.3" => ("SHA512", Box::new(Sha512::default())),
+        _ => return (oid.to_string(), None),
+    };
+    (name.to_string(), Some(hasher))
+}
+
+/// A certificate along with what identifies it as a signer
+struct ParsedCertificate<'a> {
+    cert:   Certificate,
+    issuer: &'a [u8],
+    serial: &'a [u8],
+}
+
+fn hex(data: &[u8]) -> String
+{
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Decode an X.509 certificate
+fn certificate<'a>(tlv: &Tlv<'a>) ->
+    Result<ParsedCertificate<'a>, Box<dyn Error>>
+{
+    let tbs = tlv.reader().expect(der::SEQUENCE)?;
+    let mut tbs = tbs.reader();
+    tbs.optional(der::context(0))?;
+    let serial = tbs.expect(der::INTEGER)?;
+    tbs.expect(der::SEQUENCE)?;
+    let issuer = tbs.expect(der::SEQUENCE)?;
+    tbs.expect(der::SEQUENCE)?;
+    let subject = tbs.expect(der::SEQUENCE)?;
+
+    Ok(ParsedCertificate {
+        cert: Certificate {
+            subject: der::name(&subject)?,
+            issuer:  der::name(&issuer)?,
+            subject_attributes: der::name_attributes(&subject)?,
+            serial:  hex(serial.data),
+        },
+        issuer: issuer.raw,
+        serial: serial.data,
+    })
+}
+
+/// The parts of a PKCS#7 SignerInfo we look at
+struct SignerInfo<'a> {
+    /// Issuer and serial number of the signer certificate, unless it is
+    /// identified by its key
+    issuer_serial: Option<(&'a [u8], &'a [u8])>,
+
+    authenticated:   Option<Tlv<'a>>,
+    unauthenticated: Option<Tlv<'a>>,
+}
+
+/// Decode a SignerInfo
+fn signer_info<'a>(tlv: &Tlv<'a>) -> Result<SignerInfo<'a>, Box<dyn Error>>
+{
+    let mut info = tlv.reader();
+    info.expect(der::INTEGER)?;
+    let sid = info.next()?;
+    let issuer_serial = if sid.tag == der::SEQUENCE {
+        let mut sid = sid.reader();
+        Some((sid.expect(der::SEQUENCE)?.raw, sid.expect(der::INTEGER)?.data))
+    } else {
+        None
+    };
+    info.expect(der::SEQUENCE)?;
+    let authenticated = info.optional(der::context(0))?;
+    info.expect(der::SEQUENCE)?;
+    info.expect(der::OCTET_STRING)?;
+    let unauthenticated = info.optional(der::context(1))?;
+
+    Ok(SignerInfo { issuer_serial, authenticated, unauthenticated })
+}
+
+/// Get the values of the attribute `oid` in the attribute set `attrs`
+fn attribute<'a>(attrs: &Option<Tlv<'a>>, oid: &str) ->
+    Result<Option<Tlv<'a>>, Box<dyn Error>>
+{
+    let mut attrs = match attrs {
+        Some(attrs) => attrs.reader(),
+        None => return Ok(None),
+    };
+    while !attrs.is_empty() {
+        let mut attr = attrs.expect(der::SEQUENCE)?.reader();
+        if der::oid(attr.expect(der::OID)?.data) == oid {
+            return attr.expect(der::SET).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Get the SignedData out of a PKCS#7 ContentInfo
+fn signed_data<'a>(data: &'a [u8]) -> Result<Tlv<'a>, Box<dyn Error>>
+{
+    let mut info = Reader::new(data).expect(der::SEQUENCE)?.reader();
+    if der::oid(info.expect(der::OID)?.data) != OID_SIGNED_DATA {
+        return Err("Not PKCS#7 SignedData".into());
+    }
+    info.expect(der::context(0))?.reader().expect(der::SEQUENCE)
+}
+
+/// Get the time from an RFC 3161 timestamp token
+fn rfc3161_time(token: &Tlv) -> Result<String, Box<dyn Error>>
+{
+    let mut sd = signed_data(token.raw)?.reader();
+    sd.expect(der::INTEGER)?;
+    sd.expect(der::SET)?;
+    let mut content = sd.expect(der::SEQUENCE)?.reader();
+    if der::oid(content.expect(der::OID)?.data) != OID_TST_INFO {
+        return Err("Timestamp token does not hold TSTInfo".into());
+    }
+    let tst = content.expect(der::context(0))?.reader()
+        .expect(der::OCTET_STRING)?;
+
+    /* TSTInfo: version, policy, message imprint, serial, time */
+    let mut tst = Reader::new(tst.data).expect(der::SEQUENCE)?.reader();
+    tst.expect(der::INTEGER)?;
+    tst.expect(der::OID)?;
+    tst.expect(der::SEQUENCE)?;
+    tst.expect(der::INTEGER)?;
+    der::time(&tst.expect(der::GENERALIZED_TIME)?)
+}
+
+/// Get the signing time of a signer, from its RFC 3161 or PKCS#9
+/// counter-signature or failing those its own signing time attribute
+fn signing_time(signer: &SignerInfo) -> Result<Option<String>, Box<dyn Error>>
+{
+    if let Some(tokens) = attribute(&signer.unauthenticated,
+                                    OID_RFC3161_TIMESTAMP)? {
+        return rfc3161_time(&tokens.reader().next()?).map(Some);
+    }
+
+    let signer = match attribute(&signer.unauthenticated,
+                                 OID_COUNTER_SIGNATURE)? {
+        Some(counter) => signer_info(&counter.reader().next()?)?,
+        None => return signing_time_attribute(signer),
+    };
+    signing_time_attribute(&signer)
+}
+
+/// Get the signing time attribute of a signer
+fn signing_time_attribute(signer: &SignerInfo) ->
+    Result<Option<String>, Box<dyn Error>>
+{
+    match attribute(&signer.authenticated, OID_SIGNING_TIME)? {
+        Some(times) => der::time(&times.reader().next()?).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Decode the PKCS#7 SignedData of an Authenticode signature, also giving a
+/// hasher for its digest algorithm if it is supported
+fn decode(data: &[u8]) ->
+    Result<(Signature, Option<Hasher>), Box<dyn Error>>
+{
+    let mut sd = signed_data(data)?.reader();
+    sd.expect(der::INTEGER)?;
+    sd.expect(der::SET)?;
+
+    /* SpcIndirectDataContent: the type of what is signed and its digest */
+    let mut content = sd.expect(der::SEQUENCE)?.reader();
+    if der::oid(content.expect(der::OID)?.data) != OID_SPC_INDIRECT_DATA {
+        return Err("Signature does not hold SpcIndirectDataContent".into());
+    }
+    let mut indirect = content.expect(der::context(0))?.reader()
+        .expect(der::SEQUENCE)?.reader();
+    indirect.expect(der::SEQUENCE)?;
+    let mut digest_info = indirect.expect(der::SEQUENCE)?.reader();
+    let algorithm = digest_info.expect(der::SEQUENCE)?.reader()
+        .expect(der::OID)?;
+    let signed_digest = digest_info.expect(der::OCTET_STRING)?.data.to_vec();
+
+    let mut certificates = Vec::new();
+    if let Some(certs) = sd.optional(der::context(0))? {
+        let mut certs = certs.reader();
+        while !certs.is_empty() {
+            let cert = certs.next()?;
+            if cert.tag == der::SEQUENCE {
+                certificates.push(certificate(&cert)?);
+            }
+        }
+    }
+    sd.optional(der::context(1))?;
+
+    let signer = signer_info(&sd.expect(der::SET)?.reader()
+                             .expect(der::SEQUENCE)?)?;
+    let signer_cert = signer.issuer_serial.and_then(|(issuer, serial)| {
+        certificates.iter()
+            .find(|x| x.issuer == issuer && x.serial == serial)
+            .map(|x| x.cert.clone())
+    });
+
+    let (digest_algorithm, hasher) =
+        digest_algorithm(&der::oid(algorithm.data));
+    Ok((Signature {
+        entries: 0,
+        digest_algorithm,
+        signed_digest,
+        computed_digest: None,
+        signer: signer_cert,
+        timestamp: signing_time(&signer)?,
+        certificates: certificates.into_iter().map(|x| x.cert).collect(),
+    }, hasher))
+}
+
+/// Feed the bytes of `fd` from `start` to `end` to `hasher`
+fn hash_range<R: Read + Seek>(fd: &mut R, hasher: &mut dyn DynDigest,
+                              start: u64, end: u64) ->
+    Result<(), Box<dyn Error>>
+{
+    fd.seek(SeekFrom::Start(start))?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut left = end.saturating_sub(start);
+    while left > 0 {
+        let chunk = left.min(buf.len() as u64) as usize;
+        fd.read_exact(&mut buf[..chunk])?;
+        hasher.update(&buf[..chunk]);
+        left -= chunk as u64;
+    }
+    Ok(())
+}
+
+/// Compute the Authenticode digest of the image in `fd`: everything in the
+/// file but the checksum, the security directory entry and the certificate
+/// table itself.
+///
+/// The specification hashes the headers and then the sections in file order
+/// and whatever follows them, which for any image the linker produced is the
+/// same bytes in the same order.
+fn image_digest<R: Read + Seek>(image: &PeFile, fd: &mut R,
+                                mut hasher: Hasher) ->
+    Result<Vec<u8>, Box<dyn Error>>
+{
+    let (mz, optional) = match (&image.mz, &image.optional) {
+        (Some(mz), Some(optional)) => (mz, optional),
+        _ => return Err("No optional header present".into()),
+    };
+
+    /* Both are at the same place in either optional header up to the data
+     * directories */
+    let optional_start = mz.new_header as u64 + 0x18;
+    let data_dirs = match optional {
+        OptionalHeader::Pe32(_) => 96,
+        OptionalHeader::Pe64(_) => 112,
+    };
+    let checksum = optional_start + 64;
+    let security = optional_start + data_dirs + SECURITY_DIRECTORY as u64 * 8;
+    let table = image.data_dirs[SECURITY_DIRECTORY];
+
+    let len = fd.seek(SeekFrom::End(0))?;
+    let mut skip = [(checksum, 4), (security, 8),
+                    (table.vaddr as u64, table.size as u64)];
+    skip.sort();
+
+    let mut pos = 0;
+    for &(start, size) in &skip {
+        hash_range(fd, hasher.as_mut(), pos, start)?;
+        pos = pos.max(start + size);
+    }
+    hash_range(fd, hasher.as_mut(), pos, len)?;
+    Ok(hasher.finalize().into_vec())
+}
+
+/// Read the Authenticode signature of `image` from `fd`, recomputing the
+/// digest of the image. Unsigned images give `None`.
+pub fn read<R: Read + Seek>(image: &PeFile, fd: &mut R) ->
+    Result<Option<Signature>, Box<dyn Error>>
+{
+    let table = match image.data_dirs.get(SECURITY_DIRECTORY) {
+        Some(dir) if dir.vaddr != 0 && dir.size != 0 => *dir,
+        _ => return Ok(None),
+    };
+    if table.size > MAX_CERTIFICATE_TABLE {
+        return Err("Certificate table too large".into());
+    }
+
+    /* The security directory holds a file offset rather than an RVA */
+    let len = fd.seek(SeekFrom::End(0))?;
+    if table.vaddr as u64 + table.size as u64 > len {
+        return Err("Certificate table past the end of the file".into());
+    }
+    fd.seek(SeekFrom::Start(table.vaddr as u64))?;
+    let mut data = vec![0u8; table.size as usize];
+    fd.read_exact(&mut data)?;
+
+    /* WIN_CERTIFICATE entries, each aligned to 8 bytes */
+    let mut pkcs7 = None;
+    let mut entries = 0;
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let entry = &data[offset..];
+        let length = u32::from_le_bytes([entry[0], entry[1], entry[2],
+                                         entry[3]]) as usize;
+        let typ = u16::from_le_bytes([entry[6], entry[7]]);
+        if length < 8 || length > entry.len() {
+            return Err("WIN_CERTIFICATE length out of range".into());
+        }
+        if typ == WIN_CERT_TYPE_PKCS_SIGNED_DATA && pkcs7.is_none() {
+            pkcs7 = Some(&entry[8..length]);
+        }
+        entries += 1;
+        offset += (length + 7) & !7;
+    }
+
+    let (mut signature, hasher) = match pkcs7 {
+        Some(pkcs7) => decode(pkcs7)?,
+        None => return Err("No PKCS#7 signature present".into()),
+    };
+    signature.entries = entries;
+    if let Some(hasher) = hasher {
+        signature.computed_digest = Some(image_digest(image, fd, hasher)?);
+    }
+    Ok(Some(signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a value, with a long form length when it needs one
+    fn tlv(tag: u8, parts: &[&[u8]]) -> Vec<u8>
+    {
+        let data = parts.concat();
+        let mut out = vec![tag];
+        if data.len() < 0x80 {
+            out.push(data.len() as u8);
+        } else {
+            out.extend_from_slice(&[0x82, (data.len() >> 8) as u8,
+                                    data.len() as u8]);
+        }
+        out.extend(data);
+        out
+    }
+
+    /// Encode a dotted object identifier
+    fn oid(dotted: &str) -> Vec<u8>
+    {
+        let arcs: Vec<u64> = dotted.split('.')
+            .map(|x| x.parse().unwrap()).collect();
+        let mut data = Vec::new();
+        for (ii, &arc) in arcs.iter().enumerate().skip(1) {
+            let arc = if ii == 1 { arcs[0] * 40 + arc } else { arc };
+            let mut bytes = vec![arc as u8 & 0x7f];
+            let mut rest = arc >> 7;
+            while rest != 0 {
+                bytes.push(rest as u8 | 0x80);
+                rest >>= 7;
+            }
+            data.extend(bytes.iter().rev());
+        }
+        tlv(der::OID, &[&data])
+    }
+
+    fn seq(parts: &[&[u8]]) -> Vec<u8>
+    {
+        tlv(der::SEQUENCE, parts)
+    }
+
+    /// A name with just a common name
+    fn name(cn: &str) -> Vec<u8>
+    {
+        let attr = seq(&[&oid("2.5.4.3"), &tlv(0x0c, &[cn.as_bytes()])]);
+        seq(&[&tlv(der::SET, &[&attr])])
+    }
+
+    fn certificate(serial: u8, subject: &str) -> Vec<u8>
+    {
+        let tbs = seq(&[&tlv(der::context(0), &[&tlv(der::INTEGER, &[&[2]])]),
+                        &tlv(der::INTEGER, &[&[serial]]), &seq(&[]),
+                        &name("Root"), &seq(&[]), &name(subject),
+                        &seq(&[])]);
+        seq(&[&tbs, &seq(&[]), &tlv(0x03, &[&[0]])])
+    }
+
+    fn attribute(oid_: &str, val: &[u8]) -> Vec<u8>
+    {
+        seq(&[&oid(oid_), &tlv(der::SET, &[val])])
+    }
+
+    /// A SignerInfo for the certificate with `serial`, or one identified by
+    /// key if there is none
+    fn signer(serial: Option<u8>, authenticated: &[u8],
+              unauthenticated: &[u8]) -> Vec<u8>
+    {
+        let sid = match serial {
+            Some(serial) => seq(&[&name("Root"),
+                                  &tlv(der::INTEGER, &[&[serial]])]),
+            None => tlv(0x80, &[&[1, 2, 3]]),
+        };
+        let mut parts = vec![tlv(der::INTEGER, &[&[1]]), sid, seq(&[])];
+        if !authenticated.is_empty() {
+            parts.push(tlv(der::context(0), &[authenticated]));
+        }
+        parts.push(seq(&[]));
+        parts.push(tlv(der::OCTET_STRING, &[&[0; 4]]));
+        if !unauthenticated.is_empty() {
+            parts.push(tlv(der::context(1), &[unauthenticated]));
+        }
+        let parts: Vec<&[u8]> = parts.iter().map(|x| &x[..]).collect();
+        seq(&parts)
+    }
+
+    /// A PKCS#7 ContentInfo holding SignedData of `content` (type and
+    /// value)
+    fn signed(typ: &str, content: &[u8], certs: &[&[u8]], signer: &[u8]) ->
+        Vec<u8>
+    {
+        let mut sd = vec![tlv(der::INTEGER, &[&[1]]), tlv(der::SET, &[]),
+                          seq(&[&oid(typ),
+                                &tlv(der::context(0), &[content])])];
+        if !certs.is_empty() {
+            sd.push(tlv(der::context(0), certs));
+        }
+        sd.push(tlv(der::SET, &[signer]));
+        let sd: Vec<&[u8]> = sd.iter().map(|x| &x[..]).collect();
+        seq(&[&oid(OID_SIGNED_DATA),
+              &tlv(der::context(0), &[&seq(&sd)])])
+    }
+
+    /// SpcIndirectDataContent with a digest of `algorithm`
+    fn indirect(algorithm: &str) -> Vec<u8>
+    {
+        seq(&[&seq(&[]), &seq(&[&seq(&[&oid(algorithm)]),
+                                &tlv(der::OCTET_STRING, &[&[0xab; 32]])])])
+    }
+
+    const SHA256: &str = "2.16.840.1.101.3.4.2.1";
+
+    #[test]
+    fn signer_and_counter_signature()
+    {
+        let time = attribute(OID_SIGNING_TIME,
+                             &tlv(der::UTC_TIME, &[b"200102030405Z"]));
+        let counter = attribute(OID_COUNTER_SIGNATURE,
+                                &signer(Some(9), &time, &[]));
+        let data = signed(OID_SPC_INDIRECT_DATA, &indirect(SHA256),
+                          &[&certificate(1, "Other"),
+                            &tlv(der::context(1), &[]),
+                            &certificate(2, "Microsoft Windows")],
+                          &signer(Some(2), &[], &counter));
+
+        let (sig, hasher) = decode(&data).unwrap();
+        assert!(hasher.is_some());
+        assert_eq!(sig.digest_algorithm, "SHA256");
+        assert_eq!(sig.signed_digest, [0xab; 32]);
+        assert_eq!(sig.timestamp.unwrap(), "2020-01-02 03:04:05 UTC");
+        assert_eq!(sig.certificates.len(), 2);
+        let cert = sig.signer.unwrap();
+        assert_eq!((cert.subject.as_str(), cert.issuer.as_str()),
+                   ("CN=Microsoft Windows", "CN=Root"));
+        assert_eq!(cert.subject_attributes,
+                   [("CN".to_string(), "Microsoft Windows".to_string())]);
+        assert_eq!(cert.serial, "02");
+    }
+
+    #[test]
+    fn rfc3161_timestamp()
+    {
+        let tst = seq(&[&tlv(der::INTEGER, &[&[1]]), &oid("1.2.3"),
+                        &seq(&[]), &tlv(der::INTEGER, &[&[5]]),
+                        &tlv(der::GENERALIZED_TIME,
+                             &[b"20210102030405Z"])]);
+        let token = signed(OID_TST_INFO,
+                           &tlv(der::OCTET_STRING, &[&tst]), &[],
+                           &signer(None, &[], &[]));
+        let time = attribute(OID_SIGNING_TIME,
+                             &tlv(der::UTC_TIME, &[b"200102030405Z"]));
+        let data = signed(OID_SPC_INDIRECT_DATA, &indirect("1.2.3.4"), &[],
+                          &signer(None, &time,
+                                  &attribute(OID_RFC3161_TIMESTAMP,
+                                             &token)));
+
+        let (sig, hasher) = decode(&data).unwrap();
+        assert!(hasher.is_none() && sig.signer.is_none());
+        assert_eq!(sig.digest_algorithm, "1.2.3.4");
+        assert_eq!(sig.timestamp.unwrap(), "2021-01-02 03:04:05 UTC");
+    }
+
+    #[test]
+    fn rejects_bad_signatures()
+    {
+        let time = attribute(OID_SIGNING_TIME,
+                             &tlv(der::UTC_TIME, &[b"200102030405Z"]));
+        let data = signed(OID_SPC_INDIRECT_DATA, &indirect(SHA256),
+                          &[&certificate(2, "Microsoft Windows")],
+                          &signer(Some(2), &time, &[]));
+        let (sig, _) = decode(&data).unwrap();
+        assert_eq!(sig.timestamp.unwrap(), "2020-01-02 03:04:05 UTC");
+
+        assert!(decode(&signed("1.2.3", &indirect(SHA256), &[],
+                               &signer(None, &[], &[]))).is_err());
+        for len in 0..data.len() {
+            assert!(decode(&data[..len]).is_err());
+        }
+        let mut seed = 1u32;
+        for _ in 0..10000 {
+            let mut data = data.clone();
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let pos = (seed >> 8) as usize % data.len();
+            data[pos] = (seed >> 24) as u8;
+            let _ = decode(&data);
+        }
+    }
+}
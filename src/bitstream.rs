@@ -0,0 +1,124 @@
+//! Bit reader shared by the Microsoft LZ77+Huffman style decompressors
+//! (XPRESS and LZX).
+//!
+//! Both formats store their bitstream as a sequence of little endian 16-bit
+//! words which are consumed most significant bit first. Literal bytes (such
+//! as XPRESS match lengths or LZX uncompressed blocks) are interleaved in the
+//! same byte stream, so words are only pulled in when more bits are actually
+//! required.
+
+pub struct BitReader<'a> {
+    /// Raw input
+    data: &'a [u8],
+
+    /// Offset of the next unread byte in `data`
+    pos: usize,
+
+    /// Bit buffer, the low `bits` bits are valid
+    buf: u64,
+
+    /// Number of valid bits in `buf`
+    bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a>
+    {
+        BitReader { data, pos: 0, buf: 0, bits: 0 }
+    }
+
+    /// Make sure at least `count` (at most 32) bits are buffered. Reading
+    /// past the end of the input yields zero bits, as compressors are allowed
+    /// to leave the final word of a stream partially filled.
+    pub fn ensure(&mut self, count: u32)
+    {
+        while self.bits < count {
+            let word = self.next_u16() as u64;
+            self.buf = (self.buf << 16) | word;
+            self.bits += 16;
+        }
+    }
+
+    /// Look at the next `count` bits without consuming them. `ensure` must
+    /// have been called for at least `count` bits first.
+    pub fn peek(&self, count: u32) -> u32
+    {
+        if count == 0 {
+            return 0;
+        }
+        ((self.buf >> (self.bits - count)) & ((1u64 << count) - 1)) as u32
+    }
+
+    /// Drop `count` already buffered bits
+    pub fn consume(&mut self, count: u32)
+    {
+        self.bits -= count;
+        self.buf &= (1u64 << self.bits) - 1;
+    }
+
+    /// Read `count` (at most 32) bits as an integer
+    pub fn read_bits(&mut self, count: u32) -> u32
+    {
+        self.ensure(count);
+        let val = self.peek(count);
+        self.consume(count);
+        val
+    }
+
+    /// Read a raw byte from the underlying stream, bypassing the bit buffer
+    pub fn read_u8(&mut self) -> u8
+    {
+        let val = self.data.get(self.pos).cloned().unwrap_or(0);
+        self.pos += 1;
+        val
+    }
+
+    /// Read a raw little endian 16-bit value, bypassing the bit buffer
+    pub fn read_u16(&mut self) -> u16
+    {
+        self.next_u16()
+    }
+
+    /// Read a raw little endian 32-bit value, bypassing the bit buffer
+    pub fn read_u32(&mut self) -> u32
+    {
+        let lo = self.next_u16() as u32;
+        let hi = self.next_u16() as u32;
+        lo | (hi << 16)
+    }
+
+    /// Discard whatever is left in the bit buffer so that the next read
+    /// starts on a 16-bit boundary of the input.
+    pub fn align(&mut self)
+    {
+        self.buf = 0;
+        self.bits = 0;
+    }
+
+    /// Copy `out.len()` raw bytes out of the stream
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), &'static str>
+    {
+        let end = self.pos.checked_add(out.len()).ok_or("Input overrun")?;
+        if end > self.data.len() {
+            return Err("Input overrun");
+        }
+        out.copy_from_slice(&self.data[self.pos..end]);
+        self.pos = end;
+        Ok(())
+    }
+
+    /// Whether the reader has run past the end of its input. A small amount
+    /// of overrun is expected from the zero padding in `ensure`.
+    pub fn overrun(&self) -> bool
+    {
+        self.pos > self.data.len() + 4
+    }
+
+    fn next_u16(&mut self) -> u16
+    {
+        let lo = self.data.get(self.pos).cloned().unwrap_or(0) as u16;
+        let hi = self.data.get(self.pos + 1).cloned().unwrap_or(0) as u16;
+        self.pos += 2;
+        lo | (hi << 8)
+    }
+}
@@ -0,0 +1,525 @@
+//! Reader for the single file CAB archives used to compress files in symbol
+//! stores and on older installation media. These are the files whose
+//! extension has its last character replaced with an underscore, such as
+//! `ntdll.pd_` or `kernel32.dl_`.
+
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+
+use miniz_oxide::inflate::core::{decompress, DecompressorOxide};
+use miniz_oxide::inflate::core::inflate_flags::
+    TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+use miniz_oxide::inflate::TINFLStatus;
+
+use pe::read_struct;
+use lzx::Lzx;
+
+const CAB_FLAG_PREV_CABINET:    u16 = 0x0001;
+const CAB_FLAG_NEXT_CABINET:    u16 = 0x0002;
+const CAB_FLAG_RESERVE_PRESENT: u16 = 0x0004;
+
+const COMPRESS_MASK:  u16 = 0x000f;
+const COMPRESS_NONE:  u16 = 0;
+const COMPRESS_MSZIP: u16 = 1;
+const COMPRESS_LZX:   u16 = 3;
+
+/// File names are UTF-8 rather than the current code page
+const ATTRIB_NAME_IS_UTF: u16 = 0x80;
+
+/// MSZIP blocks may refer back this far into previous blocks
+const MSZIP_WINDOW: usize = 32768;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct CabHeader {
+    signature:     [u8; 4],
+    reserved1:     u32,
+    cabinet_size:  u32,
+    reserved2:     u32,
+    files_offset:  u32,
+    reserved3:     u32,
+    version_minor: u8,
+    version_major: u8,
+    num_folders:   u16,
+    num_files:     u16,
+    flags:         u16,
+    set_id:        u16,
+    cabinet_index: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct CabReserve {
+    header_reserve: u16,
+    folder_reserve: u8,
+    data_reserve:   u8,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct CabFolder {
+    data_offset: u32,
+    num_data:    u16,
+    compression: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct CabFileEntry {
+    size:          u32,
+    folder_offset: u32,
+    folder:        u16,
+    date:          u16,
+    time:          u16,
+    attributes:    u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct CabData {
+    checksum:     u32,
+    compressed:   u16,
+    uncompressed: u16,
+}
+
+/// Check whether `magic` (the first bytes of a file) is a CAB signature
+pub fn is_cab(magic: &[u8]) -> bool
+{
+    magic.starts_with(b"MSCF")
+}
+
+/// Read a null terminated string
+fn read_string<R: Read>(fd: &mut R, utf8: bool) ->
+    Result<String, Box<dyn Error>>
+{
+    let mut name = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        fd.read_exact(&mut byte)?;
+        if byte[0] == 0 {
+            break;
+        }
+        name.push(byte[0]);
+    }
+
+    if utf8 {
+        Ok(String::from_utf8(name)?)
+    } else {
+        /* Non-UTF names are in the code page of the machine which created
+         * the CAB, treat them as Latin-1 */
+        Ok(name.iter().map(|&x| x as char).collect())
+    }
+}
+
+/// Inflate one MSZIP block, which may refer back into `history`
+fn inflate_block(input: &[u8], history: &[u8], out_size: usize) ->
+    Result<Vec<u8>, Box<dyn Error>>
+{
+    if !input.starts_with(b"CK") {
+        return Err("MSZIP block missing CK signature".into());
+    }
+
+    let hist = &history[history.len().saturating_sub(MSZIP_WINDOW)..];
+    let mut buf = hist.to_vec();
+    buf.resize(hist.len() + out_size, 0);
+
+    let mut inflater = DecompressorOxide::new();
+    let (status, _, written) = decompress(&mut inflater, &input[2..],
+        &mut buf, hist.len(), TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF);
+
+    if status != TINFLStatus::Done || written != out_size {
+        return Err("Failed to inflate MSZIP block".into());
+    }
+
+    Ok(buf[hist.len()..].to_vec())
+}
+
+/// Extract the first file from the CAB in `fd`. Returns the name of the file
+/// and its contents.
+///
+/// If `limit` is given, decompression may stop early once at least `limit`
+/// bytes of the file are available. This is used to cheaply check magics
+/// before expanding a large file.
+pub fn extract<R: Read + Seek>(fd: &mut R, limit: Option<usize>) ->
+    Result<(String, Vec<u8>), Box<dyn Error>>
+{
+    fd.seek(SeekFrom::Start(0))?;
+    let header: CabHeader = unsafe { read_struct(fd)? };
+    if !is_cab(&header.signature) {
+        return Err("No CAB header present".into());
+    }
+    if header.num_files == 0 || header.num_folders == 0 {
+        return Err("CAB contains no files".into());
+    }
+
+    let (folder_reserve, data_reserve) =
+            if header.flags & CAB_FLAG_RESERVE_PRESENT != 0 {
+        let reserve: CabReserve = unsafe { read_struct(fd)? };
+        fd.seek(SeekFrom::Current(reserve.header_reserve as i64))?;
+        (reserve.folder_reserve as i64, reserve.data_reserve as i64)
+    } else {
+        (0, 0)
+    };
+
+    if header.flags & (CAB_FLAG_PREV_CABINET | CAB_FLAG_NEXT_CABINET) != 0 {
+        return Err("Multi-cabinet sets are not supported".into());
+    }
+
+    let folders_start = fd.stream_position()?;
+
+    /* Grab the first file entry */
+    fd.seek(SeekFrom::Start(header.files_offset as u64))?;
+    let file: CabFileEntry = unsafe { read_struct(fd)? };
+    let name = read_string(fd, file.attributes & ATTRIB_NAME_IS_UTF != 0)?;
+    if file.folder >= header.num_folders {
+        return Err("CAB file is continued from another cabinet".into());
+    }
+
+    /* Find the folder holding the file */
+    let folder_size = std::mem::size_of::<CabFolder>() as u64 +
+        folder_reserve as u64;
+    fd.seek(SeekFrom::Start(folders_start +
+                            file.folder as u64 * folder_size))?;
+    let folder: CabFolder = unsafe { read_struct(fd)? };
+
+    let compression = folder.compression & COMPRESS_MASK;
+    let mut lzx = if compression == COMPRESS_LZX {
+        Some(Lzx::new_cab(((folder.compression >> 8) & 0x1f) as u32)?)
+    } else {
+        None
+    };
+
+    let start = file.folder_offset as usize;
+    let end = start.checked_add(file.size as usize)
+        .ok_or("CAB file size overflow")?;
+    let want = match limit {
+        Some(limit) => std::cmp::min(end, start.saturating_add(limit)),
+        None => end,
+    };
+
+    /* Decompress the folder until we have all of the file */
+    let mut data = Vec::new();
+    let mut input = Vec::new();
+    fd.seek(SeekFrom::Start(folder.data_offset as u64))?;
+    for _ in 0..folder.num_data {
+        if data.len() >= want {
+            break;
+        }
+
+        let block: CabData = unsafe { read_struct(fd)? };
+        fd.seek(SeekFrom::Current(data_reserve))?;
+
+        input.resize(block.compressed as usize, 0);
+        fd.read_exact(&mut input)?;
+        let out_size = block.uncompressed as usize;
+
+        let mut out = match compression {
+            COMPRESS_NONE => {
+                if input.len() != out_size {
+                    return Err("Stored CAB block size mismatch".into());
+                }
+                input.clone()
+            }
+            COMPRESS_MSZIP => inflate_block(&input, &data, out_size)?,
+            COMPRESS_LZX => lzx.as_mut().unwrap()
+                .decompress_frame(&input, out_size)?,
+            _ => return Err("Unsupported CAB compression type".into()),
+        };
+        data.append(&mut out);
+    }
+
+    if data.len() < want {
+        return Err("CAB folder is truncated".into());
+    }
+
+    data.truncate(want);
+    data.drain(..start);
+    Ok((name, data))
+}
+
+/// Get the name a file is stored under when compressed, which is the
+/// original name with the last character replaced by an underscore
+pub fn compressed_name(name: &str) -> String
+{
+    match name.rfind('.') {
+        Some(dot) if dot + 1 < name.len() => {
+            let mut compressed = name.to_string();
+            compressed.pop();
+            compressed.push('_');
+            compressed
+        }
+        _ => format!("{}._", name),
+    }
+}
+
+/// Compute the checksum of a CFDATA block as defined by the CAB format
+fn checksum(data: &[u8], seed: u32) -> u32
+{
+    let mut csum = seed;
+
+    let mut words = data.chunks_exact(4);
+    for word in words.by_ref() {
+        csum ^= u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+    }
+
+    /* Trailing bytes are folded in big endian order */
+    let mut tail = 0u32;
+    for &byte in words.remainder() {
+        tail = (tail << 8) | byte as u32;
+    }
+
+    csum ^ tail
+}
+
+/// Compress `data` into a single file MSZIP CAB which stores it as `name`,
+/// the same format `makecab` and `symstore /compress` produce.
+pub fn compress(name: &str, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>
+{
+    let header_size = std::mem::size_of::<CabHeader>();
+    let folder_size = std::mem::size_of::<CabFolder>();
+    let file_size   = std::mem::size_of::<CabFileEntry>();
+
+    if data.len() > u32::MAX as usize {
+        return Err("File too large for a CAB".into());
+    }
+
+    /* Compress every block independently. MSZIP allows referring back to
+     * the previous block but does not require it. */
+    let mut blocks = Vec::new();
+    for chunk in data.chunks(MSZIP_WINDOW) {
+        let mut block = b"CK".to_vec();
+        block.extend(miniz_oxide::deflate::compress_to_vec(chunk, 9));
+
+        let mut hdr = Vec::new();
+        hdr.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        hdr.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+        let csum = checksum(&hdr, checksum(&block, 0));
+
+        let mut ent = csum.to_le_bytes().to_vec();
+        ent.extend(hdr);
+        ent.extend(block);
+        blocks.push(ent);
+    }
+
+    let utf8 = !name.is_ascii();
+    let files_offset = header_size + folder_size;
+    let data_offset  = files_offset + file_size + name.len() + 1;
+    let total = data_offset + blocks.iter().map(|x| x.len()).sum::<usize>();
+
+    let mut cab = Vec::with_capacity(total);
+    cab.extend_from_slice(b"MSCF");
+    cab.extend_from_slice(&0u32.to_le_bytes());
+    cab.extend_from_slice(&(total as u32).to_le_bytes());
+    cab.extend_from_slice(&0u32.to_le_bytes());
+    cab.extend_from_slice(&(files_offset as u32).to_le_bytes());
+    cab.extend_from_slice(&0u32.to_le_bytes());
+    cab.extend_from_slice(&[3, 1]);
+    cab.extend_from_slice(&1u16.to_le_bytes());
+    cab.extend_from_slice(&1u16.to_le_bytes());
+    cab.extend_from_slice(&0u16.to_le_bytes());
+    cab.extend_from_slice(&0u16.to_le_bytes());
+    cab.extend_from_slice(&0u16.to_le_bytes());
+
+    /* The one folder */
+    cab.extend_from_slice(&(data_offset as u32).to_le_bytes());
+    cab.extend_from_slice(&(blocks.len() as u16).to_le_bytes());
+    cab.extend_from_slice(&COMPRESS_MSZIP.to_le_bytes());
+
+    /* The one file */
+    let attributes: u16 = 0x20 | if utf8 { ATTRIB_NAME_IS_UTF } else { 0 };
+    cab.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    cab.extend_from_slice(&0u32.to_le_bytes());
+    cab.extend_from_slice(&0u16.to_le_bytes());
+    cab.extend_from_slice(&0u16.to_le_bytes());
+    cab.extend_from_slice(&0u16.to_le_bytes());
+    cab.extend_from_slice(&attributes.to_le_bytes());
+    cab.extend_from_slice(name.as_bytes());
+    cab.push(0);
+
+    for block in blocks {
+        cab.extend(block);
+    }
+
+    Ok(cab)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Reserved space in the header, each folder and each data block
+    type Reserve = (u16, u8, u8);
+
+    /// Build a CAB holding `test.dll` of `size` bytes in one folder made of
+    /// `blocks`, each being the compressed data and its uncompressed size
+    fn build(compression: u16, reserve: Option<Reserve>, size: u32,
+             blocks: &[(Vec<u8>, u16)]) -> Vec<u8>
+    {
+        let (header_reserve, folder_reserve, data_reserve) =
+            reserve.unwrap_or((0, 0, 0));
+        let reserve_size = match reserve {
+            Some(_) => 4 + header_reserve as usize,
+            None => 0,
+        };
+        let files_offset = 36 + reserve_size + 8 + folder_reserve as usize;
+        let data_offset = files_offset + 16 + b"test.dll\0".len();
+
+        let mut cab = b"MSCF".to_vec();
+        cab.extend_from_slice(&[0; 12]);
+        cab.extend_from_slice(&(files_offset as u32).to_le_bytes());
+        cab.extend_from_slice(&[0, 0, 0, 0, 3, 1, 1, 0, 1, 0]);
+        let flags = match reserve {
+            Some(_) => CAB_FLAG_RESERVE_PRESENT,
+            None => 0,
+        };
+        cab.extend_from_slice(&flags.to_le_bytes());
+        cab.extend_from_slice(&[0; 4]);
+        if reserve.is_some() {
+            cab.extend_from_slice(&header_reserve.to_le_bytes());
+            cab.extend_from_slice(&[folder_reserve, data_reserve]);
+            cab.resize(cab.len() + header_reserve as usize, 0xcc);
+        }
+
+        cab.extend_from_slice(&(data_offset as u32).to_le_bytes());
+        cab.extend_from_slice(&(blocks.len() as u16).to_le_bytes());
+        cab.extend_from_slice(&compression.to_le_bytes());
+        cab.resize(cab.len() + folder_reserve as usize, 0xcc);
+
+        cab.extend_from_slice(&size.to_le_bytes());
+        cab.extend_from_slice(&[0; 12]);
+        cab.extend_from_slice(b"test.dll\0");
+
+        for (data, uncompressed) in blocks {
+            cab.extend_from_slice(&[0; 4]);
+            cab.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            cab.extend_from_slice(&uncompressed.to_le_bytes());
+            cab.resize(cab.len() + data_reserve as usize, 0xcc);
+            cab.extend_from_slice(data);
+        }
+        cab
+    }
+
+    /// An MSZIP block holding `data`
+    fn mszip(data: &[u8]) -> (Vec<u8>, u16)
+    {
+        let mut block = b"CK".to_vec();
+        block.extend(miniz_oxide::deflate::compress_to_vec(data, 9));
+        (block, data.len() as u16)
+    }
+
+    #[test]
+    fn stored_and_limited()
+    {
+        let cab = build(COMPRESS_NONE, None, 6,
+                        &[(b"abc".to_vec(), 3), (b"def".to_vec(), 3)]);
+        let (name, data) = extract(&mut Cursor::new(&cab), None).unwrap();
+        assert_eq!(name, "test.dll");
+        assert_eq!(data, b"abcdef");
+
+        let (_, data) = extract(&mut Cursor::new(&cab), Some(2)).unwrap();
+        assert_eq!(data, b"ab");
+    }
+
+    #[test]
+    fn mszip_with_reserve()
+    {
+        let cab = build(COMPRESS_MSZIP, Some((3, 1, 2)), 10,
+                        &[mszip(b"hello"), mszip(b"world")]);
+        let (_, data) = extract(&mut Cursor::new(&cab), None).unwrap();
+        assert_eq!(data, b"helloworld");
+
+        /* Every MSZIP block starts with a signature */
+        let (mut block, size) = mszip(b"hello");
+        block[0] = b'X';
+        let cab = build(COMPRESS_MSZIP, None, 5, &[(block, size)]);
+        assert!(extract(&mut Cursor::new(&cab), None).is_err());
+    }
+
+    #[test]
+    fn lzx_folder()
+    {
+        /* One uncompressed LZX block holding a single byte */
+        let mut block = vec![0x00, 0x30, 0x10, 0x00];
+        block.extend_from_slice(&[1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+        block.extend_from_slice(&[b'A', 0x00]);
+
+        let cab = build(COMPRESS_LZX | 15 << 8, None, 1, &[(block, 1)]);
+        let (_, data) = extract(&mut Cursor::new(&cab), None).unwrap();
+        assert_eq!(data, b"A");
+    }
+
+    #[test]
+    fn rejects_bad_cabs()
+    {
+        /* Stored blocks must be the size they claim to expand to */
+        let cab = build(COMPRESS_NONE, None, 3, &[(b"abc".to_vec(), 4)]);
+        assert!(extract(&mut Cursor::new(&cab), None).is_err());
+
+        /* The file is larger than its folder */
+        let cab = build(COMPRESS_NONE, None, 4, &[(b"abc".to_vec(), 3)]);
+        assert!(extract(&mut Cursor::new(&cab), None).is_err());
+
+        /* Quantum */
+        let cab = build(2, None, 3, &[(b"abc".to_vec(), 3)]);
+        assert!(extract(&mut Cursor::new(&cab), None).is_err());
+
+        /* Continued in the next cabinet */
+        let mut cab = build(COMPRESS_NONE, None, 3, &[(b"abc".to_vec(), 3)]);
+        cab[30] |= CAB_FLAG_NEXT_CABINET as u8;
+        assert!(extract(&mut Cursor::new(&cab), None).is_err());
+    }
+
+    #[test]
+    fn truncated_cabs_do_not_panic()
+    {
+        let cab = build(COMPRESS_MSZIP, Some((3, 1, 2)), 10,
+                        &[mszip(b"hello"), mszip(b"world")]);
+        for len in 0..cab.len() {
+            assert!(extract(&mut Cursor::new(&cab[..len]), None).is_err());
+        }
+    }
+
+    #[test]
+    fn compress_round_trip()
+    {
+        /* Spans several MSZIP blocks, the last of them partial */
+        let data: Vec<u8> = (0..100000u32)
+            .map(|x| (x.wrapping_mul(x) >> 7) as u8).collect();
+        let cab = compress("ntdll.dll", &data).unwrap();
+        assert!(cab.len() < data.len());
+
+        let (name, out) = extract(&mut Cursor::new(&cab), None).unwrap();
+        assert_eq!(name, "ntdll.dll");
+        assert_eq!(out, data);
+
+        let (name, out) = extract(&mut Cursor::new(compress("\u{e9}.dll", b"")
+                                                   .unwrap()), None).unwrap();
+        assert_eq!(name, "\u{e9}.dll");
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn block_checksums()
+    {
+        assert_eq!(checksum(b"abcd", 0), 0x64636261);
+        assert_eq!(checksum(b"abcdef", 0), 0x64636261 ^ 0x6566);
+        assert_eq!(checksum(b"", 0x1234), 0x1234);
+
+        /* The checksum of every block covers its data and then its sizes */
+        let cab = compress("a.txt", b"hello").unwrap();
+        let block = &cab[36 + 8 + 16 + b"a.txt\0".len()..];
+        let stored = u32::from_le_bytes([block[0], block[1], block[2],
+                                         block[3]]);
+        assert_eq!(stored, checksum(&block[4..8], checksum(&block[8..], 0)));
+    }
+
+    #[test]
+    fn compressed_names()
+    {
+        assert_eq!(compressed_name("ntdll.pdb"), "ntdll.pd_");
+        assert_eq!(compressed_name("a.b.dll"), "a.b.dl_");
+        assert_eq!(compressed_name("noext"), "noext._");
+        assert_eq!(compressed_name("trailing."), "trailing.._");
+    }
+}
@@ -0,0 +1,486 @@
+//! Persistent cache of what scanning a file found, so re-scanning a mostly
+//! unchanged tree does not have to open every file again.
+//!
+//! Entries are keyed by path and are only used while the size, modification
+//! time and inode of the file still match. The cache is a compact binary
+//! file: a header followed by one record per file, all little endian.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use manifest::{Kind, Record};
+use version::{FixedFileInfo, VersionInfo};
+
+/// Magic and version at the start of a cache file. Bump the version when the
+/// record layout or what is parsed out of files changes.
+const CACHE_MAGIC: &[u8; 8] = b"PDBLCACH";
+const CACHE_VERSION: u32 = 5;
+
+/// What scanning a file found
+#[derive(Clone)]
+pub struct Parsed {
+    /// Manifest records for the PDB and `.dbg` file the file references
+    pub pdbs: Vec<Record>,
+
+    /// Name and key the file is stored under, if it is an image
+    pub image: Option<(String, String)>,
+}
+
+/// Identity of a file on disk at the time it was parsed
+#[derive(Clone, Copy, PartialEq)]
+struct Stamp {
+    size:  u64,
+    mtime: u64,
+    nanos: u32,
+    inode: u64,
+}
+
+struct Entry {
+    stamp:  Stamp,
+    parsed: Parsed,
+
+    /// Whether the file was looked up this run
+    seen: bool,
+}
+
+/// Cache loaded from (and saved back to) a file
+pub struct Cache {
+    path:    PathBuf,
+    entries: HashMap<String, Entry>,
+
+    /// Number of lookups answered from the cache
+    pub hits: usize,
+}
+
+#[cfg(unix)]
+fn inode(meta: &fs::Metadata) -> u64
+{
+    use std::os::unix::fs::MetadataExt;
+    meta.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &fs::Metadata) -> u64
+{
+    0
+}
+
+/// Get the current stamp of `path`
+fn stamp(path: &Path) -> Option<Stamp>
+{
+    let meta = path.metadata().ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(Stamp {
+        size:  meta.len(),
+        mtime: mtime.as_secs(),
+        nanos: mtime.subsec_nanos(),
+        inode: inode(&meta),
+    })
+}
+
+/// Cursor over the bytes of a cache file
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>>
+    {
+        if self.data.len() < len {
+            return Err("Cache file truncated".into());
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Box<dyn Error>>
+    {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Box<dyn Error>>
+    {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>>
+    {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, Box<dyn Error>>
+    {
+        Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+
+    fn string(&mut self) -> Result<String, Box<dyn Error>>
+    {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.bytes(len)?.to_vec())?)
+    }
+
+    fn record(&mut self) -> Result<Record, Box<dyn Error>>
+    {
+        let kind = match self.u8()? {
+            0 => Kind::Pdb,
+            1 => Kind::Dbg,
+            _ => return Err("Unknown record kind".into()),
+        };
+        Ok(Record {
+            kind,
+            pdb_name:   self.string()?,
+            pdb_path:   self.string()?,
+            guid:       self.string()?,
+            age:        self.u32()?,
+            source:     self.string()?,
+            machine:    self.u16()?,
+            timestamp:  self.u32()?,
+            image_size: self.u32()?,
+            version:    self.version()?,
+        })
+    }
+
+    fn version(&mut self) -> Result<Option<VersionInfo>, Box<dyn Error>>
+    {
+        if self.u8()? == 0 {
+            return Ok(None);
+        }
+
+        let fixed = if self.u8()? != 0 {
+            let mut versions = [0u16; 8];
+            for version in versions.iter_mut() {
+                *version = self.u16()?;
+            }
+            Some(FixedFileInfo {
+                file_version:    [versions[0], versions[1],
+                                  versions[2], versions[3]],
+                product_version: [versions[4], versions[5],
+                                  versions[6], versions[7]],
+                file_flags_mask: self.u32()?,
+                file_flags:      self.u32()?,
+                file_os:         self.u32()?,
+                file_type:       self.u32()?,
+                file_subtype:    self.u32()?,
+                file_date:       self.u64()?,
+            })
+        } else {
+            None
+        };
+
+        let language = self.string()?;
+        let mut strings = Vec::new();
+        for _ in 0..self.u32()? {
+            strings.push((self.string()?, self.string()?));
+        }
+        Ok(Some(VersionInfo { fixed, language, strings }))
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, val: u32)
+{
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, val: u64)
+{
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+fn put_string(out: &mut Vec<u8>, val: &str)
+{
+    put_u32(out, val.len() as u32);
+    out.extend_from_slice(val.as_bytes());
+}
+
+fn put_record(out: &mut Vec<u8>, val: &Record)
+{
+    out.push((val.kind == Kind::Dbg) as u8);
+    put_string(out, &val.pdb_name);
+    put_string(out, &val.pdb_path);
+    put_string(out, &val.guid);
+    put_u32(out, val.age);
+    put_string(out, &val.source);
+    out.extend_from_slice(&val.machine.to_le_bytes());
+    put_u32(out, val.timestamp);
+    put_u32(out, val.image_size);
+    put_version(out, &val.version);
+}
+
+fn put_version(out: &mut Vec<u8>, val: &Option<VersionInfo>)
+{
+    let val = match val {
+        Some(val) => val,
+        None => return out.push(0),
+    };
+    out.push(1);
+
+    out.push(val.fixed.is_some() as u8);
+    if let Some(fixed) = &val.fixed {
+        for version in fixed.file_version.iter()
+                .chain(&fixed.product_version) {
+            out.extend_from_slice(&version.to_le_bytes());
+        }
+        put_u32(out, fixed.file_flags_mask);
+        put_u32(out, fixed.file_flags);
+        put_u32(out, fixed.file_os);
+        put_u32(out, fixed.file_type);
+        put_u32(out, fixed.file_subtype);
+        put_u64(out, fixed.file_date);
+    }
+
+    put_string(out, &val.language);
+    put_u32(out, val.strings.len() as u32);
+    for (key, string) in &val.strings {
+        put_string(out, key);
+        put_string(out, string);
+    }
+}
+
+/// Parse the records of a cache file
+fn parse(data: &[u8]) -> Result<HashMap<String, Entry>, Box<dyn Error>>
+{
+    let mut reader = Reader { data };
+    if reader.bytes(8)? != CACHE_MAGIC || reader.u32()? != CACHE_VERSION {
+        return Err("Not a cache file or a different version".into());
+    }
+
+    let count = reader.u32()?;
+    let mut entries = HashMap::new();
+    for _ in 0..count {
+        let path = reader.string()?;
+        let stamp = Stamp {
+            size:  reader.u64()?,
+            mtime: reader.u64()?,
+            nanos: reader.u32()?,
+            inode: reader.u64()?,
+        };
+
+        let mut pdbs = Vec::new();
+        for _ in 0..reader.u8()? {
+            pdbs.push(reader.record()?);
+        }
+        let image = if reader.u8()? != 0 {
+            Some((reader.string()?, reader.string()?))
+        } else {
+            None
+        };
+
+        entries.insert(path, Entry {
+            stamp,
+            parsed: Parsed { pdbs, image },
+            seen: false,
+        });
+    }
+
+    Ok(entries)
+}
+
+impl Cache {
+    /// Load the cache at `path`. A missing or unreadable cache is simply
+    /// empty, it is rewritten on save.
+    pub fn open(path: &Path) -> Cache
+    {
+        let entries = fs::read(path).ok()
+            .and_then(|data| parse(&data).ok())
+            .unwrap_or_default();
+
+        Cache {
+            path: path.to_path_buf(),
+            entries,
+            hits: 0,
+        }
+    }
+
+    /// Get what scanning `filename` finds, from the cache if the file has not
+    /// changed since it was cached or by calling `parse` otherwise
+    pub fn get<F>(&mut self, filename: &Path, parse: F) -> Parsed
+        where F: FnOnce(&Path) -> Parsed
+    {
+        /* Paths which can't be represented are never cached */
+        let (key, stamp) = match (filename.to_str(), stamp(filename)) {
+            (Some(key), Some(stamp)) => (key, stamp),
+            _ => return parse(filename),
+        };
+
+        if let Some(entry) = self.entries.get_mut(key) {
+            if entry.stamp == stamp {
+                entry.seen = true;
+                self.hits += 1;
+                return entry.parsed.clone();
+            }
+        }
+
+        let parsed = parse(filename);
+        self.entries.insert(key.to_string(), Entry {
+            stamp,
+            parsed: parsed.clone(),
+            seen: true,
+        });
+        parsed
+    }
+
+    /// Write the cache back out. Entries for files which no longer exist are
+    /// dropped.
+    pub fn save(mut self) -> Result<(), Box<dyn Error>>
+    {
+        self.entries.retain(|path, entry| {
+            entry.seen || Path::new(path).exists()
+        });
+
+        let mut out = CACHE_MAGIC.to_vec();
+        put_u32(&mut out, CACHE_VERSION);
+        put_u32(&mut out, self.entries.len() as u32);
+        for (path, entry) in &self.entries {
+            put_string(&mut out, path);
+            put_u64(&mut out, entry.stamp.size);
+            put_u64(&mut out, entry.stamp.mtime);
+            put_u32(&mut out, entry.stamp.nanos);
+            put_u64(&mut out, entry.stamp.inode);
+
+            let parsed = &entry.parsed;
+            out.push(parsed.pdbs.len() as u8);
+            for pdb in &parsed.pdbs {
+                put_record(&mut out, pdb);
+            }
+            out.push(parsed.image.is_some() as u8);
+            if let Some((name, key)) = &parsed.image {
+                put_string(&mut out, name);
+                put_string(&mut out, key);
+            }
+        }
+
+        /* Write then rename so an interrupted save can't corrupt it */
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, out)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What scanning an image with a version resource finds
+    fn parsed() -> Parsed
+    {
+        let version = VersionInfo {
+            fixed: Some(FixedFileInfo {
+                file_version:    [10, 0, 19041, 1],
+                product_version: [10, 0, 19041, 2],
+                file_flags_mask: 0x3f,
+                file_flags:      0,
+                file_os:         0x40004,
+                file_type:       2,
+                file_subtype:    0,
+                file_date:       0x1234_5678_9abc,
+            }),
+            language: "040904B0".into(),
+            strings: vec![("FileVersion".into(), "10.0".into())],
+        };
+        let record = Record {
+            kind:       Kind::Pdb,
+            pdb_name:   "ntdll.pdb".into(),
+            pdb_path:   "d:\\\\ntdll.pdb".into(),
+            guid:       "0123456789ABCDEF0123456789ABCDEF".into(),
+            age:        1,
+            source:     "ntdll.dll".into(),
+            machine:    0x8664,
+            timestamp:  0x1234abcd,
+            image_size: 0x5000,
+            version:    Some(version),
+        };
+        let dbg = Record {
+            kind:    Kind::Dbg,
+            guid:    String::new(),
+            version: None,
+            ..record.clone()
+        };
+        Parsed {
+            pdbs:  vec![record, dbg],
+            image: Some(("ntdll.dll".into(), "1234abcd5000".into())),
+        }
+    }
+
+    /// Create an empty scratch directory for the test `name`
+    fn scratch(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("pdblister-{}-{}", name,
+                                                     std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trip_and_invalidation()
+    {
+        let dir = scratch("cache");
+        let image = dir.join("ntdll.dll");
+        let gone = dir.join("gone.dll");
+        fs::write(&image, b"MZ").unwrap();
+        fs::write(&gone, b"MZ").unwrap();
+
+        let mut cache = Cache::open(&dir.join("cache"));
+        cache.get(&image, |_| parsed());
+        cache.get(&gone, |_| parsed());
+        assert_eq!(cache.hits, 0);
+        cache.save().unwrap();
+        fs::remove_file(&gone).unwrap();
+
+        let mut cache = Cache::open(&dir.join("cache"));
+        let found = cache.get(&image, |_| panic!("cached file parsed"));
+        assert_eq!(cache.hits, 1);
+        let want = parsed();
+        assert_eq!(found.image, want.image);
+        let json = |x: &Parsed| -> Vec<String> {
+            x.pdbs.iter().map(|x| x.json()).collect()
+        };
+        assert_eq!(json(&found), json(&want));
+        cache.save().unwrap();
+
+        /* Deleted files are dropped and changed ones parsed again */
+        let cache = Cache::open(&dir.join("cache"));
+        assert_eq!(cache.entries.len(), 1);
+        fs::write(&image, b"MZ..").unwrap();
+        let mut cache = cache;
+        let found = cache.get(&image, |_| Parsed { pdbs: Vec::new(),
+                                                   image: None });
+        assert!(found.image.is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_caches()
+    {
+        let dir = scratch("cache-bad");
+        let image = dir.join("ntdll.dll");
+        fs::write(&image, b"MZ").unwrap();
+        let mut cache = Cache::open(&dir.join("cache"));
+        cache.get(&image, |_| parsed());
+        cache.save().unwrap();
+        let data = fs::read(dir.join("cache")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(parse(&data).unwrap().len(), 1);
+        for len in 0..data.len() {
+            assert!(parse(&data[..len]).is_err());
+        }
+
+        let mut other = data.clone();
+        other[8] ^= 1;
+        assert!(parse(&other).is_err());
+
+        /* Lengths past the end of the file */
+        let mut huge = data[..16].to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse(&huge).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//! The transitive dependency closure of root images, for fetching symbols
+//! for just what a process loads.
+//!
+//! Imports are resolved the way the Windows loader resolves them, with
+//! KnownDLLs and the current directory left out as they can't be known
+//! offline:
+//!
+//! 1. API sets are redirected to their host through the API set schema.
+//!    Those the schema has no host for are looked for as files, as the
+//!    loader does for the `api-ms-win-crt-*` DLLs of the redistributable
+//!    UCRT on older Windows.
+//! 2. Modules already in the closure are reused by name.
+//! 3. The directory of the root image (the application directory) is
+//!    searched, then each search directory in order, eg. `System32`, then
+//!    `System`, then the Windows directory and then `PATH`.
+//! 4. Anything else is looked up by name in a scanned tree, if there is one.
+//!
+//! Only images for the same machine as the importer are used, as the loader
+//! would fail to load any others.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+
+use open_image;
+use apiset::{self, Schema};
+use deps::{Graph, Module};
+use pe;
+use store::DirIndex;
+
+/// Where imports are looked for
+#[derive(Default)]
+pub struct Search {
+    /// Directories searched after the application directory, in order
+    pub dirs: Vec<PathBuf>,
+
+    /// Scanned tree searched last
+    pub tree: Option<Graph>,
+
+    /// API set schema redirecting `api-ms-win-*` imports, if not that of
+    /// `tree`
+    pub schema: Option<Schema>,
+
+    /// Whether delay-load imports are followed too
+    pub delay: bool,
+
+    /// Listings of the directories searched so far
+    listings: RefCell<DirIndex>,
+}
+
+/// The modules roots load
+pub struct Closure {
+    /// Every module, roots first and then in the order they were found
+    pub modules: Vec<Module>,
+
+    /// Names of the DLLs which could not be found, lowercased
+    pub missing: BTreeSet<String>,
+}
+
+/// Read the imports of the image at `path`
+pub fn load(path: &Path) -> Option<Module>
+{
+    let mut image = open_image(path).ok()?;
+    let mut parsed = pe::parse(&mut image.stream);
+    parsed.decode_imports(&mut image.stream);
+    Module::new(&path.display().to_string(), &image.name, &parsed)
+}
+
+impl Search {
+    /// Find the file named `name` in `dir`, ignoring case like Windows
+    fn find_in_dir(&self, dir: &Path, name: &str) -> Option<PathBuf>
+    {
+        self.listings.borrow_mut().find(dir, name).filter(|x| x.is_file())
+    }
+
+    /// Find the image a `machine` image loads for `name` in `app_dir`, the
+    /// search directories and the tree, in that order. With no `machine`
+    /// any image with the name is taken.
+    pub fn find(&self, name: &str, machine: Option<u16>,
+                app_dir: Option<&Path>) -> Option<Module>
+    {
+        let matches = |module: &Module| {
+            machine.is_none_or(|x| x == module.machine)
+        };
+
+        let dirs = app_dir.into_iter()
+            .chain(self.dirs.iter().map(|x| x.as_path()));
+        for dir in dirs {
+            let module = self.find_in_dir(dir, name).and_then(|x| load(&x));
+            if let Some(module) = module.filter(matches) {
+                return Some(module);
+            }
+        }
+
+        let tree = self.tree.as_ref()?;
+        tree.resolve(name, machine.unwrap_or(0)).into_iter()
+            .map(|x| &tree.modules[x])
+            .find(|x| matches(x))
+            .cloned()
+    }
+
+    /// Read the API set schema from the `apisetschema.dll` of the search
+    /// directories, which is in `System32` on any Windows recent enough to
+    /// have one. Without one there the schema of the tree is used.
+    pub fn load_schema(&mut self) -> Result<(), String>
+    {
+        let path = self.dirs.iter()
+            .find_map(|x| self.find_in_dir(x, "apisetschema.dll"));
+        match path {
+            Some(path) => {
+                self.schema = Some(apiset::load(&path).map_err(|x| {
+                    format!("{}: {}", path.display(), x)
+                })?);
+                Ok(())
+            }
+            None if self.schema().is_some() => Ok(()),
+            None => Err("apisetschema.dll not found".into()),
+        }
+    }
+
+    /// Get the API set schema in use
+    fn schema(&self) -> Option<&Schema>
+    {
+        self.schema.as_ref()
+            .or_else(|| self.tree.as_ref().and_then(|x| x.schema.as_ref()))
+    }
+
+    /// Get the closure of the images at `roots`. Roots which aren't images
+    /// are left out.
+    pub fn closure(&self, roots: &[PathBuf]) -> Closure
+    {
+        let mut closure = Closure {
+            modules: Vec::new(),
+            missing: BTreeSet::new(),
+        };
+
+        /* Names looked for so far, whether they were found or not, by
+         * machine. Also the application directory each module was loaded
+         * for. */
+        let mut seen: HashSet<(String, u16)> = HashSet::new();
+        let mut app_dirs: Vec<PathBuf> = Vec::new();
+        for root in roots {
+            if let Some(module) = load(root) {
+                if seen.insert((module.name.to_lowercase(), module.machine)) {
+                    app_dirs.push(root.parent().unwrap_or(Path::new(""))
+                                  .to_path_buf());
+                    closure.modules.push(module);
+                }
+            }
+        }
+
+        /* Modules are appended as they are found, so walking them in order
+         * is a breadth first search */
+        let mut ii = 0;
+        while ii < closure.modules.len() {
+            let (importer, machine) = (closure.modules[ii].name.clone(),
+                                       closure.modules[ii].machine);
+            let deps = closure.modules[ii].dependencies.clone();
+            for dep in deps {
+                if dep.delay && !self.delay {
+                    continue;
+                }
+
+                let host = self.schema()
+                    .and_then(|x| x.resolve(&dep.name, &importer));
+                let name = host.unwrap_or(&dep.name).to_string();
+
+                if !seen.insert((name.to_lowercase(), machine)) {
+                    continue;
+                }
+                match self.find(&name, Some(machine), Some(&app_dirs[ii])) {
+                    Some(module) => {
+                        seen.insert((module.name.to_lowercase(), machine));
+                        closure.modules.push(module);
+                        app_dirs.push(app_dirs[ii].clone());
+                    }
+                    None => {
+                        closure.missing.insert(name.to_lowercase());
+                    }
+                }
+            }
+            ii += 1;
+        }
+        closure
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use apiset::{ApiSet, Host};
+
+    /// Create an empty scratch directory for the test `name`
+    fn scratch(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("pdblister-{}-{}", name,
+                                                     std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write an image importing `deps`, given as names with a leading `~`
+    /// for delay-loaded ones, to `path`. Sections are aligned like files,
+    /// so RVAs are file offsets.
+    fn image(path: &Path, pe64: bool, deps: &[&str])
+    {
+        let mut out = vec![0u8; 0x800];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            out[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(0, b"MZ");
+        put(0x3c, &0x40u32.to_le_bytes());
+        put(0x40, b"PE\0\0");
+        let (machine, magic, size) = if pe64 {
+            (pe::IMAGE_FILE_MACHINE_AMD64, 0x20bu16, 112)
+        } else {
+            (pe::IMAGE_FILE_MACHINE_I386, 0x10b, 96)
+        };
+        put(0x44, &machine.to_le_bytes());
+        put(0x54, &(size as u16 + 16 * 8).to_le_bytes());
+
+        let optional = 0x58;
+        put(optional, &magic.to_le_bytes());
+        put(optional + 32, &0x200u32.to_le_bytes());
+        put(optional + 36, &0x200u32.to_le_bytes());
+        put(optional + 60, &0x200u32.to_le_bytes());
+        put(optional + size - 4, &16u32.to_le_bytes());
+
+        /* Import descriptors at 0x200 and delay-load ones at 0x300, all
+         * with an empty thunk table at 0x3f0 */
+        let dirs = optional + size;
+        put(dirs + 8, &0x200u32.to_le_bytes());
+        put(dirs + 12, &0x100u32.to_le_bytes());
+        put(dirs + 13 * 8, &0x300u32.to_le_bytes());
+        put(dirs + 13 * 8 + 4, &0xf0u32.to_le_bytes());
+        let (mut import, mut delay) = (0x200, 0x300);
+        for (ii, dep) in deps.iter().enumerate() {
+            let name = 0x400 + ii * 0x40;
+            put(name, dep.trim_start_matches('~').as_bytes());
+            let name = name as u32;
+            if dep.starts_with('~') {
+                for (jj, val) in [1, name, 0, 0, 0x3f0].iter().enumerate() {
+                    put(delay + jj * 4, &val.to_le_bytes());
+                }
+                delay += 32;
+            } else {
+                put(import + 12, &name.to_le_bytes());
+                put(import + 16, &0x3f0u32.to_le_bytes());
+                import += 20;
+            }
+        }
+        fs::write(path, out).unwrap();
+    }
+
+    fn paths(closure: &Closure, dir: &Path) -> Vec<String>
+    {
+        closure.modules.iter().map(|x| {
+            Path::new(&x.path).strip_prefix(dir).unwrap()
+                .to_string_lossy().replace('\\', "/")
+        }).collect()
+    }
+
+    #[test]
+    fn closure_of_cycles_and_api_sets()
+    {
+        let dir = scratch("closure");
+        let (app, sys) = (dir.join("app"), dir.join("sys"));
+        fs::create_dir_all(&app).unwrap();
+        fs::create_dir_all(&sys).unwrap();
+
+        image(&app.join("a.exe"), false,
+              &["B.DLL", "api-ms-win-core-l1-1-0.dll", "~d.dll"]);
+        image(&sys.join("b.dll"), false,
+              &["c.dll", "missing.dll", "api-ms-win-crt-l1-1-0.dll"]);
+        image(&sys.join("c.dll"), false, &["b.dll"]);
+        image(&app.join("c.dll"), true, &[]);
+        image(&sys.join("KernelBase.dll"), false, &[]);
+        image(&sys.join("d.dll"), false, &[]);
+        image(&sys.join("api-ms-win-crt-l1-1-0.dll"), false, &[]);
+
+        /* API sets the schema has no host for are looked for as files */
+        let mut search = Search {
+            dirs:   vec![sys.clone()],
+            schema: Some(Schema::new(6, vec![ApiSet {
+                name:  "api-ms-win-core-l1-1-0".into(),
+                hosts: vec![Host {
+                    importer: None,
+                    host:     "kernelbase.dll".into(),
+                }],
+            }])),
+            ..Search::default()
+        };
+        let roots = [app.join("a.exe"), app.join("a.exe"),
+                     app.join("missing.exe")];
+        let closure = search.closure(&roots);
+        assert_eq!(paths(&closure, &dir), ["app/a.exe", "sys/b.dll",
+                                           "sys/KernelBase.dll", "sys/c.dll",
+                                           "sys/api-ms-win-crt-l1-1-0.dll"]);
+        let missing: Vec<&str> = closure.missing.iter()
+            .map(|x| x.as_str()).collect();
+        assert_eq!(missing, ["missing.dll"]);
+
+        search.delay = true;
+        let closure = search.closure(&roots);
+        assert_eq!(paths(&closure, &dir)[3], "sys/d.dll");
+        assert_eq!(closure.modules.len(), 6);
+
+        /* Without a schema the API set itself is missing */
+        search.schema = None;
+        let closure = search.closure(&roots);
+        assert!(closure.missing.contains("api-ms-win-core-l1-1-0.dll"));
+        assert!(search.load_schema().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1061 @@
+//! The commands of the command line, each a function taking its parsed
+//! options.
+//!
+//! Commands report what they do and what they skip as they go. Errors which
+//! stop a command are returned and printed by `main`.
+
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::Entry;
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use rand::{thread_rng, Rng};
+
+use {cache, closure, download, info, json, manifest, pe, rich, serve, store,
+     symstore, wim};
+use {Damage, STATUS_MESSAGES};
+use {compress_entry, dependency_graph, download_native, download_worker,
+     get_wim_pdbs, is_signed_by, is_stored, load_apiset_schema, open_dedup,
+     open_image, open_store_file, parse_cached, parse_case, parse_file,
+     recursive_listdir, save_cache, store_image, verify_entry};
+#[cfg(target_os = "linux")]
+use watch_dir;
+use options::Options;
+
+type CommandResult = Result<(), Box<dyn Error>>;
+
+/// A command and what it accepts
+pub struct Command {
+    /// Name of the command, two words for the `store` commands
+    pub name: &'static str,
+
+    /// Options which are switches, and options which take a value
+    pub switches: &'static [&'static str],
+    pub valued:   &'static [&'static str],
+
+    /// Least and most positional arguments
+    pub min_args: usize,
+    pub max_args: usize,
+
+    pub run: fn(&Options) -> CommandResult,
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "manifest", switches: &[],
+        valued: &["--cache", "--signed-by", "--format"],
+        min_args: 1, max_args: 1, run: cmd_manifest,
+    },
+    Command {
+        name: "manifest-diff",
+        switches: &["--json", "--added", "--removed", "--common"],
+        valued: &[], min_args: 2, max_args: 2, run: cmd_manifest_diff,
+    },
+    Command {
+        name: "manifest-merge", switches: &[], valued: &[],
+        min_args: 2, max_args: usize::MAX, run: cmd_manifest_merge,
+    },
+    Command {
+        name: "wim", switches: &[], valued: &[],
+        min_args: 1, max_args: 2, run: cmd_wim,
+    },
+    Command {
+        name: "download", switches: &["--native"], valued: &[],
+        min_args: 1, max_args: 1, run: cmd_download,
+    },
+    Command {
+        name: "filestore", switches: &["--compress"],
+        valued: &["--dedup", "--dedup-index", "--case", "--cache",
+                  "--signed-by"],
+        min_args: 1, max_args: 1, run: cmd_filestore,
+    },
+    Command {
+        name: "add", switches: &["--compress"],
+        valued: &["--dedup", "--dedup-index", "--version", "--comment",
+                  "--case"],
+        min_args: 3, max_args: 3, run: cmd_add,
+    },
+    Command {
+        name: "store prune", switches: &["--dry-run"],
+        valued: &["--transaction", "--days", "--max-size"],
+        min_args: 1, max_args: 1, run: cmd_store_prune,
+    },
+    Command {
+        name: "store verify", switches: &["--repair"],
+        valued: &["--quarantine"],
+        min_args: 1, max_args: 1, run: cmd_store_verify,
+    },
+    Command {
+        name: "serve", switches: &[],
+        valued: &["--listen", "--upstream", "--case"],
+        min_args: 1, max_args: 1, run: cmd_serve,
+    },
+    Command {
+        name: "compress", switches: &[], valued: &[],
+        min_args: 1, max_args: 1, run: cmd_compress,
+    },
+    Command {
+        name: "deps", switches: &["--dot", "--json"], valued: &["--loaders"],
+        min_args: 1, max_args: 1, run: cmd_deps,
+    },
+    Command {
+        name: "closure", switches: &["--no-delay-load"],
+        valued: &["--path", "--tree", "--format"],
+        min_args: 0, max_args: usize::MAX, run: cmd_closure,
+    },
+    Command {
+        name: "apiset", switches: &["--json"],
+        valued: &["--resolve", "--importer"],
+        min_args: 1, max_args: 1, run: cmd_apiset,
+    },
+    Command {
+        name: "toolchain", switches: &["--json"], valued: &["--older-than"],
+        min_args: 1, max_args: 1, run: cmd_toolchain,
+    },
+    Command {
+        name: "info", switches: &["--json"], valued: &[],
+        min_args: 1, max_args: 1, run: cmd_info,
+    },
+    Command {
+        name: "convertstore", switches: &[], valued: &[],
+        min_args: 2, max_args: 2, run: cmd_convertstore,
+    },
+    Command {
+        name: "watch", switches: &[], valued: &[],
+        min_args: 1, max_args: 1, run: cmd_watch,
+    },
+    Command {
+        name: "clean", switches: &[], valued: &[],
+        min_args: 0, max_args: 0, run: cmd_clean,
+    },
+];
+
+/// Find the command `args` (without the program name) start with, giving it
+/// and the arguments following its name
+pub fn find(args: &[String]) -> Option<(&'static Command, &[String])>
+{
+    COMMANDS.iter().find_map(|command| {
+        let words: Vec<&str> = command.name.split(' ').collect();
+        let matches = args.len() >= words.len() &&
+            words.iter().zip(args).all(|(word, arg)| word == arg);
+        if matches { Some((command, &args[words.len()..])) } else { None }
+    })
+}
+
+/// Get the output format given with `--format`, symchk if there is none
+fn format_option(opts: &Options) -> Result<manifest::Format, Box<dyn Error>>
+{
+    match opts.value("--format") {
+        Some(name) => manifest::Format::parse(name)
+            .ok_or_else(|| "--format must be symchk, jsonl or csv".into()),
+        None => Ok(manifest::Format::Symchk),
+    }
+}
+
+/// Get the case given with `--case`, preserve if there is none
+fn case_option(opts: &Options) -> Result<store::Case, Box<dyn Error>>
+{
+    match opts.value("--case") {
+        Some(case) => parse_case(case)
+            .ok_or_else(|| "--case must be preserve or symsrv".into()),
+        None => Ok(store::Case::Preserve),
+    }
+}
+
+/// Open the parse cache given with `--cache`, if there is one
+fn cache_option(opts: &Options) -> Option<cache::Cache>
+{
+    opts.value("--cache").map(|path| cache::Cache::open(Path::new(path)))
+}
+
+fn cmd_manifest(opts: &Options) -> CommandResult
+{
+    let mut cache = cache_option(opts);
+    let format = format_option(opts)?;
+    let signed_by = opts.value("--signed-by");
+
+    /* List all files in the directory specified */
+    println!("Generating file listing...");
+    let listing = recursive_listdir(Path::new(opts.args[0])).
+        expect("Failed to list directory");
+    println!("Done!");
+
+    /* For each file, try to parse PDB information out of it and print the
+     * manifest-style information to the screen.
+     */
+    let mut output_pdbs = Vec::new();
+    for (ii, filename) in listing.iter().enumerate() {
+        let pdbs = parse_cached(&mut cache, filename).pdbs;
+        if !pdbs.is_empty() &&
+                signed_by.is_none_or(|x| is_signed_by(filename, x)) {
+            output_pdbs.extend(pdbs);
+        }
+
+        if STATUS_MESSAGES {
+            print!("\rParsed {} of {} files ({} pdbs)",
+                ii + 1, listing.len(), output_pdbs.len());
+        }
+    }
+    println!();
+    save_cache(cache);
+
+    let mut output_file = File::create(format.file_name()).
+        expect("Failed to create output manifest file");
+
+    /* Write out all the PDBs */
+    output_file.write_all(manifest::format(&output_pdbs, format)
+                          .as_bytes()).
+        expect("Failed to write pdbs to manifest file");
+    Ok(())
+}
+
+fn cmd_manifest_diff(opts: &Options) -> CommandResult
+{
+    let output = opts.one_of(&["--json", "--added", "--removed",
+                               "--common"])?;
+    let old = manifest::Manifest::open(Path::new(opts.args[0]))?;
+    let new = manifest::Manifest::open(Path::new(opts.args[1]))?;
+    let diff = old.diff(&new);
+
+    let entries = match output {
+        None => None,
+        Some("--json") => {
+            println!("{}", diff.json());
+            return Ok(());
+        }
+        Some("--added")   => Some(&diff.added),
+        Some("--removed") => Some(&diff.removed),
+        Some(_)           => Some(&diff.common),
+    };
+    if let Some(entries) = entries {
+        let entries = entries.clone();
+        println!("{}", manifest::Manifest { entries }.text());
+        return Ok(());
+    }
+
+    /* Common entries are only counted, listing them would bury the
+     * changes */
+    for (name, group) in diff.by_name() {
+        if group.added.is_empty() && group.removed.is_empty() {
+            continue;
+        }
+        println!("{}: {} added, {} removed, {} in common", name,
+                 group.added.len(), group.removed.len(),
+                 group.common.len());
+        for entry in group.added {
+            println!("    + {}", entry.line());
+        }
+        for entry in group.removed {
+            println!("    - {}", entry.line());
+        }
+    }
+    println!("{} added, {} removed, {} in common", diff.added.len(),
+             diff.removed.len(), diff.common.len());
+    Ok(())
+}
+
+fn cmd_manifest_merge(opts: &Options) -> CommandResult
+{
+    let output = opts.args[0];
+    let mut manifests = Vec::new();
+    for path in &opts.args[1..] {
+        manifests.push(manifest::Manifest::open(Path::new(path))?);
+    }
+
+    let merged = manifest::Manifest::merge(&manifests);
+    let total: usize = manifests.iter().map(|x| x.entries.len()).sum();
+    std::fs::write(output, merged.text())
+        .map_err(|err| format!("Failed to write {}: {}", output, err))?;
+    println!("Merged {} entries into {} unique entries in {}", total,
+             merged.entries.len(), output);
+    Ok(())
+}
+
+fn cmd_wim(opts: &Options) -> CommandResult
+{
+    let mut wim = wim::Wim::open(Path::new(opts.args[0]))
+        .map_err(|err| format!("Failed to open WIM: {}", err))?;
+
+    /* Either scan the one image requested or all of them */
+    let images: Vec<u32> = match opts.args.get(1) {
+        Some(index) => vec![index.parse()
+            .map_err(|_| format!("Invalid image index {}", index))?],
+        None => (1..=wim.image_count()).collect(),
+    };
+
+    /* Results of parsing each unique blob, as the same file is commonly
+     * present in every image of a WIM */
+    let mut parsed: HashMap<[u8; 20], Vec<String>> = HashMap::new();
+
+    let mut output_pdbs = Vec::new();
+    for image in images {
+        let files = match wim.image_files(image) {
+            Ok(files) => files,
+            Err(err) => {
+                println!("Failed to read image {}: {}", image, err);
+                continue;
+            }
+        };
+
+        let mut pdbs = 0;
+        for (ii, file) in files.iter().enumerate() {
+            let manifest_strs = match parsed.entry(file.hash) {
+                Entry::Occupied(ent) => ent.into_mut(),
+                Entry::Vacant(ent) => {
+                    let manifest_strs = get_wim_pdbs(&mut wim, file);
+                    output_pdbs.extend(manifest_strs.iter().cloned());
+                    ent.insert(manifest_strs)
+                }
+            };
+
+            pdbs += manifest_strs.len();
+
+            if STATUS_MESSAGES {
+                print!("\rImage {}: parsed {} of {} files ({} pdbs)",
+                    image, ii + 1, files.len(), pdbs);
+            }
+        }
+        println!();
+    }
+
+    let mut output_file = File::create("manifest").
+        expect("Failed to create output manifest file");
+
+    /* Write out all the unique PDBs */
+    output_file.write_all(output_pdbs.join("\n").as_bytes()).
+        expect("Failed to write pdbs to manifest file");
+    Ok(())
+}
+
+fn cmd_download(opts: &Options) -> CommandResult
+{
+    const NUM_PIECES: usize = 64;
+
+    let sympath = opts.args[0];
+    let native = if opts.switch("--native") {
+        Some(download::parse_sympath(sympath)
+             .ok_or("--native needs a symbol path of the form \
+                     srv*<store>*<url>")?)
+    } else {
+        None
+    };
+
+    if !Path::new("manifest").exists() {
+        return Err("Failed to open manifest, did you create one?".into());
+    }
+
+    /* Read and validate the manifest */
+    let mut entries = manifest::Manifest::open(Path::new("manifest"))?
+        .entries;
+
+    /* If there is nothing to download, return out early */
+    if entries.is_empty() {
+        println!("Nothing to download");
+        return Ok(());
+    }
+
+    /* Calculate number of entries per temporary manifest to split into
+     * NUM_PIECES chunks.
+     */
+    let chunk_size = entries.len().div_ceil(NUM_PIECES);
+
+    println!("Original manifest has {} PDBs", entries.len());
+
+    entries.sort_by_key(|x| x.line());
+    entries.dedup();
+
+    println!("Deduped manifest has {} PDBs", entries.len());
+
+    /* Shuffle filenames so files are not biased to the downloader based
+     * on name. This should lead to download threads having more even
+     * work.
+     */
+    thread_rng().shuffle(&mut entries);
+
+    if let Some((root, url)) = native {
+        download_native(&root, &url, entries, chunk_size);
+        return Ok(());
+    }
+
+    /* Create worker threads downloading each chunk using symchk, with
+     * the entries turned back into lines as symchk expects them */
+    let mut threads = Vec::new();
+    for (ii, entries) in entries.chunks(chunk_size).enumerate() {
+        let lines: Vec<String> = entries.iter().map(|x| x.line()).collect();
+        let mut tmp_path = env::temp_dir();
+        tmp_path.push(format!("manifest_{:04}", ii));
+
+        {
+            /* Create chunked manifest file */
+            let mut fd = File::create(&tmp_path).
+                expect("Failed to create split manifest");
+            fd.write_all(lines.join("\n").as_bytes()).
+                expect("Failed to write split manifest");
+        }
+
+        /* Create worker */
+        let sympath = sympath.to_string();
+        threads.push(thread::spawn(move || {
+            download_worker(tmp_path, sympath);
+        }));
+    }
+
+    /* Wait for all threads to complete. Discard return status */
+    for thr in threads {
+        let _ = thr.join();
+    }
+    Ok(())
+}
+
+fn cmd_filestore(opts: &Options) -> CommandResult
+{
+    let compress = opts.switch("--compress");
+    let case = case_option(opts)?;
+    let mut cache = cache_option(opts);
+    let signed_by = opts.value("--signed-by");
+
+    /* List all files in the directory specified */
+    println!("Generating file listing...");
+    let listing = recursive_listdir(Path::new(opts.args[0])).
+        expect("Failed to list directory");
+    println!("Done!");
+
+    /* Write in whichever layout the filestore already uses */
+    let root = Path::new("filestore");
+    let layout = store::detect_layout(root);
+
+    let mut dedup = open_dedup(root, opts.value("--dedup"),
+                               opts.value("--dedup-index"))
+        .map_err(|err| format!("Failed to open dedup index: {}", err))?;
+
+    let mut index = store::DirIndex::new();
+    let mut copies = 0;
+    for (ii, filename) in listing.iter().enumerate() {
+        /* Only files which aren't stored yet need to be opened again */
+        if let Some((name, key)) = parse_cached(&mut cache, filename).image {
+            let fsname = index.entry_path(root, layout, case, &name, &key);
+            let fsname = fsname.as_path();
+
+            if !is_stored(&mut index, fsname) &&
+                    signed_by.is_none_or(|x| is_signed_by(filename, x)) {
+                let res = open_image(filename).and_then(|image| {
+                    if let Some(dir) = fsname.parent() {
+                        std::fs::create_dir_all(dir)?;
+                    }
+                    store_image(image, filename, fsname, compress,
+                                dedup.as_mut())
+                });
+                match res {
+                    Ok(()) => copies += 1,
+                    Err(err) => println!("Failed to copy file {:?}: {}",
+                                         filename, err),
+                }
+            }
+        }
+
+        if STATUS_MESSAGES {
+            print!("\rParsed {} of {} files ({} copies)",
+                ii + 1, listing.len(), copies);
+        }
+    }
+    println!();
+    save_cache(cache);
+
+    if let Some(dedup) = dedup {
+        println!("Deduplicated {} files, saving {} bytes",
+                 dedup.linked, dedup.saved);
+    }
+    Ok(())
+}
+
+fn cmd_add(opts: &Options) -> CommandResult
+{
+    let root = Path::new(opts.args[0]);
+    let compress = opts.switch("--compress");
+    let case = case_option(opts)?;
+
+    let mut transaction = symstore::Transaction::new(
+            root, opts.args[2], opts.value("--version").unwrap_or(""),
+            opts.value("--comment").unwrap_or(""))
+        .map_err(|err| format!("Failed to start transaction: {}", err))?;
+
+    /* Accept either a single file or a directory to walk */
+    let path = Path::new(opts.args[1]);
+    let listing = if path.is_file() {
+        vec![path.to_path_buf()]
+    } else {
+        println!("Generating file listing...");
+        let listing = recursive_listdir(path).
+            expect("Failed to list directory");
+        println!("Done!");
+        listing
+    };
+
+    let layout = store::detect_layout(root);
+
+    let mut dedup = open_dedup(root, opts.value("--dedup"),
+                               opts.value("--dedup-index"))
+        .map_err(|err| format!("Failed to open dedup index: {}", err))?;
+
+    let mut index = store::DirIndex::new();
+    let mut added = 0;
+    for (ii, filename) in listing.iter().enumerate() {
+        /* Files which are not images still count towards the progress */
+        if let Ok((image, key)) = open_store_file(filename) {
+            let name = case.name(&image.name);
+            let key = case.key(&key);
+            let fsname = index.entry_path(root, layout, case, &name, &key);
+
+            /* Files already in the store are still referenced by this
+             * transaction, just like symstore does */
+            let stored = is_stored(&mut index, &fsname) || {
+                let res = fsname.parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .map_err(|err| err.into())
+                    .and_then(|_| store_image(image, filename, &fsname,
+                                              compress, dedup.as_mut()));
+                if let Err(err) = &res {
+                    println!("Failed to copy file {:?}: {}", filename, err);
+                }
+                res.is_ok()
+            };
+
+            if stored {
+                transaction.add(&name, &key, filename);
+                added += 1;
+            }
+        }
+
+        if STATUS_MESSAGES {
+            print!("\rParsed {} of {} files ({} added)",
+                ii + 1, listing.len(), added);
+        }
+    }
+    println!();
+
+    if let Some(dedup) = dedup {
+        println!("Deduplicated {} files, saving {} bytes",
+                 dedup.linked, dedup.saved);
+    }
+
+    if added == 0 {
+        println!("No files to add");
+        return Ok(());
+    }
+    let id = transaction.id;
+    transaction.commit(root)
+        .map_err(|err| format!("Failed to commit transaction: {}", err))?;
+    println!("Committed transaction {:010}", id);
+    Ok(())
+}
+
+fn cmd_store_prune(opts: &Options) -> CommandResult
+{
+    let root = Path::new(opts.args[0]);
+    let dry_run = opts.switch("--dry-run");
+
+    let transaction = match opts.value("--transaction") {
+        Some(id) => Some(id.parse::<u32>()
+                         .map_err(|_| "--transaction needs a transaction ID")?),
+        None => None,
+    };
+    let number = |opt: &str| -> Result<Option<u64>, Box<dyn Error>> {
+        match opts.value(opt) {
+            Some(val) => Ok(Some(val.parse()
+                                 .map_err(|_| format!("{} needs a number",
+                                                      opt))?)),
+            None => Ok(None),
+        }
+    };
+    let days = number("--days")?;
+    let max_size = number("--max-size")?;
+    if transaction.is_some() && (days.is_some() || max_size.is_some()) {
+        return Err("--transaction can't be used with --days or --max-size"
+                   .into());
+    }
+
+    /* Gather the `<name>/<key>` directories to delete */
+    let mut doomed = Vec::new();
+    if let Some(id) = transaction {
+        let files = symstore::unreferenced_files(root, id)
+            .map_err(|err| format!("Failed to read transaction: {}", err))?;
+
+        let mut index = store::DirIndex::new();
+        for (name, key) in files {
+            if let Some(dir) = index.find_entry_dir(root, &name, &key) {
+                doomed.push(dir);
+            }
+        }
+    } else if days.is_some() || max_size.is_some() {
+        /* Least recently used first. Stores are often mounted noatime,
+         * so a file added recently counts as used. */
+        let mut dirs: HashMap<PathBuf, std::time::SystemTime> =
+            HashMap::new();
+        for entry in store::list_entries(root) {
+            let meta = match entry.path.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            let used = std::cmp::max(
+                meta.accessed().unwrap_or(std::time::UNIX_EPOCH),
+                meta.modified().unwrap_or(std::time::UNIX_EPOCH));
+
+            let dir = entry.path.parent().unwrap().to_path_buf();
+            let last = dirs.entry(dir).or_insert(used);
+            *last = std::cmp::max(*last, used);
+        }
+        let mut dirs: Vec<_> = dirs.into_iter().collect();
+        dirs.sort_by_key(|x| x.1);
+        let mut dirs = VecDeque::from(dirs);
+
+        if let Some(days) = days {
+            let cutoff = days.checked_mul(86400)
+                .map(std::time::Duration::from_secs)
+                .and_then(|x| std::time::SystemTime::now().checked_sub(x))
+                .ok_or("--days is too large")?;
+            while dirs.front().is_some_and(|x| x.1 < cutoff) {
+                doomed.push(dirs.pop_front().unwrap().0);
+            }
+        }
+
+        if let Some(max_size) = max_size {
+            let mut total: u64 =
+                dirs.iter().map(|x| store::dir_size(&x.0)).sum();
+            for (dir, _) in dirs {
+                if total <= max_size {
+                    break;
+                }
+                total -= store::dir_size(&dir);
+                doomed.push(dir);
+            }
+        }
+    } else {
+        return Err("Nothing to prune, give --transaction, --days or \
+                    --max-size".into());
+    }
+
+    let mut freed = 0;
+    let mut failed = 0;
+    for dir in &doomed {
+        let size = store::dir_size(dir);
+        if dry_run {
+            println!("Would delete {:?} ({} bytes)", dir, size);
+        } else if let Err(err) = store::remove_entry_dir(root, dir) {
+            println!("Failed to delete {:?}: {}", dir, err);
+            failed += 1;
+            continue;
+        }
+        freed += size;
+    }
+
+    /* The transaction stays live until all of its files are gone, so
+     * the deletion can be retried */
+    if let (Some(id), false) = (transaction, dry_run) {
+        if failed != 0 {
+            println!("Not recording deletion of transaction {:010}, {} \
+                      entries could not be deleted", id, failed);
+        } else if let Err(err) = symstore::record_delete(root, id) {
+            println!("Failed to record deletion: {}", err);
+        }
+    }
+
+    if dry_run {
+        println!("Would free {} bytes in {} entries", freed, doomed.len());
+    } else {
+        println!("Freed {} bytes in {} entries", freed, doomed.len());
+    }
+    Ok(())
+}
+
+fn cmd_store_verify(opts: &Options) -> CommandResult
+{
+    let root = Path::new(opts.args[0]);
+    let quarantine = opts.value("--quarantine").map(PathBuf::from);
+    let repair = opts.switch("--repair") || quarantine.is_some();
+
+    println!("Generating store listing...");
+    let listing = store::list_entries(root);
+    println!("Done!");
+
+    let layout = store::detect_layout(root);
+    let mut index = store::DirIndex::new();
+
+    let mut damaged = 0;
+    let mut fixed = 0;
+    for entry in &listing {
+        let damage = match verify_entry(entry) {
+            Ok(()) => continue,
+            Err(damage) => damage,
+        };
+        damaged += 1;
+
+        let path = &entry.path;
+        match &damage {
+            Damage::Empty => println!("{:?}: empty file", path),
+            Damage::Stray => println!("{:?}: stray file", path),
+            Damage::Corrupt(err) => println!("{:?}: {}", path, err),
+            Damage::Misfiled(key) =>
+                println!("{:?}: stored under the wrong key, should be {}",
+                         path, key),
+        }
+
+        if !repair {
+            continue;
+        }
+
+        /* Misfiled entries are moved to where they belong, everything
+         * else is deleted or quarantined */
+        let res = match (&damage, &quarantine) {
+            (Damage::Misfiled(key), _) => {
+                let dest = index.entry_dir(root, layout,
+                    store::Case::Preserve, &entry.name, key)
+                    .join(path.file_name().unwrap());
+                if dest.exists() {
+                    std::fs::remove_file(path)
+                } else {
+                    std::fs::create_dir_all(dest.parent().unwrap())
+                        .and_then(|_| std::fs::rename(path, &dest))
+                }
+            }
+            (_, Some(dir)) => {
+                let dest = dir.join(path.strip_prefix(root).unwrap());
+                std::fs::create_dir_all(dest.parent().unwrap())
+                    .and_then(|_| std::fs::rename(path, &dest))
+            }
+            (_, None) => std::fs::remove_file(path),
+        };
+
+        match res {
+            Ok(()) => {
+                fixed += 1;
+                store::remove_empty_dirs(root, path.parent().unwrap());
+            }
+            Err(err) => println!("Failed to fix {:?}: {}", path, err),
+        }
+    }
+
+    println!("Checked {} files, {} damaged, {} fixed",
+             listing.len(), damaged, fixed);
+    Ok(())
+}
+
+fn cmd_serve(opts: &Options) -> CommandResult
+{
+    let listen = opts.value("--listen").unwrap_or("127.0.0.1:8080");
+    let upstream = opts.value("--upstream").map(|x| x.to_string());
+    let case = case_option(opts)?;
+
+    serve::serve(Path::new(opts.args[0]), listen, upstream, case)
+        .map_err(|err| format!("Failed to serve: {}", err).into())
+}
+
+fn cmd_compress(opts: &Options) -> CommandResult
+{
+    /* List all entries in the store specified */
+    println!("Generating store listing...");
+    let listing = store::list_entries(Path::new(opts.args[0]));
+    println!("Done!");
+
+    let mut compressed = 0;
+    let mut saved = 0i64;
+    for (ii, entry) in listing.iter().enumerate() {
+        match compress_entry(entry) {
+            Ok(Some(size)) => {
+                compressed += 1;
+                saved += size;
+            }
+            Ok(None) => {}
+            Err(_) => {
+                println!("Failed to compress file {:?}", entry.path)
+            }
+        }
+
+        if STATUS_MESSAGES {
+            print!("\rProcessed {} of {} files ({} compressed)",
+                ii + 1, listing.len(), compressed);
+        }
+    }
+    println!();
+    println!("Saved {} bytes", saved);
+    Ok(())
+}
+
+fn cmd_deps(opts: &Options) -> CommandResult
+{
+    let format = opts.one_of(&["--dot", "--json"])?;
+    let (dot, json) = (format == Some("--dot"), format == Some("--json"));
+    let graph = dependency_graph(Path::new(opts.args[0]))
+        .map_err(|err| format!("Failed to list {}: {}", opts.args[0], err))?;
+
+    if let Some(name) = opts.value("--loaders") {
+        if dot {
+            return Err("--dot can't be used with --loaders".into());
+        }
+        let loaders = graph.loaders(name);
+        if json {
+            let loaders: Vec<String> = loaders.iter()
+                .map(|x| x.json()).collect();
+            println!("{}", json::array(&loaders));
+            return Ok(());
+        }
+        for loader in &loaders {
+            println!("{:3} {}{}", loader.depth, loader.module.path,
+                     if loader.delay { " (delay-load)" } else { "" });
+        }
+        println!("{} modules load {}", loaders.len(), name);
+        return Ok(());
+    }
+
+    if dot {
+        print!("{}", graph.dot());
+        return Ok(());
+    }
+    if json {
+        println!("{}", graph.json());
+        return Ok(());
+    }
+    for module in &graph.modules {
+        println!("{}", module.path);
+        for dep in &module.dependencies {
+            let target = graph.target(dep, module);
+            let found = !graph.targets(dep, module).is_empty();
+            println!("    {}{}{}{}", dep.name,
+                     if target != dep.name {
+                         format!(" -> {}", target)
+                     } else {
+                         String::new()
+                     },
+                     if dep.delay { " (delay-load)" } else { "" },
+                     if found { "" } else { " (not found)" });
+        }
+    }
+    println!("{} images, {} DLLs not found", graph.modules.len(),
+             graph.missing().len());
+    Ok(())
+}
+
+fn cmd_closure(opts: &Options) -> CommandResult
+{
+    let roots: Vec<PathBuf> = opts.args.iter().map(PathBuf::from).collect();
+    let format = format_option(opts)?;
+    if roots.is_empty() {
+        return Err("No root images given".into());
+    }
+
+    let mut search = closure::Search::default();
+    search.delay = !opts.switch("--no-delay-load");
+    search.dirs = opts.values("--path").iter().map(PathBuf::from).collect();
+
+    if let Some(dir) = opts.value("--tree") {
+        search.tree = Some(dependency_graph(Path::new(dir))
+            .map_err(|err| format!("Failed to list {}: {}", dir, err))?);
+    }
+    if let Err(err) = search.load_schema() {
+        println!("No API set schema, API sets are looked for as files \
+                  ({})", err);
+    }
+
+    let closure = search.closure(&roots);
+    let mut output_pdbs = Vec::new();
+    for module in &closure.modules {
+        output_pdbs.extend(parse_file(Path::new(&module.path)).pdbs);
+    }
+
+    let mut output_file = File::create(format.file_name()).
+        expect("Failed to create output manifest file");
+    output_file.write_all(manifest::format(&output_pdbs, format)
+                          .as_bytes()).
+        expect("Failed to write pdbs to manifest file");
+
+    println!("{} images in the closure ({} pdbs)", closure.modules.len(),
+             output_pdbs.len());
+    if !closure.missing.is_empty() {
+        println!("Not found:");
+        for name in &closure.missing {
+            println!("    {}", name);
+        }
+    }
+    Ok(())
+}
+
+fn cmd_apiset(opts: &Options) -> CommandResult
+{
+    /* Either the schema itself or a tree to find it in */
+    let path = Path::new(opts.args[0]);
+    let paths: Vec<PathBuf> = if path.is_file() {
+        vec![path.to_path_buf()]
+    } else {
+        recursive_listdir(path).unwrap_or_default().into_iter()
+            .filter(|x| x.file_name().and_then(|x| x.to_str())
+                    .is_some_and(|x| {
+                        x.eq_ignore_ascii_case("apisetschema.dll")
+                    }))
+            .collect()
+    };
+    let (path, schema) = load_apiset_schema(&paths).ok_or_else(|| {
+        format!("No API set schema found in {}", opts.args[0])
+    })?;
+
+    if let Some(name) = opts.value("--resolve") {
+        match schema.resolve(name, opts.value("--importer").unwrap_or("")) {
+            Some(host) => println!("{}", host),
+            None => println!("{} is not an API set with a host", name),
+        }
+        return Ok(());
+    }
+    if opts.switch("--json") {
+        println!("{}", schema.json());
+        return Ok(());
+    }
+
+    println!("{}: schema version {}, {} API sets", path.display(),
+             schema.version, schema.sets.len());
+    for set in &schema.sets {
+        let hosts: Vec<String> = set.hosts.iter().map(|x| {
+            match &x.importer {
+                Some(importer) => format!("{} (for {})", x.host, importer),
+                None => x.host.clone(),
+            }
+        }).collect();
+        if hosts.is_empty() || hosts == [""] {
+            println!("{} (no host)", set.name);
+        } else {
+            println!("{} -> {}", set.name, hosts.join(", "));
+        }
+    }
+    Ok(())
+}
+
+fn cmd_toolchain(opts: &Options) -> CommandResult
+{
+    let older_than = opts.value("--older-than");
+    if older_than.is_some_and(|x| rich::version_index(x).is_none()) {
+        return Err("--older-than needs a version from VS97 to VS2022, eg. \
+                    VS2015".into());
+    }
+
+    let listing = recursive_listdir(Path::new(opts.args[0]))
+        .map_err(|err| format!("Failed to list {}: {}", opts.args[0], err))?;
+
+    /* Every image with its Rich header, if it has a readable one */
+    let mut images = Vec::new();
+    for (ii, filename) in listing.iter().enumerate() {
+        if let Ok(mut image) = open_image(filename) {
+            let mut parsed = pe::parse(&mut image.stream);
+            if parsed.pe.is_some() {
+                parsed.decode_rich(&mut image.stream);
+                images.push((filename, parsed.rich.and_then(|x| x.ok())));
+            }
+        }
+
+        /* The report itself goes to stdout */
+        if STATUS_MESSAGES {
+            eprint!("\rParsed {} of {} files ({} images)",
+                    ii + 1, listing.len(), images.len());
+        }
+    }
+    eprintln!();
+
+    /* Images without a known toolchain can't be told to be older */
+    if let Some(version) = older_than {
+        images.retain(|(_, rich)| rich.as_ref()
+                      .and_then(|x| x.toolchain())
+                      .is_some_and(|x| rich::older_than(x, version)));
+    }
+
+    if opts.switch("--json") {
+        let images: Vec<String> = images.iter().map(|(path, rich)| {
+            json::object(&[
+                ("path", json::quote(&path.display().to_string())),
+                ("rich", rich.as_ref()
+                             .map_or("null".into(), |x| x.json())),
+            ])
+        }).collect();
+        println!("{}", json::array(&images));
+        return Ok(());
+    }
+
+    let mut without = 0;
+    for (path, rich) in &images {
+        let rich = match rich {
+            Some(rich) => rich,
+            None => {
+                without += 1;
+                println!("{:<8} {:>5} {:<8} {}", "-", "", "",
+                         path.display());
+                continue;
+            }
+        };
+        println!("{:<8} {:>5} {:<8} {}{}",
+                 rich.toolchain().unwrap_or("unknown"),
+                 rich.linker().map_or(String::new(),
+                                      |x| x.build.to_string()),
+                 rich.oldest().unwrap_or(""), path.display(),
+                 if rich.checksum_valid { "" } else {
+                     " (checksum mismatch)"
+                 });
+    }
+    println!("{} images, {} without a Rich header", images.len(), without);
+    Ok(())
+}
+
+fn cmd_info(opts: &Options) -> CommandResult
+{
+    let mut image = open_image(Path::new(opts.args[0]))
+        .map_err(|err| format!("Failed to open {}: {}", opts.args[0], err))?;
+    let mut parsed = pe::parse(&mut image.stream);
+    parsed.decode_debug_dirs(&mut image.stream);
+    parsed.decode_version(&mut image.stream);
+    parsed.decode_signature(&mut image.stream);
+    parsed.decode_imports(&mut image.stream);
+    parsed.decode_rich(&mut image.stream);
+    if opts.switch("--json") {
+        println!("{}", info::json(&parsed));
+    } else {
+        print!("{}", info::text(&parsed));
+    }
+    Ok(())
+}
+
+fn cmd_convertstore(opts: &Options) -> CommandResult
+{
+    let layout = match opts.args[1] {
+        "flat"     => store::Layout::Flat,
+        "two-tier" => store::Layout::TwoTier,
+        layout => return Err(format!("Unknown store layout {}",
+                                     layout).into()),
+    };
+
+    let moved = store::migrate(Path::new(opts.args[0]), layout)
+        .map_err(|err| format!("Failed to convert store: {}", err))?;
+    println!("Moved {} entries", moved);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn cmd_watch(opts: &Options) -> CommandResult
+{
+    watch_dir(Path::new(opts.args[0]))
+        .map_err(|err| format!("Failed to watch: {}", err).into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cmd_watch(_opts: &Options) -> CommandResult
+{
+    Err("watch is only supported on Linux".into())
+}
+
+fn cmd_clean(_opts: &Options) -> CommandResult
+{
+    /* Ignores all errors during clean */
+    let _ = std::fs::remove_dir_all("symbols");
+    let _ = std::fs::remove_dir_all("filestore");
+    let _ = std::fs::remove_file("manifest");
+    let _ = std::fs::remove_file("manifest.jsonl");
+    let _ = std::fs::remove_file("manifest.csv");
+    Ok(())
+}
@@ -0,0 +1,107 @@
+//! Legacy `.dbg` files, which NT4 and Windows 2000 era toolchains split
+//! symbols into.
+//!
+//! A `.dbg` file starts with an IMAGE_SEPARATE_DEBUG_HEADER carrying the
+//! timestamp and image size of the image it was split from. Like images they
+//! are stored under `<timestamp><image size>` keys.
+
+use std::error::Error;
+use std::io::Read;
+
+use pe::read_struct;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SeparateDebugHeader {
+    pub signature:            [u8; 2],
+    pub flags:                u16,
+    pub machine:              u16,
+    pub characteristics:      u16,
+    pub timestamp:            u32,
+    pub checksum:             u32,
+    pub image_base:           u32,
+    pub size_of_image:        u32,
+    pub num_sections:         u32,
+    pub exported_names_size:  u32,
+    pub debug_directory_size: u32,
+    pub section_alignment:    u32,
+    pub reserved:             [u32; 2],
+}
+
+impl SeparateDebugHeader {
+    /// Key the file is stored under in a store, the same as the image it
+    /// was split from
+    pub fn key(&self) -> String
+    {
+        format!("{:08x}{:x}", {self.timestamp}, {self.size_of_image})
+    }
+}
+
+/// Check whether `name` is that of a `.dbg` file. The two byte signature is
+/// too weak to go by alone.
+pub fn is_dbg_name(name: &str) -> bool
+{
+    name.to_lowercase().ends_with(".dbg")
+}
+
+/// Read and validate the header of the `.dbg` file in `fd`
+pub fn get_dbg_info<R: Read>(fd: &mut R) ->
+    Result<SeparateDebugHeader, Box<dyn Error>>
+{
+    let header: SeparateDebugHeader = unsafe { read_struct(fd)? };
+    if &header.signature != b"DI" {
+        return Err("No DI separate debug header present".into());
+    }
+    if header.size_of_image == 0 {
+        return Err("DBG header has a zero image size".into());
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A separate debug header for an image with `timestamp` and
+    /// `size_of_image`
+    fn header(timestamp: u32, size_of_image: u32) -> Vec<u8>
+    {
+        let mut data = b"DI".to_vec();
+        data.extend_from_slice(&[0, 0, 0x4c, 0x01, 0x02, 0x21]);
+        data.extend_from_slice(&timestamp.to_le_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&size_of_image.to_le_bytes());
+        data.resize(48, 0);
+        data
+    }
+
+    #[test]
+    fn keys()
+    {
+        assert_eq!(std::mem::size_of::<SeparateDebugHeader>(), 48);
+
+        let key = |data: &[u8]| get_dbg_info(&mut &data[..])
+            .map(|x| x.key()).map_err(|x| x.to_string());
+        assert_eq!(key(&header(0x3b7d8410, 0x7b000)).unwrap(),
+                   "3b7d84107b000");
+        assert_eq!(key(&header(0x10, 0x1000)).unwrap(), "000000101000");
+
+        let mut data = header(0x10, 0x1000);
+        data[0] = b'M';
+        assert_eq!(key(&data).unwrap_err(),
+                   "No DI separate debug header present");
+        assert_eq!(key(&header(0x10, 0)).unwrap_err(),
+                   "DBG header has a zero image size");
+        assert!(key(&header(0x10, 0x1000)[..47]).is_err());
+    }
+
+    #[test]
+    fn names()
+    {
+        assert!(is_dbg_name("ntdll.dbg"));
+        assert!(is_dbg_name("NTDLL.DBG"));
+        assert!(!is_dbg_name("ntdll.dll"));
+        assert!(!is_dbg_name("ntdll.db_"));
+        assert!(!is_dbg_name("dbg"));
+    }
+}
@@ -0,0 +1,345 @@
+//! Deduplication of identical files written into stores.
+//!
+//! Every file written is recorded in a content hash index, a text file of
+//! `<hash> <size> <path>` lines which may be shared between stores. When a
+//! file with the same content was written before it is linked to rather than
+//! stored again. Candidates are compared byte for byte before linking so
+//! hash collisions are harmless.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use store;
+
+/// How duplicate files are stored
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Strategy {
+    /// Whichever of the below first works on the file system being written
+    Auto,
+
+    /// Copy-on-write clone sharing the same extents (btrfs, XFS)
+    Reflink,
+
+    /// Hard link to the existing file
+    Hardlink,
+
+    /// Plain copy, only indexing files
+    Copy,
+}
+
+impl Strategy {
+    /// Parse a strategy as given on the command line
+    pub fn parse(name: &str) -> Option<Strategy>
+    {
+        match name {
+            "auto"     => Some(Strategy::Auto),
+            "reflink"  => Some(Strategy::Reflink),
+            "hardlink" => Some(Strategy::Hardlink),
+            "copy"     => Some(Strategy::Copy),
+            _ => None,
+        }
+    }
+}
+
+/// Content hash index being written to
+pub struct Dedup {
+    strategy: Strategy,
+
+    /// Paths of every indexed file by content hash and size
+    index: HashMap<(u64, u64), Vec<PathBuf>>,
+
+    /// Index file, new files are appended to it
+    index_fd: File,
+
+    /// Strategy found to work for each file system with `Strategy::Auto`
+    chosen: HashMap<u64, Strategy>,
+
+    /// Number of files which were linked rather than copied
+    pub linked: usize,
+
+    /// Bytes saved by linking
+    pub saved: u64,
+}
+
+/// 64-bit FNV-1a. Unlike the std hashers this is stable, which matters as
+/// the index persists between runs.
+fn fnv1a(data: &[u8]) -> u64
+{
+    let mut hash = 0xcbf29ce484222325u64;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Make `dst` a copy-on-write clone of `src`
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dst: &Path) -> Result<(), Box<dyn Error>>
+{
+    use std::os::unix::io::AsRawFd;
+
+    /* FICLONE from linux/fs.h */
+    const FICLONE: libc::c_ulong = 0x40049409;
+
+    let src_fd = File::open(src)?;
+    let dst_fd = File::create(dst)?;
+    if unsafe { libc::ioctl(dst_fd.as_raw_fd(), FICLONE as _,
+                            src_fd.as_raw_fd()) } != 0 {
+        let err = std::io::Error::last_os_error();
+        drop(dst_fd);
+        let _ = fs::remove_file(dst);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &Path, _dst: &Path) -> Result<(), Box<dyn Error>>
+{
+    Err("Reflinks are not supported on this platform".into())
+}
+
+/// Identify the file system `path` is on
+#[cfg(unix)]
+fn device(path: &Path) -> u64
+{
+    use std::os::unix::fs::MetadataExt;
+    path.metadata().map(|x| x.dev()).unwrap_or(0)
+}
+
+#[cfg(not(unix))]
+fn device(_path: &Path) -> u64
+{
+    0
+}
+
+impl Dedup {
+    /// Open (or create) the index at `index_path`
+    pub fn open(index_path: &Path, strategy: Strategy) ->
+        Result<Dedup, Box<dyn Error>>
+    {
+        let mut index: HashMap<(u64, u64), Vec<PathBuf>> = HashMap::new();
+        if index_path.exists() {
+            for line in fs::read_to_string(index_path)?.lines() {
+                let mut fields = line.splitn(3, ' ');
+                let hash = fields.next()
+                    .and_then(|x| u64::from_str_radix(x, 16).ok());
+                let size = fields.next().and_then(|x| x.parse().ok());
+                if let (Some(hash), Some(size), Some(path)) =
+                        (hash, size, fields.next()) {
+                    index.entry((hash, size)).or_default()
+                        .push(PathBuf::from(path));
+                }
+            }
+        }
+
+        if let Some(parent) = index_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let index_fd = OpenOptions::new().create(true).append(true)
+            .open(index_path)?;
+
+        Ok(Dedup {
+            strategy,
+            index,
+            index_fd,
+            chosen: HashMap::new(),
+            linked: 0,
+            saved:  0,
+        })
+    }
+
+    /// Make `dst` a link to `src` using `strategy`. Clones are made under a
+    /// temporary name, so `dst` never exists half made.
+    fn link(strategy: Strategy, src: &Path, dst: &Path) ->
+        Result<(), Box<dyn Error>>
+    {
+        match strategy {
+            Strategy::Reflink => {
+                let tmp = store::temp_path(dst);
+                let res = reflink(src, &tmp)
+                    .and_then(|_| Ok(fs::rename(&tmp, dst)?));
+                if res.is_err() {
+                    let _ = fs::remove_file(&tmp);
+                }
+                res
+            }
+            Strategy::Hardlink => Ok(fs::hard_link(src, dst)?),
+            _ => Err("Not a linking strategy".into()),
+        }
+    }
+
+    /// Try to store `data` at `path` by linking to an identical file which
+    /// was already indexed
+    fn try_link(&mut self, path: &Path, data: &[u8], key: (u64, u64)) -> bool
+    {
+        let strategies = match self.strategy {
+            Strategy::Copy => return false,
+            Strategy::Auto => {
+                let dev = device(path.parent().unwrap_or(path));
+                match self.chosen.get(&dev) {
+                    Some(&chosen) => vec![chosen],
+                    None => vec![Strategy::Reflink, Strategy::Hardlink],
+                }
+            }
+            strategy => vec![strategy],
+        };
+
+        let candidates = match self.index.get(&key) {
+            Some(candidates) => candidates,
+            None => return false,
+        };
+        let src = candidates.iter().find(|x| {
+            fs::read(x).map(|x| x == data).unwrap_or(false)
+        });
+        let src = match src {
+            Some(src) => src.clone(),
+            None => return false,
+        };
+
+        for strategy in strategies {
+            if Dedup::link(strategy, &src, path).is_ok() {
+                if self.strategy == Strategy::Auto {
+                    let dev = device(path.parent().unwrap_or(path));
+                    self.chosen.insert(dev, strategy);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Store `data` at `path`, linking to an identical file if one has been
+    /// indexed, and index it
+    pub fn write(&mut self, path: &Path, data: &[u8]) ->
+        Result<(), Box<dyn Error>>
+    {
+        let key = (fnv1a(data), data.len() as u64);
+
+        if self.try_link(path, data, key) {
+            self.linked += 1;
+            self.saved += data.len() as u64;
+        } else {
+            store::write_file(path, data)?;
+        }
+
+        let path = fs::canonicalize(path)?;
+        writeln!(self.index_fd, "{:016x} {} {}", key.0, key.1,
+                 path.display())?;
+        self.index.entry(key).or_default().push(path);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    /// Create an empty scratch directory for the test `name`
+    fn scratch(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("pdblister-{}-{}", name,
+                                                     std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn inode(path: &Path) -> u64
+    {
+        path.metadata().unwrap().ino()
+    }
+
+    #[test]
+    fn hardlinks_identical_files()
+    {
+        let dir = scratch("dedup-link");
+        let mut dedup = Dedup::open(&dir.join("dedup.idx"),
+                                    Strategy::Hardlink).unwrap();
+        dedup.write(&dir.join("a"), b"ntdll").unwrap();
+        dedup.write(&dir.join("b"), b"ntdll").unwrap();
+        dedup.write(&dir.join("c"), b"other").unwrap();
+        assert_eq!((dedup.linked, dedup.saved), (1, 5));
+        assert_eq!(inode(&dir.join("a")), inode(&dir.join("b")));
+        assert_ne!(inode(&dir.join("a")), inode(&dir.join("c")));
+
+        /* Whatever works is used, and no temporary files are left */
+        let mut dedup = Dedup::open(&dir.join("auto.idx"), Strategy::Auto)
+            .unwrap();
+        dedup.write(&dir.join("d"), b"auto").unwrap();
+        dedup.write(&dir.join("e"), b"auto").unwrap();
+        assert_eq!(dedup.linked, 1);
+        assert_eq!(fs::read(dir.join("e")).unwrap(), b"auto");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 7);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_when_contents_differ()
+    {
+        /* The indexed file changed since, so its hash and size match but
+         * its contents don't */
+        let dir = scratch("dedup-differ");
+        let mut dedup = Dedup::open(&dir.join("dedup.idx"),
+                                    Strategy::Hardlink).unwrap();
+        dedup.write(&dir.join("a"), b"ntdll").unwrap();
+        fs::write(dir.join("a"), b"NTDLL").unwrap();
+
+        dedup.write(&dir.join("b"), b"ntdll").unwrap();
+        assert_eq!(dedup.linked, 0);
+        assert_ne!(inode(&dir.join("a")), inode(&dir.join("b")));
+        assert_eq!(fs::read(dir.join("b")).unwrap(), b"ntdll");
+
+        /* The new file is indexed, and linked to next time */
+        dedup.write(&dir.join("c"), b"ntdll").unwrap();
+        assert_eq!(inode(&dir.join("b")), inode(&dir.join("c")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copy_never_links()
+    {
+        let dir = scratch("dedup-copy");
+        let mut dedup = Dedup::open(&dir.join("dedup.idx"), Strategy::Copy)
+            .unwrap();
+        dedup.write(&dir.join("a"), b"ntdll").unwrap();
+        dedup.write(&dir.join("b"), b"ntdll").unwrap();
+        assert_eq!((dedup.linked, dedup.saved), (0, 0));
+        assert_ne!(inode(&dir.join("a")), inode(&dir.join("b")));
+
+        /* Files are still indexed for other stores to link to */
+        let index = fs::read_to_string(dir.join("dedup.idx")).unwrap();
+        assert_eq!(index.lines().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn index_survives_reopen()
+    {
+        let dir = scratch("dedup-reopen");
+        let index = dir.join("idx").join("dedup.idx");
+        let mut dedup = Dedup::open(&index, Strategy::Hardlink).unwrap();
+        dedup.write(&dir.join("a"), b"ntdll").unwrap();
+        drop(dedup);
+
+        /* Lines which don't parse are skipped */
+        let mut fd = OpenOptions::new().append(true).open(&index).unwrap();
+        fd.write_all(b"nonsense\nzz 5 /missing\n").unwrap();
+        drop(fd);
+
+        let mut dedup = Dedup::open(&index, Strategy::Hardlink).unwrap();
+        dedup.write(&dir.join("b"), b"ntdll").unwrap();
+        assert_eq!(dedup.linked, 1);
+        assert_eq!(inode(&dir.join("a")), inode(&dir.join("b")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
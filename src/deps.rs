@@ -0,0 +1,416 @@
+//! Dependency graph of the images in a scanned tree.
+//!
+//! Every image is a node, with an edge to each DLL it imports or delay-load
+//! imports. Imports are matched against the scanned images by file name,
+//! ignoring case the way Windows does, preferring images for the same
+//! machine as the importer. DLLs not found in the tree are kept as leaves
+//! so the graph shows what the tree is missing.
+//!
+//! With an API set schema, imports of API sets are redirected to their host
+//! first.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use apiset::Schema;
+use json;
+use manifest;
+use pe::PeFile;
+
+/// A DLL an image imports
+#[derive(Clone)]
+pub struct Dependency {
+    /// Name of the DLL as recorded in the import, eg. `KERNEL32.dll`
+    pub name: String,
+
+    /// Whether it is only delay-loaded
+    pub delay: bool,
+}
+
+/// An image in the graph
+#[derive(Clone)]
+pub struct Module {
+    /// Where the image was found
+    pub path: String,
+
+    /// Name of the image, for CAB compressed files the original name
+    pub name: String,
+
+    pub machine: u16,
+
+    /// Every DLL imported, once each. DLLs both imported and delay-loaded
+    /// count as imported.
+    pub dependencies: Vec<Dependency>,
+}
+
+/// Dependency graph of the images in a tree
+#[derive(Default)]
+pub struct Graph {
+    pub modules: Vec<Module>,
+
+    /// API set schema imports are redirected through
+    pub schema: Option<Schema>,
+
+    /// Indices into `modules` by lowercased name
+    by_name: HashMap<String, Vec<usize>>,
+}
+
+/// A module which loads another, and how
+pub struct Loader<'a> {
+    pub module: &'a Module,
+
+    /// Number of imports between the two, 1 for direct imports
+    pub depth: usize,
+
+    /// Whether every path between the two goes through a delay-load import
+    pub delay: bool,
+}
+
+impl Module {
+    /// Build a module out of an image whose imports are decoded. Images
+    /// without a PE header give `None`.
+    pub fn new(path: &str, name: &str, image: &PeFile) -> Option<Module>
+    {
+        let pe = image.pe?;
+
+        let mut dependencies: Vec<Dependency> = Vec::new();
+        let imports = image.imports.iter().flat_map(|x| x.iter()).flatten();
+        for import in imports {
+            match dependencies.iter_mut()
+                    .find(|x| x.name.eq_ignore_ascii_case(&import.dll)) {
+                Some(dep) => dep.delay &= import.delay,
+                None => dependencies.push(Dependency {
+                    name:  import.dll.clone(),
+                    delay: import.delay,
+                }),
+            }
+        }
+
+        Some(Module {
+            path: path.into(),
+            name: name.into(),
+            machine: pe.machine,
+            dependencies,
+        })
+    }
+}
+
+/// Get how many edges of `edges` away from `targets` every node is, if it
+/// can be reached. Delay-load edges are only followed with `delay`.
+fn distances(edges: &[Vec<(usize, bool)>], targets: &[usize], delay: bool) ->
+    Vec<Option<usize>>
+{
+    let mut dist = vec![None; edges.len()];
+    let mut queue = VecDeque::new();
+    for &target in targets {
+        dist[target] = Some(0);
+        queue.push_back(target);
+    }
+    while let Some(node) = queue.pop_front() {
+        for &(next, edge_delay) in &edges[node] {
+            if dist[next].is_none() && (delay || !edge_delay) {
+                dist[next] = dist[node].map(|x| x + 1);
+                queue.push_back(next);
+            }
+        }
+    }
+    dist
+}
+
+impl Graph {
+    pub fn add(&mut self, module: Module)
+    {
+        self.by_name.entry(module.name.to_lowercase()).or_default()
+            .push(self.modules.len());
+        self.modules.push(module);
+    }
+
+    /// Get the modules `name` imported by a `machine` image resolves to.
+    /// Modules for the same machine are preferred, if there are none all
+    /// modules with the name are given.
+    pub fn resolve(&self, name: &str, machine: u16) -> Vec<usize>
+    {
+        let found = match self.by_name.get(&name.to_lowercase()) {
+            Some(found) => found,
+            None => return Vec::new(),
+        };
+        let same: Vec<usize> = found.iter().cloned()
+            .filter(|&x| self.modules[x].machine == machine).collect();
+        if same.is_empty() { found.clone() } else { same }
+    }
+
+    /// Get the name of the DLL `dep` of `importer` loads, which is the host
+    /// of the API set for API sets known to the schema
+    pub fn target<'a>(&'a self, dep: &'a Dependency, importer: &Module) ->
+        &'a str
+    {
+        self.schema.as_ref()
+            .and_then(|x| x.resolve(&dep.name, &importer.name))
+            .unwrap_or(&dep.name)
+    }
+
+    /// Get the modules `dep` of `importer` resolves to
+    pub fn targets(&self, dep: &Dependency, importer: &Module) -> Vec<usize>
+    {
+        self.resolve(self.target(dep, importer), importer.machine)
+    }
+
+    /// Get every module which loads a module named `name`, directly or
+    /// through other modules, closest first
+    pub fn loaders(&self, name: &str) -> Vec<Loader<'_>>
+    {
+        /* Reverse edges, from each module to the modules importing it */
+        let mut importers: Vec<Vec<(usize, bool)>> =
+            vec![Vec::new(); self.modules.len()];
+        for (ii, module) in self.modules.iter().enumerate() {
+            for dep in &module.dependencies {
+                for target in self.targets(dep, module) {
+                    importers[target].push((ii, dep.delay));
+                }
+            }
+        }
+
+        /* Modules loading one through imports alone don't depend on it
+         * being delay-loaded */
+        let targets: Vec<usize> = self.by_name.get(&name.to_lowercase())
+            .cloned().unwrap_or_default();
+        let direct = distances(&importers, &targets, false);
+        let all = distances(&importers, &targets, true);
+
+        let mut loaders: Vec<Loader> = all.iter().enumerate()
+            .filter(|&(ii, _)| !targets.contains(&ii))
+            .filter_map(|(ii, x)| x.map(|depth| Loader {
+                module: &self.modules[ii],
+                depth,
+                delay:  direct[ii].is_none(),
+            }))
+            .collect();
+        loaders.sort_by_key(|x| (x.depth, x.module.path.clone()));
+        loaders
+    }
+
+    /// Get the names of the DLLs imported which are not in the graph,
+    /// lowercased
+    pub fn missing(&self) -> BTreeSet<String>
+    {
+        self.modules.iter()
+            .flat_map(|x| x.dependencies.iter().map(move |dep| (x, dep)))
+            .map(|(module, dep)| self.target(dep, module).to_lowercase())
+            .filter(|x| !self.by_name.contains_key(x))
+            .collect()
+    }
+
+    /// Format this as a Graphviz digraph. Modules are labelled by name with
+    /// their path as tooltip, delay-load imports are dashed and DLLs not in
+    /// the tree are grey.
+    pub fn dot(&self) -> String
+    {
+        let mut out = String::from("digraph dependencies {\n");
+        out.push_str("    node [shape=box];\n");
+        for (ii, module) in self.modules.iter().enumerate() {
+            out.push_str(&format!("    m{} [label={}, tooltip={}];\n", ii,
+                                  json::quote(&module.name),
+                                  json::quote(&module.path)));
+        }
+        for name in self.missing() {
+            out.push_str(&format!("    {} [style=filled, fillcolor=grey];\n",
+                                  json::quote(&name)));
+        }
+
+        for (ii, module) in self.modules.iter().enumerate() {
+            for dep in &module.dependencies {
+                let style = if dep.delay { " [style=dashed]" } else { "" };
+                let targets = self.targets(dep, module);
+                if targets.is_empty() {
+                    let name = json::quote(&self.target(dep, module)
+                                           .to_lowercase());
+                    out.push_str(&format!("    m{} -> {}{};\n", ii, name,
+                                          style));
+                }
+                for target in targets {
+                    out.push_str(&format!("    m{} -> m{}{};\n", ii, target,
+                                          style));
+                }
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Format this as a JSON object: every module with the host of each of
+    /// its imports of API sets and the paths each import resolves to (none
+    /// for DLLs not in the tree), then the names of the DLLs not in the
+    /// tree
+    pub fn json(&self) -> String
+    {
+        let modules: Vec<String> = self.modules.iter().map(|module| {
+            let deps: Vec<String> = module.dependencies.iter().map(|dep| {
+                let paths: Vec<String> = self.targets(dep, module).iter()
+                    .map(|&x| json::quote(&self.modules[x].path))
+                    .collect();
+                let target = self.target(dep, module);
+                json::object(&[
+                    ("name",  json::quote(&dep.name)),
+                    ("host",  if target == dep.name { "null".into() } else {
+                        json::quote(target)
+                    }),
+                    ("delay", dep.delay.to_string()),
+                    ("paths", json::array(&paths)),
+                ])
+            }).collect();
+
+            json::object(&[
+                ("path",         json::quote(&module.path)),
+                ("name",         json::quote(&module.name)),
+                ("machine",
+                 json::quote(&manifest::machine_name(module.machine))),
+                ("dependencies", json::array(&deps)),
+            ])
+        }).collect();
+
+        let missing: Vec<String> = self.missing().iter()
+            .map(|x| json::quote(x)).collect();
+        json::object(&[
+            ("modules", json::array(&modules)),
+            ("missing", json::array(&missing)),
+        ])
+    }
+}
+
+impl<'a> Loader<'a> {
+    /// Format this as a JSON object
+    pub fn json(&self) -> String
+    {
+        json::object(&[
+            ("path",  json::quote(&self.module.path)),
+            ("name",  json::quote(&self.module.name)),
+            ("depth", self.depth.to_string()),
+            ("delay", self.delay.to_string()),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apiset::{ApiSet, Host};
+    use imports::Import;
+    use pe::{PEHeader, IMAGE_FILE_MACHINE_I386, IMAGE_FILE_MACHINE_AMD64};
+    use pe::IMAGE_FILE_MACHINE_IA64;
+
+    const X86: u16 = IMAGE_FILE_MACHINE_I386;
+    const X64: u16 = IMAGE_FILE_MACHINE_AMD64;
+
+    /// A module importing `deps`, given as names with a leading `~` for
+    /// delay-loaded ones
+    fn module(path: &str, machine: u16, deps: &[&str]) -> Module
+    {
+        Module {
+            path: path.into(),
+            name: path.rsplit('/').next().unwrap().into(),
+            machine,
+            dependencies: deps.iter().map(|x| Dependency {
+                name:  x.trim_start_matches('~').into(),
+                delay: x.starts_with('~'),
+            }).collect(),
+        }
+    }
+
+    fn graph() -> Graph
+    {
+        let mut graph = Graph::default();
+        graph.add(module("x86/kernel32.dll", X86, &[]));
+        graph.add(module("x64/KERNEL32.DLL", X64, &[]));
+        graph.add(module("x86/b.dll", X86, &["KERNEL32.dll"]));
+        graph.add(module("x86/c.dll", X86, &["~B.DLL"]));
+        graph.add(module("x86/a.exe", X86, &["c.dll", "b.dll"]));
+        graph.add(module("x64/d.exe", X64, &["kernel32.dll", "missing.dll",
+                                             "api-ms-win-core-l1-1-0.dll"]));
+        graph
+    }
+
+    fn loaders(graph: &Graph, name: &str) -> Vec<(String, usize, bool)>
+    {
+        graph.loaders(name).iter()
+            .map(|x| (x.module.path.clone(), x.depth, x.delay)).collect()
+    }
+
+    #[test]
+    fn resolve_by_machine()
+    {
+        let graph = graph();
+        assert_eq!(graph.resolve("KERNEL32.dll", X86), [0]);
+        assert_eq!(graph.resolve("kernel32.dll", X64), [1]);
+        assert_eq!(graph.resolve("kernel32.dll", IMAGE_FILE_MACHINE_IA64),
+                   [0, 1]);
+        assert_eq!(graph.resolve("b.dll", X64), [2]);
+        assert!(graph.resolve("missing.dll", X86).is_empty());
+
+        let missing: Vec<String> = graph.missing().into_iter().collect();
+        assert_eq!(missing, ["api-ms-win-core-l1-1-0.dll", "missing.dll"]);
+    }
+
+    #[test]
+    fn loaders_closest_first()
+    {
+        let graph = graph();
+        assert_eq!(loaders(&graph, "KERNEL32.DLL"), [
+            ("x64/d.exe".to_string(), 1, false),
+            ("x86/b.dll".to_string(), 1, false),
+            ("x86/a.exe".to_string(), 2, false),
+            ("x86/c.dll".to_string(), 2, true),
+        ]);
+
+        /* a.exe imports b.dll directly as well as through c.dll */
+        assert_eq!(loaders(&graph, "b.dll"), [
+            ("x86/a.exe".to_string(), 1, false),
+            ("x86/c.dll".to_string(), 1, true),
+        ]);
+        assert!(loaders(&graph, "a.exe").is_empty());
+        assert!(loaders(&graph, "missing.dll").is_empty());
+    }
+
+    #[test]
+    fn api_set_redirects()
+    {
+        let mut graph = graph();
+        graph.schema = Some(Schema::new(6, vec![ApiSet {
+            name:  "api-ms-win-core-l1-1-0".into(),
+            hosts: vec![Host { importer: None, host: "kernel32.dll".into() }],
+        }]));
+
+        let d = &graph.modules[5];
+        assert_eq!(graph.target(&d.dependencies[2], d), "kernel32.dll");
+        assert_eq!(graph.targets(&d.dependencies[2], d), [1]);
+        assert_eq!(graph.missing().len(), 1);
+        assert!(graph.json().contains("\"name\":\"api-ms-win-core-l1-1-0.dll\",\
+                                       \"host\":\"kernel32.dll\""));
+    }
+
+    #[test]
+    fn module_imports()
+    {
+        let mut pe: PEHeader = unsafe { std::mem::zeroed() };
+        pe.machine = X64;
+        let import = |dll: &str, delay| Import {
+            dll: dll.into(),
+            delay,
+            functions: Vec::new(),
+        };
+        let image = PeFile {
+            pe: Some(pe),
+            imports: Some(Ok(vec![import("ntdll.dll", true),
+                                  import("USER32.dll", true),
+                                  import("NTDLL.DLL", false),
+                                  import("user32.dll", true)])),
+            ..PeFile::default()
+        };
+
+        /* Imported DLLs are only delay-loaded if they always are */
+        let module = Module::new("x64/a.exe", "a.exe", &image).unwrap();
+        let deps: Vec<(&str, bool)> = module.dependencies.iter()
+            .map(|x| (x.name.as_str(), x.delay)).collect();
+        assert_eq!(deps, [("ntdll.dll", false), ("USER32.dll", true)]);
+        assert_eq!(module.machine, X64);
+        assert!(Module::new("a.exe", "a.exe", &PeFile::default()).is_none());
+    }
+}
@@ -0,0 +1,332 @@
+//! Just enough DER decoding to read the PKCS#7 and X.509 structures of
+//! Authenticode signatures.
+//!
+//! Values are borrowed out of the encoded data rather than decoded into
+//! structures, callers walk them with a `Reader` in the order the ASN.1
+//! definitions give.
+
+use std::error::Error;
+
+pub const INTEGER:          u8 = 0x02;
+pub const OCTET_STRING:     u8 = 0x04;
+pub const OID:              u8 = 0x06;
+pub const T61_STRING:       u8 = 0x14;
+pub const UTC_TIME:         u8 = 0x17;
+pub const GENERALIZED_TIME: u8 = 0x18;
+pub const BMP_STRING:       u8 = 0x1e;
+pub const SEQUENCE:         u8 = 0x30;
+pub const SET:              u8 = 0x31;
+
+/// Tag of the constructed context specific `[n]`
+pub fn context(n: u8) -> u8
+{
+    0xa0 | n
+}
+
+/// A decoded tag, length and value
+#[derive(Clone, Copy)]
+pub struct Tlv<'a> {
+    pub tag: u8,
+
+    /// The value, without the tag and length
+    pub data: &'a [u8],
+
+    /// The whole encoding, with the tag and length
+    pub raw: &'a [u8],
+}
+
+impl<'a> Tlv<'a> {
+    /// Read the values this (constructed) value holds
+    pub fn reader(&self) -> Reader<'a>
+    {
+        Reader { data: self.data }
+    }
+}
+
+/// Cursor over a sequence of DER values
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a>
+    {
+        Reader { data }
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.data.is_empty()
+    }
+
+    /// Read the next value, whatever it is
+    pub fn next(&mut self) -> Result<Tlv<'a>, Box<dyn Error>>
+    {
+        let data = self.data;
+        let (tag, first) = match (data.first(), data.get(1)) {
+            (Some(&tag), Some(&first)) => (tag, first as usize),
+            _ => return Err("DER value truncated".into()),
+        };
+        if tag & 0x1f == 0x1f {
+            return Err("Unsupported DER tag".into());
+        }
+
+        let (header, len) = match first {
+            0x80 => return Err("Indefinite DER length".into()),
+            len if len < 0x80 => (2, len),
+            len => {
+                let count = len & 0x7f;
+                let bytes = match data.get(2..2 + count) {
+                    Some(bytes) if count <= 4 => bytes,
+                    _ => return Err("DER length out of range".into()),
+                };
+                (2 + count,
+                 bytes.iter().fold(0usize, |acc, &x| acc << 8 | x as usize))
+            }
+        };
+
+        let end = match header.checked_add(len) {
+            Some(end) if end <= data.len() => end,
+            _ => return Err("DER value truncated".into()),
+        };
+        self.data = &data[end..];
+        Ok(Tlv { tag, data: &data[header..end], raw: &data[..end] })
+    }
+
+    /// Read the next value, which must be tagged `tag`
+    pub fn expect(&mut self, tag: u8) -> Result<Tlv<'a>, Box<dyn Error>>
+    {
+        let tlv = self.next()?;
+        if tlv.tag != tag {
+            return Err(format!("Expected DER tag 0x{:02x}, found 0x{:02x}",
+                               tag, tlv.tag).into());
+        }
+        Ok(tlv)
+    }
+
+    /// Read the next value if it is tagged `tag`
+    pub fn optional(&mut self, tag: u8) ->
+        Result<Option<Tlv<'a>>, Box<dyn Error>>
+    {
+        match self.data.first() {
+            Some(&first) if first == tag => self.next().map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Format an object identifier in dotted form
+pub fn oid(data: &[u8]) -> String
+{
+    let mut arcs = Vec::new();
+    let mut val: u64 = 0;
+    for &byte in data {
+        val = val << 7 | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (val / 40).min(2);
+                arcs.push(first);
+                arcs.push(val - first * 40);
+            } else {
+                arcs.push(val);
+            }
+            val = 0;
+        }
+    }
+    arcs.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(".")
+}
+
+/// Decode a string value of any of the string types
+pub fn string(tlv: &Tlv) -> String
+{
+    match tlv.tag {
+        BMP_STRING => {
+            let chars: Vec<u16> = tlv.data.chunks_exact(2)
+                .map(|x| u16::from_be_bytes([x[0], x[1]]))
+                .collect();
+            String::from_utf16_lossy(&chars)
+        }
+        /* T.61 is close enough to Latin-1 for the names found in
+         * certificates */
+        T61_STRING => tlv.data.iter().map(|&x| x as char).collect(),
+        /* UTF8String, and PrintableString and IA5String which are ASCII */
+        _ => String::from_utf8_lossy(tlv.data).into_owned(),
+    }
+}
+
+/// Format a UTCTime or GeneralizedTime as `YYYY-MM-DD HH:MM:SS UTC`
+pub fn time(tlv: &Tlv) -> Result<String, Box<dyn Error>>
+{
+    let text = std::str::from_utf8(tlv.data)?;
+    let (year, rest) = match tlv.tag {
+        UTC_TIME if text.len() >= 12 => {
+            /* Two digit years from 50 on are in the 1900s */
+            let year: u32 = text[..2].parse()?;
+            (if year >= 50 { 1900 + year } else { 2000 + year }, &text[2..])
+        }
+        GENERALIZED_TIME if text.len() >= 14 => {
+            (text[..4].parse()?, &text[4..])
+        }
+        _ => return Err("Invalid DER time".into()),
+    };
+    if !rest.is_char_boundary(10) ||
+            !rest[..10].chars().all(|x| x.is_ascii_digit()) {
+        return Err("Invalid DER time".into());
+    }
+    Ok(format!("{:04}-{}-{} {}:{}:{} UTC", year, &rest[0..2], &rest[2..4],
+               &rest[4..6], &rest[6..8], &rest[8..10]))
+}
+
+/// Get the short name of a distinguished name attribute
+fn attribute_name(oid: &str) -> Option<&'static str>
+{
+    Some(match oid {
+        "2.5.4.3"  => "CN",
+        "2.5.4.5"  => "SERIALNUMBER",
+        "2.5.4.6"  => "C",
+        "2.5.4.7"  => "L",
+        "2.5.4.8"  => "ST",
+        "2.5.4.9"  => "STREET",
+        "2.5.4.10" => "O",
+        "2.5.4.11" => "OU",
+        "1.2.840.113549.1.9.1" => "E",
+        _ => return None,
+    })
+}
+
+/// Get the attributes of an X.509 Name as short name (or dotted OID) and
+/// value pairs, most specific attribute first
+pub fn name_attributes(tlv: &Tlv) ->
+    Result<Vec<(String, String)>, Box<dyn Error>>
+{
+    let mut parts = Vec::new();
+    let mut rdns = tlv.reader();
+    while !rdns.is_empty() {
+        let mut attrs = rdns.expect(SET)?.reader();
+        while !attrs.is_empty() {
+            let mut attr = attrs.expect(SEQUENCE)?.reader();
+            let oid = oid(attr.expect(OID)?.data);
+            let val = string(&attr.next()?);
+            let key = attribute_name(&oid).map_or(oid.clone(), |x| x.into());
+            parts.push((key, val));
+        }
+    }
+    parts.reverse();
+    Ok(parts)
+}
+
+/// Format an X.509 Name like RFC 4514, most specific attribute first, eg.
+/// `CN=Microsoft Windows, O=Microsoft Corporation, L=Redmond, C=US`
+pub fn name(tlv: &Tlv) -> Result<String, Box<dyn Error>>
+{
+    let parts: Vec<String> = name_attributes(tlv)?.iter()
+        .map(|(key, val)| format!("{}={}", key, val))
+        .collect();
+    Ok(parts.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a value, with a long form length when it needs one
+    fn tlv(tag: u8, parts: &[&[u8]]) -> Vec<u8>
+    {
+        let data = parts.concat();
+        let mut out = vec![tag];
+        if data.len() < 0x80 {
+            out.push(data.len() as u8);
+        } else {
+            out.extend_from_slice(&[0x82, (data.len() >> 8) as u8,
+                                    data.len() as u8]);
+        }
+        out.extend(data);
+        out
+    }
+
+    /// An attribute of a distinguished name, in its own RDN
+    fn rdn(oid: &[u8], tag: u8, val: &[u8]) -> Vec<u8>
+    {
+        tlv(SET, &[&tlv(SEQUENCE, &[&tlv(OID, &[oid]), &tlv(tag, &[val])])])
+    }
+
+    #[test]
+    fn read_values()
+    {
+        let long = vec![7u8; 300];
+        let data = [tlv(INTEGER, &[&[1]]), tlv(OCTET_STRING, &[&long]),
+                    tlv(SEQUENCE, &[])].concat();
+        let mut reader = Reader::new(&data);
+        assert!(reader.optional(SEQUENCE).unwrap().is_none());
+        assert_eq!(reader.expect(INTEGER).unwrap().data, [1]);
+        let string = reader.optional(OCTET_STRING).unwrap().unwrap();
+        assert_eq!((string.data.len(), string.raw.len()), (300, 304));
+        assert!(reader.optional(SET).unwrap().is_none());
+        assert!(reader.next().unwrap().reader().is_empty());
+        assert!(Reader::new(&data).expect(SET).is_err());
+        assert!(reader.is_empty() && reader.next().is_err());
+
+        /* Truncated values, indefinite and oversized lengths, and high tag
+         * numbers */
+        for bad in [&[0x02][..], &[0x02, 0x02, 0x00], &[0x30, 0x80, 0, 0],
+                    &[0x04, 0x85, 0, 0, 0, 0, 1, 0],
+                    &[0x04, 0x84, 0xff, 0xff, 0xff, 0xff],
+                    &[0x1f, 0x01, 0x00]] {
+            assert!(Reader::new(bad).next().is_err());
+        }
+    }
+
+    #[test]
+    fn oids_strings_and_times()
+    {
+        assert_eq!(oid(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07,
+                         0x02]), "1.2.840.113549.1.7.2");
+        assert_eq!(oid(&[0x88, 0x37, 0x03]), "2.999.3");
+
+        let string = |tag: u8, data: &[u8]| {
+            super::string(&Tlv { tag, data, raw: data })
+        };
+        assert_eq!(string(BMP_STRING, &[0, b'M', 0x20, 0xac]), "M\u{20ac}");
+        assert_eq!(string(T61_STRING, &[b'M', 0xe9]), "M\u{e9}");
+        assert_eq!(string(0x0c, "M\u{e9}".as_bytes()), "M\u{e9}");
+
+        let time = |tag: u8, text: &str| {
+            super::time(&Tlv { tag, data: text.as_bytes(), raw: &[] })
+        };
+        assert_eq!(time(UTC_TIME, "490102030405Z").unwrap(),
+                   "2049-01-02 03:04:05 UTC");
+        assert_eq!(time(UTC_TIME, "500102030405Z").unwrap(),
+                   "1950-01-02 03:04:05 UTC");
+        assert_eq!(time(GENERALIZED_TIME, "21000102030405Z").unwrap(),
+                   "2100-01-02 03:04:05 UTC");
+        assert!(time(UTC_TIME, "4901020304Z").is_err());
+        assert!(time(UTC_TIME, "49010203040\u{e9}").is_err());
+        assert!(time(GENERALIZED_TIME, "490102030405Z").is_err());
+        assert!(time(OCTET_STRING, "21000102030405Z").is_err());
+    }
+
+    #[test]
+    fn names()
+    {
+        let data = tlv(SEQUENCE, &[
+            &rdn(&[0x55, 4, 6], 0x13, b"US"),
+            &rdn(&[0x55, 4, 10], 0x0c, b"Microsoft Corporation"),
+            &rdn(&[0x2b, 6, 1, 4, 1, 0x82, 0x37, 0x3c, 2, 1, 1], 0x13,
+                 b"Private"),
+            &rdn(&[0x55, 4, 3], BMP_STRING, &[0, b'W', 0, b'i', 0, b'n']),
+        ]);
+        let name_tlv = Reader::new(&data).next().unwrap();
+        assert_eq!(name_attributes(&name_tlv).unwrap(), [
+            ("CN".to_string(), "Win".to_string()),
+            ("1.3.6.1.4.1.311.60.2.1.1".to_string(), "Private".to_string()),
+            ("O".to_string(), "Microsoft Corporation".to_string()),
+            ("C".to_string(), "US".to_string()),
+        ]);
+        assert_eq!(name(&name_tlv).unwrap(),
+                   "CN=Win, 1.3.6.1.4.1.311.60.2.1.1=Private, \
+                    O=Microsoft Corporation, C=US");
+
+        let bad = tlv(SEQUENCE, &[&tlv(SEQUENCE, &[])]);
+        assert!(name(&Reader::new(&bad).next().unwrap()).is_err());
+    }
+}
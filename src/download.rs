@@ -0,0 +1,315 @@
+//! Native downloader which fetches the entries of a manifest straight from a
+//! symbol server into a local store, rather than going through symchk. The
+//! transfers themselves are left to the `curl` command line tool, which has
+//! to be installed.
+//!
+//! Files are requested as `<url>/<name>/<key>/<name>`. Servers which only
+//! hold the compressed `.pd_` style counterpart of a file answer that with
+//! an error, in which case the compressed file is fetched instead and
+//! expanded into the store.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use cab;
+use manifest::Entry;
+use store::{self, temp_path, DirIndex};
+
+/// User agent sent to symbol servers, some refuse anything but symsrv
+const USER_AGENT: &str = "Microsoft-Symbol-Server/10.0.0.0";
+
+/// Program the downloads are done with
+const CURL: &str = "curl";
+
+/// Exit status of `curl -f` when the server answered with an HTTP error
+const CURL_HTTP_ERROR: i32 = 22;
+
+/// Whether `name` is a plain file name which can be put in a URL as is
+pub fn valid_name(name: &str) -> bool
+{
+    !name.is_empty() && !name.starts_with('.') &&
+        name.chars().all(|x| x.is_ascii_alphanumeric() || "._-+~$".contains(x))
+}
+
+/// Split a `srv*<store>*<url>` symbol path into the local store and the
+/// server to download from
+pub fn parse_sympath(sympath: &str) -> Option<(PathBuf, String)>
+{
+    let parts: Vec<&str> = sympath.split('*').collect();
+    match parts[..] {
+        [srv, store, url] if srv.eq_ignore_ascii_case("srv") &&
+                !store.is_empty() &&
+                (url.starts_with("http://") || url.starts_with("https://")) =>
+            Some((PathBuf::from(store), url.trim_end_matches('/').into())),
+        _ => None,
+    }
+}
+
+/// Fetch `url` into `dest` with curl. The download goes to a temporary file
+/// first so that failed or concurrent downloads never leave a partial `dest`
+/// behind. Returns `false` if the server does not have the file.
+pub fn fetch(url: &str, dest: &Path) -> Result<bool, Box<dyn Error>>
+{
+    curl(CURL, url, dest)
+}
+
+/// `fetch` using the curl binary `program`
+fn curl(program: &str, url: &str, dest: &Path) -> Result<bool, Box<dyn Error>>
+{
+    let tmp = temp_path(dest);
+    let status = Command::new(program)
+        .args(["-fsgL", "-A", USER_AGENT, "-o"])
+        .arg(&tmp).arg(url)
+        .status();
+
+    match status {
+        Ok(status) if status.success() => {
+            fs::rename(&tmp, dest)?;
+            Ok(true)
+        }
+        Ok(status) => {
+            let _ = fs::remove_file(&tmp);
+            if status.code() == Some(CURL_HTTP_ERROR) {
+                Ok(false)
+            } else {
+                Err(format!("Failed to fetch {}", url).into())
+            }
+        }
+        Err(err) => {
+            Err(format!("Failed to run {}: {}", program, err).into())
+        }
+    }
+}
+
+/// Download `entry` from the server at `url` into the store at `root`,
+/// falling back to its compressed counterpart. Returns the path the file was
+/// stored at, or `None` if it was already present.
+pub fn download(index: &mut DirIndex, root: &Path, url: &str, entry: &Entry)
+    -> Result<Option<PathBuf>, Box<dyn Error>>
+{
+    download_with(index, root, url, entry, fetch)
+}
+
+/// `download` fetching files with `fetch`
+fn download_with<F>(index: &mut DirIndex, root: &Path, url: &str,
+                    entry: &Entry, fetch: F) ->
+    Result<Option<PathBuf>, Box<dyn Error>>
+    where F: Fn(&str, &Path) -> Result<bool, Box<dyn Error>>
+{
+    /* Manifests may name PDBs by their full build path */
+    let name = entry.name.rsplit(['\\', '/']).next().unwrap_or("");
+    if !valid_name(name) {
+        return Err(format!("Not downloading invalid name {:?}", name).into());
+    }
+
+    let layout = store::detect_layout(root);
+    let dest = index.entry_path(root, layout, store::Case::Preserve, name,
+                                &entry.key);
+    let dir = dest.parent().unwrap();
+    let compressed = cab::compressed_name(name);
+    if dest.exists() || index.find(dir, &compressed).is_some() {
+        return Ok(None);
+    }
+    fs::create_dir_all(dir)?;
+
+    let base = format!("{}/{}/{}", url, name, entry.key);
+    let res = fetch(&format!("{}/{}", base, name), &dest).and_then(|found| {
+        if found {
+            return Ok(true);
+        }
+
+        /* Expand the compressed file into place */
+        let cab_path = temp_path(&dir.join(&compressed));
+        if !fetch(&format!("{}/{}", base, compressed), &cab_path)? {
+            return Ok(false);
+        }
+        let data = File::open(&cab_path).map_err(Box::<dyn Error>::from)
+            .and_then(|mut fd| cab::extract(&mut fd, None));
+        let _ = fs::remove_file(&cab_path);
+
+        store::write_file(&dest, &data?.1)?;
+        Ok(true)
+    });
+
+    match res {
+        Ok(true) => Ok(Some(dest)),
+        Ok(false) => {
+            store::remove_empty_dirs(root, dir);
+            Err(format!("{}/{} not found", base, name).into())
+        }
+        Err(err) => {
+            store::remove_empty_dirs(root, dir);
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    use manifest::Kind;
+
+    const KEY: &str = "0102030405060708090A0B0C0D0E0F101";
+    const URL: &str = "http://127.0.0.1/symbols";
+
+    /// Create an empty scratch directory for the test `name`
+    fn scratch(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("pdblister-{}-{}", name,
+                                                     std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A CAB holding `data` uncompressed
+    fn cab(data: &[u8]) -> Vec<u8>
+    {
+        let mut cab = b"MSCF".to_vec();
+        cab.extend_from_slice(&[0; 12]);
+        cab.extend_from_slice(&44u32.to_le_bytes());
+        cab.extend_from_slice(&[0, 0, 0, 0, 3, 1, 1, 0, 1, 0, 0, 0]);
+        cab.extend_from_slice(&[0; 4]);
+        cab.extend_from_slice(&70u32.to_le_bytes());
+        cab.extend_from_slice(&[1, 0, 0, 0]);
+        cab.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        cab.extend_from_slice(&[0; 12]);
+        cab.extend_from_slice(b"ntdll.pdb\0");
+        cab.extend_from_slice(&[0; 4]);
+        cab.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        cab.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        cab.extend_from_slice(data);
+        cab
+    }
+
+    /// Download `ntdll.pdb` into `root` from a server holding `files`,
+    /// giving the result and the URLs requested
+    fn download(root: &Path, files: &[(&str, &[u8])]) ->
+        (Result<Option<PathBuf>, String>, Vec<String>)
+    {
+        let entry = Entry {
+            name: r"d:\build\ntdll.pdb".into(),
+            key:  KEY.into(),
+            kind: Kind::Pdb,
+        };
+        let urls = RefCell::new(Vec::new());
+        let fetch = |url: &str, dest: &Path| {
+            urls.borrow_mut().push(url.to_string());
+            match files.iter().find(|x| url.ends_with(x.0)) {
+                Some((_, data)) => {
+                    fs::write(dest, data)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        };
+        let res = download_with(&mut DirIndex::new(), root, URL, &entry,
+                                fetch);
+        (res.map_err(|x| x.to_string()), urls.into_inner())
+    }
+
+    fn listing(dir: &Path) -> Vec<String>
+    {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn falls_back_to_compressed()
+    {
+        let root = scratch("download-fallback");
+        let base = format!("{}/ntdll.pdb/{}", URL, KEY);
+        let dest = root.join("ntdll.pdb").join(KEY).join("ntdll.pdb");
+
+        let (res, urls) = download(&root, &[("/ntdll.pd_",
+                                             &cab(b"pdb contents"))]);
+        assert_eq!(res.unwrap(), Some(dest.clone()));
+        assert_eq!(urls, [format!("{}/ntdll.pdb", base),
+                          format!("{}/ntdll.pd_", base)]);
+        assert_eq!(fs::read(&dest).unwrap(), b"pdb contents");
+        assert_eq!(listing(dest.parent().unwrap()), ["ntdll.pdb"]);
+
+        /* Nothing is fetched once the file is there */
+        let (res, urls) = download(&root, &[]);
+        assert_eq!(res.unwrap(), None);
+        assert!(urls.is_empty());
+
+        /* Files the server has uncompressed are used as they are */
+        fs::remove_file(&dest).unwrap();
+        let (res, urls) = download(&root, &[("/ntdll.pdb", b"plain"),
+                                            ("/ntdll.pd_", b"bad cab")]);
+        assert_eq!(res.unwrap(), Some(dest.clone()));
+        assert_eq!(urls.len(), 1);
+        assert_eq!(fs::read(&dest).unwrap(), b"plain");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn cleans_up_failures()
+    {
+        let root = scratch("download-failures");
+
+        let (res, urls) = download(&root, &[]);
+        assert_eq!(res.unwrap_err(), format!("{}/ntdll.pdb/{}/ntdll.pdb not \
+                                              found", URL, KEY));
+        assert_eq!(urls.len(), 2);
+        assert!(listing(&root).is_empty());
+
+        /* The compressed file is removed when it can't be expanded */
+        let (res, _) = download(&root, &[("/ntdll.pd_", b"bad cab")]);
+        assert!(res.is_err());
+        assert!(listing(&root).is_empty());
+
+        /* Other files in the store are left alone */
+        let other = root.join("ntdll.pdb").join("OTHER");
+        fs::create_dir_all(&other).unwrap();
+        let (res, _) = download(&root, &[]);
+        assert!(res.is_err());
+        assert_eq!(listing(&root.join("ntdll.pdb")), ["OTHER"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn curl_temp_files()
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch("download-curl");
+        let dest = dir.join("ntdll.pdb");
+
+        /* A curl writing part of the file before exiting with `status` */
+        let fake = |status: i32| {
+            let program = dir.join(format!("curl{}", status));
+            fs::write(&program, format!("#!/bin/sh\n\
+                                         printf partial > \"$5\"\n\
+                                         exit {}\n", status)).unwrap();
+            fs::set_permissions(&program, fs::Permissions::from_mode(0o755))
+                .unwrap();
+            program.to_str().unwrap().to_string()
+        };
+
+        let program = fake(CURL_HTTP_ERROR);
+        assert!(!curl(&program, URL, &dest).unwrap());
+        let program = fake(7);
+        assert_eq!(curl(&program, URL, &dest).unwrap_err().to_string(),
+                   format!("Failed to fetch {}", URL));
+        assert_eq!(listing(&dir), ["curl22", "curl7"]);
+
+        let program = fake(0);
+        assert!(curl(&program, URL, &dest).unwrap());
+        assert_eq!(fs::read(&dest).unwrap(), b"partial");
+        assert_eq!(listing(&dir), ["curl0", "curl22", "curl7", "ntdll.pdb"]);
+
+        let missing = dir.join("missing");
+        assert!(curl(missing.to_str().unwrap(), URL, &dest).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+//! Canonical Huffman decoding as used by XPRESS, LZX and LZMS.
+
+use bitstream::BitReader;
+
+/// Number of bits resolved by the direct lookup table. Longer codes fall
+/// back to a canonical search which is rare in practice.
+const TABLE_BITS: u32 = 10;
+
+pub struct Huffman {
+    /// Direct lookup table indexed by the next `TABLE_BITS` bits. Each entry
+    /// is `(symbol << 5) | length`, a length of 0 means the code is longer
+    /// than `TABLE_BITS` (or invalid).
+    table: Vec<u32>,
+
+    /// Longest codeword length in this code
+    max_len: u32,
+
+    /// Number of codes of each length
+    counts: [u32; 33],
+
+    /// First canonical code of each length
+    first_code: [u32; 33],
+
+    /// Index into `sorted` of the first symbol of each length
+    first_index: [u32; 33],
+
+    /// Symbols sorted by (length, symbol)
+    sorted: Vec<u16>,
+}
+
+impl Huffman {
+    /// Build a decoder from a table of codeword lengths, one per symbol. A
+    /// length of zero means the symbol is not used.
+    pub fn new(lengths: &[u8], max_len: u32) -> Result<Huffman, &'static str>
+    {
+        let mut counts = [0u32; 33];
+        for &len in lengths {
+            if len as u32 > max_len {
+                return Err("Huffman codeword length too long");
+            }
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        /* Make sure the code is not over-subscribed. Incomplete codes are
+         * permitted as encoders emit them for sparse alphabets. */
+        let mut left: i64 = 1;
+        for &count in &counts[1..=max_len as usize] {
+            left = (left << 1) - count as i64;
+            if left < 0 {
+                return Err("Over-subscribed Huffman code");
+            }
+        }
+
+        let mut first_code  = [0u32; 33];
+        let mut first_index = [0u32; 33];
+        let mut code  = 0u32;
+        let mut index = 0u32;
+        for len in 1..=max_len as usize {
+            code = (code + counts[len - 1]) << 1;
+            first_code[len]  = code;
+            first_index[len] = index;
+            index += counts[len];
+        }
+
+        let mut offsets = first_index;
+        let mut sorted = vec![0u16; index as usize];
+        for (sym, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                sorted[offsets[len as usize] as usize] = sym as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        /* Fill in the direct lookup table for all short codes */
+        let mut table = vec![0u32; 1 << TABLE_BITS];
+        for len in 1..=std::cmp::min(max_len, TABLE_BITS) as usize {
+            for ii in 0..counts[len] {
+                let sym  = sorted[(first_index[len] + ii) as usize] as u32;
+                let code = first_code[len] + ii;
+                let fill = TABLE_BITS - len as u32;
+                let base = (code << fill) as usize;
+                for ent in &mut table[base..base + (1 << fill)] {
+                    *ent = (sym << 5) | len as u32;
+                }
+            }
+        }
+
+        Ok(Huffman { table, max_len, counts, first_code, first_index, sorted })
+    }
+
+    /// Decode the next symbol from `reader`
+    pub fn decode(&self, reader: &mut BitReader) -> Result<u16, &'static str>
+    {
+        reader.ensure(self.max_len);
+
+        if self.max_len >= TABLE_BITS {
+            let ent = self.table[reader.peek(TABLE_BITS) as usize];
+            if ent & 0x1f != 0 {
+                reader.consume(ent & 0x1f);
+                return Ok((ent >> 5) as u16);
+            }
+        }
+
+        for len in 1..=self.max_len {
+            let code  = reader.peek(len);
+            let delta = code.wrapping_sub(self.first_code[len as usize]);
+            if delta < self.counts[len as usize] {
+                reader.consume(len);
+                let idx = self.first_index[len as usize] + delta;
+                return Ok(self.sorted[idx as usize]);
+            }
+        }
+
+        Err("Invalid Huffman code in stream")
+    }
+}
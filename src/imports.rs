@@ -0,0 +1,515 @@
+//! Import, delay-load import and export tables of PE images.
+//!
+//! Imports are arrays of descriptors naming a DLL and pointing at a table of
+//! thunks, each importing a function by name or by ordinal. Delay-load
+//! imports are the same with their own descriptor layout. Exports are an
+//! array of function RVAs with a sorted table of names alongside; RVAs which
+//! point back into the export directory are forwarders to another DLL.
+
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+
+use pe::{PeFile, OptionalHeader, le16, le32};
+
+/// Indices among the data directories
+const EXPORT_DIRECTORY:       usize = 0;
+const IMPORT_DIRECTORY:       usize = 1;
+const DELAY_IMPORT_DIRECTORY: usize = 13;
+
+/// Set in `attributes` of delay-load descriptors using RVAs. Descriptors
+/// written by VC++ 6 without it use virtual addresses.
+const DELAY_ATTRIBUTE_RVA: u32 = 1;
+
+/// Most descriptors and functions read out of a single table, well past what
+/// any real image has
+const MAX_DESCRIPTORS: usize = 4096;
+const MAX_FUNCTIONS:   u32 = 0x10000;
+
+/// Longest DLL or function name we will read
+const MAX_NAME: u32 = 512;
+
+/// How a function is imported
+#[derive(Clone)]
+pub enum Function {
+    /// By name, with a hint of where it is in the export name table
+    Name { hint: u16, name: String },
+
+    Ordinal(u16),
+}
+
+/// Functions imported from one DLL
+#[derive(Clone)]
+pub struct Import {
+    /// Name of the DLL as recorded, eg. `KERNEL32.dll`
+    pub dll: String,
+
+    /// Whether this is a delay-load import, loaded on first use
+    pub delay: bool,
+
+    pub functions: Vec<Function>,
+}
+
+/// An exported function
+#[derive(Clone)]
+pub struct Export {
+    pub ordinal: u32,
+    pub rva:     u32,
+
+    /// Name, unless the function is only exported by ordinal
+    pub name: Option<String>,
+
+    /// `<dll>.<function>` the export is forwarded to, if it is
+    pub forwarder: Option<String>,
+}
+
+/// The export directory of an image
+#[derive(Clone)]
+pub struct Exports {
+    /// Name the DLL was linked as
+    pub dll: String,
+
+    pub timestamp:    u32,
+    pub ordinal_base: u32,
+
+    /// Every exported function, by ordinal
+    pub functions: Vec<Export>,
+}
+
+/// Read up to `size` bytes from the file starting at `rva`. Only the start
+/// has to be mapped, tables and strings are read in chunks that may run
+/// past their end.
+fn read_from<R: Read + Seek>(image: &PeFile, fd: &mut R, rva: u32,
+                             size: u32) -> Result<Vec<u8>, Box<dyn Error>>
+{
+    let offset = match image.rva_to_offset(rva, 1) {
+        Some(offset) => offset,
+        None => return Err(format!("Unable to find RVA 0x{:x} in the file",
+                                   rva).into()),
+    };
+    fd.seek(SeekFrom::Start(offset))?;
+
+    let mut data = Vec::new();
+    fd.by_ref().take(size as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Get the NUL terminated string at the start of `data`
+fn string(data: &[u8], rva: u32) -> Result<String, Box<dyn Error>>
+{
+    match data.iter().position(|&x| x == 0) {
+        Some(end) => Ok(String::from_utf8_lossy(&data[..end]).into_owned()),
+        None => Err(format!("Name at RVA 0x{:x} not terminated", rva).into()),
+    }
+}
+
+/// Read the NUL terminated string at `rva`
+fn read_string<R: Read + Seek>(image: &PeFile, fd: &mut R, rva: u32) ->
+    Result<String, Box<dyn Error>>
+{
+    string(&read_from(image, fd, rva, MAX_NAME)?, rva)
+}
+
+/// Get the data directory `index` if it is present
+fn directory(image: &PeFile, index: usize) -> Option<(u32, u32)>
+{
+    match image.data_dirs.get(index) {
+        Some(dir) if dir.vaddr != 0 && dir.size != 0 =>
+            Some((dir.vaddr, dir.size)),
+        _ => None,
+    }
+}
+
+/// Read the thunk table at `rva`, up to its terminating zero thunk
+fn read_thunks<R: Read + Seek>(image: &PeFile, fd: &mut R, rva: u32) ->
+    Result<Vec<Function>, Box<dyn Error>>
+{
+    let pe64 = matches!(image.optional, Some(OptionalHeader::Pe64(_)));
+    let size: u32 = if pe64 { 8 } else { 4 };
+
+    /* Read the thunks in chunks, the table is only as long as it is */
+    let mut thunks = Vec::new();
+    let mut chunk_rva = rva;
+    'table: loop {
+        let chunk = read_from(image, fd, chunk_rva, 64 * size)?;
+        if chunk.len() < size as usize {
+            return Err("Thunk table truncated".into());
+        }
+        for thunk in chunk.chunks_exact(size as usize) {
+            let thunk = match (le32(thunk, 0), le32(thunk, 4)) {
+                (Some(low), Some(high)) if pe64 =>
+                    low as u64 | (high as u64) << 32,
+                (Some(low), _) => low as u64,
+                _ => return Err("Thunk table truncated".into()),
+            };
+            if thunk == 0 {
+                break 'table;
+            }
+            if thunks.len() as u32 >= MAX_FUNCTIONS {
+                return Err("Thunk table not terminated".into());
+            }
+            thunks.push(thunk);
+        }
+        chunk_rva = chunk_rva.checked_add(chunk.len() as u32 / size * size)
+            .ok_or("Thunk table out of range")?;
+    }
+
+    let mut functions = Vec::new();
+    for thunk in thunks {
+        let by_ordinal = if pe64 {
+            thunk & (1 << 63) != 0
+        } else {
+            thunk & (1 << 31) != 0
+        };
+        if by_ordinal {
+            functions.push(Function::Ordinal(thunk as u16));
+            continue;
+        }
+
+        /* IMAGE_IMPORT_BY_NAME */
+        let data = read_from(image, fd, thunk as u32, MAX_NAME + 2)?;
+        let hint = le16(&data, 0).ok_or("Import name truncated")?;
+        functions.push(Function::Name {
+            hint,
+            name: string(&data[2..], (thunk as u32).wrapping_add(2))?,
+        });
+    }
+    Ok(functions)
+}
+
+/// Read the import directory
+fn read_imports<R: Read + Seek>(image: &PeFile, fd: &mut R) ->
+    Result<Vec<Import>, Box<dyn Error>>
+{
+    let (rva, _) = match directory(image, IMPORT_DIRECTORY) {
+        Some(dir) => dir,
+        None => return Ok(Vec::new()),
+    };
+
+    /* IMAGE_IMPORT_DESCRIPTORs, up to an all zero one */
+    let mut imports = Vec::new();
+    for ii in 0..MAX_DESCRIPTORS as u32 {
+        let desc = image.read_rva(fd, rva.checked_add(ii * 20)
+                                  .ok_or("Import directory out of range")?,
+                                  20)?;
+        let field = |offset| {
+            le32(&desc, offset).ok_or("Import descriptor truncated")
+        };
+        let (lookup, name, iat) = (field(0)?, field(12)?, field(16)?);
+        if name == 0 && iat == 0 {
+            return Ok(imports);
+        }
+
+        /* The lookup table is optional, in which case the import address
+         * table still holds the same thunks in the file */
+        let thunks = if lookup != 0 { lookup } else { iat };
+        imports.push(Import {
+            dll:       read_string(image, fd, name)?,
+            delay:     false,
+            functions: read_thunks(image, fd, thunks)?,
+        });
+    }
+    Err("Import directory not terminated".into())
+}
+
+/// Read the delay-load import directory
+fn read_delay_imports<R: Read + Seek>(image: &PeFile, fd: &mut R) ->
+    Result<Vec<Import>, Box<dyn Error>>
+{
+    let (rva, _) = match directory(image, DELAY_IMPORT_DIRECTORY) {
+        Some(dir) => dir,
+        None => return Ok(Vec::new()),
+    };
+    let image_base = image.optional.map_or(0, |x| x.image_base());
+
+    /* ImgDelayDescrs, up to one without a name */
+    let mut imports = Vec::new();
+    for ii in 0..MAX_DESCRIPTORS as u32 {
+        let desc = image.read_rva(fd, rva.checked_add(ii * 32)
+                                  .ok_or("Delay import directory out of \
+                                          range")?,
+                                  32)?;
+        let field = |offset| {
+            le32(&desc, offset).ok_or("Delay import descriptor truncated")
+        };
+        let (attributes, name, names) = (field(0)?, field(4)?, field(16)?);
+        if name == 0 {
+            return Ok(imports);
+        }
+
+        let to_rva = |addr: u32| -> Result<u32, Box<dyn Error>> {
+            if attributes & DELAY_ATTRIBUTE_RVA != 0 {
+                return Ok(addr);
+            }
+            (addr as u64).checked_sub(image_base).map(|x| x as u32)
+                .ok_or_else(|| "Delay import address below image base".into())
+        };
+        imports.push(Import {
+            dll:       read_string(image, fd, to_rva(name)?)?,
+            delay:     true,
+            functions: read_thunks(image, fd, to_rva(names)?)?,
+        });
+    }
+    Err("Delay import directory not terminated".into())
+}
+
+/// Read the import and delay-load import directories, in that order
+pub fn read<R: Read + Seek>(image: &PeFile, fd: &mut R) ->
+    Result<Vec<Import>, Box<dyn Error>>
+{
+    let mut imports = read_imports(image, fd)?;
+    imports.extend(read_delay_imports(image, fd)?);
+    Ok(imports)
+}
+
+/// Read the export directory. Images which export nothing give `None`.
+pub fn read_exports<R: Read + Seek>(image: &PeFile, fd: &mut R) ->
+    Result<Option<Exports>, Box<dyn Error>>
+{
+    let (rva, size) = match directory(image, EXPORT_DIRECTORY) {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+    let dir = image.read_rva(fd, rva, 40)?;
+    let field = |offset| {
+        le32(&dir, offset).ok_or("Export directory truncated")
+    };
+    let (timestamp, name, ordinal_base) = (field(4)?, field(12)?, field(16)?);
+    let (num_functions, num_names) = (field(20)?, field(24)?);
+    let (functions_rva, names_rva, ordinals_rva) =
+        (field(28)?, field(32)?, field(36)?);
+    if num_functions > MAX_FUNCTIONS || num_names > MAX_FUNCTIONS {
+        return Err("Too many exports".into());
+    }
+
+    let mut functions = Vec::new();
+    if num_functions > 0 {
+        let rvas = image.read_rva(fd, functions_rva, num_functions * 4)?;
+        for (ii, func) in rvas.chunks_exact(4).enumerate() {
+            let func = le32(func, 0).ok_or("Export table truncated")?;
+
+            /* Forwarders point at their `<dll>.<function>` string inside
+             * the export directory */
+            let forwarder = if func >= rva && func - rva < size {
+                Some(read_string(image, fd, func)?)
+            } else {
+                None
+            };
+            functions.push(Export {
+                ordinal: ordinal_base.wrapping_add(ii as u32),
+                rva:     func,
+                name:    None,
+                forwarder,
+            });
+        }
+    }
+
+    if num_names > 0 {
+        let names = image.read_rva(fd, names_rva, num_names * 4)?;
+        let ordinals = image.read_rva(fd, ordinals_rva, num_names * 2)?;
+        for ii in 0..num_names as usize {
+            let (index, name) = match (le16(&ordinals, ii * 2),
+                                       le32(&names, ii * 4)) {
+                (Some(index), Some(name)) => (index as usize, name),
+                _ => return Err("Export name table truncated".into()),
+            };
+            let name = read_string(image, fd, name)?;
+            match functions.get_mut(index) {
+                Some(func) => func.name = Some(name),
+                None => return Err("Export name ordinal out of range".into()),
+            }
+        }
+    }
+
+    /* Unused slots in the function table are zero */
+    functions.retain(|x| x.rva != 0);
+
+    let dll = if name != 0 { read_string(image, fd, name)? } else {
+        String::new()
+    };
+    Ok(Some(Exports { dll, timestamp, ordinal_base, functions }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use pe;
+
+    const IMAGE_BASE: u32 = 0x40_0000;
+
+    /// Build an image with the data directories `dirs` and the tables in
+    /// `data` at their RVAs. Sections are aligned like files, so RVAs are
+    /// file offsets.
+    fn image(pe64: bool, dirs: &[(usize, u32, u32)],
+             data: &[(u32, &[u8])]) -> Vec<u8>
+    {
+        let mut out = vec![0u8; 0x800];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            out[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(0, b"MZ");
+        put(0x3c, &0x40u32.to_le_bytes());
+        put(0x40, b"PE\0\0");
+        let (machine, magic, size) = if pe64 {
+            (pe::IMAGE_FILE_MACHINE_AMD64, 0x20bu16, 112)
+        } else {
+            (pe::IMAGE_FILE_MACHINE_I386, 0x10b, 96)
+        };
+        put(0x44, &machine.to_le_bytes());
+        put(0x54, &(size as u16 + 16 * 8).to_le_bytes());
+
+        let optional = 0x58;
+        put(optional, &magic.to_le_bytes());
+        if pe64 {
+            put(optional + 24, &(IMAGE_BASE as u64).to_le_bytes());
+        } else {
+            put(optional + 28, &IMAGE_BASE.to_le_bytes());
+        }
+        put(optional + 32, &0x200u32.to_le_bytes());
+        put(optional + 36, &0x200u32.to_le_bytes());
+        put(optional + 60, &0x200u32.to_le_bytes());
+        put(optional + size - 4, &16u32.to_le_bytes());
+        for &(index, rva, len) in dirs {
+            let dir = optional + size + index * 8;
+            put(dir, &rva.to_le_bytes());
+            put(dir + 4, &len.to_le_bytes());
+        }
+        for &(rva, bytes) in data {
+            put(rva as usize, bytes);
+        }
+        out
+    }
+
+    fn dwords(vals: &[u32]) -> Vec<u8>
+    {
+        vals.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    /// A thunk table, 64-bit if `pe64`
+    fn thunks(pe64: bool, vals: &[u64]) -> Vec<u8>
+    {
+        vals.iter().flat_map(|&x| {
+            if pe64 { x.to_le_bytes().to_vec() } else {
+                (x as u32).to_le_bytes().to_vec()
+            }
+        }).collect()
+    }
+
+    fn functions(import: &Import) -> Vec<String>
+    {
+        import.functions.iter().map(|x| match x {
+            Function::Name { hint, name } => format!("{}:{}", hint, name),
+            Function::Ordinal(ordinal) => format!("#{}", ordinal),
+        }).collect()
+    }
+
+    fn imports(pe64: bool) -> Vec<u8>
+    {
+        let ordinal = if pe64 { 1 << 63 } else { 1 << 31 };
+        let base = IMAGE_BASE;
+        image(pe64, &[(IMPORT_DIRECTORY, 0x200, 60),
+                      (DELAY_IMPORT_DIRECTORY, 0x400, 96)], &[
+            /* One descriptor with a lookup table, one with just the IAT */
+            (0x200, &dwords(&[0x300, 0, 0, 0x280, 0x300,
+                              0, 0, 0, 0x290, 0x340])),
+            (0x280, b"KERNEL32.dll\0"),
+            (0x290, b"ntdll.dll\0"),
+            (0x300, &thunks(pe64, &[0x380, ordinal | 7, 0])),
+            (0x340, &thunks(pe64, &[0x3a0, 0])),
+            (0x380, b"\x05\0CreateFileW\0"),
+            (0x3a0, b"\0\0NtClose\0"),
+
+            /* Delay-load descriptors using an RVA and a virtual address */
+            (0x400, &dwords(&[1, 0x460, 0, 0, 0x480, 0, 0, 0,
+                              0, base + 0x470, 0, 0, base + 0x4a0, 0, 0,
+                              0])),
+            (0x460, b"USER32.dll\0"),
+            (0x470, b"GDI32.dll\0"),
+            (0x480, &thunks(pe64, &[ordinal | 3, 0])),
+            (0x4a0, &thunks(pe64, &[0x380, 0])),
+        ])
+    }
+
+    #[test]
+    fn imports_and_delay_imports()
+    {
+        for &pe64 in &[false, true] {
+            let data = imports(pe64);
+            let image = pe::parse(&mut Cursor::new(&data));
+            let imports = read(&image, &mut Cursor::new(&data)).unwrap();
+            let got: Vec<(&str, bool, Vec<String>)> = imports.iter()
+                .map(|x| (x.dll.as_str(), x.delay, functions(x))).collect();
+            assert_eq!(got, [
+                ("KERNEL32.dll", false,
+                 vec!["5:CreateFileW".to_string(), "#7".to_string()]),
+                ("ntdll.dll", false, vec!["0:NtClose".to_string()]),
+                ("USER32.dll", true, vec!["#3".to_string()]),
+                ("GDI32.dll", true, vec!["5:CreateFileW".to_string()]),
+            ]);
+            assert!(read_exports(&image, &mut Cursor::new(&data)).unwrap()
+                    .is_none());
+        }
+    }
+
+    #[test]
+    fn exports()
+    {
+        let data = image(false, &[(EXPORT_DIRECTORY, 0x600, 0x100)], &[
+            (0x600, &dwords(&[0, 0x1234, 0, 0x680, 5, 3, 2, 0x6a0, 0x6c0,
+                              0x6d0])),
+            (0x680, b"test.dll\0"),
+            (0x6a0, &dwords(&[0x1000, 0, 0x6e0])),
+            (0x6c0, &dwords(&[0x6f0, 0x700])),
+            (0x6d0, &[0, 0, 2, 0]),
+            (0x6e0, b"NTDLL.RtlFoo\0"),
+            (0x6f0, b"Alpha\0"),
+            (0x700, b"Fwd\0"),
+        ]);
+        let image = pe::parse(&mut Cursor::new(&data));
+        let exports = read_exports(&image, &mut Cursor::new(&data)).unwrap()
+            .unwrap();
+        assert_eq!((exports.dll.as_str(), exports.timestamp,
+                    exports.ordinal_base), ("test.dll", 0x1234, 5));
+        let got: Vec<_> = exports.functions.iter()
+            .map(|x| (x.ordinal, x.rva, x.name.as_deref(),
+                      x.forwarder.as_deref()))
+            .collect();
+        assert_eq!(got, [(5, 0x1000, Some("Alpha"), None),
+                         (7, 0x6e0, Some("Fwd"), Some("NTDLL.RtlFoo"))]);
+        assert!(read(&image, &mut Cursor::new(&data)).unwrap().is_empty());
+
+        /* A name for an ordinal past the function table */
+        let mut bad = data.clone();
+        bad[0x6d2] = 3;
+        assert!(read_exports(&image, &mut Cursor::new(&bad)).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_tables()
+    {
+        let data = imports(true);
+        let image = pe::parse(&mut Cursor::new(&data));
+
+        /* Unterminated names, and delay-load addresses below the image
+         * base */
+        let mut bad = data.clone();
+        bad[0x280..0x800].iter_mut().for_each(|x| *x = 0x41);
+        bad.truncate(0x300);
+        assert!(read(&image, &mut Cursor::new(&bad)).is_err());
+        let mut bad = data.clone();
+        bad[0x426] = 0;
+        assert!(read(&image, &mut Cursor::new(&bad)).is_err());
+
+        let mut seed = 1u32;
+        for _ in 0..10000 {
+            let mut bad = data.clone();
+            for _ in 0..4 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let pos = 0x200 + (seed >> 8) as usize % 0x300;
+                bad[pos] = (seed >> 24) as u8;
+            }
+            let _ = read(&image, &mut Cursor::new(&bad));
+        }
+    }
+}
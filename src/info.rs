@@ -0,0 +1,847 @@
+//! `pdblister info`, a dump of everything read out of an image while looking
+//! for its PDB, and of its Rich header, version resource, Authenticode
+//! signature, imports and exports, in human readable or JSON form.
+
+use json;
+use manifest::machine_name;
+use pe::{self, PeFile, OptionalHeader, DebugData};
+use pe::{MZHeader, PEHeader, ImageSectionHeader, ImageDebugDirectory};
+use version::VersionInfo;
+use authenticode::{Signature, Certificate};
+use imports::{Import, Exports, Export, Function};
+use rich::RichHeader;
+
+/// A header field and its value
+type Field = (&'static str, u64);
+
+fn mz_fields(hdr: &MZHeader) -> Vec<Field>
+{
+    vec![
+        ("last_page_bytes", hdr.last_page_bytes as u64),
+        ("num_pages",       hdr.num_pages as u64),
+        ("num_relocations", hdr.num_relocations as u64),
+        ("header_size",     hdr.header_size as u64),
+        ("min_memory",      hdr.min_memory as u64),
+        ("max_memory",      hdr.max_memory as u64),
+        ("initial_ss",      hdr.initial_ss as u64),
+        ("initial_sp",      hdr.initial_sp as u64),
+        ("checksum",        hdr.checksum as u64),
+        ("entry",           hdr.entry as u64),
+        ("ptr_relocation",  hdr.ptr_relocation as u64),
+        ("overlay",         hdr.overlay as u64),
+        ("new_header",      hdr.new_header as u64),
+    ]
+}
+
+fn pe_fields(hdr: &PEHeader) -> Vec<Field>
+{
+    vec![
+        ("machine",              hdr.machine as u64),
+        ("num_sections",         hdr.num_sections as u64),
+        ("timestamp",            hdr.timestamp as u64),
+        ("ptr_symtable",         hdr.ptr_symtable as u64),
+        ("num_smtable",          hdr.num_smtable as u64),
+        ("optional_header_size", hdr.optional_header_size as u64),
+        ("characteristics",      hdr.characteristics as u64),
+    ]
+}
+
+fn optional_fields(hdr: &OptionalHeader) -> Vec<Field>
+{
+    /* Only the widths of a few fields differ between the two */
+    macro_rules! fields {
+        ($hdr:expr, $($extra:tt)*) => {
+            vec![
+                ("magic",                      $hdr.magic as u64),
+                ("linker_major_version",       $hdr.linker_major_version as u64),
+                ("linker_minor_version",       $hdr.linker_minor_version as u64),
+                ("size_of_code",               $hdr.size_of_code as u64),
+                ("size_of_initialized_data",   $hdr.size_of_initialized_data as u64),
+                ("size_of_uninitialized_data", $hdr.size_of_uninitialized_data as u64),
+                ("entry",                      $hdr.entry as u64),
+                ("code_base",                  $hdr.code_base as u64),
+                $($extra)*
+                ("image_base",                 $hdr.image_base as u64),
+                ("section_align",              $hdr.section_align as u64),
+                ("file_align",                 $hdr.file_align as u64),
+                ("major_os_version",           $hdr.major_os_version as u64),
+                ("minor_os_version",           $hdr.minor_os_version as u64),
+                ("major_image_version",        $hdr.major_image_version as u64),
+                ("minor_image_version",        $hdr.minor_image_version as u64),
+                ("major_subsystem_version",    $hdr.major_subsystem_version as u64),
+                ("minor_subsystem_version",    $hdr.minor_subsystem_version as u64),
+                ("win32_version",              $hdr.win32_version as u64),
+                ("size_of_image",              $hdr.size_of_image as u64),
+                ("size_of_headers",            $hdr.size_of_headers as u64),
+                ("checksum",                   $hdr.checksum as u64),
+                ("subsystem",                  $hdr.subsystem as u64),
+                ("dll_characteristics",        $hdr.dll_characteristics as u64),
+                ("size_of_stack_reserve",      $hdr.size_of_stack_reserve as u64),
+                ("size_of_stack_commit",       $hdr.size_of_stack_commit as u64),
+                ("size_of_heap_reserve",       $hdr.size_of_heap_reserve as u64),
+                ("size_of_heap_commit",        $hdr.size_of_heap_commit as u64),
+                ("loader_flags",               $hdr.loader_flags as u64),
+                ("num_tables",                 $hdr.num_tables as u64),
+            ]
+        }
+    }
+
+    match hdr {
+        OptionalHeader::Pe32(hdr) => {
+            fields!(hdr, ("data_base", hdr.data_base as u64),)
+        }
+        OptionalHeader::Pe64(hdr) => fields!(hdr,),
+    }
+}
+
+/// Get the name of an optional header format
+fn optional_format(hdr: &OptionalHeader) -> &'static str
+{
+    match hdr {
+        OptionalHeader::Pe32(_) => "PE32",
+        OptionalHeader::Pe64(_) => "PE32+",
+    }
+}
+
+fn data_dir_name(index: usize) -> &'static str
+{
+    pe::DATA_DIRECTORY_NAMES.get(index).cloned().unwrap_or("?")
+}
+
+/// Get the name of a section, which is NUL padded rather than terminated
+fn section_name(sec: &ImageSectionHeader) -> String
+{
+    let end = sec.name.iter().position(|&x| x == 0).unwrap_or(8);
+    String::from_utf8_lossy(&sec.name[..end]).into_owned()
+}
+
+fn section_fields(sec: &ImageSectionHeader) -> Vec<Field>
+{
+    vec![
+        ("vaddr",               sec.vaddr as u64),
+        ("vsize",               sec.vsize as u64),
+        ("pointer_to_raw_data", sec.pointer_to_raw_data as u64),
+        ("raw_data_size",       sec.raw_data_size as u64),
+        ("characteristics",     sec.characteristics as u64),
+    ]
+}
+
+fn debug_fields(de: &ImageDebugDirectory) -> Vec<Field>
+{
+    vec![
+        ("characteristics",     de.characteristics as u64),
+        ("timestamp",           de.timestamp as u64),
+        ("major_version",       de.major_version as u64),
+        ("minor_version",       de.minor_version as u64),
+        ("typ",                 de.typ as u64),
+        ("size_of_data",        de.size_of_data as u64),
+        ("address_of_raw_data", de.address_of_raw_data as u64),
+        ("pointer_to_raw_data", de.pointer_to_raw_data as u64),
+    ]
+}
+
+/// Start a new section titled `title` in `out`
+fn push_title(out: &mut String, title: &str)
+{
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out.push_str(title);
+    out.push('\n');
+}
+
+/// Format `data` as hex
+fn hex(data: &[u8]) -> String
+{
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Get the names of the IMAGE_DLLCHARACTERISTICS_EX_* flags set in `flags`
+fn ex_dll_names(flags: u32) -> Vec<&'static str>
+{
+    pe::EX_DLLCHARACTERISTICS_NAMES.iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Get the name of the frame type of an FPO record
+fn fpo_frame_name(frame: u8) -> &'static str
+{
+    match frame {
+        0 => "FPO",
+        1 => "TRAP",
+        2 => "TSS",
+        _ => "NONFPO",
+    }
+}
+
+/// Append `fields` to `out`, one per line and aligned
+fn push_fields(out: &mut String, fields: &[Field])
+{
+    for (name, val) in fields {
+        out.push_str(&format!("    {:<28} 0x{:x}\n", name, val));
+    }
+}
+
+/// Append a line with a `name` and a value to `out`, aligned with fields
+fn push_line(out: &mut String, name: &str, val: &str)
+{
+    out.push_str(&format!("    {:<28} {}\n", name, val));
+}
+
+/// Append the decoded contents of a debug directory entry to `out`
+fn push_debug_data(out: &mut String, data: &Result<DebugData, String>)
+{
+    let data = match data {
+        Ok(data) => data,
+        Err(err) => return push_line(out, "error", err),
+    };
+
+    match data {
+        DebugData::Codeview(cv) => {
+            push_line(out, "guid", &cv.guid);
+            push_line(out, "age", &cv.age.to_string());
+            push_line(out, "path", &cv.path);
+        }
+        DebugData::Misc { data_type, name } => {
+            push_line(out, "data_type", &data_type.to_string());
+            push_line(out, "name", name);
+        }
+        DebugData::Fpo(records) => {
+            push_line(out, "records", &records.len().to_string());
+            for fpo in records {
+                out.push_str(&format!(
+                    "      start 0x{:08x} size 0x{:x} locals {} params {} \
+                     prolog {} regs {} seh {} bp {} {}\n",
+                    fpo.start, fpo.proc_size, fpo.locals, fpo.params,
+                    fpo.prolog, fpo.regs, fpo.has_seh, fpo.use_bp,
+                    fpo_frame_name(fpo.frame)));
+            }
+        }
+        DebugData::Pogo { signature, entries } => {
+            push_line(out, "signature", signature);
+            for entry in entries {
+                out.push_str(&format!("      0x{:08x} 0x{:08x} {}\n",
+                                      entry.rva, entry.size, entry.name));
+            }
+        }
+        DebugData::Iltcg => {
+            push_line(out, "iltcg", "linked with incremental LTCG");
+        }
+        DebugData::VcFeature(vc) => {
+            push_line(out, "pre_vc11", &vc.pre_vc11.to_string());
+            push_line(out, "c_cpp", &vc.c_cpp.to_string());
+            push_line(out, "gs", &vc.gs.to_string());
+            push_line(out, "sdl", &vc.sdl.to_string());
+            push_line(out, "guard_n", &vc.guard_n.to_string());
+        }
+        DebugData::Repro(hash) if hash.is_empty() => {
+            push_line(out, "hash", "(none)");
+        }
+        DebugData::Repro(hash) => push_line(out, "hash", &hex(hash)),
+        DebugData::ExDllCharacteristics(flags) => {
+            push_line(out, "flags", &format!("0x{:x} {}", flags,
+                                             ex_dll_names(*flags).join(" ")));
+        }
+        DebugData::Mpx(data) | DebugData::Other(data) => {
+            push_line(out, "data", &hex(data));
+        }
+    }
+}
+
+/// Append a version resource to `out`
+fn push_version(out: &mut String, version: &Result<VersionInfo, String>)
+{
+    let version = match version {
+        Ok(version) => version,
+        Err(err) => return push_line(out, "error", err),
+    };
+
+    if let Some(fixed) = &version.fixed {
+        push_line(out, "file_version", &version.file_version().unwrap());
+        push_line(out, "product_version",
+                  &version.product_version().unwrap());
+        push_fields(out, &[
+            ("file_flags_mask", fixed.file_flags_mask as u64),
+            ("file_flags",      fixed.file_flags as u64),
+            ("file_os",         fixed.file_os as u64),
+            ("file_type",       fixed.file_type as u64),
+            ("file_subtype",    fixed.file_subtype as u64),
+            ("file_date",       fixed.file_date),
+        ]);
+    }
+    if !version.language.is_empty() {
+        push_line(out, "language", &version.language);
+    }
+    for (key, val) in &version.strings {
+        push_line(out, key, val);
+    }
+}
+
+/// Append a Rich header to `out`, with a line per tool
+fn push_rich(out: &mut String, rich: &Result<RichHeader, String>)
+{
+    let rich = match rich {
+        Ok(rich) => rich,
+        Err(err) => return push_line(out, "error", err),
+    };
+
+    push_fields(out, &[
+        ("offset", rich.offset as u64),
+        ("key",    rich.key as u64),
+    ]);
+    push_line(out, "checksum_valid", &rich.checksum_valid.to_string());
+    push_line(out, "toolchain", rich.toolchain().unwrap_or("(unknown)"));
+    push_line(out, "oldest", rich.oldest().unwrap_or("(unknown)"));
+    push_line(out, "entries", &rich.entries.len().to_string());
+    for entry in &rich.entries {
+        out.push_str(&format!("      {:<24} build {:5} count {:5}{}\n",
+                              entry.name(), entry.build, entry.count,
+                              entry.visual_studio()
+                                  .map_or(String::new(),
+                                          |x| format!(" {}", x))));
+    }
+}
+
+/// Append an Authenticode signature to `out`
+fn push_signature(out: &mut String, signature: &Result<Signature, String>)
+{
+    let signature = match signature {
+        Ok(signature) => signature,
+        Err(err) => return push_line(out, "error", err),
+    };
+
+    push_line(out, "entries", &signature.entries.to_string());
+    push_line(out, "digest_algorithm", &signature.digest_algorithm);
+    push_line(out, "signed_digest", &hex(&signature.signed_digest));
+    push_line(out, "computed_digest", &signature.computed_digest.as_ref()
+              .map_or("(unsupported algorithm)".into(), |x| hex(x)));
+    push_line(out, "digest_matches", &signature.digest_matches().to_string());
+    match &signature.signer {
+        Some(signer) => {
+            push_line(out, "signer", &signer.subject);
+            push_line(out, "signer_issuer", &signer.issuer);
+            push_line(out, "signer_serial", &signer.serial);
+        }
+        None => push_line(out, "signer", "(certificate not embedded)"),
+    }
+    push_line(out, "timestamp", signature.timestamp.as_ref()
+              .map_or("(none)", |x| x.as_str()));
+    push_line(out, "certificates", &signature.certificates.len().to_string());
+    for cert in &signature.certificates {
+        out.push_str(&format!("      {}\n", cert.subject));
+    }
+}
+
+/// Append the functions imported from each DLL to `out`
+fn push_imports(out: &mut String, imports: &[Import])
+{
+    for import in imports {
+        out.push_str(&format!("  {}{}\n", import.dll,
+                              if import.delay { " (delay-load)" } else { "" }));
+        for func in &import.functions {
+            match func {
+                Function::Name { hint, name } => {
+                    out.push_str(&format!("      {} (hint {})\n", name, hint));
+                }
+                Function::Ordinal(ordinal) => {
+                    out.push_str(&format!("      #{}\n", ordinal));
+                }
+            }
+        }
+    }
+}
+
+/// Append an export directory to `out`
+fn push_exports(out: &mut String, exports: &Exports)
+{
+    push_line(out, "dll", &exports.dll);
+    push_fields(out, &[
+        ("timestamp",    exports.timestamp as u64),
+        ("ordinal_base", exports.ordinal_base as u64),
+    ]);
+    push_line(out, "functions", &exports.functions.len().to_string());
+    for func in &exports.functions {
+        out.push_str(&format!("      {:5} 0x{:08x} {}{}\n", func.ordinal,
+                              func.rva,
+                              func.name.as_ref().map_or("(no name)",
+                                                        |x| x.as_str()),
+                              func.forwarder.as_ref()
+                                  .map_or(String::new(),
+                                          |x| format!(" -> {}", x))));
+    }
+}
+
+/// Format everything read out of `image` for humans
+pub fn text(image: &PeFile) -> String
+{
+    let mut out = String::new();
+
+    if let Some(mz) = &image.mz {
+        push_title(&mut out, "MZ header");
+        push_fields(&mut out, &mz_fields(mz));
+    }
+
+    if let Some(rich) = &image.rich {
+        push_title(&mut out, "Rich header");
+        push_rich(&mut out, rich);
+    }
+
+    if let Some(hdr) = &image.pe {
+        push_title(&mut out,
+                   &format!("PE header ({})", machine_name(hdr.machine)));
+        push_fields(&mut out, &pe_fields(hdr));
+    }
+
+    if let Some(hdr) = &image.optional {
+        push_title(&mut out,
+                   &format!("Optional header ({})", optional_format(hdr)));
+        push_fields(&mut out, &optional_fields(hdr));
+    }
+
+    if !image.data_dirs.is_empty() {
+        push_title(&mut out, "Data directories");
+        for (ii, dir) in image.data_dirs.iter().enumerate() {
+            out.push_str(&format!("    {:2} {:<16} vaddr 0x{:08x} size 0x{:x}\n",
+                                  ii, data_dir_name(ii), {dir.vaddr},
+                                  {dir.size}));
+        }
+    }
+
+    if !image.sections.is_empty() {
+        push_title(&mut out, "Sections");
+        for sec in &image.sections {
+            out.push_str(&format!("  {}\n", section_name(sec)));
+            push_fields(&mut out, &section_fields(sec));
+        }
+    }
+
+    if !image.debug_dirs.is_empty() {
+        push_title(&mut out, "Debug directories");
+        for (ii, de) in image.debug_dirs.iter().enumerate() {
+            out.push_str(&format!("  {}\n", pe::debug_type_name(de.typ)));
+            push_fields(&mut out, &debug_fields(de));
+            if let Some(data) = image.debug_data.get(ii) {
+                push_debug_data(&mut out, data);
+            }
+        }
+    }
+
+    if let Some(cv) = &image.codeview {
+        push_title(&mut out, "CodeView (RSDS)");
+        out.push_str(&format!("    {:<28} {}\n", "guid", cv.guid));
+        out.push_str(&format!("    {:<28} {}\n", "age", cv.age));
+        out.push_str(&format!("    {:<28} {}\n", "path", cv.path));
+        out.push_str(&format!("    {:<28} {}{:x}\n", "pdb_key", cv.guid,
+                              cv.age));
+    }
+
+    if let Some(version) = &image.version {
+        push_title(&mut out, "Version resource");
+        push_version(&mut out, version);
+    }
+
+    if let Some(signature) = &image.signature {
+        push_title(&mut out, "Authenticode signature");
+        push_signature(&mut out, signature);
+    }
+
+    match &image.imports {
+        Some(Ok(imports)) if !imports.is_empty() => {
+            push_title(&mut out, "Imports");
+            push_imports(&mut out, imports);
+        }
+        Some(Err(err)) => {
+            push_title(&mut out, "Imports");
+            push_line(&mut out, "error", err);
+        }
+        _ => {}
+    }
+
+    if let Some(exports) = &image.exports {
+        push_title(&mut out, "Exports");
+        match exports {
+            Ok(exports) => push_exports(&mut out, exports),
+            Err(err) => push_line(&mut out, "error", err),
+        }
+    }
+
+    match &image.stop {
+        Some(reason) => push_title(&mut out, &format!("Stopped: {}", reason)),
+        None => push_title(&mut out, "Parsed through the CodeView record"),
+    }
+    out
+}
+
+/// Convert `fields` for `json::object`
+fn json_fields(fields: &[Field]) -> Vec<(&'static str, String)>
+{
+    fields.iter().map(|(name, val)| (*name, val.to_string())).collect()
+}
+
+/// Format the decoded contents of a debug directory entry as JSON
+fn debug_data_json(data: &DebugData) -> String
+{
+    match data {
+        DebugData::Codeview(cv) => json::object(&[
+            ("guid", json::quote(&cv.guid)),
+            ("age",  cv.age.to_string()),
+            ("path", json::quote(&cv.path)),
+        ]),
+        DebugData::Misc { data_type, name } => json::object(&[
+            ("data_type", data_type.to_string()),
+            ("name",      json::quote(name)),
+        ]),
+        DebugData::Fpo(records) => {
+            let records: Vec<String> = records.iter().map(|fpo| {
+                json::object(&[
+                    ("start",     fpo.start.to_string()),
+                    ("proc_size", fpo.proc_size.to_string()),
+                    ("locals",    fpo.locals.to_string()),
+                    ("params",    fpo.params.to_string()),
+                    ("prolog",    fpo.prolog.to_string()),
+                    ("regs",      fpo.regs.to_string()),
+                    ("has_seh",   fpo.has_seh.to_string()),
+                    ("use_bp",    fpo.use_bp.to_string()),
+                    ("frame",     json::quote(fpo_frame_name(fpo.frame))),
+                ])
+            }).collect();
+            json::array(&records)
+        }
+        DebugData::Pogo { signature, entries } => {
+            let entries: Vec<String> = entries.iter().map(|entry| {
+                json::object(&[
+                    ("rva",  entry.rva.to_string()),
+                    ("size", entry.size.to_string()),
+                    ("name", json::quote(&entry.name)),
+                ])
+            }).collect();
+            json::object(&[
+                ("signature", json::quote(signature)),
+                ("entries",   json::array(&entries)),
+            ])
+        }
+        DebugData::Iltcg => "true".to_string(),
+        DebugData::VcFeature(vc) => json::object(&[
+            ("pre_vc11", vc.pre_vc11.to_string()),
+            ("c_cpp",    vc.c_cpp.to_string()),
+            ("gs",       vc.gs.to_string()),
+            ("sdl",      vc.sdl.to_string()),
+            ("guard_n",  vc.guard_n.to_string()),
+        ]),
+        DebugData::Repro(hash) => json::object(&[
+            ("hash", json::quote(&hex(hash))),
+        ]),
+        DebugData::ExDllCharacteristics(flags) => {
+            let names: Vec<String> = ex_dll_names(*flags).iter()
+                .map(|x| json::quote(x)).collect();
+            json::object(&[
+                ("flags", flags.to_string()),
+                ("names", json::array(&names)),
+            ])
+        }
+        DebugData::Mpx(data) | DebugData::Other(data) => json::quote(&hex(data)),
+    }
+}
+
+/// Format a certificate as JSON
+fn certificate_json(cert: &Certificate) -> String
+{
+    json::object(&[
+        ("subject", json::quote(&cert.subject)),
+        ("issuer",  json::quote(&cert.issuer)),
+        ("serial",  json::quote(&cert.serial)),
+    ])
+}
+
+/// Format an Authenticode signature as JSON
+fn signature_json(signature: &Signature) -> String
+{
+    let null = || "null".to_string();
+    let certs: Vec<String> = signature.certificates.iter()
+        .map(certificate_json).collect();
+
+    json::object(&[
+        ("entries",          signature.entries.to_string()),
+        ("digest_algorithm", json::quote(&signature.digest_algorithm)),
+        ("signed_digest",    json::quote(&hex(&signature.signed_digest))),
+        ("computed_digest",  signature.computed_digest.as_ref()
+                                 .map_or_else(null, |x| json::quote(&hex(x)))),
+        ("digest_matches",   signature.digest_matches().to_string()),
+        ("signer",           signature.signer.as_ref()
+                                 .map_or_else(null, certificate_json)),
+        ("timestamp",        signature.timestamp.as_ref()
+                                 .map_or_else(null, |x| json::quote(x))),
+        ("certificates",     json::array(&certs)),
+    ])
+}
+
+/// Format the imports from one DLL as JSON
+fn import_json(import: &Import) -> String
+{
+    let functions: Vec<String> = import.functions.iter().map(|func| {
+        match func {
+            Function::Name { hint, name } => json::object(&[
+                ("name", json::quote(name)),
+                ("hint", hint.to_string()),
+            ]),
+            Function::Ordinal(ordinal) => json::object(&[
+                ("ordinal", ordinal.to_string()),
+            ]),
+        }
+    }).collect();
+
+    json::object(&[
+        ("dll",       json::quote(&import.dll)),
+        ("delay",     import.delay.to_string()),
+        ("functions", json::array(&functions)),
+    ])
+}
+
+/// Format an exported function as JSON
+fn export_json(func: &Export) -> String
+{
+    let null = || "null".to_string();
+    json::object(&[
+        ("ordinal",   func.ordinal.to_string()),
+        ("rva",       func.rva.to_string()),
+        ("name",      func.name.as_ref().map_or_else(null, |x| json::quote(x))),
+        ("forwarder", func.forwarder.as_ref()
+                          .map_or_else(null, |x| json::quote(x))),
+    ])
+}
+
+/// Format an export directory as JSON
+fn exports_json(exports: &Exports) -> String
+{
+    let functions: Vec<String> = exports.functions.iter()
+        .map(export_json).collect();
+    json::object(&[
+        ("dll",          json::quote(&exports.dll)),
+        ("timestamp",    exports.timestamp.to_string()),
+        ("ordinal_base", exports.ordinal_base.to_string()),
+        ("functions",    json::array(&functions)),
+    ])
+}
+
+/// Format everything read out of `image` as a JSON object. Parts which were
+/// not reached are `null` or empty.
+pub fn json(image: &PeFile) -> String
+{
+    let null = || "null".to_string();
+
+    let pe = image.pe.as_ref().map_or_else(null, |hdr| {
+        let mut fields = json_fields(&pe_fields(hdr));
+        fields.push(("machine_name", json::quote(&machine_name(hdr.machine))));
+        json::object(&fields)
+    });
+
+    let optional = image.optional.as_ref().map_or_else(null, |hdr| {
+        let mut fields = json_fields(&optional_fields(hdr));
+        fields.push(("format", json::quote(optional_format(hdr))));
+        json::object(&fields)
+    });
+
+    let data_dirs: Vec<String> = image.data_dirs.iter().enumerate()
+        .map(|(ii, dir)| json::object(&[
+            ("index", ii.to_string()),
+            ("name",  json::quote(data_dir_name(ii))),
+            ("vaddr", {dir.vaddr}.to_string()),
+            ("size",  {dir.size}.to_string()),
+        ]))
+        .collect();
+
+    let sections: Vec<String> = image.sections.iter()
+        .map(|sec| {
+            let mut fields = vec![("name", json::quote(&section_name(sec)))];
+            fields.extend(json_fields(&section_fields(sec)));
+            json::object(&fields)
+        })
+        .collect();
+
+    let debug_dirs: Vec<String> = image.debug_dirs.iter().enumerate()
+        .map(|(ii, de)| {
+            let mut fields = vec![
+                ("type_name", json::quote(pe::debug_type_name(de.typ))),
+            ];
+            fields.extend(json_fields(&debug_fields(de)));
+            match image.debug_data.get(ii) {
+                Some(Ok(data)) => fields.push(("data", debug_data_json(data))),
+                Some(Err(err)) => fields.push(("error", json::quote(err))),
+                None => {}
+            }
+            json::object(&fields)
+        })
+        .collect();
+
+    let codeview = image.codeview.as_ref().map_or_else(null, |cv| {
+        json::object(&[
+            ("guid",    json::quote(&cv.guid)),
+            ("age",     cv.age.to_string()),
+            ("path",    json::quote(&cv.path)),
+            ("pdb_key", json::quote(&format!("{}{:x}", cv.guid, cv.age))),
+        ])
+    });
+
+    let version = match &image.version {
+        Some(Ok(version)) => version.json(),
+        Some(Err(err)) => json::object(&[("error", json::quote(err))]),
+        None => null(),
+    };
+
+    let signature = match &image.signature {
+        Some(Ok(signature)) => signature_json(signature),
+        Some(Err(err)) => json::object(&[("error", json::quote(err))]),
+        None => null(),
+    };
+
+    let imports = match &image.imports {
+        Some(Ok(imports)) => {
+            json::array(&imports.iter().map(import_json).collect::<Vec<_>>())
+        }
+        Some(Err(err)) => json::object(&[("error", json::quote(err))]),
+        None => null(),
+    };
+
+    let exports = match &image.exports {
+        Some(Ok(exports)) => exports_json(exports),
+        Some(Err(err)) => json::object(&[("error", json::quote(err))]),
+        None => null(),
+    };
+
+    let mz = image.mz.as_ref().map_or_else(null, |hdr| {
+        json::object(&json_fields(&mz_fields(hdr)))
+    });
+
+    let rich = match &image.rich {
+        Some(Ok(rich)) => rich.json(),
+        Some(Err(err)) => json::object(&[("error", json::quote(err))]),
+        None => null(),
+    };
+
+    json::object(&[
+        ("mz",                mz),
+        ("rich",              rich),
+        ("pe",                pe),
+        ("optional",          optional),
+        ("data_directories",  json::array(&data_dirs)),
+        ("sections",          json::array(&sections)),
+        ("debug_directories", json::array(&debug_dirs)),
+        ("codeview",          codeview),
+        ("version",           version),
+        ("signature",         signature),
+        ("imports",           imports),
+        ("exports",           exports),
+        ("stop",              image.stop.as_ref()
+                                  .map_or_else(null, |x| json::quote(x))),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A 32-bit image with only a debug directory holding a CodeView entry.
+    /// Sections are aligned like files, so RVAs are file offsets.
+    fn image() -> Vec<u8>
+    {
+        let mut out = vec![0u8; 0x400];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            out[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(0, b"MZ");
+        put(0x3c, &0x40u32.to_le_bytes());
+        put(0x40, b"PE\0\0");
+        put(0x44, &pe::IMAGE_FILE_MACHINE_I386.to_le_bytes());
+        put(0x54, &(96u16 + 16 * 8).to_le_bytes());
+
+        let optional = 0x58;
+        put(optional, &0x10bu16.to_le_bytes());
+        put(optional + 32, &0x200u32.to_le_bytes());
+        put(optional + 36, &0x200u32.to_le_bytes());
+        put(optional + 60, &0x200u32.to_le_bytes());
+        put(optional + 92, &16u32.to_le_bytes());
+        put(optional + 96 + 6 * 8, &0x200u32.to_le_bytes());
+        put(optional + 96 + 6 * 8 + 4, &28u32.to_le_bytes());
+
+        /* CODEVIEW entry pointing at the RSDS record by file offset */
+        put(0x20c, &2u32.to_le_bytes());
+        put(0x210, &(24u32 + 10).to_le_bytes());
+        put(0x218, &0x300u32.to_le_bytes());
+        put(0x300, b"RSDS");
+        put(0x304, &[0x11; 16]);
+        put(0x314, &3u32.to_le_bytes());
+        put(0x318, b"ntdll.pdb\0");
+        out
+    }
+
+    fn stop(data: &[u8]) -> Option<String>
+    {
+        pe::parse(&mut Cursor::new(data)).stop
+    }
+
+    #[test]
+    fn parsed_through_codeview()
+    {
+        let image = pe::parse(&mut Cursor::new(image()));
+        assert_eq!(image.stop, None);
+
+        let text = text(&image);
+        assert!(text.starts_with("MZ header\n"));
+        assert!(text.contains("\nPE header (I386)\n"));
+        assert!(text.contains(&format!("    {:<28} {}\n", "pdb_key",
+                                       "11111111111111111111111111111111\
+                                        3")));
+        assert!(text.ends_with("\nParsed through the CodeView record\n"));
+
+        let json = json(&image);
+        assert!(json.contains("\"path\":\"ntdll.pdb\""));
+        assert!(json.ends_with(",\"stop\":null}"));
+    }
+
+    #[test]
+    fn stop_messages()
+    {
+        let mut data = image();
+        data[0] = b'X';
+        assert_eq!(stop(&data).unwrap(), "No MZ header present");
+        let parsed = pe::parse(&mut Cursor::new(&data));
+        assert_eq!(text(&parsed), "Stopped: No MZ header present\n");
+        assert!(json(&parsed).starts_with("{\"mz\":null,"));
+        assert!(json(&parsed).ends_with(",\"stop\":\"No MZ header present\"}"));
+
+        let mut data = image();
+        data[0x41] = b'X';
+        assert_eq!(stop(&data).unwrap(), "No PE header present");
+        let parsed = pe::parse(&mut Cursor::new(&data));
+        assert!(text(&parsed).starts_with("MZ header\n"));
+        assert!(!text(&parsed).contains("\nPE header ("));
+        assert!(json(&parsed).contains(",\"pe\":null,"));
+
+        /* Too few data directories to have a debug one */
+        let mut data = image();
+        data[0x58 + 92] = 6;
+        assert_eq!(stop(&data).unwrap(), "No debug data directory");
+
+        let mut data = image();
+        data[0x58 + 96 + 6 * 8 + 4] = 0;
+        assert_eq!(stop(&data).unwrap(),
+                   "Debug directory not present or zero sized");
+
+        /* A debug directory without a CodeView entry */
+        let mut data = image();
+        data[0x20c] = 4;
+        assert_eq!(stop(&data).unwrap(),
+                   "Failed to find RSDS codeview directory");
+        let parsed = pe::parse(&mut Cursor::new(&data));
+        assert!(text(&parsed).contains("\nDebug directories\n  MISC\n"));
+        assert!(text(&parsed)
+                .ends_with("\nStopped: Failed to find RSDS codeview \
+                            directory\n"));
+
+        assert!(stop(&data[..0x20]).unwrap()
+                .starts_with("Failed to read MZ header at offset 0x0"));
+    }
+}
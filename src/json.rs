@@ -0,0 +1,39 @@
+//! Just enough JSON writing for our output formats, which are flat enough
+//! not to need a serialization library.
+
+/// Quote and escape `val` as a JSON string
+pub fn quote(val: &str) -> String
+{
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('"');
+    for chr in val.chars() {
+        match chr {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            chr if (chr as u32) < 0x20 => {
+                out.push_str(&format!("\\u{:04x}", chr as u32));
+            }
+            chr => out.push(chr),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Build a JSON object out of `(key, already encoded value)` pairs
+pub fn object(fields: &[(&str, String)]) -> String
+{
+    let fields: Vec<String> = fields.iter()
+        .map(|(key, val)| format!("{}:{}", quote(key), val))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Build a JSON array out of already encoded values
+pub fn array(values: &[String]) -> String
+{
+    format!("[{}]", values.join(","))
+}
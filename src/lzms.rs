@@ -0,0 +1,660 @@
+//! Decompressor for LZMS as used for solid WIM (ESD) resource chunks.
+//!
+//! LZMS drives the choice between literals, LZ matches and delta matches
+//! with an adaptive binary range coder read from the start of the chunk.
+//! Literals, lengths and offsets come out of adaptive Huffman codes in a
+//! second bitstream which is read backwards from the end of the chunk.
+//! Finally the output is run through an x86 filter which undoes the
+//! translation of RIP relative and CALL targets done by the compressor.
+
+use bitstream::BitReader;
+use huffman::Huffman;
+
+/// Longest codeword allowed in the adaptive Huffman codes
+const MAX_CODEWORD_LEN: u32 = 15;
+
+/// Number of symbols in the literal, length and delta power alphabets
+const NUM_LITERAL_SYMS:     usize = 256;
+const NUM_LENGTH_SYMS:      usize = 54;
+const NUM_DELTA_POWER_SYMS: usize = 8;
+
+/// Number of symbols decoded from each code before it is rebuilt from the
+/// symbol frequencies
+const LITERAL_REBUILD_FREQ:      u32 = 1024;
+const LZ_OFFSET_REBUILD_FREQ:    u32 = 1024;
+const LENGTH_REBUILD_FREQ:       u32 = 512;
+const DELTA_OFFSET_REBUILD_FREQ: u32 = 1024;
+const DELTA_POWER_REBUILD_FREQ:  u32 = 512;
+
+/// Number of states of each of the range coded decisions
+const NUM_MAIN_STATES:  usize = 16;
+const NUM_MATCH_STATES: usize = 32;
+const NUM_LZ_STATES:    usize = 64;
+const NUM_DELTA_STATES: usize = 64;
+const NUM_REP_STATES:   usize = 64;
+
+/// Number of recently used LZ offsets and delta pairs which can be
+/// repeated. The queues hold one more entry to allow for the delayed update
+/// done by the compressor.
+const NUM_REPS: usize = 3;
+
+/// Probabilities are expressed in 64ths
+const PROBABILITY_BITS: u32 = 6;
+const INITIAL_PROBABILITY: u32 = 48;
+const INITIAL_RECENT_BITS: u64 = 0x5555_5555;
+
+/// How far back an x86 instruction may refer to a recently used target and
+/// still be translated, and how long a target is remembered for
+const X86_MAX_TRANSLATION_OFFSET: i64 = 1023;
+const X86_ID_WINDOW_SIZE: i64 = 65535;
+
+/// Number of offset slots in each run, slots in run `i` have `i` extra bits
+const OFFSET_SLOT_RUNS: [u32; 21] = [
+    9, 0, 9, 7, 10, 15, 15, 20, 20, 30, 33, 40, 42, 45, 60, 73, 80, 85, 95,
+    105, 6,
+];
+
+/// Upper bound of the last offset slot
+const OFFSET_SLOT_FINAL: u32 = 0x7fff_ffff;
+
+/// Number of length slots in each run, slots in run `i` have `i` extra bits
+const LENGTH_SLOT_RUNS: [u32; 17] = [
+    27, 4, 6, 4, 5, 2, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 1,
+];
+
+/// Upper bound of the last length slot
+const LENGTH_SLOT_FINAL: u32 = 0x4001_08ab;
+
+/// Base values and extra bit counts of the offset or length slots
+struct Slots {
+    base:  Vec<u32>,
+    extra: Vec<u32>,
+}
+
+impl Slots {
+    fn new(runs: &[u32], last: u32) -> Slots
+    {
+        let mut base  = Vec::new();
+        let mut extra = Vec::new();
+
+        let mut value = 0u32;
+        for (bits, &count) in runs.iter().enumerate() {
+            for _ in 0..count {
+                value += 1 << bits;
+                if !base.is_empty() {
+                    extra.push(bits as u32);
+                }
+                base.push(value);
+            }
+        }
+        extra.push(31 - (last - value).leading_zeros());
+
+        Slots { base, extra }
+    }
+
+    /// Index of the slot `value` falls into
+    fn slot(&self, value: u32) -> usize
+    {
+        self.base.iter().rposition(|&base| base <= value).unwrap_or(0)
+    }
+
+    /// Read the extra bits for `slot` and return the full value
+    fn value(&self, slot: usize, reader: &mut BitReader) -> u64
+    {
+        self.base[slot] as u64 + reader.read_bits(self.extra[slot]) as u64
+    }
+}
+
+/// Adaptive probability of a zero bit, based on the last 64 bits coded
+#[derive(Clone, Copy)]
+struct Probability {
+    zeros:  u32,
+    recent: u64,
+}
+
+impl Probability {
+    fn get(&self) -> u32
+    {
+        /* 0% and 100% are not allowed */
+        self.zeros.clamp(1, (1 << PROBABILITY_BITS) - 1)
+    }
+
+    fn update(&mut self, bit: u32)
+    {
+        self.zeros = (self.zeros + (self.recent >> 63) as u32) - bit;
+        self.recent = (self.recent << 1) | bit as u64;
+    }
+}
+
+/// A range coded decision. The state is made up of the last few bits
+/// decoded and selects the probability used for the next one.
+struct Decision {
+    state: usize,
+    probs: Vec<Probability>,
+}
+
+impl Decision {
+    fn new(num_states: usize) -> Decision
+    {
+        let prob = Probability {
+            zeros:  INITIAL_PROBABILITY,
+            recent: INITIAL_RECENT_BITS,
+        };
+        Decision { state: 0, probs: vec![prob; num_states] }
+    }
+}
+
+/// Range decoder reading 16-bit words forwards from the start of the chunk
+struct RangeDecoder<'a> {
+    input: &'a [u8],
+    pos:   usize,
+    range: u32,
+    code:  u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(input: &'a [u8]) -> RangeDecoder<'a>
+    {
+        let mut rd = RangeDecoder {
+            input,
+            pos:   0,
+            range: 0xffff_ffff,
+            code:  0,
+        };
+        rd.code = (rd.next_u16() << 16) | rd.next_u16();
+        rd
+    }
+
+    fn next_u16(&mut self) -> u32
+    {
+        match self.input.get(self.pos..self.pos + 2) {
+            Some(word) => {
+                self.pos += 2;
+                u16::from_le_bytes([word[0], word[1]]) as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn decode(&mut self, decision: &mut Decision) -> u32
+    {
+        if self.range & 0xffff_0000 == 0 {
+            self.range <<= 16;
+            self.code = (self.code << 16) | self.next_u16();
+        }
+
+        let prob = &mut decision.probs[decision.state];
+        let bound = (self.range >> PROBABILITY_BITS) * prob.get();
+        let bit = if self.code < bound {
+            self.range = bound;
+            0
+        } else {
+            self.range -= bound;
+            self.code  -= bound;
+            1
+        };
+
+        prob.update(bit);
+        decision.state =
+            ((decision.state << 1) | bit as usize) & (decision.probs.len() - 1);
+        bit
+    }
+}
+
+/// Huffman code which is periodically rebuilt from the frequencies of the
+/// symbols decoded so far
+struct AdaptiveCode {
+    freqs:         Vec<u32>,
+    rebuild_freq:  u32,
+    until_rebuild: u32,
+    code:          Huffman,
+}
+
+impl AdaptiveCode {
+    fn new(num_syms: usize, rebuild_freq: u32) ->
+        Result<AdaptiveCode, &'static str>
+    {
+        let freqs = vec![1u32; num_syms];
+        let code = Huffman::new(&code_lengths(&freqs), MAX_CODEWORD_LEN)?;
+        Ok(AdaptiveCode {
+            freqs,
+            rebuild_freq,
+            until_rebuild: rebuild_freq,
+            code,
+        })
+    }
+
+    fn decode(&mut self, reader: &mut BitReader) -> Result<usize, &'static str>
+    {
+        self.until_rebuild -= 1;
+        if self.until_rebuild == 0 {
+            self.code = Huffman::new(&code_lengths(&self.freqs),
+                                     MAX_CODEWORD_LEN)?;
+
+            /* Halve the frequencies so the code adapts to recent data */
+            for freq in self.freqs.iter_mut() {
+                *freq = (*freq >> 1) + 1;
+            }
+            self.until_rebuild = self.rebuild_freq;
+        }
+
+        let sym = self.code.decode(reader)? as usize;
+        self.freqs[sym] += 1;
+        Ok(sym)
+    }
+}
+
+/// Compute length limited Huffman codeword lengths for `freqs`. This has to
+/// produce exactly the lengths the compressor used, so it follows the
+/// in-place tree construction of the reference implementation including
+/// how ties are broken and how overlong codewords are redistributed.
+fn code_lengths(freqs: &[u32]) -> Vec<u8>
+{
+    let num_syms = freqs.len();
+    let mut lens = vec![0u8; num_syms];
+    if num_syms < 2 {
+        if num_syms == 1 {
+            lens[0] = 1;
+        }
+        return lens;
+    }
+
+    /* Symbols sorted by frequency, then by symbol value */
+    let mut syms: Vec<usize> = (0..num_syms).collect();
+    syms.sort_by_key(|&sym| (freqs[sym], sym));
+
+    /* Build the tree in place. `node` starts out holding the frequencies of
+     * the sorted leaves, internal nodes are written to the front of the
+     * array and every consumed entry is overwritten with its parent. */
+    let mut node: Vec<u32> = syms.iter().map(|&sym| freqs[sym]).collect();
+    let (mut leaf, mut next, mut end) = (0, 0, 0);
+    loop {
+        let mut pick = || {
+            if leaf != num_syms && (next == end || node[leaf] <= node[next]) {
+                leaf += 1;
+                leaf - 1
+            } else {
+                next += 1;
+                next - 1
+            }
+        };
+        let first  = pick();
+        let second = pick();
+
+        let freq = node[first] + node[second];
+        node[first]  = end as u32;
+        node[second] = end as u32;
+        node[end] = freq;
+        end += 1;
+
+        if num_syms - end <= 1 {
+            break;
+        }
+    }
+
+    /* Walk down from the root computing depths, and count how many
+     * codewords of each length there are. Nodes deeper than the limit are
+     * moved up to the deepest level which still has room. */
+    let max = MAX_CODEWORD_LEN as usize;
+    let mut len_counts = [0u32; MAX_CODEWORD_LEN as usize + 1];
+    len_counts[1] = 2;
+
+    let root = num_syms - 2;
+    node[root] = 0;
+    for ii in (0..root).rev() {
+        let depth = node[node[ii] as usize] + 1;
+        node[ii] = depth;
+
+        let mut len = depth as usize;
+        if len >= max {
+            len = max - 1;
+            while len_counts[len] == 0 {
+                len -= 1;
+            }
+        }
+        len_counts[len] -= 1;
+        len_counts[len + 1] += 2;
+    }
+
+    /* The least frequent symbols get the longest codewords */
+    let mut sorted = syms.iter();
+    for len in (1..=max).rev() {
+        for _ in 0..len_counts[len] {
+            lens[*sorted.next().unwrap()] = len as u8;
+        }
+    }
+
+    lens
+}
+
+/// Number of offset slots needed for a chunk of `size` bytes
+fn num_offset_slots(offsets: &Slots, size: usize) -> usize
+{
+    if size < 2 {
+        return 0;
+    }
+    let max = std::cmp::min(size - 1, u32::MAX as usize) as u32;
+    offsets.slot(max) + 1
+}
+
+/// Pick the entry `idx` out of a recent offset queue and move it to the
+/// front. `delayed` is set if the previous item was of the same kind, in
+/// which case the compressor had not yet pushed its offset to the queue.
+fn take_recent<T: Copy>(recent: &mut [T; NUM_REPS + 1], idx: usize,
+                        delayed: bool) -> T
+{
+    let slot = idx + delayed as usize;
+    let value = recent[slot];
+    recent[slot] = recent[idx];
+    for ii in (1..=idx).rev() {
+        recent[ii] = recent[ii - 1];
+    }
+    value
+}
+
+/// Index of the repeated entry, from up to `NUM_REPS - 1` decisions
+fn decode_rep(rd: &mut RangeDecoder, reps: &mut [Decision]) -> usize
+{
+    reps.iter_mut()
+        .position(|rep| rd.decode(rep) == 0)
+        .unwrap_or(NUM_REPS - 1)
+}
+
+/// Undo the x86 filter the compressor applies to the whole chunk. It turns
+/// the relative targets of CALLs and RIP relative memory operands into
+/// absolute ones when the same target was used recently.
+fn undo_x86_filter(data: &mut [u8])
+{
+    if data.len() <= 17 {
+        return;
+    }
+
+    let mut last_target = vec![-X86_ID_WINDOW_SIZE - 1; 65536];
+    let mut closest = -X86_MAX_TRANSLATION_OFFSET - 1;
+
+    /* The very first byte is never an opcode */
+    let mut ii = 1;
+    while ii < data.len() - 16 {
+        let (opcode_len, max_offset) =
+            match (data[ii], data[ii + 1], data[ii + 2]) {
+                (0x48, 0x8b, 0x05) | (0x48, 0x8b, 0x0d) =>
+                    (3, X86_MAX_TRANSLATION_OFFSET),
+                (0x48, 0x8d, modrm) | (0x4c, 0x8d, modrm)
+                    if modrm & 7 == 5 => (3, X86_MAX_TRANSLATION_OFFSET),
+                (0xe8, _, _) => (1, X86_MAX_TRANSLATION_OFFSET >> 1),
+                (0xe9, _, _) => {
+                    ii += 5;
+                    continue;
+                }
+                (0xf0, 0x83, 0x05) => (3, X86_MAX_TRANSLATION_OFFSET),
+                (0xff, 0x15, _) => (2, X86_MAX_TRANSLATION_OFFSET),
+                _ => {
+                    ii += 1;
+                    continue;
+                }
+            };
+
+        let pos = ii as i64;
+        let field = &mut data[ii + opcode_len..ii + opcode_len + 4];
+        if pos - closest <= max_offset {
+            let mut val = [0u8; 4];
+            val.copy_from_slice(field);
+            let val = u32::from_le_bytes(val).wrapping_sub(ii as u32);
+            field.copy_from_slice(&val.to_le_bytes());
+        }
+        let target = (ii as u16)
+            .wrapping_add(u16::from_le_bytes([field[0], field[1]])) as usize;
+
+        let end = pos + opcode_len as i64 + 3;
+        if end - last_target[target] <= X86_ID_WINDOW_SIZE {
+            closest = end;
+        }
+        last_target[target] = end;
+
+        ii = end as usize + 1;
+    }
+}
+
+/// Decompress a single LZMS chunk `input` which expands to exactly
+/// `out_size` bytes.
+pub fn decompress(input: &[u8], out_size: usize) ->
+    Result<Vec<u8>, Box<dyn std::error::Error>>
+{
+    /* The chunk is made of 16-bit words, and the range decoder needs two of
+     * them to get started */
+    if input.len() & 1 != 0 || input.len() < 4 {
+        return Err("Invalid LZMS chunk size".into());
+    }
+
+    let offsets = Slots::new(&OFFSET_SLOT_RUNS, OFFSET_SLOT_FINAL);
+    let lengths = Slots::new(&LENGTH_SLOT_RUNS, LENGTH_SLOT_FINAL);
+    let num_offsets = num_offset_slots(&offsets, out_size);
+
+    /* Reverse the order of the words so the Huffman coded bitstream can be
+     * consumed with the same reader as XPRESS and LZX */
+    let mut backward = Vec::with_capacity(input.len());
+    for word in input.chunks(2).rev() {
+        backward.extend_from_slice(word);
+    }
+    let mut reader = BitReader::new(&backward);
+    let mut rd = RangeDecoder::new(input);
+
+    let mut is_match     = Decision::new(NUM_MAIN_STATES);
+    let mut is_delta     = Decision::new(NUM_MATCH_STATES);
+    let mut lz_is_rep    = Decision::new(NUM_LZ_STATES);
+    let mut delta_is_rep = Decision::new(NUM_DELTA_STATES);
+    let mut lz_reps: Vec<Decision> = (0..NUM_REPS - 1)
+        .map(|_| Decision::new(NUM_REP_STATES)).collect();
+    let mut delta_reps: Vec<Decision> = (0..NUM_REPS - 1)
+        .map(|_| Decision::new(NUM_REP_STATES)).collect();
+
+    let mut literal_code =
+        AdaptiveCode::new(NUM_LITERAL_SYMS, LITERAL_REBUILD_FREQ)?;
+    let mut lz_offset_code =
+        AdaptiveCode::new(num_offsets, LZ_OFFSET_REBUILD_FREQ)?;
+    let mut length_code =
+        AdaptiveCode::new(NUM_LENGTH_SYMS, LENGTH_REBUILD_FREQ)?;
+    let mut delta_offset_code =
+        AdaptiveCode::new(num_offsets, DELTA_OFFSET_REBUILD_FREQ)?;
+    let mut delta_power_code =
+        AdaptiveCode::new(NUM_DELTA_POWER_SYMS, DELTA_POWER_REBUILD_FREQ)?;
+
+    let mut recent_offsets: [u64; NUM_REPS + 1] = [1, 2, 3, 4];
+    let mut recent_pairs:   [(u32, u64); NUM_REPS + 1] =
+        [(0, 1), (0, 2), (0, 3), (0, 4)];
+
+    /* Kind of the previous item: 0 literal, 1 LZ match, 2 delta match */
+    let mut prev_type = 0u32;
+
+    let mut out = Vec::with_capacity(out_size);
+    while out.len() < out_size {
+        if rd.decode(&mut is_match) == 0 {
+            out.push(literal_code.decode(&mut reader)? as u8);
+            prev_type = 0;
+            continue;
+        }
+
+        if rd.decode(&mut is_delta) == 0 {
+            let offset = if rd.decode(&mut lz_is_rep) == 0 {
+                let slot = lz_offset_code.decode(&mut reader)?;
+                let offset = offsets.value(slot, &mut reader);
+                for ii in (1..=NUM_REPS).rev() {
+                    recent_offsets[ii] = recent_offsets[ii - 1];
+                }
+                offset
+            } else {
+                let idx = decode_rep(&mut rd, &mut lz_reps);
+                take_recent(&mut recent_offsets, idx, prev_type & 1 != 0)
+            };
+            recent_offsets[0] = offset;
+            prev_type = 1;
+
+            let slot = length_code.decode(&mut reader)?;
+            let length = lengths.value(slot, &mut reader);
+
+            if offset > out.len() as u64 {
+                return Err("LZMS match offset out of bounds".into());
+            }
+            if length > (out_size - out.len()) as u64 {
+                return Err("LZMS match extends past end of chunk".into());
+            }
+
+            /* Matches may overlap the bytes they produce */
+            let start = out.len() - offset as usize;
+            for ii in 0..length as usize {
+                let byte = out[start + ii];
+                out.push(byte);
+            }
+            continue;
+        }
+
+        let pair = if rd.decode(&mut delta_is_rep) == 0 {
+            let power = delta_power_code.decode(&mut reader)? as u32;
+            let slot = delta_offset_code.decode(&mut reader)?;
+            let raw = offsets.value(slot, &mut reader);
+            for ii in (1..=NUM_REPS).rev() {
+                recent_pairs[ii] = recent_pairs[ii - 1];
+            }
+            (power, raw)
+        } else {
+            let idx = decode_rep(&mut rd, &mut delta_reps);
+            take_recent(&mut recent_pairs, idx, prev_type & 2 != 0)
+        };
+        recent_pairs[0] = pair;
+        prev_type = 2;
+
+        let slot = length_code.decode(&mut reader)?;
+        let length = lengths.value(slot, &mut reader);
+
+        /* Each byte is predicted from the bytes `offset` and `span` back
+         * and the one `offset + span` back */
+        let (power, raw) = pair;
+        let span = 1u64 << power;
+        let offset = raw << power;
+        if offset + span > out.len() as u64 {
+            return Err("LZMS delta match offset out of bounds".into());
+        }
+        if length > (out_size - out.len()) as u64 {
+            return Err("LZMS delta match extends past end of chunk".into());
+        }
+
+        let (offset, span) = (offset as usize, span as usize);
+        for _ in 0..length {
+            let pos = out.len();
+            let byte = out[pos - offset]
+                .wrapping_add(out[pos - span])
+                .wrapping_sub(out[pos - offset - span]);
+            out.push(byte);
+        }
+    }
+
+    undo_x86_filter(&mut out);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "abc" repeated 21 times, a few literals followed by a repeat match
+    const ABC: [u8; 12] = [
+        0xee, 0x64, 0xd0, 0xff, 0x00, 0x00, 0x00, 0x80, 0xe0, 0x63, 0x62, 0x61,
+    ];
+
+    #[test]
+    fn decompress_repeat_match()
+    {
+        let out = decompress(&ABC, 63).unwrap();
+        assert_eq!(out, b"abc".repeat(21));
+    }
+
+    #[test]
+    fn rejects_bad_chunk_sizes()
+    {
+        assert!(decompress(&ABC[..11], 63).is_err());
+        assert!(decompress(&ABC[..2], 63).is_err());
+    }
+
+    #[test]
+    fn hostile_input_does_not_panic()
+    {
+        /* Truncated streams decode zeros and must either fail cleanly or
+         * produce exactly the requested size */
+        for len in (4..ABC.len()).step_by(2) {
+            if let Ok(out) = decompress(&ABC[..len], 63) {
+                assert_eq!(out.len(), 63);
+            }
+        }
+
+        let mut seed = 0x1234_5678u32;
+        for size in [1usize, 2, 17, 100, 4096].iter() {
+            for _ in 0..50 {
+                let input: Vec<u8> = (0..64).map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                }).collect();
+                if let Ok(out) = decompress(&input, *size) {
+                    assert_eq!(out.len(), *size);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn slot_tables()
+    {
+        let offsets = Slots::new(&OFFSET_SLOT_RUNS, OFFSET_SLOT_FINAL);
+        let lengths = Slots::new(&LENGTH_SLOT_RUNS, LENGTH_SLOT_FINAL);
+        assert_eq!(offsets.base.len(), 799);
+        assert_eq!(lengths.base.len(), NUM_LENGTH_SYMS);
+        assert_eq!(&offsets.base[7..11], &[8, 9, 13, 17]);
+        assert_eq!(offsets.extra[8], 2);
+        assert_eq!(lengths.base[27..29], [29, 31]);
+        assert_eq!(lengths.extra[53], 30);
+
+        assert_eq!(num_offset_slots(&offsets, 0), 0);
+        assert_eq!(num_offset_slots(&offsets, 2), 1);
+        assert_eq!(num_offset_slots(&offsets, 11), 9);
+        assert_eq!(num_offset_slots(&offsets, 14), 10);
+    }
+
+    #[test]
+    fn code_lengths_are_complete_and_limited()
+    {
+        assert!(code_lengths(&[1; 256]).iter().all(|&len| len == 8));
+
+        /* Fibonacci frequencies want a codeword per symbol of increasing
+         * length and have to be limited */
+        let mut freqs = vec![1u32, 1];
+        while freqs.len() < 30 {
+            let next = freqs[freqs.len() - 1] + freqs[freqs.len() - 2];
+            freqs.push(next);
+        }
+        let lens = code_lengths(&freqs);
+        assert!(lens.iter().all(|len| (1..=15).contains(len)));
+        let kraft: u32 = lens.iter().map(|&len| 1 << (15 - len)).sum();
+        assert_eq!(kraft, 1 << 15);
+        assert_eq!(lens[29], 1);
+
+        /* Ties go to the lower symbol */
+        assert_eq!(code_lengths(&[1, 1, 1]), vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn x86_filter_translates_repeated_call_targets()
+    {
+        let mut data = vec![0x90u8; 64];
+        for &pos in [4usize, 20, 36].iter() {
+            data[pos] = 0xe8;
+            let rel = 0x104 - pos as i32;
+            data[pos + 1..pos + 5].copy_from_slice(&rel.to_le_bytes());
+        }
+        let want = data.clone();
+
+        /* Only the third call is translated, the first two establish the
+         * target as recently used */
+        data[37..41].copy_from_slice(&0x104i32.to_le_bytes());
+        undo_x86_filter(&mut data);
+        assert_eq!(data, want);
+    }
+}
@@ -0,0 +1,615 @@
+//! Decompressor for LZX as used in WIM resources and CAB files.
+//!
+//! The WIM flavour of LZX differs from the CAB one in a few ways: every chunk
+//! is compressed independently with a window the size of the chunk, there is
+//! no stream header, block sizes default to 32768 with a one bit flag, and E8
+//! call translation is always enabled with a fixed translation size of
+//! 12000000.
+//!
+//! In CAB files the window and all decoder state carry over between the
+//! 32 KiB frames (CFDATA blocks) of a folder, and a header at the start of
+//! the stream says whether E8 translation is in use.
+
+use bitstream::BitReader;
+use huffman::Huffman;
+
+const BLOCK_TYPE_VERBATIM:     u32 = 1;
+const BLOCK_TYPE_ALIGNED:      u32 = 2;
+const BLOCK_TYPE_UNCOMPRESSED: u32 = 3;
+
+/// Block size used when a WIM block header says "default"
+const DEFAULT_BLOCK_SIZE: usize = 32768;
+
+/// Size of the frames E8 translation works on in CAB files
+const FRAME_SIZE: usize = 32768;
+
+const NUM_CHARS:          usize = 256;
+const NUM_PRIMARY_LENS:   usize = 7;
+const NUM_LEN_SYMBOLS:    usize = 249;
+const NUM_ALIGNED_SYMS:   usize = 8;
+const NUM_PRETREE_SYMS:   usize = 20;
+const MIN_MATCH_LEN:      usize = 2;
+const MAX_MAIN_CODEWORD:  u32   = 16;
+const MAX_PRE_CODEWORD:   u32   = 15;
+const MAX_ALIGN_CODEWORD: u32   = 7;
+
+/// Magic file size WIM uses for E8 translation
+const WIM_E8_TRANSLATION_SIZE: i32 = 12000000;
+
+/// Number of extra offset bits for each offset slot
+const EXTRA_BITS: [u8; 50] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10,
+    11, 11, 12, 12, 13, 13, 14, 14, 15, 15, 16, 16, 17, 17, 17, 17, 17, 17,
+    17, 17, 17, 17, 17, 17, 17, 17,
+];
+
+/// Get the number of offset slots for a window of `1 << window_order` bytes
+fn num_offset_slots(window_order: u32) -> Result<usize, &'static str>
+{
+    Ok(match window_order {
+        15 => 30,
+        16 => 32,
+        17 => 34,
+        18 => 36,
+        19 => 38,
+        20 => 42,
+        21 => 50,
+        _  => return Err("Unsupported LZX window size"),
+    })
+}
+
+/// Read codeword lengths for `lens[start..end]` using a pretree. Lengths are
+/// delta coded against whatever was in `lens` from the previous block.
+fn read_lengths(reader: &mut BitReader, lens: &mut [u8], start: usize,
+                end: usize) -> Result<(), &'static str>
+{
+    let mut pre_lens = [0u8; NUM_PRETREE_SYMS];
+    for len in pre_lens.iter_mut() {
+        *len = reader.read_bits(4) as u8;
+    }
+    let pretree = Huffman::new(&pre_lens, MAX_PRE_CODEWORD)?;
+
+    let mut ii = start;
+    while ii < end {
+        let presym = pretree.decode(reader)?;
+
+        let (run, len) = match presym {
+            0..=16 => {
+                (1, ((lens[ii] as u16 + 17 - presym) % 17) as u8)
+            }
+            17 => (4  + reader.read_bits(4) as usize, 0),
+            18 => (20 + reader.read_bits(5) as usize, 0),
+            19 => {
+                let run = 4 + reader.read_bits(1) as usize;
+                let presym = pretree.decode(reader)?;
+                if presym > 16 {
+                    return Err("Invalid LZX pretree run symbol");
+                }
+                (run, ((lens[ii] as u16 + 17 - presym) % 17) as u8)
+            }
+            _ => return Err("Invalid LZX pretree symbol"),
+        };
+
+        for len_ent in lens[ii..std::cmp::min(ii + run, end)].iter_mut() {
+            *len_ent = len;
+        }
+        ii += run;
+    }
+
+    Ok(())
+}
+
+/// Undo the E8 (x86 CALL) translation applied by the compressor. `base` is
+/// the position of `data` in the uncompressed stream.
+fn undo_e8_translation(data: &mut [u8], base: usize, translation_size: i32)
+{
+    if data.len() <= 10 {
+        return;
+    }
+
+    let mut ii = 0;
+    while ii < data.len() - 10 {
+        if data[ii] != 0xe8 {
+            ii += 1;
+            continue;
+        }
+
+        let mut abs = [0u8; 4];
+        abs.copy_from_slice(&data[ii + 1..ii + 5]);
+        let abs = i32::from_le_bytes(abs);
+        let pos = (base + ii) as i32;
+
+        if abs >= -pos && abs < translation_size {
+            let rel = if abs >= 0 {
+                abs - pos
+            } else {
+                abs + translation_size
+            };
+            data[ii + 1..ii + 5].copy_from_slice(&rel.to_le_bytes());
+        }
+
+        ii += 5;
+    }
+}
+
+/// Huffman codes of the block currently being decoded
+struct Block {
+    block_type: u32,
+
+    /// Uncompressed size of the whole block
+    size: usize,
+
+    /// Uncompressed bytes left in this block
+    remaining: usize,
+
+    main:    Option<Huffman>,
+    length:  Option<Huffman>,
+    aligned: Option<Huffman>,
+}
+
+/// LZX decoder state which persists across blocks and, for CAB, frames
+pub struct Lzx {
+    /// Whether this is the WIM flavour of LZX
+    wim: bool,
+
+    window_order: u32,
+    num_main:     usize,
+    slot_base:    [usize; 50],
+
+    main_lens: Vec<u8>,
+    len_lens:  [u8; NUM_LEN_SYMBOLS],
+    recent:    [usize; 3],
+
+    block: Option<Block>,
+
+    /// Whether the CAB E8 header has been read, and the translation size it
+    /// specified (zero for none)
+    header_read: bool,
+    e8_size:     i32,
+
+    /// Untranslated history. Matches refer back into this, E8 translation
+    /// is only undone on the copy handed back to the caller.
+    history: Vec<u8>,
+
+    /// Position in the uncompressed stream of `history[0]`
+    history_base: usize,
+
+    /// Number of bytes handed back to the caller so far
+    produced: usize,
+}
+
+impl Lzx {
+    fn new(window_order: u32, wim: bool) -> Result<Lzx, &'static str>
+    {
+        let num_main = NUM_CHARS + num_offset_slots(window_order)? * 8;
+
+        /* Compute the base offset of every offset slot */
+        let mut slot_base = [0usize; 50];
+        for ii in 1..slot_base.len() {
+            slot_base[ii] = slot_base[ii - 1] + (1 << EXTRA_BITS[ii - 1]);
+        }
+
+        Ok(Lzx {
+            wim,
+            window_order,
+            num_main,
+            slot_base,
+            main_lens: vec![0u8; num_main],
+            len_lens: [0u8; NUM_LEN_SYMBOLS],
+            recent: [1; 3],
+            block: None,
+            header_read: wim,
+            e8_size: if wim { WIM_E8_TRANSLATION_SIZE } else { 0 },
+            history: Vec::new(),
+            history_base: 0,
+            produced: 0,
+        })
+    }
+
+    /// Create a decoder for an LZX compressed CAB folder with a window of
+    /// `1 << window_order` bytes
+    pub fn new_cab(window_order: u32) -> Result<Lzx, &'static str>
+    {
+        Lzx::new(window_order, false)
+    }
+
+    /// Decode a single CAB frame (CFDATA block) `input` which expands to
+    /// `out_size` bytes
+    pub fn decompress_frame(&mut self, input: &[u8], out_size: usize) ->
+        Result<Vec<u8>, Box<dyn std::error::Error>>
+    {
+        let start = self.produced;
+        let end = start + out_size;
+
+        let mut reader = BitReader::new(input);
+        if !self.header_read {
+            self.e8_size = if reader.read_bits(1) == 1 {
+                let hi = reader.read_bits(16);
+                let lo = reader.read_bits(16);
+                ((hi << 16) | lo) as i32
+            } else {
+                0
+            };
+            self.header_read = true;
+        }
+
+        /* Matches from the previous frame may have already produced some
+         * of this frame */
+        while self.history_base + self.history.len() < end {
+            let want = end - (self.history_base + self.history.len());
+            self.decode(&mut reader, want)?;
+        }
+
+        let mut out =
+            self.history[start - self.history_base..end - self.history_base]
+            .to_vec();
+        if self.e8_size != 0 && (self.wim || start / FRAME_SIZE < 32768) {
+            let base = if self.wim { 0 } else { start };
+            undo_e8_translation(&mut out, base, self.e8_size);
+        }
+        self.produced = end;
+
+        /* Only keep a window's worth of history around */
+        let window = 1usize << self.window_order;
+        if self.history.len() > window * 2 {
+            let drop = self.history.len() - window;
+            self.history.drain(..drop);
+            self.history_base += drop;
+        }
+
+        Ok(out)
+    }
+
+    /// Read the header of the next block
+    fn read_block_header(&mut self, reader: &mut BitReader) ->
+        Result<(), Box<dyn std::error::Error>>
+    {
+        let block_type = reader.read_bits(3);
+
+        let size = if self.wim {
+            if reader.read_bits(1) == 1 {
+                DEFAULT_BLOCK_SIZE
+            } else {
+                let mut size = reader.read_bits(16) as usize;
+                if self.window_order >= 16 {
+                    size = (size << 8) | reader.read_bits(8) as usize;
+                }
+                size
+            }
+        } else {
+            let hi = reader.read_bits(16) as usize;
+            let lo = reader.read_bits(8) as usize;
+            (hi << 8) | lo
+        };
+
+        let mut block = Block {
+            block_type,
+            size,
+            remaining: size,
+            main:    None,
+            length:  None,
+            aligned: None,
+        };
+
+        match block_type {
+            BLOCK_TYPE_UNCOMPRESSED => {
+                /* Realign to 16 bits. If we are already aligned a full 16
+                 * bits of padding are present. */
+                reader.ensure(1);
+                reader.align();
+                for rec in self.recent.iter_mut() {
+                    *rec = reader.read_u32() as usize;
+                    if *rec == 0 {
+                        return Err("Invalid LZX recent offset".into());
+                    }
+                }
+            }
+            BLOCK_TYPE_VERBATIM | BLOCK_TYPE_ALIGNED => {
+                if block_type == BLOCK_TYPE_ALIGNED {
+                    let mut lens = [0u8; NUM_ALIGNED_SYMS];
+                    for len in lens.iter_mut() {
+                        *len = reader.read_bits(3) as u8;
+                    }
+                    block.aligned = Some(Huffman::new(&lens,
+                                                      MAX_ALIGN_CODEWORD)?);
+                }
+
+                read_lengths(reader, &mut self.main_lens, 0, NUM_CHARS)?;
+                read_lengths(reader, &mut self.main_lens, NUM_CHARS,
+                             self.num_main)?;
+                block.main = Some(Huffman::new(&self.main_lens,
+                                               MAX_MAIN_CODEWORD)?);
+
+                read_lengths(reader, &mut self.len_lens, 0, NUM_LEN_SYMBOLS)?;
+                if self.len_lens.iter().any(|&x| x != 0) {
+                    block.length = Some(Huffman::new(&self.len_lens,
+                                                     MAX_MAIN_CODEWORD)?);
+                }
+            }
+            _ => return Err("Invalid LZX block type".into()),
+        }
+
+        self.block = Some(block);
+        Ok(())
+    }
+
+    /// Decode at least `want` more bytes (unless the block ends) into the
+    /// history
+    fn decode(&mut self, reader: &mut BitReader, want: usize) ->
+        Result<(), Box<dyn std::error::Error>>
+    {
+        if self.block.as_ref().is_none_or(|block| block.remaining == 0) {
+            self.read_block_header(reader)?;
+        }
+
+        let mut block = self.block.take().unwrap();
+        let target = std::cmp::min(want, block.remaining);
+        let start = self.history.len();
+
+        if block.block_type == BLOCK_TYPE_UNCOMPRESSED {
+            self.history.resize(start + target, 0);
+            reader.read_bytes(&mut self.history[start..])?;
+            block.remaining -= target;
+
+            /* Uncompressed blocks are padded to an even length. The block
+             * may have been read in several pieces, so go by its total
+             * size rather than what was read just now. */
+            if block.remaining == 0 {
+                if block.size & 1 != 0 {
+                    reader.read_u8();
+                }
+                reader.align();
+            }
+
+            self.block = Some(block);
+            return Ok(());
+        }
+
+        let main = block.main.as_ref().unwrap();
+        while self.history.len() - start < target {
+            let sym = main.decode(reader)? as usize;
+            if sym < NUM_CHARS {
+                self.history.push(sym as u8);
+                continue;
+            }
+
+            let sym = sym - NUM_CHARS;
+            let mut length = sym & 7;
+            let slot = sym >> 3;
+
+            if length == NUM_PRIMARY_LENS {
+                let len_code = block.length.as_ref()
+                    .ok_or("LZX length symbol with empty length tree")?;
+                length += len_code.decode(reader)? as usize;
+            }
+            length += MIN_MATCH_LEN;
+
+            let offset = if slot < 3 {
+                /* Repeat offset, swap it to the front of the queue */
+                self.recent.swap(slot, 0);
+                self.recent[0]
+            } else {
+                let extra = EXTRA_BITS[slot] as u32;
+                let mut offset = self.slot_base[slot];
+
+                match block.aligned {
+                    Some(ref aligned) if extra >= 3 => {
+                        offset += (reader.read_bits(extra - 3) as usize) << 3;
+                        offset += aligned.decode(reader)? as usize;
+                    }
+                    _ => offset += reader.read_bits(extra) as usize,
+                }
+
+                let offset = offset - 2;
+                self.recent[2] = self.recent[1];
+                self.recent[1] = self.recent[0];
+                self.recent[0] = offset;
+                offset
+            };
+
+            if offset == 0 || offset > self.history.len() {
+                return Err("LZX match offset out of bounds".into());
+            }
+
+            /* Matches may overlap the bytes they produce */
+            let from = self.history.len() - offset;
+            for ii in 0..length {
+                let byte = self.history[from + ii];
+                self.history.push(byte);
+            }
+
+            if reader.overrun() {
+                return Err("LZX input overrun".into());
+            }
+        }
+
+        let produced = self.history.len() - start;
+        block.remaining = block.remaining.saturating_sub(produced);
+        self.block = Some(block);
+        Ok(())
+    }
+}
+
+/// Decompress a single WIM LZX chunk `input` which expands to exactly
+/// `out_size` bytes. `window_order` is log2 of the WIM chunk size.
+pub fn decompress(input: &[u8], out_size: usize, window_order: u32) ->
+    Result<Vec<u8>, Box<dyn std::error::Error>>
+{
+    let mut lzx = Lzx::new(window_order, true)?;
+    lzx.decompress_frame(input, out_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Recent offsets following the header of an uncompressed block
+    const RECENT: [u8; 12] = [1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0];
+
+    #[test]
+    fn uncompressed_block_spanning_frames()
+    {
+        /* A 3 byte uncompressed block of which the first frame only takes
+         * one byte. The second frame holds the other two, the padding byte
+         * for the odd sized block and then a 1 byte block. */
+        let mut first = vec![0x00, 0x30, 0x30, 0x00];
+        first.extend_from_slice(&RECENT);
+        first.push(b'A');
+
+        let mut second = vec![b'B', b'C', 0x00, 0x00, 0x60, 0x20, 0x00];
+        second.extend_from_slice(&RECENT);
+        second.extend_from_slice(&[b'D', 0x00]);
+
+        let mut lzx = Lzx::new_cab(15).unwrap();
+        assert_eq!(lzx.decompress_frame(&first, 1).unwrap(), b"A");
+        assert_eq!(lzx.decompress_frame(&second, 3).unwrap(), b"BCD");
+    }
+
+    /// Pack (value, count) pairs of bits into 16-bit little endian words
+    fn pack(bits: &[(u32, u32)]) -> Vec<u8>
+    {
+        let mut stream = Vec::new();
+        for &(value, count) in bits {
+            for bit in (0..count).rev() {
+                stream.push((value >> bit) & 1);
+            }
+        }
+        while stream.len() % 16 != 0 {
+            stream.push(0);
+        }
+
+        let mut out = Vec::new();
+        for word in stream.chunks(16) {
+            let word = word.iter().fold(0u16, |acc, &x| acc << 1 | x as u16);
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+
+    /// Bits of a pretree where symbols 0, 16, 17 and 18 have 2 bit codes,
+    /// which is all it takes to write any mix of zero and one lengths
+    fn pretree() -> Vec<(u32, u32)>
+    {
+        (0..NUM_PRETREE_SYMS as u32)
+            .map(|sym| (if [0, 16, 17, 18].contains(&sym) { 2 } else { 0 }, 4))
+            .collect()
+    }
+
+    /// Bits of a run of `count` zero lengths, which must be at least 4
+    fn zeros(mut count: u32) -> Vec<(u32, u32)>
+    {
+        let mut bits = Vec::new();
+        while count >= 20 {
+            let run = std::cmp::min(count, 51);
+            bits.extend(&[(0b11, 2), (run - 20, 5)]);
+            count -= run;
+        }
+        if count > 0 {
+            bits.extend(&[(0b10, 2), (count - 4, 4)]);
+        }
+        bits
+    }
+
+    /// A CAB frame holding a verbatim block of `size` bytes, whose main
+    /// tree has one bit codes for the literal `a` (0) and for a 7 byte
+    /// match at the most recent offset (1), followed by `codes`
+    fn verbatim_frame(size: u32, codes: &[(u32, u32)]) -> Vec<u8>
+    {
+        let mut bits = vec![(0, 1), (BLOCK_TYPE_VERBATIM, 3),
+                            (size >> 8, 16), (size & 0xff, 8)];
+
+        /* Literals, with `a` getting a length of one */
+        bits.extend(pretree());
+        bits.extend(zeros(b'a' as u32));
+        bits.push((0b01, 2));
+        bits.extend(zeros(NUM_CHARS as u32 - b'a' as u32 - 1));
+
+        /* Matches, symbol 5 is slot 0 with a length of 5 + 2 */
+        bits.extend(pretree());
+        bits.extend(zeros(5));
+        bits.push((0b01, 2));
+        bits.extend(zeros(30 * 8 - 6));
+
+        /* Empty length tree */
+        bits.extend(pretree());
+        bits.extend(zeros(NUM_LEN_SYMBOLS as u32));
+
+        bits.extend_from_slice(codes);
+        pack(&bits)
+    }
+
+    #[test]
+    fn verbatim_block()
+    {
+        let frame = verbatim_frame(8, &[(0, 1), (1, 1)]);
+        let mut lzx = Lzx::new_cab(15).unwrap();
+        assert_eq!(lzx.decompress_frame(&frame, 8).unwrap(), b"aaaaaaaa");
+
+        /* A match before anything was decoded has nothing to refer to */
+        let frame = verbatim_frame(8, &[(1, 1)]);
+        let mut lzx = Lzx::new_cab(15).unwrap();
+        assert!(lzx.decompress_frame(&frame, 8).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_headers()
+    {
+        assert!(Lzx::new_cab(14).is_err());
+        assert!(Lzx::new_cab(22).is_err());
+
+        /* Block type 0 does not exist */
+        let frame = pack(&[(0, 1), (0, 3), (0, 16), (8, 8)]);
+        assert!(Lzx::new_cab(15).unwrap().decompress_frame(&frame, 8)
+                .is_err());
+
+        /* Uncompressed blocks can't have a recent offset of zero */
+        let mut frame = pack(&[(0, 1), (BLOCK_TYPE_UNCOMPRESSED, 3),
+                               (0, 16), (1, 8)]);
+        frame.extend_from_slice(&[0; 12]);
+        frame.push(b'A');
+        assert!(Lzx::new_cab(15).unwrap().decompress_frame(&frame, 1)
+                .is_err());
+    }
+
+    #[test]
+    fn e8_translation()
+    {
+        /* A call at position 1 to absolute address 105 is relative 104 */
+        let mut data = vec![0u8; 16];
+        data[1] = 0xe8;
+        data[2] = 105;
+        undo_e8_translation(&mut data, 0, 12000000);
+        assert_eq!(&data[1..6], &[0xe8, 104, 0, 0, 0]);
+
+        /* Addresses outside of the translation size are left alone */
+        let mut data = vec![0u8; 16];
+        data[1] = 0xe8;
+        data[2..6].copy_from_slice(&12000000i32.to_le_bytes());
+        let orig = data.clone();
+        undo_e8_translation(&mut data, 0, 12000000);
+        assert_eq!(data, orig);
+    }
+
+    #[test]
+    fn hostile_input_does_not_panic()
+    {
+        let frame = verbatim_frame(8, &[(0, 1), (1, 1)]);
+        for len in 0..frame.len() {
+            let mut lzx = Lzx::new_cab(15).unwrap();
+            if let Ok(out) = lzx.decompress_frame(&frame[..len], 8) {
+                assert_eq!(out.len(), 8);
+            }
+        }
+
+        let mut seed = 0x2468_ace0u32;
+        for _ in 0..500 {
+            let input: Vec<u8> = (0..128).map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            }).collect();
+            if let Ok(out) = decompress(&input, 256, 15) {
+                assert_eq!(out.len(), 256);
+            }
+        }
+    }
+}
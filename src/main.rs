@@ -1,17 +1,52 @@
-/// This is a tiny project to be a quick alternative to symchk for generating
-/// manifests. This mimics symchk of the form `symchk /om manifest /r <path>`
-/// but only looks for MZ/PE files.
-///
-/// Due to symchk doing some weird things it can often crash or get stuck in
-/// infinite loops. Thus this is a stricter (and much faster) alternative.
-///
-/// The output manifest is compatible with symchk and thus symchk is currently
-/// used for the actual download. To download symbols after this manifest
-/// has been generated use `symchk /im manifest /s <symbol path>`
+//! This is a tiny project to be a quick alternative to symchk for generating
+//! manifests. This mimics symchk of the form `symchk /om manifest /r <path>`
+//! but only looks for MZ/PE files.
+//!
+//! Due to symchk doing some weird things it can often crash or get stuck in
+//! infinite loops. Thus this is a stricter (and much faster) alternative.
+//!
+//! The output manifest is compatible with symchk and thus symchk is currently
+//! used for the actual download. To download symbols after this manifest
+//! has been generated use `symchk /im manifest /s <symbol path>`
 
 extern crate rand;
-
-use rand::{thread_rng, Rng};
+extern crate miniz_oxide;
+extern crate sha1;
+extern crate sha2;
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+mod apiset;
+mod authenticode;
+mod bitstream;
+mod cab;
+mod cache;
+mod closure;
+mod commands;
+mod dbg;
+mod dedup;
+mod deps;
+mod der;
+mod download;
+mod huffman;
+mod imports;
+mod info;
+mod json;
+mod lzms;
+mod lzx;
+mod manifest;
+mod options;
+mod pdb;
+mod pe;
+mod rich;
+mod serve;
+mod store;
+mod symstore;
+mod version;
+#[cfg(target_os = "linux")]
+mod watch;
+mod wim;
+mod xpress;
 
 use std::io;
 use std::env;
@@ -19,17 +54,26 @@ use std::time::Instant;
 use std::thread;
 use std::process::Command;
 use std::fs::File;
-use std::io::{Read, Write, Seek, SeekFrom};
+use std::io::{Read, Write, Seek, SeekFrom, Cursor};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-const USAGE: &'static str =
+use options::Options;
+
+const USAGE: &str =
 "Usage:
 
-    pdblister [manifest | download | filestore | clean] <filepath>
+    pdblister [manifest | manifest-diff | manifest-merge | wim | download |
+               filestore | add | store | serve | watch | deps | closure |
+               apiset | toolchain | info | compress | convertstore |
+               clean] <filepath>
  
     === Create manifest === 
     
-        pdblister manifest <filepath>
+        pdblister manifest <filepath> [--cache <cachefile>]
+                           [--format <symchk | jsonl | csv>]
+                           [--signed-by <signer>]
 
         This command takes in a filepath to recursively search for files that
         have a corresponding PDB. This creates a file called `manifest` which
@@ -39,39 +83,395 @@ const USAGE: &'static str =
         containing all of the PDB signatures for all of the files in
         C:\\windows.
 
+        CAB compressed files (such as `kernel32.dl_`) are transparently
+        expanded while scanning. MSZIP and LZX compression are supported.
+
+        Older images which split their symbols into a `.dbg` file name it in
+        a MISC debug entry. Those get a manifest line for the `.dbg` file as
+        well, keyed by the timestamp and image size of the image.
+
+        With `--format jsonl` or `--format csv` the manifest is written to
+        `manifest.jsonl` or `manifest.csv` instead, with the path of every
+        image, its machine, timestamp and image size and the full PDB path
+        it records alongside the PDB name, GUID and age. The `kind` column
+        tells PDBs from `.dbg` files, which have no GUID or age. JSON Lines
+        records also carry the version resource of the image under
+        `version`: the file and product versions and the StringFileInfo
+        strings such as FileVersion, ProductName, CompanyName and
+        OriginalFilename.
+
+        With `--cache` what was found in every file is kept in <cachefile>,
+        keyed by path, size, modification time and inode. Re-scanning a
+        mostly unchanged tree with the same cache only opens files which
+        changed since.
+
+        With `--signed-by` only images with an Authenticode signature from
+        a signer whose subject common name (CN) or organization (O) is
+        exactly <signer> (ignoring case), and which still have the digest
+        they were signed with, are listed, eg.
+        `--signed-by \"Microsoft Corporation\"`. This is not a trust check:
+        signatures are checked offline and the certificate chain is not
+        verified, so anyone can make a certificate with any subject. It
+        only tells which files claim to be signed by whom and are
+        unmodified since. Every signed image is read in full to recompute
+        its digest.
+
+    === Compare manifests ===
+
+        pdblister manifest-diff <old manifest> <new manifest>
+                                [--json | --added | --removed | --common]
+
+        This command compares two symchk manifests, such as those of two
+        builds of an OS, and lists the entries added, removed and in common
+        grouped by PDB name. Entries match if their file name and key do,
+        ignoring case and any directory in the name.
+
+        With `--json` a summary with the totals and the keys added, removed
+        and in common for every name is printed instead. `--added`,
+        `--removed` and `--common` print just those entries as a manifest.
+
+    === Merge manifests ===
+
+        pdblister manifest-merge <output> <manifest> [<manifest> ...]
+
+        This command writes every entry of the given manifests to <output>
+        once, in the order they first appear.
+
+    === Create manifest from a WIM image ===
+
+        pdblister wim <wimfile> [image index]
+
+        This command reads the files directly out of a WIM image such as the
+        `install.wim` or `boot.wim` from Windows installation media and
+        creates `manifest` for all PEs found in it. If an image index is
+        given only that image (edition) is scanned, otherwise all images are.
+        Files shared between images are only parsed once.
+
+        XPRESS, LZX and LZMS compressed WIMs are supported, including the
+        solid resources found in `install.esd`.
+
     === Download from manifest ===
 
-        pdblister download <sympath>
+        pdblister download <sympath> [--native]
+
+        This command simply downloads all the PDBs specified in the
+        `manifest` file, using symchk.
 
-        This command takes no parameters. It simply downloads all the PDBs
-        specified in the `manifest` file.
+        The manifest is validated first. Blank lines and CRLF line endings
+        are accepted, and entries may be PDBs or DBG files (type `1`) or
+        binaries (type `2`). Any other line is reported with its line number
+        and nothing is downloaded.
+
+        With `--native` files are fetched with `curl`, which must be
+        installed, instead of symchk. The symbol path must then be of the
+        form `srv*<store>*<url>`. Files the server only has compressed
+        (`ntdll.pd_` and so on) are fetched in that form and expanded into
+        the store.
 
     === Create a file store ===
 
-        pdblister filestore <filepath>
+        pdblister filestore <filepath> [--compress]
+                            [--case <preserve | symsrv>]
+                            [--dedup <auto | reflink | hardlink | copy>]
+                            [--dedup-index <indexfile>]
+                            [--cache <cachefile>]
+                            [--signed-by <signer>]
 
         This command recursively walks filepath to find all PEs. Any PE file
         that is found is copied to the local directory 'filestore' using the
         layout that symchk.exe uses to store normal files. This is used to
         create a store of all PEs (such as .dlls), which can be used by a
         kernel debugger to read otherwise paged out memory by downloading the
-        original PE source file from this filestore.
+        original PE source file from this filestore. CAB compressed PEs are
+        stored expanded under their original name.
+
+        Legacy `.dbg` files found are stored as well, under the timestamp
+        and image size of the image they were split from.
 
         To use this filestore simply merge the contents in with a symbol
         store/cache path. We keep it separate in this tool just to make it
         easier to only get PDBs if that's all you really want.
 
+        With `--compress` files are stored as MSZIP compressed CABs named
+        like `kernel32.dl_`, which WinDbg and symsrv read transparently.
+
+        If 'filestore' contains an `index2.txt` file the two-tier layout
+        `<first two chars of name>/<name>/<key>/<name>` is used instead.
+
+        Existing directories are reused even if their case differs, so
+        `Kernel32.dll` is stored alongside `kernel32.dll` rather than next to
+        it. With `--case symsrv` new directories and files are named like
+        symsrv does, with lowercase names and uppercase keys. By default the
+        case of names and keys is preserved.
+
+        With `--dedup` every file written is recorded in a content hash index
+        (`dedup.idx` in the store, or the file given with `--dedup-index`,
+        which can be shared between stores). Files identical to one already
+        indexed are reflinked or hard linked to it instead of being copied.
+        `auto` uses whichever of these works on the file system, falling
+        back to a plain copy.
+
+        `--cache` works the same as for `manifest`, with files which are
+        already in the filestore not being opened at all. `--signed-by` also
+        works the same, only storing images claiming to be signed by
+        <signer>. It is not a trust check either.
+
+    === Add to a symbol store ===
+
+        pdblister add <storepath> <filepath> <product> [--version <version>]
+                      [--comment <comment>] [--compress]
+                      [--case <preserve | symsrv>]
+                      [--dedup <auto | reflink | hardlink | copy>]
+                      [--dedup-index <indexfile>]
+
+        This command adds PDBs, PEs and `.dbg` files found at filepath (a
+        single file or a directory which is searched recursively) to the
+        symbol store at storepath, the same as `symstore add /r /f
+        <filepath> /s <storepath> /t <product>`. The addition is recorded
+        as a transaction in the `000Admin` directory of the store
+        (`server.txt`, `history.txt`, `lastid.txt` and a file listing the
+        transaction), so the store can still be managed with `symstore del`
+        and other Microsoft tooling.
+
+        Files already present in the store are not overwritten. With
+        `--compress` files are stored as MSZIP compressed CABs. Two-tier
+        stores are supported. Dates are recorded in UTC. `--case` and
+        `--dedup` work the same as for `filestore`.
+
+    === Prune a symbol store ===
+
+        pdblister store prune <storepath> --transaction <id> [--dry-run]
+        pdblister store prune <storepath> [--days <n>] [--max-size <bytes>]
+                              [--dry-run]
+
+        With `--transaction` this deletes a transaction made by `add` (or
+        symstore), the same as `symstore del /i <id> /s <storepath>`. Files
+        which are still referenced by another live transaction are kept, and
+        the deletion is recorded in the `000Admin` history.
+
+        Otherwise entries are deleted least recently used first, based on
+        file access and modification times. `--days` deletes entries not
+        used within that many days and `--max-size` deletes entries until
+        the store fits in that many bytes. Entries deleted this way are not
+        removed from the `000Admin` history.
+
+        With `--dry-run` nothing is deleted, instead every entry which would
+        be is listed along with how much space would be freed.
+
+    === Verify a symbol store ===
+
+        pdblister store verify <storepath> [--repair | --quarantine <dir>]
+
+        This command checks every `<name>/<key>/<file>` entry of a store.
+        PEs must parse and have the timestamp and image size of their key,
+        and PDBs must have the GUID and age of their key. Compressed entries
+        are expanded to be checked. Empty files, truncated files and stray
+        files (such as temporary files left behind by interrupted copies)
+        are reported too.
+
+        With `--repair` entries stored under the wrong key are moved to
+        where they belong and all other damaged files are deleted. With
+        `--quarantine` damaged files are moved into <dir> instead of being
+        deleted.
+
+    === Serve a store over HTTP ===
+
+        pdblister serve <storepath> [--listen <addr>] [--upstream <url>]
+                        [--case <preserve | symsrv>]
+
+        This command serves a symbol store or filestore over HTTP, by default
+        on 127.0.0.1:8080, so debuggers can use it with a symbol path such as
+        `srv*C:\\symcache*http://127.0.0.1:8080`. Lookups ignore case, files
+        which are only stored compressed are expanded on the fly and entries
+        holding a `file.ptr` are redirected to where it points. Pointers to
+        local files are only followed if the file lies inside the store.
+
+        With `--upstream` requests for files not in the store are fetched
+        from that server (for example
+        `https://msdl.microsoft.com/download/symbols`) and cached in the
+        store. Only plain file names with a well formed key are fetched.
+        This uses `curl`, which must be installed. `--case` controls how
+        cached files are named, the same as for `filestore`.
+
+    === Watch a directory ===
+
+        pdblister watch <dirpath>
+
+        This command watches dirpath (recursively, using inotify) for files
+        being created, written or moved in. Once a file has not been written
+        to for a couple of seconds it is parsed, its PDB is appended to
+        `manifest` and it is copied to 'filestore', the same as `manifest`
+        and `filestore` would. Files already present when watching starts
+        are left alone, run those commands first to pick them up. If so many
+        changes happen at once that the kernel drops some of them, every
+        file under dirpath is picked up again instead.
+
+        This is only supported on Linux.
+
+    === Dependency graph ===
+
+        pdblister deps <filepath> [--dot | --json] [--loaders <module>]
+
+        This command recursively walks filepath, reads the import and
+        delay-load import tables of every PE found and lists the DLLs each
+        one imports. Imports are matched to the images in the tree by file
+        name, ignoring case, preferring images for the same machine. DLLs
+        which are not in the tree are marked as not found. If the tree holds
+        an `apisetschema.dll` imports of API sets are redirected to their
+        host, the same as for `apiset`.
+
+        With `--dot` the graph is printed in Graphviz format instead, with
+        delay-load imports dashed and DLLs not in the tree in grey, eg.
+        `pdblister deps C:\\windows\\system32 --dot | dot -Tsvg > deps.svg`.
+        With `--json` it is printed as a JSON object.
+
+        With `--loaders` every image which loads <module>, directly or
+        through other images, is listed instead, along with how many imports
+        away it is and whether it only loads it through a delay-load import.
+        `--json` prints these as a JSON array.
+
+    === Manifest of a dependency closure ===
+
+        pdblister closure <root> [<root> ...] [--path <dir> ...]
+                          [--tree <dirpath>] [--no-delay-load]
+                          [--format <symchk | jsonl | csv>]
+
+        This command follows the imports of the given root images, and the
+        imports of those, and so on, and writes a manifest (the same as
+        `manifest` does) with the PDBs of just those images. Downloading
+        that gets the symbols needed to debug one program or service rather
+        than those of a whole OS.
+
+        DLLs are looked for the way Windows looks for them: in the directory
+        of the root image, then in each `--path` directory in the order
+        given, which should mirror the search path of the program, eg.
+        `--path C:\\windows\\system32 --path C:\\windows`. With `--tree` DLLs
+        not found there are looked for by name in that tree. Only DLLs for
+        the same machine as the image importing them are used.
+
+        API sets such as `api-ms-win-core-synch-l1-2-0.dll` are redirected
+        to the DLL implementing them using the API set schema of the
+        `apisetschema.dll` found in the `--path` directories, or else in the
+        tree. API sets the schema has no host for are looked for as files.
+
+        Delay-load imports are followed too, unless `--no-delay-load` is
+        given. DLLs which could not be found are listed at the end.
+
+    === API set schema ===
+
+        pdblister apiset <filepath> [--json]
+                         [--resolve <name> [--importer <module>]]
+
+        This command reads the API set schema out of `apisetschema.dll`
+        (filepath is either the DLL or a directory to search for it) and
+        lists every API set with the DLLs implementing it: the default host
+        and the hosts used for specific importing modules. Schema versions
+        2 (Windows 7 and 8), 4 (Windows 8.1) and 6 (Windows 10 and later)
+        are supported.
+
+        With `--json` the schema is printed as a JSON object instead. With
+        `--resolve` just the host an import of the API set <name> by
+        <module> is redirected to is printed, eg. `--resolve
+        api-ms-win-core-synch-l1-2-0.dll --importer kernel32.dll`.
+
+    === Toolchain of images ===
+
+        pdblister toolchain <filepath> [--json] [--older-than <version>]
+
+        This command recursively walks filepath and decodes the Rich header
+        of every image found. The Rich header is left between the MZ and PE
+        headers by Microsoft linkers and lists the product ID and build
+        number of every tool which produced an object linked into the image.
+        Product IDs are mapped to the Visual Studio version the tool shipped
+        with.
+
+        Every image is listed with the version of its linker and the linker
+        build number, then the oldest version of any tool, which is where
+        objects from old static libraries show up. Images whose Rich header
+        checksum doesn't match were edited after linking. Images built by
+        other linkers, or with the Rich header stripped, have none.
+
+        With `--older-than` only images linked by a toolchain older than
+        <version> are listed, eg. `--older-than VS2015` to find third-party
+        modules built with ancient toolchains. Versions are VS97, VS6,
+        VS2002, VS2003, VS2005, VS2008, VS2010, VS2012, VS2013, VS2015,
+        VS2017, VS2019 and VS2022. With `--json` every image is printed with
+        its decoded Rich header as a JSON array instead.
+
+    === Inspect an image ===
+
+        pdblister info <filepath> [--json]
+
+        This command dumps what is read out of a single image while looking
+        for its PDB: the MZ and PE headers, the optional header, the data
+        directories, the section table, every debug directory entry and the
+        CodeView record. If parsing stops before the CodeView record the
+        exact reason is given, which is why the image would be left out of a
+        manifest. CAB compressed images are expanded first.
+
+        Every debug directory entry is decoded: CodeView, MISC (the name of
+        a legacy .DBG file), FPO, POGO, ILTCG, VC_FEATURE counters, REPRO
+        hashes, EX_DLLCHARACTERISTICS flags, and MPX and other types as raw
+        data. Images built with /Brepro from the same sources have the same
+        REPRO hash, which makes deterministic builds easy to check.
+
+        The version resource (VS_VERSIONINFO) is dumped after the debug
+        data: the file and product versions and flags from VS_FIXEDFILEINFO
+        and the strings of the first StringFileInfo table.
+
+        So is the Authenticode signature: the signer certificate subject,
+        issuer and serial, the signing time from the timestamp
+        counter-signature, the embedded certificates, and the signed digest
+        of the image next to the digest recomputed from the image as it is.
+        SHA1, SHA256, SHA384 and SHA512 digests are supported.
+
+        Last are the functions imported from each DLL, by name (with the
+        hint) or ordinal, delay-load imports included, and the exported
+        functions with their ordinals, RVAs, names and forwarders.
+
+        The Rich header is dumped after the MZ header, with every tool it
+        lists and the Visual Studio version it shipped with.
+
+        With `--json` the same is printed as a JSON object instead.
+
+    === Compress a store ===
+
+        pdblister compress <storepath>
+
+        This command compresses every `<name>/<key>/<name>` entry of an
+        existing symbol store or filestore (such as the `symbols` folder
+        symchk downloads into) in place, replacing `ntdll.pdb` with
+        `ntdll.pd_` and so on. Entries which are already compressed are left
+        alone. Both flat and two-tier stores are supported.
+
+    === Convert store layout ===
+
+        pdblister convertstore <storepath> [flat | two-tier]
+
+        This command converts a store between the flat `<name>/<key>/<file>`
+        layout and the two-tier `<na>/<name>/<key>/<file>` layout used by
+        large stores. Two-tier stores are marked with an `index2.txt` file,
+        which this creates or removes. Entries are staged in a `.migrating`
+        directory of the store while it is converted, running the command
+        again finishes a conversion which was interrupted.
+
     === Clean ===
 
         pdblister clean
 
-        This command removes the `manifest` file as well as the symbol folder
-        and the filestore folder
+        This command removes the `manifest` files as well as the symbol
+        folder and the filestore folder
 ";
 
 /// Set this to true to enable status/progress messages
 const STATUS_MESSAGES: bool = true;
 
+/// How long a file must go unwritten before `watch` picks it up, so files
+/// which are still being copied in aren't parsed half written
+#[cfg(target_os = "linux")]
+const WATCH_SETTLE_TIME: std::time::Duration =
+    std::time::Duration::from_secs(2);
+
 /// Given a `path`, return a vector of all the files recursively found from
 /// that path.
 ///
@@ -96,532 +496,772 @@ fn recursive_listdir(path: &Path) -> io::Result<Vec<PathBuf>>
     Ok(result)
 }
 
-#[repr(C, packed)]
-#[derive(Clone, Copy)]
-struct MZHeader {
-    signature:       [u8; 2],
-    last_page_bytes: u16,
-    num_pages:       u16,
-    num_relocations: u16,
-    header_size:     u16,
-    min_memory:      u16,
-    max_memory:      u16,
-    initial_ss:      u16,
-    initial_sp:      u16,
-    checksum:        u16,
-    entry:           u32,
-    ptr_relocation:  u16,
-    overlay:         u16,
-    reserved:        [u8; 32],
-    new_header:      u32,
-}
+/// Stream an image is parsed from
+enum ImageStream {
+    /// Read directly from the file on disk
+    File(File),
 
-#[repr(C, packed)]
-#[derive(Clone, Copy)]
-struct PEHeader {
-    signature:            [u8; 4],
-    machine:              u16,
-    num_sections:         u16,
-    timestamp:            u32,
-    ptr_symtable:         u32,
-    num_smtable:          u32,
-    optional_header_size: u16,
-    characteristics:      u16,
+    /// Expanded into memory from a CAB compressed file
+    Expanded(Cursor<Vec<u8>>),
 }
 
-const IMAGE_FILE_MACHINE_I386:  u16 = 0x014c;
-const IMAGE_FILE_MACHINE_IA64:  u16 = 0x0200;
-const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
-
-#[repr(C, packed)]
-#[derive(Clone, Copy)]
-struct WindowsPEHeader32 {
-    magic:                      u16,
-    linker_major_version:       u8,
-    linker_minor_version:       u8,
-    size_of_code:               u32,
-    size_of_initialized_data:   u32,
-    size_of_uninitialized_data: u32,
-    entry:                      u32,
-    code_base:                  u32,
-    data_base:                  u32,
-    image_base:                 u32,
-    section_align:              u32,
-    file_align:                 u32,
-    major_os_version:           u16,
-    minor_os_version:           u16,
-    major_image_version:        u16,
-    minor_image_version:        u16,
-    major_subsystem_version:    u16,
-    minor_subsystem_version:    u16,
-    win32_version:              u32,
-    size_of_image:              u32,
-    size_of_headers:            u32,
-    checksum:                   u32,
-    subsystem:                  u16,
-    dll_characteristics:        u16,
-    size_of_stack_reserve:      u32,
-    size_of_stack_commit:       u32,
-    size_of_heap_reserve:       u32,
-    size_of_heap_commit:        u32,
-    loader_flags:               u32,
-    num_tables:                 u32,
+impl Read for ImageStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        match *self {
+            ImageStream::File(ref mut fd)     => fd.read(buf),
+            ImageStream::Expanded(ref mut fd) => fd.read(buf),
+        }
+    }
 }
 
-#[repr(C, packed)]
-#[derive(Clone, Copy)]
-struct WindowsPEHeader64 {
-    magic:                      u16,
-    linker_major_version:       u8,
-    linker_minor_version:       u8,
-    size_of_code:               u32,
-    size_of_initialized_data:   u32,
-    size_of_uninitialized_data: u32,
-    entry:                      u32,
-    code_base:                  u32,
-    image_base:                 u64,
-    section_align:              u32,
-    file_align:                 u32,
-    major_os_version:           u16,
-    minor_os_version:           u16,
-    major_image_version:        u16,
-    minor_image_version:        u16,
-    major_subsystem_version:    u16,
-    minor_subsystem_version:    u16,
-    win32_version:              u32,
-    size_of_image:              u32,
-    size_of_headers:            u32,
-    checksum:                   u32,
-    subsystem:                  u16,
-    dll_characteristics:        u16,
-    size_of_stack_reserve:      u64,
-    size_of_stack_commit:       u64,
-    size_of_heap_reserve:       u64,
-    size_of_heap_commit:        u64,
-    loader_flags:               u32,
-    num_tables:                 u32,
+impl Seek for ImageStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64>
+    {
+        match *self {
+            ImageStream::File(ref mut fd)     => fd.seek(pos),
+            ImageStream::Expanded(ref mut fd) => fd.seek(pos),
+        }
+    }
 }
 
-#[repr(C, packed)]
-#[derive(Clone, Copy)]
-struct ImageDataDirectory {
-    vaddr: u32,
-    size:  u32,
-}
+/// A file opened for parsing
+struct Image {
+    /// Name of the image. For compressed files this is the original name
+    /// stored in the CAB rather than the name of the `.dl_` file on disk.
+    name: String,
 
-#[repr(C, packed)]
-#[derive(Clone, Copy)]
-struct ImageSectionHeader {
-    name:                    [u8; 8],
-    vsize:                   u32,
-    vaddr:                   u32,
-    raw_data_size:           u32,
-    pointer_to_raw_data:     u32,
-    pointer_to_relocations:  u32,
-    pointer_to_line_numbers: u32,
-    number_of_relocations:   u16,
-    number_of_line_numbers:  u16,
-    characteristics:         u32,
+    stream: ImageStream,
 }
 
-#[repr(C, packed)]
-#[derive(Clone, Copy)]
-struct ImageDebugDirectory {
-    characteristics:      u32,
-    timestamp:            u32,
-    major_version:        u16,
-    minor_version:        u16,
-    typ:                  u32,
-    size_of_data:         u32,
-    address_of_raw_data:  u32,
-    pointer_to_raw_data:  u32,
-}
+/// Open `filename` for parsing, transparently expanding it if it is a CAB
+/// compressed file (such as `kernel32.dl_`).
+fn open_image(filename: &Path) -> Result<Image, Box<dyn std::error::Error>>
+{
+    let mut fd = File::open(filename)?;
 
-#[repr(C, packed)]
-#[derive(Clone, Copy)]
-struct CodeviewEntry {
-    signature: [u8; 4], // RSDS
-    guid_a:    u32,
-    guid_b:    u16,
-    guid_c:    u16,
-    guid_d:    [u8; 8],
-    age:       u32,
-}
+    let mut magic = [0u8; 4];
+    let magic_len = fd.read(&mut magic)?;
+    if !cab::is_cab(&magic[..magic_len]) {
+        fd.seek(SeekFrom::Start(0))?;
+        return Ok(Image {
+            name: filename.file_name().unwrap().to_str().unwrap().into(),
+            stream: ImageStream::File(fd),
+        });
+    }
 
-const IMAGE_DEBUG_TYPE_CODEVIEW: u32 = 2;
+    /* Peek at the start of the compressed file before expanding all of it,
+     * compressed PDBs can be huge and we only want images (and `.dbg`
+     * files) */
+    let (_, head) = cab::extract(&mut fd, Some(2))?;
+    if &head[..] != b"MZ" && &head[..] != b"DI" {
+        return Err("No MZ header present".into());
+    }
 
-/// Read a structure from a file stream, directly interpreting the raw bytes
-/// of the file as T.
-///
-/// User must make sure the shape of the structure `T` is safe to use in this
-/// way, hence being unsafe.
-unsafe fn read_struct<T: Copy>(fd: &mut File) -> io::Result<T>
-{
-    let mut ret: T = std::mem::zeroed();
-    fd.read_exact(std::slice::from_raw_parts_mut(
-            &mut ret as *mut _ as *mut u8,
-            std::mem::size_of_val(&ret)))?;
-    Ok(ret)
+    let (name, data) = cab::extract(&mut fd, None)?;
+    Ok(Image {
+        name,
+        stream: ImageStream::Expanded(Cursor::new(data)),
+    })
 }
 
-/// Implementation mimicing #![feature(range_contains)] for those stable rust
-/// users.
-fn contains(range: &std::ops::Range<u32>, item: u32) -> bool
+/// Get the key an opened `image` is stored under in a store, which is its
+/// timestamp followed by its image size. `.dbg` files carry those of the
+/// image they were split from.
+fn get_file_key(image: &mut Image) -> Result<String, Box<dyn std::error::Error>>
 {
-    (range.start <= item) && (item < range.end)
+    if dbg::is_dbg_name(&image.name) {
+        return Ok(dbg::get_dbg_info(&mut image.stream)?.key());
+    }
+
+    let pe = pe::parse(&mut image.stream);
+    match (pe.pe, pe.optional) {
+        (Some(pe_header), Some(optional)) => {
+            Ok(format!("{:08x}{:x}", {pe_header.timestamp},
+                       optional.size_of_image()))
+        }
+        _ => Err(pe.stop.unwrap_or_default().into()),
+    }
 }
 
-fn parse_pe(filename: &Path) ->
-    Result<(File, MZHeader, PEHeader, u32, u32), Box<std::error::Error>>
+/// Open `filename` as either an image or a PDB, returning it along with the
+/// key it is stored under in a store. PDBs are keyed by their GUID and age.
+/// Either may be CAB compressed.
+fn open_store_file(filename: &Path) ->
+    Result<(Image, String), Box<dyn std::error::Error>>
 {
     let mut fd = File::open(filename)?;
 
-    /* Check for an MZ header */
-    let mz_header: MZHeader = unsafe { read_struct(&mut fd)? };
-    if &mz_header.signature != b"MZ" {
-        return Err("No MZ header present".into());
+    let mut magic = [0u8; 32];
+    let magic_len = fd.read(&mut magic)?;
+    if pdb::is_pdb(&magic[..magic_len]) {
+        let key = pdb::get_pdb_info(&mut fd)?.key();
+        fd.seek(SeekFrom::Start(0))?;
+        return Ok((Image {
+            name: filename.file_name().unwrap().to_str().unwrap().into(),
+            stream: ImageStream::File(fd),
+        }, key));
     }
 
-    /* Seek to where the PE header should be */
-    if fd.seek(SeekFrom::Start(mz_header.new_header as u64))? !=
-            mz_header.new_header as u64 {
-        return Err("Failed to seek to PE header".into());
+    if cab::is_cab(&magic[..magic_len]) &&
+            pdb::is_pdb(&cab::extract(&mut fd, Some(32))?.1) {
+        let (name, data) = cab::extract(&mut fd, None)?;
+        let mut stream = Cursor::new(data);
+        let key = pdb::get_pdb_info(&mut stream)?.key();
+        stream.set_position(0);
+        return Ok((Image {
+            name,
+            stream: ImageStream::Expanded(stream),
+        }, key));
     }
 
-    /* Check for a PE header */
-    let pe_header: PEHeader = unsafe { read_struct(&mut fd)? };
-    if &pe_header.signature != b"PE\0\0" {
-        return Err("No PE header present".into());
+    let mut image = open_image(filename)?;
+    let key = get_file_key(&mut image)?;
+    Ok((image, key))
+}
+
+/// Get the size the image in `fd` should be on disk, which is the end of its
+/// last section's raw data
+fn get_pe_raw_size<R: Read + Seek>(fd: &mut R) ->
+    Result<u64, Box<dyn std::error::Error>>
+{
+    fd.seek(SeekFrom::Start(0))?;
+    let image = pe::parse(fd);
+
+    /* Parsing only has to get through the section table */
+    let complete = image.optional.is_some() && image.pe.is_some_and(|x| {
+        x.num_sections as usize == image.sections.len()
+    });
+    if !complete {
+        return Err(image.stop.unwrap_or_default().into());
     }
 
-    /* Grab the number of tables from the bitness-specific table */
-    let (image_size, num_tables) = match pe_header.machine {
-        IMAGE_FILE_MACHINE_I386 => {
-            let opthdr: WindowsPEHeader32 = unsafe { read_struct(&mut fd)? };
-            (opthdr.size_of_image, opthdr.num_tables)
-        }
-        IMAGE_FILE_MACHINE_IA64 | IMAGE_FILE_MACHINE_AMD64 => {
-            let opthdr: WindowsPEHeader64 = unsafe { read_struct(&mut fd)? };
-            (opthdr.size_of_image, opthdr.num_tables)
-        }
-        _ => return Err("Unsupported PE machine type".into())
-    };
+    let size = image.sections.iter()
+        .filter(|x| x.raw_data_size != 0)
+        .map(|x| x.pointer_to_raw_data as u64 + x.raw_data_size as u64)
+        .max();
+    Ok(size.unwrap_or(0))
+}
+
+/// Something wrong with a file in a store, found by `store verify`
+enum Damage {
+    /// Zero byte file
+    Empty,
+
+    /// File which does not belong in an entry, such as a leftover temporary
+    /// file from an interrupted copy
+    Stray,
+
+    /// File which does not parse or is truncated
+    Corrupt(String),
 
-    Ok((fd, mz_header, pe_header, image_size, num_tables))
+    /// Valid file stored under the wrong key
+    Misfiled(String),
 }
 
-fn get_file_path(filename: &Path) -> Result<String, Box<std::error::Error>>
+/// Read the first bytes of an opened store file to tell PDBs from images
+fn peek_magic(stream: &mut ImageStream) -> Vec<u8>
 {
-    let (_, _, pe_header, image_size, _) = parse_pe(filename)?;
-
-    let filestr = format!("filestore/{}/{:08x}{:x}/{}",
-                          filename.file_name().unwrap().to_str().unwrap(),
-                          {pe_header.timestamp},
-                          image_size,
-                          filename.file_name().unwrap().to_str().unwrap());
-
-    /* For hashes
-    let filestr = format!("{},{:08x}{:x},1",
-                          filename.file_name()
-                            .unwrap().to_str().unwrap(),
-                          pe_header.timestamp,
-                          image_size);*/
-
-    Ok(filestr)
+    let mut magic = vec![0u8; 32];
+    let len = stream.seek(SeekFrom::Start(0))
+        .and_then(|_| stream.read(&mut magic)).unwrap_or(0);
+    magic.truncate(len);
+    magic
 }
 
-/// Given a `filename`, attempt to parse out any mention of a PDB file in it.
-///
-/// This returns success if it successfully parses the MZ, PE, finds a debug
-/// header, matches RSDS signature, and contains a valid reference to a PDB.
-///
-/// Returns a string which is the same representation you get from `symchk`
-/// when outputting a manifest for the PDB "<filename>,<guid><age>,1"
-fn get_pdb(filename: &Path) -> Result<String, Box<std::error::Error>>
+/// Check the file of a store `entry`
+fn verify_entry(entry: &store::Entry) -> Result<(), Damage>
 {
-    let (mut fd, mz_header, pe_header, _, num_tables) =
-        parse_pe(filename)?;
+    let file = entry.path.file_name().unwrap().to_string_lossy()
+        .to_lowercase();
+    let name = entry.name.to_lowercase();
+    if file == "file.ptr" || file == "refs.ptr" {
+        return Ok(());
+    }
+    if file != name && file != cab::compressed_name(&name) {
+        return Err(Damage::Stray);
+    }
 
-    /* Load all the data directories into a vector */
-    let mut data_dirs = Vec::new();
-    for _ in 0..num_tables {
-        let datadir: ImageDataDirectory = unsafe { read_struct(&mut fd)? };
-        data_dirs.push(datadir);
+    match entry.path.metadata() {
+        Ok(meta) if meta.len() == 0 => return Err(Damage::Empty),
+        Ok(_) => {}
+        Err(err) => return Err(Damage::Corrupt(err.to_string())),
     }
 
-    /* Debug directory is at offset 6, validate we have at least 7 entries */
-    if data_dirs.len() < 7 {
-        return Err("No debug data directory".into());
+    let (mut image, key) = open_store_file(&entry.path)
+        .map_err(|err| Damage::Corrupt(err.to_string()))?;
+
+    /* PDBs have already been checked for truncation by reading them, and
+     * `.dbg` files only have their header checked */
+    if !pdb::is_pdb(&peek_magic(&mut image.stream)) &&
+            !dbg::is_dbg_name(&image.name) {
+        let want = get_pe_raw_size(&mut image.stream)
+            .map_err(|err| Damage::Corrupt(err.to_string()))?;
+        let have = image.stream.seek(SeekFrom::End(0))
+            .map_err(|err| Damage::Corrupt(err.to_string()))?;
+        if have < want {
+            return Err(Damage::Corrupt(
+                format!("Truncated, {} of {} bytes present", have, want)));
+        }
     }
 
-    /* Grab the debug table */
-    let debug_table = data_dirs[6];
-    if debug_table.vaddr == 0 || debug_table.size == 0 {
-        return Err("Debug directory not present or zero sized".into());
+    if !key.eq_ignore_ascii_case(&entry.key) {
+        return Err(Damage::Misfiled(key));
     }
 
-    /* Validate debug table size is sane */
-    let iddlen = std::mem::size_of::<ImageDebugDirectory>() as u32;
-    let debug_table_ents = debug_table.size / iddlen;
-    if (debug_table.size % iddlen) != 0 || debug_table_ents == 0 {
-        return Err("No debug entries or not mod ImageDebugDirectory".into());
+    Ok(())
+}
+
+/// Parse everything scans want to know about `filename`: the PDB and `.dbg`
+/// file it references and the name and key it is stored under
+fn parse_file(filename: &Path) -> cache::Parsed
+{
+    let mut image = match open_image(filename) {
+        Ok(image) => image,
+        Err(_) => return cache::Parsed { pdbs: Vec::new(), image: None },
+    };
+
+    let mut parsed = pe::parse(&mut image.stream);
+    parsed.decode_version(&mut image.stream);
+    let mut pdbs: Vec<manifest::Record> = pdb_record(&parsed).ok().into_iter()
+        .chain(dbg_record(&parsed, &mut image.stream, &image.name))
+        .collect();
+    for record in &mut pdbs {
+        record.source = filename.display().to_string();
     }
 
-    /* Seek to where the section table should be */
-    let section_headers = mz_header.new_header as u64 + 0x18 +
-                          pe_header.optional_header_size as u64;
-    if fd.seek(SeekFrom::Start(section_headers))? != section_headers {
-        return Err("Failed to seek to section table".into());
+    let key = image.stream.seek(SeekFrom::Start(0)).ok()
+        .and_then(|_| get_file_key(&mut image).ok());
+
+    cache::Parsed {
+        pdbs,
+        image: key.map(|key| (image.name, key)),
     }
+}
 
-    /* Parse all the sections into a vector */
-    let mut sections = Vec::new();
-    for _ in 0..pe_header.num_sections {
-        let sechdr: ImageSectionHeader = unsafe { read_struct(&mut fd)? };
-        sections.push(sechdr);
+/// Check whether `filename` has an Authenticode signature from a signer
+/// whose subject CN or O is `signer` (ignoring case), and still has the
+/// digest it was signed with. Signed images are read in full.
+///
+/// This is not a trust check, the certificate chain is not verified.
+fn is_signed_by(filename: &Path, signer: &str) -> bool
+{
+    let mut image = match open_image(filename) {
+        Ok(image) => image,
+        Err(_) => return false,
+    };
+    let mut parsed = pe::parse(&mut image.stream);
+    parsed.decode_signature(&mut image.stream);
+
+    match parsed.signature {
+        Some(Ok(signature)) => signature.digest_matches() &&
+            signature.signer.is_some_and(|x| {
+                x.subject_attributes.iter().any(|(key, val)| {
+                    (key == "CN" || key == "O") &&
+                        val.eq_ignore_ascii_case(signer)
+                })
+            }),
+        _ => false,
     }
+}
 
-    /* Find the section the debug table belongs to */
-    let mut debug_data = None;
-    for section in &sections {
-        /* We use raw_data_size instead of vsize as we are not loading the
-         * file and only care about raw contents in the file.
-         */
-        let secrange = section.vaddr..section.vaddr + section.raw_data_size;
+/// Build the dependency graph of every image found under `dir`
+fn dependency_graph(dir: &Path) -> io::Result<deps::Graph>
+{
+    let listing = recursive_listdir(dir)?;
+
+    let mut graph = deps::Graph::default();
+    for (ii, filename) in listing.iter().enumerate() {
+        if let Ok(mut image) = open_image(filename) {
+            let mut parsed = pe::parse(&mut image.stream);
+            parsed.decode_imports(&mut image.stream);
+            let path = filename.display().to_string();
+            if let Some(module) = deps::Module::new(&path, &image.name,
+                                                    &parsed) {
+                graph.add(module);
+            }
+        }
 
-        /* Check if the entire debug table is contained in this sections
-         * virtual address range.
-         */
-        if contains(&secrange, debug_table.vaddr) &&
-                contains(&secrange, debug_table.vaddr + debug_table.size - 1) {
-            debug_data = Some(debug_table.vaddr - section.vaddr +
-                              section.pointer_to_raw_data);
-            break;
+        /* The graph itself goes to stdout */
+        if STATUS_MESSAGES {
+            eprint!("\rParsed {} of {} files ({} images)",
+                    ii + 1, listing.len(), graph.modules.len());
         }
     }
+    eprintln!();
+
+    /* Imports of API sets are redirected through the schema in the tree */
+    let paths: Vec<PathBuf> = graph.modules.iter()
+        .filter(|x| x.name.eq_ignore_ascii_case("apisetschema.dll"))
+        .map(|x| PathBuf::from(&x.path))
+        .collect();
+    graph.schema = load_apiset_schema(&paths).map(|x| x.1);
+    Ok(graph)
+}
 
-    if debug_data.is_none() {
-        return Err("Unable to find debug data".into());
+/// Read the API set schema out of the first of `paths` holding one,
+/// reporting those which don't
+fn load_apiset_schema(paths: &[PathBuf]) -> Option<(PathBuf, apiset::Schema)>
+{
+    for path in paths {
+        match apiset::load(path) {
+            Ok(schema) => return Some((path.clone(), schema)),
+            Err(err) => eprintln!("Failed to read {}: {}", path.display(), err),
+        }
     }
-    let debug_raw_ptr = debug_data.unwrap() as u64;
+    None
+}
 
-    /* Seek to where the debug directories should be */
-    if fd.seek(SeekFrom::Start(debug_raw_ptr))? != debug_raw_ptr {
-        return Err("Failed to seek to debug directories".into());
+/// Parse `filename`, going through `cache` if there is one
+fn parse_cached(cache: &mut Option<cache::Cache>, filename: &Path) ->
+    cache::Parsed
+{
+    match cache {
+        Some(cache) => cache.get(filename, parse_file),
+        None => parse_file(filename),
     }
+}
+
+/// Watch `dir` for new and changed files, appending them to `manifest` and
+/// storing them in `filestore` as they settle
+#[cfg(target_os = "linux")]
+fn watch_dir(dir: &Path) -> Result<(), Box<dyn std::error::Error>>
+{
+    use std::collections::HashSet;
+
+    let mut watcher = watch::Watcher::new(dir)?;
+    println!("Watching {:?}", dir);
+
+    /* Don't repeat PDBs which are already in the manifest */
+    let existing = std::fs::read_to_string("manifest").unwrap_or_default();
+    let mut needs_newline = !existing.is_empty() && !existing.ends_with('\n');
+    let mut listed: HashSet<String> =
+        manifest::Manifest::parse(&existing)?.entries.iter()
+            .map(|x| x.line()).collect();
 
-    /* Look through all debug table entries for codeview entries */
-    for _ in 0..debug_table_ents {
-        let de: ImageDebugDirectory = unsafe { read_struct(&mut fd)? };
+    let root = Path::new("filestore");
 
-        if de.typ == IMAGE_DEBUG_TYPE_CODEVIEW {
-            /* Seek to where the codeview entry should be */
-            let cvo = de.pointer_to_raw_data as u64;
-            if fd.seek(SeekFrom::Start(cvo))? != cvo {
-                return Err("Failed to seek to codeview entry".into());
+    let mut pending = watch::Pending::new(WATCH_SETTLE_TIME);
+    loop {
+        for path in watcher.wait(pending.timeout())? {
+            pending.touch(path);
+        }
+
+        for filename in pending.settled() {
+            if !filename.is_file() {
+                continue;
             }
 
-            let cv: CodeviewEntry = unsafe { read_struct(&mut fd)? };
-            if &cv.signature != b"RSDS" {
-                return Err("No RSDS signature present in codeview ent".into());
+            let parsed = parse_file(&filename);
+
+            for pdb in parsed.pdbs.iter().map(|x| x.symchk_line()) {
+                if listed.insert(pdb.clone()) {
+                    let mut fd = std::fs::OpenOptions::new()
+                        .create(true).append(true).open("manifest")?;
+                    if needs_newline {
+                        fd.write_all(b"\n")?;
+                    }
+                    fd.write_all(pdb.as_bytes())?;
+                    needs_newline = true;
+                    println!("{:?}: added {}", filename, pdb);
+                }
             }
 
-            /* Calculate theoretical string length based on the size of the
-             * section vs the size of the header */
-            let cv_strlen = de.size_of_data as usize -
-                std::mem::size_of_val(&cv);
-
-            /* Read in the debug path */
-            let mut dpath = vec![0u8; cv_strlen];
-            fd.read_exact(&mut dpath)?;
-
-            /* PDB strings are utf8 and null terminated, find the first null
-             * and we will split it there.
-             */
-            if let Some(null_strlen) = dpath.iter().position(|&x| x == 0) {
-                let dpath = std::str::from_utf8(&dpath[..null_strlen])?;
-
-                /* Further, since this path can be a full path, we get only
-                 * the filename component of this path.
-                 */
-                if let Some(pdbfilename) = Path::new(dpath).file_name() {
-                    /* This is the format string used by symchk.
-                     * Original is in SymChkCheckFiles()
-                     * "%s,%08X%04X%04X%02X%02X%02X%02X%02X%02X%02X%02X%x,1"
-                     */
-                    let guidstr = format!("{},{:08X}{:04X}{:04X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:x},1",
-                                          pdbfilename.to_str().unwrap(),
-                                          {cv.guid_a}, {cv.guid_b}, {cv.guid_c},
-                                          {cv.guid_d[0]}, {cv.guid_d[1]},
-                                          {cv.guid_d[2]}, {cv.guid_d[3]},
-                                          {cv.guid_d[4]}, {cv.guid_d[5]},
-                                          {cv.guid_d[6]}, {cv.guid_d[7]},
-                                          {cv.age});
-                    return Ok(guidstr)
-                } else {
-                    return Err("Could not parse file from RSDS path".into())
+            if let Some((name, key)) = parsed.image {
+                let layout = store::detect_layout(root);
+                let mut index = store::DirIndex::new();
+                let fsname = index.entry_path(root, layout,
+                    store::Case::Preserve, &name, &key);
+                if !is_stored(&mut index, &fsname) {
+                    let res = open_image(&filename).and_then(|image| {
+                        if let Some(dir) = fsname.parent() {
+                            std::fs::create_dir_all(dir)?;
+                        }
+                        store_image(image, &filename, &fsname, false, None)
+                    });
+                    match res {
+                        Ok(()) => println!("{:?}: stored as {:?}",
+                                           filename, fsname),
+                        Err(err) => println!("{:?}: failed to store: {}",
+                                             filename, err),
+                    }
                 }
-            } else {
-                return Err("Failed to find null terminiator in RSDS".into())
             }
         }
     }
+}
 
-    Err("Failed to find RSDS codeview directory".into())
+/// Save the parse cache, if there is one
+fn save_cache(cache: Option<cache::Cache>)
+{
+    if let Some(cache) = cache {
+        println!("{} files were unchanged since the last scan", cache.hits);
+        if let Err(err) = cache.save() {
+            println!("Failed to save cache: {}", err);
+        }
+    }
 }
 
-fn download_worker(filename: PathBuf, sympath: String)
+/// Check whether the store file `fsname`, or its compressed counterpart, is
+/// already present. The case of the compressed name is ignored.
+fn is_stored(index: &mut store::DirIndex, fsname: &Path) -> bool
 {
-    let _ = Command::new("symchk").args(
-        &["/im", filename.to_str().unwrap(), "/s",
-        sympath.as_str()]).
-        output().expect("Failed to run command");
+    let name = fsname.file_name().unwrap().to_string_lossy();
+    let compressed = fsname.with_file_name(cab::compressed_name(&name));
+    fsname.exists() || compressed.exists() ||
+        index.find(fsname.parent().unwrap(),
+                   &cab::compressed_name(&name)).is_some()
 }
 
-fn main()
+/// Open the deduplication index for the `--dedup` and `--dedup-index`
+/// options of commands which write to the store at `root`
+fn open_dedup(root: &Path, strategy: Option<&str>, index: Option<&str>) ->
+    Result<Option<dedup::Dedup>, Box<dyn std::error::Error>>
 {
-    let args: Vec<String> = env::args().collect();
+    let strategy = match strategy {
+        Some(strategy) => dedup::Strategy::parse(strategy)
+            .ok_or("--dedup must be auto, reflink, hardlink or copy")?,
+        None if index.is_some() => dedup::Strategy::Auto,
+        None => return Ok(None),
+    };
 
-    let it = Instant::now();
+    let index = match index {
+        Some(index) => PathBuf::from(index),
+        None => root.join("dedup.idx"),
+    };
+    Ok(Some(dedup::Dedup::open(&index, strategy)?))
+}
 
-    if args.len() == 3 && args[1] == "manifest" {
-        /* List all files in the directory specified by args[2] */
-        print!("Generating file listing...\n");
-        let listing = recursive_listdir(&Path::new(args[2].as_str())).
-            expect("Failed to list directory");
-        print!("Done!\n");
-
-        /* For each file, try to parse PDB information out of it and print the
-         * manifest-style information to the screen.
-         */
-        let mut output_pdbs = Vec::new();
-        for (ii, filename) in listing.iter().enumerate() {
-            if let Ok(manifest_str) = get_pdb(&filename) {
-                output_pdbs.push(manifest_str);
-            }
+/// Parse the `--case` option of commands which write to stores
+fn parse_case(case: &str) -> Option<store::Case>
+{
+    match case {
+        "preserve" => Some(store::Case::Preserve),
+        "symsrv"   => Some(store::Case::Symsrv),
+        _ => None,
+    }
+}
 
-            if STATUS_MESSAGES {
-                print!("\rParsed {} of {} files ({} pdbs)",
-                    ii + 1, listing.len(), output_pdbs.len());
-            }
+/// Write `image` (opened from `filename`) into a store at `fsname`.
+///
+/// If `compress` is set the file is written as an MSZIP CAB alongside where
+/// `fsname` would be, with the last character of its name replaced by an
+/// underscore. Otherwise files are stored as-is, with CAB compressed inputs
+/// being stored expanded.
+///
+/// If `dedup` is given the file is linked to an identical one written before
+/// where possible.
+fn store_image(image: Image, filename: &Path, fsname: &Path, compress: bool,
+               dedup: Option<&mut dedup::Dedup>)
+    -> Result<(), Box<dyn std::error::Error>>
+{
+    let name = fsname.file_name().unwrap().to_string_lossy();
+
+    let data = match image.stream {
+        ImageStream::File(_) if !compress && dedup.is_none() => {
+            std::fs::copy(filename, fsname)?;
+            return Ok(());
         }
-        print!("\n");
-
-        let mut output_file = File::create("manifest").
-            expect("Failed to create output manifest file");
-
-        /* Write out all the PDBs */
-        output_file.write_all(output_pdbs.join("\n").as_bytes()).
-            expect("Failed to write pdbs to manifest file");
-
-    } else if args.len() == 3 && args[1] == "download" {
-        const NUM_PIECES: usize = 64;
-
-        /* Read the entire manifest file into a string */
-        let mut buf = String::new();
-        let mut fd = match File::open("manifest") {
-            Ok(fd) => fd,
-            Err(_) => {
-                print!("Failed to open manifest, did you create one?\n");
-                return;
-            },
-        };
-        fd.read_to_string(&mut buf).expect("Failed to read file");
+        ImageStream::File(_) => std::fs::read(filename)?,
+        ImageStream::Expanded(data) => data.into_inner(),
+    };
 
-        /* Split the file into lines and collect into a vector */
-        let mut lines: Vec<String> =
-            buf.lines().map(|l| String::from(l)).collect();
+    let (fsname, data) = if compress {
+        (fsname.with_file_name(cab::compressed_name(&name)),
+         cab::compress(&name, &data)?)
+    } else {
+        (fsname.to_path_buf(), data)
+    };
 
-        /* If there is nothing to download, return out early */
-        if lines.len() == 0 {
-            print!("Nothing to download\n");
-            return;
-        }
+    match dedup {
+        Some(dedup) => dedup.write(&fsname, &data)?,
+        None => std::fs::write(&fsname, data)?,
+    }
 
-        /* Calculate number of entries per temporary manifest to split into
-         * NUM_PIECES chunks.
-         */
-        let chunk_size = (lines.len() + NUM_PIECES - 1) / NUM_PIECES;
+    Ok(())
+}
 
-        print!("Original manifest has {} PDBs\n", lines.len());
+/// Replace the store `entry` with its MSZIP compressed counterpart. The
+/// compressed file is safely on disk before the original is removed.
+///
+/// Returns the number of bytes saved, or `None` if the entry was left alone
+/// as it is already compressed.
+fn compress_entry(entry: &store::Entry)
+    -> Result<Option<i64>, Box<dyn std::error::Error>>
+{
+    /* Only touch uncompressed files, stored under their own name */
+    let filename = &entry.path;
+    let name = entry.name.as_str();
+    if filename.file_name().and_then(|x| x.to_str()) != Some(name) {
+        return Ok(None);
+    }
 
-        lines.sort();
-        lines.dedup();
+    let data = std::fs::read(filename)?;
+    if cab::is_cab(&data) {
+        return Ok(None);
+    }
 
-        print!("Deduped manifest has {} PDBs\n", lines.len());
+    let cab = cab::compress(name, &data)?;
+    store::write_file(&filename.with_file_name(cab::compressed_name(name)),
+                      &cab)?;
+    std::fs::remove_file(filename)?;
+    Ok(Some(data.len() as i64 - cab.len() as i64))
+}
 
-        /* Shuffle filenames so files are not biased to the downloader based
-         * on name. This should lead to download threads having more even
-         * work.
-         */
-        thread_rng().shuffle(&mut lines);
+/// Build the manifest record for the PDB referenced by a parsed `image`.
+///
+/// This returns success if parsing got through the MZ, PE, found a debug
+/// header, matched RSDS signature, and found a valid reference to a PDB.
+///
+/// The record has everything but the source of the image filled in.
+fn pdb_record(image: &pe::PeFile) ->
+    Result<manifest::Record, Box<dyn std::error::Error>>
+{
+    let (pe_header, optional, cv) =
+        match (&image.pe, &image.optional, &image.codeview) {
+            (Some(pe_header), Some(optional), Some(cv)) =>
+                (pe_header, optional, cv),
+            _ => return Err(image.stop.clone().unwrap_or_default().into()),
+        };
 
-        /* Create worker threads downloading each chunk using symchk */
-        let mut threads = Vec::new();
-        for (ii, lines) in lines.chunks(chunk_size).enumerate() {
-            let mut tmp_path = env::temp_dir();
-            tmp_path.push(format!("manifest_{:04}", ii));
+    Ok(manifest::Record {
+        kind:       manifest::Kind::Pdb,
+        pdb_name:   cv.name.clone(),
+        pdb_path:   cv.path.clone(),
+        guid:       cv.guid.clone(),
+        age:        cv.age,
+        source:     String::new(),
+        machine:    pe_header.machine,
+        timestamp:  pe_header.timestamp,
+        image_size: optional.size_of_image(),
+        version:    image.version.clone().and_then(|x| x.ok()),
+    })
+}
 
-            {
-                /* Create chunked manifest file */
-                let mut fd = File::create(&tmp_path).
-                    expect("Failed to create split manifest");
-                fd.write_all(lines.join("\n").as_bytes()).
-                    expect("Failed to write split manifest");
-            }
+/// Build the manifest record for the legacy `.dbg` file a parsed `image`
+/// (read from `fd`) names in its MISC debug entry, if any. If the name given
+/// is not that of a `.dbg` file, `<image name>.dbg` is assumed like symchk.
+fn dbg_record<R: Read + Seek>(image: &pe::PeFile, fd: &mut R,
+                              image_name: &str) -> Option<manifest::Record>
+{
+    let (pe_header, optional) = match (&image.pe, &image.optional) {
+        (Some(pe_header), Some(optional)) => (pe_header, optional),
+        _ => return None,
+    };
 
-            /* Create worker */
-            let sympath = args[2].clone();
-            threads.push(thread::spawn(move || {
-                download_worker(tmp_path, sympath);
-            }));
-        }
+    let path = image.misc_name(fd)?;
+    let name = match Path::new(&path).file_name().and_then(|x| x.to_str()) {
+        Some(name) if dbg::is_dbg_name(name) => name.to_string(),
+        _ => Path::new(image_name).with_extension("dbg")
+            .to_string_lossy().into_owned(),
+    };
 
-        /* Wait for all threads to complete. Discard return status */
-        for thr in threads {
-            let _ = thr.join();
-        }
-    } else if args.len() == 3 && args[1] == "filestore" {
-        /* List all files in the directory specified by args[2] */
-        print!("Generating file listing...\n");
-        let listing = recursive_listdir(&Path::new(args[2].as_str())).
-            expect("Failed to list directory");
-        print!("Done!\n");
-
-        let mut copies = 0;
-        for (ii, filename) in listing.iter().enumerate() {
-            if let Ok(fsname) = get_file_path(filename) {
-                let fsname = Path::new(&fsname);
-
-                if !fsname.exists() {
-                    let dir = fsname.parent().unwrap();
-                    std::fs::create_dir_all(dir).unwrap();
-                    
-                    if let Err(_) = std::fs::copy(filename, fsname) {
-                        print!("Failed to copy file {:?}\n", filename);
-                    } else {
-                        copies += 1;
+    Some(manifest::Record {
+        kind:       manifest::Kind::Dbg,
+        pdb_name:   name,
+        pdb_path:   path,
+        guid:       String::new(),
+        age:        0,
+        source:     String::new(),
+        machine:    pe_header.machine,
+        timestamp:  pe_header.timestamp,
+        image_size: optional.size_of_image(),
+        version:    image.version.clone().and_then(|x| x.ok()),
+    })
+}
+
+/// Get the manifest lines for the PDB and `.dbg` file referenced by `file`
+/// in `wim`, the same as `parse_file` finds for a file on disk.
+///
+/// Only the first chunk of the file is decompressed unless it starts with an
+/// MZ header, so non-PE files are skipped cheaply.
+fn get_wim_pdbs(wim: &mut wim::Wim, file: &wim::WimFile) -> Vec<String>
+{
+    let size = wim.blob_size(&file.hash).unwrap_or(0);
+    if size < std::mem::size_of::<pe::MZHeader>() as u64 {
+        return Vec::new();
+    }
+
+    let is_pe = wim.read_blob(&file.hash, Some(2))
+        .is_ok_and(|x| x.starts_with(b"MZ"));
+    if !is_pe {
+        return Vec::new();
+    }
+
+    let data = match wim.read_blob(&file.hash, None) {
+        Ok(data) => data,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut stream = Cursor::new(data);
+    let parsed = pe::parse(&mut stream);
+    pdb_record(&parsed).ok().into_iter()
+        .chain(dbg_record(&parsed, &mut stream, &file.name))
+        .map(|x| x.symchk_line())
+        .collect()
+}
+
+fn download_worker(filename: PathBuf, sympath: String)
+{
+    let _ = Command::new("symchk").args(
+        ["/im", filename.to_str().unwrap(), "/s",
+        sympath.as_str()]).
+        output().expect("Failed to run command");
+}
+
+/// Download `entries` from the symbol server at `url` into the store at
+/// `root` without symchk, `chunk_size` entries per thread
+fn download_native(root: &Path, url: &str, entries: Vec<manifest::Entry>,
+                   chunk_size: usize)
+{
+    let failed = Arc::new(AtomicUsize::new(0));
+
+    let mut threads = Vec::new();
+    for chunk in entries.chunks(chunk_size) {
+        let chunk = chunk.to_vec();
+        let root = root.to_path_buf();
+        let url = url.to_string();
+        let failed = failed.clone();
+        threads.push(thread::spawn(move || {
+            let mut index = store::DirIndex::new();
+            for entry in &chunk {
+                match download::download(&mut index, &root, &url, entry) {
+                    Ok(Some(path)) => println!("Downloaded {:?}", path),
+                    Ok(None) => {}
+                    Err(err) => {
+                        println!("Failed to download {}: {}", entry.name, err);
+                        failed.fetch_add(1, Ordering::SeqCst);
                     }
                 }
             }
+        }));
+    }
 
-            if STATUS_MESSAGES {
-                print!("\rParsed {} of {} files ({} copies)",
-                    ii + 1, listing.len(), copies);
+    for thr in threads {
+        let _ = thr.join();
+    }
+
+    println!("{} of {} entries failed to download",
+             failed.load(Ordering::SeqCst), entries.len());
+}
+
+fn main()
+{
+    let args: Vec<String> = env::args().collect();
+
+    let it = Instant::now();
+
+    /* Print out usage information for anything which isn't a command */
+    match commands::find(&args[1..]) {
+        Some((command, args)) => {
+            match Options::parse(args, command.switches, command.valued) {
+                Ok(opts) if opts.args.len() < command.min_args ||
+                        opts.args.len() > command.max_args => {
+                    print!("{}", USAGE);
+                }
+                Ok(opts) => if let Err(err) = (command.run)(&opts) {
+                    println!("{}", err);
+                },
+                Err(err) => println!("{}", err),
             }
         }
-        print!("\n");
-
-    } else if args.len() == 2 && args[1] == "clean" {
-        /* Ignores all errors during clean */
-        let _ = std::fs::remove_dir_all("symbols");
-        let _ = std::fs::remove_dir_all("filestore");
-        let _ = std::fs::remove_file("manifest");
-    } else {
-        /* Print out usage information */
-        print!("{}", USAGE);
+        None => print!("{}", USAGE),
     }
 
-    print!("Time elapsed: {} seconds\n", it.elapsed().as_secs());
+    println!("Time elapsed: {} seconds", it.elapsed().as_secs());
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty scratch directory for the test `name`
+    fn scratch(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("pdblister-{}-{}", name,
+                                                     std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A 32-bit image keyed `5F00A0001000`, with one section whose raw
+    /// data ends the file. Sections are aligned like files.
+    fn image() -> Vec<u8>
+    {
+        let mut out = vec![0u8; 0x400];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            out[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(0, b"MZ");
+        put(0x3c, &0x40u32.to_le_bytes());
+        put(0x40, b"PE\0\0");
+        put(0x44, &pe::IMAGE_FILE_MACHINE_I386.to_le_bytes());
+        put(0x46, &1u16.to_le_bytes());
+        put(0x48, &0x5f00a000u32.to_le_bytes());
+        put(0x54, &(96u16 + 16 * 8).to_le_bytes());
+
+        let optional = 0x58;
+        put(optional, &0x10bu16.to_le_bytes());
+        put(optional + 32, &0x200u32.to_le_bytes());
+        put(optional + 36, &0x200u32.to_le_bytes());
+        put(optional + 56, &0x1000u32.to_le_bytes());
+        put(optional + 60, &0x200u32.to_le_bytes());
+        put(optional + 92, &16u32.to_le_bytes());
+
+        let section = optional + 96 + 16 * 8;
+        put(section, b".text\0\0\0");
+        put(section + 8, &0x200u32.to_le_bytes());
+        put(section + 12, &0x200u32.to_le_bytes());
+        put(section + 16, &0x200u32.to_le_bytes());
+        put(section + 20, &0x200u32.to_le_bytes());
+        out
+    }
+
+    /// Store `data` as `file` in the `ntdll.dll` entry keyed `key` and
+    /// verify it
+    fn verify(root: &Path, key: &str, file: &str, data: &[u8]) -> String
+    {
+        let dir = root.join("ntdll.dll").join(key);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(file), data).unwrap();
+
+        let entries = store::list_entries(root);
+        let entry = entries.iter().find(|x| x.path == dir.join(file))
+            .unwrap();
+        let res = match verify_entry(entry) {
+            Ok(()) => "ok".to_string(),
+            Err(Damage::Empty) => "empty".to_string(),
+            Err(Damage::Stray) => "stray".to_string(),
+            Err(Damage::Corrupt(err)) => format!("corrupt: {}", err),
+            Err(Damage::Misfiled(key)) => format!("misfiled: {}", key),
+        };
+        std::fs::remove_dir_all(root.join("ntdll.dll")).unwrap();
+        res
+    }
+
+    #[test]
+    fn verify_entries()
+    {
+        let root = scratch("verify");
+        let key = "5F00A0001000";
+        let data = image();
+        assert_eq!(verify(&root, key, "ntdll.dll", &data), "ok");
+        assert_eq!(verify(&root, &key.to_lowercase(), "NTDLL.DLL", &data),
+                   "ok");
+        assert_eq!(verify(&root, key, "ntdll.dll", &[]), "empty");
+        assert_eq!(verify(&root, key, "ntdll.dll", &data[..0x3ff]),
+                   "corrupt: Truncated, 1023 of 1024 bytes present");
+        assert!(verify(&root, key, "ntdll.dll", &data[..0x100])
+                .starts_with("corrupt: "));
+        assert_eq!(verify(&root, "5F00A0002000", "ntdll.dll", &data),
+                   "misfiled: 5f00a0001000");
+
+        /* Leftovers of interrupted copies and unrelated files */
+        let tmp = store::temp_path(Path::new("ntdll.dll"));
+        assert_eq!(verify(&root, key, tmp.to_str().unwrap(), &data), "stray");
+        assert_eq!(verify(&root, key, "kernel32.dll", &data), "stray");
+        assert_eq!(verify(&root, key, "file.ptr", b"MSDL"), "ok");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,602 @@
+//! Manifests of the PDBs referenced by scanned images.
+//!
+//! The default format is symchk's `<pdb name>,<guid><age>,1` lines. The
+//! JSON Lines and CSV formats carry everything else known about the image
+//! each PDB was found in, so downstream tools don't have to parse it again.
+//! JSON Lines also carries the version resource of the image.
+//!
+//! Existing symchk manifests are read back with `Manifest`, which validates
+//! every line so that garbage is never handed on to symchk. Manifests can
+//! then be merged or compared with each other.
+
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use json;
+use version::VersionInfo;
+use pe::{IMAGE_FILE_MACHINE_I386, IMAGE_FILE_MACHINE_IA64,
+         IMAGE_FILE_MACHINE_AMD64};
+
+/// A PDB, or a legacy `.dbg` file, referenced by an image
+#[derive(Clone)]
+pub struct Record {
+    /// Either `Kind::Pdb` or `Kind::Dbg`
+    pub kind: Kind,
+
+    /// File name of the PDB, eg. `ntdll.pdb`
+    pub pdb_name: String,
+
+    /// Full path of the PDB as recorded in the image
+    pub pdb_path: String,
+
+    /// GUID of the PDB as 32 uppercase hex digits, empty for `.dbg` files
+    pub guid: String,
+
+    /// Age of the PDB, zero for `.dbg` files
+    pub age: u32,
+
+    /// Path of the image the PDB was found in
+    pub source: String,
+
+    /// Machine type from the PE header
+    pub machine: u16,
+
+    /// Timestamp from the PE header
+    pub timestamp: u32,
+
+    /// Size of the image from the optional header
+    pub image_size: u32,
+
+    /// Version resource of the image, if it has one
+    pub version: Option<VersionInfo>,
+}
+
+/// Format manifests are written in
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    /// `<pdb name>,<guid><age>,1` as read by `symchk /im`
+    Symchk,
+
+    /// One JSON object per line
+    Jsonl,
+
+    /// Comma separated values with a header line
+    Csv,
+}
+
+/// Columns of the CSV format, also the keys of the JSON Lines format next to
+/// `version`
+const COLUMNS: &[&str] = &["source", "pdb_name", "pdb_path", "guid", "age",
+                           "pdb_key", "machine", "timestamp", "image_size",
+                           "file_key", "kind"];
+
+impl Format {
+    /// Parse a format as given on the command line
+    pub fn parse(name: &str) -> Option<Format>
+    {
+        match name {
+            "symchk" => Some(Format::Symchk),
+            "jsonl"  => Some(Format::Jsonl),
+            "csv"    => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    /// Name of the file manifests in this format are written to
+    pub fn file_name(self) -> &'static str
+    {
+        match self {
+            Format::Symchk => "manifest",
+            Format::Jsonl  => "manifest.jsonl",
+            Format::Csv    => "manifest.csv",
+        }
+    }
+}
+
+/// Get a printable name for a PE machine type
+pub fn machine_name(machine: u16) -> String
+{
+    match machine {
+        IMAGE_FILE_MACHINE_I386  => "I386".into(),
+        IMAGE_FILE_MACHINE_IA64  => "IA64".into(),
+        IMAGE_FILE_MACHINE_AMD64 => "AMD64".into(),
+        _ => format!("0x{:04x}", machine),
+    }
+}
+
+/// Quote a CSV field if it needs it
+fn csv_field(val: &str) -> String
+{
+    if val.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", val.replace('"', "\"\""))
+    } else {
+        val.to_string()
+    }
+}
+
+impl Record {
+    /// Key the PDB is stored under in a symbol store. `.dbg` files are
+    /// stored under the same key as their image.
+    pub fn pdb_key(&self) -> String
+    {
+        match self.kind {
+            Kind::Dbg => self.file_key(),
+            _ => format!("{}{:x}", self.guid, self.age),
+        }
+    }
+
+    /// Key the image is stored under in a symbol store
+    pub fn file_key(&self) -> String
+    {
+        format!("{:08x}{:x}", self.timestamp, self.image_size)
+    }
+
+    /// Format this as a symchk manifest line. This is the format string used
+    /// by symchk, originally in SymChkCheckFiles():
+    /// "%s,%08X%04X%04X%02X%02X%02X%02X%02X%02X%02X%02X%x,1"
+    pub fn symchk_line(&self) -> String
+    {
+        format!("{},{},1", self.pdb_name, self.pdb_key())
+    }
+
+    /// Values of `COLUMNS` for this record, as strings. The age of `.dbg`
+    /// files is left empty as they have none.
+    fn values(&self) -> Vec<String>
+    {
+        let (age, kind) = match self.kind {
+            Kind::Dbg => (String::new(), "dbg"),
+            _ => (self.age.to_string(), "pdb"),
+        };
+        vec![self.source.clone(), self.pdb_name.clone(), self.pdb_path.clone(),
+             self.guid.clone(), age, self.pdb_key(),
+             machine_name(self.machine), self.timestamp.to_string(),
+             self.image_size.to_string(), self.file_key(), kind.into()]
+    }
+
+    /// Format this as a JSON object
+    pub fn json(&self) -> String
+    {
+        let numeric = ["age", "timestamp", "image_size"];
+        let mut fields: Vec<(&str, String)> = COLUMNS.iter().zip(self.values())
+            .map(|(&key, val)| {
+                if numeric.contains(&key) && val.is_empty() {
+                    (key, "null".into())
+                } else if numeric.contains(&key) {
+                    (key, val)
+                } else {
+                    (key, json::quote(&val))
+                }
+            })
+            .collect();
+        fields.push(("version", self.version.as_ref()
+                         .map_or_else(|| "null".into(), |x| x.json())));
+        json::object(&fields)
+    }
+
+    /// Format this as a CSV line
+    pub fn csv_line(&self) -> String
+    {
+        let fields: Vec<String> = self.values().iter()
+            .map(|x| csv_field(x)).collect();
+        fields.join(",")
+    }
+}
+
+/// Format `records` as a whole manifest in `format`
+pub fn format(records: &[Record], format: Format) -> String
+{
+    match format {
+        /* symchk manifests have never had a trailing newline */
+        Format::Symchk => records.iter().map(|x| x.symchk_line())
+            .collect::<Vec<_>>().join("\n"),
+        Format::Jsonl => records.iter().map(|x| x.json() + "\n").collect(),
+        Format::Csv => {
+            let mut out = COLUMNS.join(",") + "\n";
+            for record in records {
+                out.push_str(&record.csv_line());
+                out.push('\n');
+            }
+            out
+        }
+    }
+}
+
+/// What a symchk manifest entry refers to
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Kind {
+    /// A PDB, keyed by GUID and age (type field `1`). Old NB10 PDBs are
+    /// keyed by their timestamp signature and age instead.
+    Pdb,
+
+    /// A DBG file, keyed by timestamp and image size (type field `1`, but
+    /// with a `.dbg` name)
+    Dbg,
+
+    /// A binary, keyed by timestamp and image size (type field `2`)
+    Binary,
+}
+
+impl Kind {
+    /// Kind of the store entry for a file called `name`, as far as can be
+    /// told from the name alone
+    pub fn from_name(name: &str) -> Kind
+    {
+        let name = name.to_lowercase();
+        if name.ends_with(".pdb") {
+            Kind::Pdb
+        } else if name.ends_with(".dbg") {
+            Kind::Dbg
+        } else {
+            Kind::Binary
+        }
+    }
+
+    /// Make sure `key` is a well formed store key for this kind of file
+    pub fn check_key(self, key: &str) -> Result<(), String>
+    {
+        if key.is_empty() || !key.chars().all(|x| x.is_ascii_hexdigit()) {
+            return Err(format!("key {:?} is not hexadecimal", key));
+        }
+        let valid = match self {
+            /* 32 digit GUID, or 8 digit NB10 signature, followed by the
+             * age */
+            Kind::Pdb => matches!(key.len(), 9..=16 | 33..=40),
+
+            /* 8 digit timestamp followed by the image size */
+            Kind::Dbg | Kind::Binary => key.len() > 8 && key.len() <= 16,
+        };
+        if !valid {
+            return Err(format!("key {:?} has the wrong length for a {:?}",
+                               key, self));
+        }
+        Ok(())
+    }
+}
+
+/// An entry of a symchk manifest
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Entry {
+    /// Name of the file as written in the manifest, which may be a full path
+    pub name: String,
+
+    /// Key the file is stored under in a symbol store
+    pub key: String,
+
+    pub kind: Kind,
+}
+
+/// A parsed symchk manifest
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+impl Entry {
+    /// Parse a single non-blank manifest line
+    fn parse(line: &str) -> Result<Entry, String>
+    {
+        /* Names may be paths containing commas, so split from the right */
+        let mut fields = line.rsplitn(3, ',');
+        let kind = fields.next().unwrap_or("").trim();
+        let (key, name) = match (fields.next(), fields.next()) {
+            (Some(key), Some(name)) => (key.trim(), name.trim()),
+            _ => return Err("expected <name>,<key>,<type>".into()),
+        };
+        if name.is_empty() {
+            return Err("empty file name".into());
+        }
+
+        let dbg = name.to_lowercase().ends_with(".dbg");
+        let kind = match kind {
+            "1" if dbg => Kind::Dbg,
+            "1" => Kind::Pdb,
+            "2" => Kind::Binary,
+            _ => return Err(format!("unknown type field {:?}", kind)),
+        };
+
+        kind.check_key(key)?;
+
+        Ok(Entry {
+            name: name.to_string(),
+            key:  key.to_string(),
+            kind,
+        })
+    }
+
+    /// Format this as a manifest line
+    pub fn line(&self) -> String
+    {
+        let kind = match self.kind {
+            Kind::Pdb | Kind::Dbg => 1,
+            Kind::Binary => 2,
+        };
+        format!("{},{},{}", self.name, self.key, kind)
+    }
+}
+
+impl Manifest {
+    /// Parse a manifest. Blank lines and CRLF line endings are fine, any
+    /// other malformed line is an error naming every bad line.
+    pub fn parse(text: &str) -> Result<Manifest, Box<dyn Error>>
+    {
+        let text = text.trim_start_matches('\u{feff}');
+
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+        for (ii, line) in text.split('\n').enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Entry::parse(line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => errors.push(format!("line {}: {}", ii + 1, err)),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors.join("\n").into());
+        }
+        Ok(Manifest { entries })
+    }
+
+    /// Read and parse the manifest at `path`
+    pub fn open(path: &Path) -> Result<Manifest, Box<dyn Error>>
+    {
+        let text = fs::read_to_string(path)
+            .map_err(|x| format!("{}: {}", path.display(), x))?;
+        Manifest::parse(&text).map_err(|x| {
+            format!("Invalid manifest {}:\n{}", path.display(), x).into()
+        })
+    }
+}
+
+/// Differences between two manifests
+pub struct Diff {
+    /// Entries only in the new manifest
+    pub added: Vec<Entry>,
+
+    /// Entries only in the old manifest
+    pub removed: Vec<Entry>,
+
+    /// Entries in both, as written in the new manifest
+    pub common: Vec<Entry>,
+}
+
+/// Changes to the entries sharing one file name
+#[derive(Default)]
+pub struct Group<'a> {
+    pub added:   Vec<&'a Entry>,
+    pub removed: Vec<&'a Entry>,
+    pub common:  Vec<&'a Entry>,
+}
+
+impl Entry {
+    /// File name of the entry, without any directory and lowercased. Names
+    /// are often full build paths, which differ between builds of the same
+    /// file.
+    pub fn file_name(&self) -> String
+    {
+        let name = self.name.rsplit(['\\', '/']).next().unwrap_or("");
+        name.to_lowercase()
+    }
+
+    /// What makes two entries refer to the same file in a symbol store
+    fn identity(&self) -> (String, String, Kind)
+    {
+        (self.file_name(), self.key.to_uppercase(), self.kind)
+    }
+}
+
+impl Manifest {
+    /// Merge `manifests` into one, keeping the first of any duplicate
+    /// entries
+    pub fn merge(manifests: &[Manifest]) -> Manifest
+    {
+        let mut seen = HashSet::new();
+        let entries = manifests.iter().flat_map(|x| x.entries.iter())
+            .filter(|x| seen.insert(x.identity()))
+            .cloned()
+            .collect();
+        Manifest { entries }
+    }
+
+    /// Get what changed going from `self` to `new`
+    pub fn diff(&self, new: &Manifest) -> Diff
+    {
+        let old_ids: HashSet<_> = self.entries.iter()
+            .map(|x| x.identity()).collect();
+        let new_ids: HashSet<_> = new.entries.iter()
+            .map(|x| x.identity()).collect();
+
+        let mut diff = Diff {
+            added:   Vec::new(),
+            removed: Vec::new(),
+            common:  Vec::new(),
+        };
+        for entry in &Manifest::merge(std::slice::from_ref(new)).entries {
+            if old_ids.contains(&entry.identity()) {
+                diff.common.push(entry.clone());
+            } else {
+                diff.added.push(entry.clone());
+            }
+        }
+        for entry in &Manifest::merge(std::slice::from_ref(self)).entries {
+            if !new_ids.contains(&entry.identity()) {
+                diff.removed.push(entry.clone());
+            }
+        }
+        diff
+    }
+
+    /// Format this as a symchk manifest
+    pub fn text(&self) -> String
+    {
+        let lines: Vec<String> = self.entries.iter().map(|x| x.line())
+            .collect();
+        lines.join("\n")
+    }
+}
+
+impl Diff {
+    /// Group the changes by file name
+    pub fn by_name(&self) -> BTreeMap<String, Group<'_>>
+    {
+        let mut groups: BTreeMap<String, Group> = BTreeMap::new();
+        for entry in &self.added {
+            groups.entry(entry.file_name()).or_default().added.push(entry);
+        }
+        for entry in &self.removed {
+            groups.entry(entry.file_name()).or_default().removed.push(entry);
+        }
+        for entry in &self.common {
+            groups.entry(entry.file_name()).or_default().common.push(entry);
+        }
+        groups
+    }
+
+    /// Format this as a JSON summary: totals, then the keys added, removed
+    /// and in common for every file name
+    pub fn json(&self) -> String
+    {
+        let keys = |entries: &[&Entry]| {
+            let keys: Vec<String> = entries.iter()
+                .map(|x| json::quote(&x.key)).collect();
+            json::array(&keys)
+        };
+
+        let groups = self.by_name();
+        let names: Vec<(&str, String)> = groups.iter()
+            .map(|(name, group)| {
+                (name.as_str(), json::object(&[
+                    ("added",   keys(&group.added)),
+                    ("removed", keys(&group.removed)),
+                    ("common",  keys(&group.common)),
+                ]))
+            })
+            .collect();
+
+        json::object(&[
+            ("added",   self.added.len().to_string()),
+            ("removed", self.removed.len().to_string()),
+            ("common",  self.common.len().to_string()),
+            ("names",   json::object(&names)),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_entries()
+    {
+        let text = "\u{feff}c:\\build,x64\\ntdll.pdb,\
+                    0123456789ABCDEF0123456789abcdef1,1\r\n\
+                    \r\n\
+                    ntdll.dll , 1234ABCD5000 , 2\n\
+                    ntdll.dbg,1234abcd5000,1\n\
+                    old.pdb,3A2B1C0D2,1\n  \n";
+        let manifest = Manifest::parse(text).unwrap();
+        let got: Vec<(&str, &str, Kind)> = manifest.entries.iter()
+            .map(|x| (x.name.as_str(), x.key.as_str(), x.kind)).collect();
+        assert_eq!(got, [
+            ("c:\\build,x64\\ntdll.pdb",
+             "0123456789ABCDEF0123456789abcdef1", Kind::Pdb),
+            ("ntdll.dll", "1234ABCD5000", Kind::Binary),
+            ("ntdll.dbg", "1234abcd5000", Kind::Dbg),
+            ("old.pdb", "3A2B1C0D2", Kind::Pdb),
+        ]);
+        assert_eq!(manifest.entries[0].file_name(), "ntdll.pdb");
+        assert_eq!(Manifest::parse(&manifest.text()).unwrap().entries,
+                   manifest.entries);
+    }
+
+    #[test]
+    fn rejects_bad_lines()
+    {
+        let text = "ntdll.dll,1234ABCD5000,2\n\
+                    ntdll.dll,1234ABCD5000\n\
+                    ntdll.dll,1234ABCD5000,3\n\
+                    \n\
+                    ,1234ABCD5000,2\n\
+                    ntdll.dll,1234ABCDZ000,2\n\
+                    ntdll.dll,1234ABCD,2\n\
+                    ntdll.pdb,0123456789ABCDEF0123456789ABCDEF,1\n\
+                    ntdll.pdb,0123456789ABCDEF0123456789ABCDEF1,1\n";
+        let err = Manifest::parse(text).err().unwrap().to_string();
+        let lines: Vec<&str> = err.lines()
+            .map(|x| x.split(':').next().unwrap()).collect();
+        assert_eq!(lines, ["line 2", "line 3", "line 5", "line 6", "line 7",
+                           "line 8"]);
+    }
+
+    #[test]
+    fn key_lengths()
+    {
+        let valid = |line: &str| Manifest::parse(line).is_ok();
+        let guid = "0123456789ABCDEF0123456789ABCDEF";
+        assert!(valid(&format!("a.pdb,{}1,1", guid)));
+        assert!(valid(&format!("a.pdb,{}FFFFFFFF,1", guid)));
+        assert!(!valid(&format!("a.pdb,{},1", guid)));
+        assert!(!valid(&format!("a.pdb,{}FFFFFFFFF,1", guid)));
+        assert!(valid("a.pdb,3A2B1C0D1,1"));
+        assert!(valid("a.pdb,3A2B1C0DFFFFFFFF,1"));
+        assert!(!valid("a.pdb,3A2B1C0D,1"));
+        assert!(!valid("a.pdb,3A2B1C0DFFFFFFFFF,1"));
+        assert!(valid("a.dll,1234ABCD1,2"));
+        assert!(valid("a.dll,1234ABCD12345678,2"));
+        assert!(!valid("a.dll,1234ABCD123456789,2"));
+        assert!(!valid("a.dbg,,1"));
+        assert!(!valid("a.dbg,+234ABCD1,1"));
+    }
+
+    #[test]
+    fn merge_and_diff()
+    {
+        let old = Manifest::parse("a\\ntdll.dll,1234ABCD5000,2\n\
+                                   ntdll.exe,3A2B1C0D2,2\n\
+                                   gone.dll,11111111100,2\n").unwrap();
+        let new = Manifest::parse("b\\NTDLL.DLL,1234abcd5000,2\n\
+                                   ntdll.exe,3A2B1C0D3,2\n\
+                                   ntdll.exe,3A2B1C0D3,2\n\
+                                   ntdll.dbg,1234ABCD5000,1\n").unwrap();
+
+        /* Same file name and key, ignoring case and directories */
+        let merged = Manifest::merge(&[old, new]);
+        let names: Vec<&str> = merged.entries.iter()
+            .map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["a\\ntdll.dll", "ntdll.exe", "gone.dll",
+                           "ntdll.exe", "ntdll.dbg"]);
+
+        let old = Manifest { entries: merged.entries[..3].to_vec() };
+        let new = Manifest { entries: merged.entries[3..].to_vec() };
+        let new = Manifest::merge(&[new, Manifest {
+            entries: vec![old.entries[0].clone()],
+        }]);
+        let diff = old.diff(&new);
+        let keys = |x: &[Entry]| -> Vec<String> {
+            x.iter().map(|x| x.key.clone()).collect()
+        };
+        assert_eq!(keys(&diff.added), ["3A2B1C0D3", "1234ABCD5000"]);
+        assert_eq!(keys(&diff.removed), ["3A2B1C0D2", "11111111100"]);
+        assert_eq!(keys(&diff.common), ["1234ABCD5000"]);
+
+        let groups = diff.by_name();
+        let names: Vec<&str> = groups.keys().map(|x| x.as_str()).collect();
+        assert_eq!(names, ["gone.dll", "ntdll.dbg", "ntdll.dll",
+                           "ntdll.exe"]);
+        assert_eq!(diff.json(), "{\"added\":2,\"removed\":2,\"common\":1,\
+                    \"names\":{\
+                    \"gone.dll\":{\"added\":[],\"removed\":[\"11111111100\"],\
+                    \"common\":[]},\
+                    \"ntdll.dbg\":{\"added\":[\"1234ABCD5000\"],\"removed\":[],\
+                    \"common\":[]},\
+                    \"ntdll.dll\":{\"added\":[],\"removed\":[],\
+                    \"common\":[\"1234ABCD5000\"]},\
+                    \"ntdll.exe\":{\"added\":[\"3A2B1C0D3\"],\
+                    \"removed\":[\"3A2B1C0D2\"],\"common\":[]}}}");
+    }
+}
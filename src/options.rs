@@ -0,0 +1,129 @@
+//! Command line options shared by every command.
+//!
+//! Commands take positional arguments and `--name` options in any order.
+//! Options are either switches, or take the argument following them as their
+//! value. Options with a value may be given more than once.
+
+/// The arguments of a command, split into positional arguments and options
+pub struct Options<'a> {
+    /// Arguments which are not options, in order
+    pub args: Vec<&'a str>,
+
+    switches: Vec<&'a str>,
+    values:   Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Options<'a> {
+    /// Split `args` up given the names of the options which are `switches`
+    /// and of those which take a value. Unknown options and options missing
+    /// their value are errors.
+    pub fn parse(args: &'a [String], switches: &[&str], valued: &[&str]) ->
+        Result<Options<'a>, String>
+    {
+        let mut opts = Options {
+            args:     Vec::new(),
+            switches: Vec::new(),
+            values:   Vec::new(),
+        };
+
+        let mut args = args.iter().map(|x| x.as_str());
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                opts.args.push(arg);
+            } else if switches.contains(&arg) {
+                opts.switches.push(arg);
+            } else if valued.contains(&arg) {
+                match args.next() {
+                    Some(val) => opts.values.push((arg, val)),
+                    None => return Err(format!("{} needs a value", arg)),
+                }
+            } else {
+                return Err(format!("Unknown option {}", arg));
+            }
+        }
+        Ok(opts)
+    }
+
+    /// Whether the switch `name` was given
+    pub fn switch(&self, name: &str) -> bool
+    {
+        self.switches.contains(&name)
+    }
+
+    /// Value of the option `name`, the last one if it was given more than
+    /// once
+    pub fn value(&self, name: &str) -> Option<&'a str>
+    {
+        self.values.iter().rev().find(|x| x.0 == name).map(|x| x.1)
+    }
+
+    /// Every value given for the option `name`, in order
+    pub fn values(&self, name: &str) -> Vec<&'a str>
+    {
+        self.values.iter().filter(|x| x.0 == name).map(|x| x.1).collect()
+    }
+
+    /// Which of the switches `names`, which can't be combined, was given.
+    /// Giving more than one of them is an error.
+    pub fn one_of(&self, names: &[&'static str]) ->
+        Result<Option<&'static str>, String>
+    {
+        let given: Vec<&'static str> = names.iter().cloned()
+            .filter(|x| self.switch(x)).collect();
+        if given.len() > 1 {
+            return Err(format!("{} can't be used together",
+                               given.join(" and ")));
+        }
+        Ok(given.first().cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String>
+    {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn switches_values_and_arguments()
+    {
+        let args = args(&["a", "--path", "x", "--json", "b", "--path",
+                          "--json", "--tree", "t", "--tree", "u"]);
+        let opts = Options::parse(&args, &["--json", "--dot"],
+                                  &["--path", "--tree"]).unwrap();
+        assert_eq!(opts.args, ["a", "b"]);
+        assert!(opts.switch("--json") && !opts.switch("--dot"));
+        assert_eq!(opts.values("--path"), ["x", "--json"]);
+        assert_eq!(opts.value("--tree"), Some("u"));
+        assert_eq!(opts.value("--json"), None);
+        assert!(opts.values("--dot").is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_options()
+    {
+        let err = |list: &[&str]| {
+            Options::parse(&args(list), &["--json"], &["--path"]).err()
+        };
+        assert_eq!(err(&["a", "--dot"]).unwrap(), "Unknown option --dot");
+        assert_eq!(err(&["--path"]).unwrap(), "--path needs a value");
+        assert_eq!(err(&["-x", "--json"]), None);
+    }
+
+    #[test]
+    fn exclusive_switches()
+    {
+        let names = ["--added", "--removed", "--common"];
+        let one_of = |list: &[&str]| {
+            let args = args(list);
+            Options::parse(&args, &names, &[]).unwrap().one_of(&names)
+        };
+        assert_eq!(one_of(&["a"]), Ok(None));
+        assert_eq!(one_of(&["--common", "--common"]), Ok(Some("--common")));
+        assert_eq!(one_of(&["--common", "--added"]).unwrap_err(),
+                   "--added and --common can't be used together");
+    }
+}
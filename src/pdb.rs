@@ -0,0 +1,292 @@
+//! Minimal reader for PDB (MSF 7.00) files, just enough to get the GUID and
+//! age a PDB is indexed by in a symbol store.
+
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+
+use pe::read_struct;
+
+/// Magic at the start of every MSF 7.00 file
+const MSF_MAGIC: &[u8; 32] = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0";
+
+/// Stream holding the PDB GUID and age
+const PDB_INFO_STREAM: usize = 1;
+
+/// Stream holding the debug info header, whose age is the one matched
+/// against the CodeView record of an image
+const DBI_STREAM: usize = 3;
+
+/// Size used by the stream directory for deleted streams
+const NIL_STREAM_SIZE: u32 = 0xffffffff;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SuperBlock {
+    magic:            [u8; 32],
+    block_size:       u32,
+    free_block_map:   u32,
+    num_blocks:       u32,
+    num_dir_bytes:    u32,
+    unknown:          u32,
+    block_map_addr:   u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct PdbInfoHeader {
+    version:   u32,
+    signature: u32,
+    age:       u32,
+    guid_a:    u32,
+    guid_b:    u16,
+    guid_c:    u16,
+    guid_d:    [u8; 8],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct DbiHeader {
+    version_signature: i32,
+    version_header:    u32,
+    age:               u32,
+}
+
+/// Identity of a PDB
+pub struct PdbInfo {
+    pub guid_a: u32,
+    pub guid_b: u16,
+    pub guid_c: u16,
+    pub guid_d: [u8; 8],
+    pub age:    u32,
+}
+
+impl PdbInfo {
+    /// Key this PDB is stored under in a symbol store, the same GUID and age
+    /// format used in manifests
+    pub fn key(&self) -> String
+    {
+        format!("{:08X}{:04X}{:04X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:x}",
+                self.guid_a, self.guid_b, self.guid_c,
+                self.guid_d[0], self.guid_d[1], self.guid_d[2],
+                self.guid_d[3], self.guid_d[4], self.guid_d[5],
+                self.guid_d[6], self.guid_d[7], self.age)
+    }
+}
+
+/// Check whether `magic` (the first bytes of a file) is an MSF 7.00 header
+pub fn is_pdb(magic: &[u8]) -> bool
+{
+    magic.starts_with(&MSF_MAGIC[..])
+}
+
+/// Read `size` bytes laid out over `blocks` of `block_size` bytes each
+fn read_blocks<R: Read + Seek>(fd: &mut R, block_size: u32, blocks: &[u32],
+                               size: usize) -> Result<Vec<u8>, Box<dyn Error>>
+{
+    if blocks.len() < size.div_ceil(block_size as usize) {
+        return Err("MSF block list truncated".into());
+    }
+
+    let mut data = vec![0u8; size];
+    for (chunk, &block) in data.chunks_mut(block_size as usize).zip(blocks) {
+        fd.seek(SeekFrom::Start(block as u64 * block_size as u64))?;
+        fd.read_exact(chunk)?;
+    }
+    Ok(data)
+}
+
+/// Read a little endian u32 from `data` at `offset`
+fn le32(data: &[u8], offset: usize) -> Result<u32, Box<dyn Error>>
+{
+    let bytes = data.get(offset..offset + 4)
+        .ok_or("MSF stream directory truncated")?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Read the streams `wanted` out of the MSF file in `fd`. Streams which do
+/// not exist come back empty.
+fn read_streams<R: Read + Seek>(fd: &mut R, wanted: &[usize]) ->
+    Result<Vec<Vec<u8>>, Box<dyn Error>>
+{
+    fd.seek(SeekFrom::Start(0))?;
+    let sb: SuperBlock = unsafe { read_struct(fd)? };
+    if !is_pdb(&sb.magic) {
+        return Err("No MSF 7.00 header present".into());
+    }
+
+    let block_size = sb.block_size;
+    if !matches!(block_size, 512 | 1024 | 2048 | 4096 | 8192 | 16384 |
+                 32768) {
+        return Err("Invalid MSF block size".into());
+    }
+
+    let file_size = fd.seek(SeekFrom::End(0))?;
+    if file_size < sb.num_blocks as u64 * block_size as u64 {
+        return Err("MSF file is truncated".into());
+    }
+
+    /* The block map lists the blocks holding the stream directory */
+    let dir_blocks = (sb.num_dir_bytes as usize)
+        .div_ceil(block_size as usize);
+    if dir_blocks * 4 > block_size as usize {
+        return Err("MSF stream directory too large".into());
+    }
+    let map = read_blocks(fd, block_size, &[sb.block_map_addr],
+                          dir_blocks * 4)?;
+    let map: Vec<u32> = map.chunks_exact(4)
+        .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect();
+    let dir = read_blocks(fd, block_size, &map, sb.num_dir_bytes as usize)?;
+
+    /* Directory is the stream count, every stream's size, then every
+     * stream's block list */
+    let num_streams = le32(&dir, 0)? as usize;
+    if num_streams > (dir.len() - 4) / 4 {
+        return Err("MSF stream directory truncated".into());
+    }
+    let mut sizes = Vec::with_capacity(num_streams);
+    for ii in 0..num_streams {
+        sizes.push(le32(&dir, 4 + ii * 4)?);
+    }
+
+    let mut offset = 4 + num_streams * 4;
+    let mut streams = vec![Vec::new(); wanted.len()];
+    for (ii, &size) in sizes.iter().enumerate() {
+        let size = if size == NIL_STREAM_SIZE { 0 } else { size as usize };
+        let num_blocks = size.div_ceil(block_size as usize);
+
+        if let Some(idx) = wanted.iter().position(|&x| x == ii) {
+            let mut blocks = Vec::with_capacity(num_blocks);
+            for blk in 0..num_blocks {
+                blocks.push(le32(&dir, offset + blk * 4)?);
+            }
+            streams[idx] = read_blocks(fd, block_size, &blocks, size)?;
+        }
+
+        offset += num_blocks * 4;
+    }
+
+    Ok(streams)
+}
+
+/// Get the GUID and age of the PDB in `fd`
+pub fn get_pdb_info<R: Read + Seek>(fd: &mut R) ->
+    Result<PdbInfo, Box<dyn Error>>
+{
+    let streams = read_streams(fd, &[PDB_INFO_STREAM, DBI_STREAM])?;
+
+    if streams[0].len() < std::mem::size_of::<PdbInfoHeader>() {
+        return Err("PDB info stream missing or truncated".into());
+    }
+    let info: PdbInfoHeader = unsafe { read_struct(&mut &streams[0][..])? };
+
+    /* Prefer the DBI age, as that is what images reference. The info
+     * stream age can be bumped without the DBI stream changing. */
+    let age = if streams[1].len() >= std::mem::size_of::<DbiHeader>() {
+        let dbi: DbiHeader = unsafe { read_struct(&mut &streams[1][..])? };
+        dbi.age
+    } else {
+        info.age
+    };
+
+    Ok(PdbInfo {
+        guid_a: info.guid_a,
+        guid_b: info.guid_b,
+        guid_c: info.guid_c,
+        guid_d: info.guid_d,
+        age,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const BLOCK: usize = 512;
+
+    /// Build an MSF file whose stream directory is `dir`, placed in block 4
+    /// with the block map in block 3. Block 5 holds a PDB info stream with
+    /// an age of 2 and block 6 a DBI stream with an age of 5.
+    fn msf(dir: &[u32]) -> Vec<u8>
+    {
+        let mut file = vec![0u8; BLOCK * 7];
+        let dir_size = dir.len() as u32 * 4;
+        let header = [BLOCK as u32, 1, 7, dir_size, 0, 3];
+        file[..32].copy_from_slice(MSF_MAGIC);
+        for (ii, val) in header.iter().enumerate() {
+            file[32 + ii * 4..36 + ii * 4].copy_from_slice(&val.to_le_bytes());
+        }
+        file[BLOCK * 3..BLOCK * 3 + 4].copy_from_slice(&4u32.to_le_bytes());
+        for (ii, val) in dir.iter().enumerate() {
+            let off = BLOCK * 4 + ii * 4;
+            file[off..off + 4].copy_from_slice(&val.to_le_bytes());
+        }
+
+        let info = &mut file[BLOCK * 5..];
+        info[..4].copy_from_slice(&20000404u32.to_le_bytes());
+        info[8..12].copy_from_slice(&2u32.to_le_bytes());
+        for (ii, byte) in info[12..28].iter_mut().enumerate() {
+            *byte = ii as u8;
+        }
+
+        let dbi = &mut file[BLOCK * 6..];
+        dbi[..4].copy_from_slice(&(-1i32).to_le_bytes());
+        dbi[8..12].copy_from_slice(&5u32.to_le_bytes());
+        file
+    }
+
+    /// Directory of four streams, the info and DBI streams in blocks 5
+    /// and 6 and the others empty
+    const DIR: [u32; 7] = [4, 0, 28, NIL_STREAM_SIZE, 12, 5, 6];
+
+    #[test]
+    fn guid_and_dbi_age()
+    {
+        let info = get_pdb_info(&mut Cursor::new(msf(&DIR))).unwrap();
+        assert_eq!(info.key(), "030201000504070608090A0B0C0D0E0F5");
+
+        /* Without a DBI stream the age of the info stream is used */
+        let info = get_pdb_info(&mut Cursor::new(msf(&[2, 0, 28, 5])))
+            .unwrap();
+        assert_eq!(info.key(), "030201000504070608090A0B0C0D0E0F2");
+    }
+
+    #[test]
+    fn rejects_bad_files()
+    {
+        let mut file = msf(&DIR);
+        file[0] = b'm';
+        assert!(get_pdb_info(&mut Cursor::new(file)).is_err());
+
+        /* Block size */
+        let mut file = msf(&DIR);
+        file[32] = 1;
+        assert!(get_pdb_info(&mut Cursor::new(file)).is_err());
+
+        /* Fewer blocks than the superblock says */
+        let file = msf(&DIR);
+        assert!(get_pdb_info(&mut Cursor::new(&file[..BLOCK * 6])).is_err());
+
+        /* Missing info stream */
+        assert!(get_pdb_info(&mut Cursor::new(msf(&[1, 0]))).is_err());
+
+        /* More streams than the directory has room for */
+        assert!(get_pdb_info(&mut Cursor::new(msf(&[0x4000_0000, 0])))
+                .is_err());
+
+        /* Block lists running off the end of the directory */
+        assert!(get_pdb_info(&mut Cursor::new(msf(&[2, 0, 28]))).is_err());
+    }
+
+    #[test]
+    fn short_block_list()
+    {
+        let file = msf(&DIR);
+        let mut fd = Cursor::new(&file);
+        assert_eq!(read_blocks(&mut fd, BLOCK as u32, &[5], 28).unwrap()
+                   .len(), 28);
+        assert!(read_blocks(&mut fd, BLOCK as u32, &[5], BLOCK + 1).is_err());
+        assert!(read_blocks(&mut fd, BLOCK as u32, &[], 1).is_err());
+    }
+}
@@ -0,0 +1,1290 @@
+//! Walking the headers and tables of a PE image.
+//!
+//! `parse` reads everything pdblister looks at in an image, in the order it
+//! looks at it, and keeps the reason it had to stop. Finding the PDB of an
+//! image and `pdblister info` are both built on it, so what `info` reports
+//! is exactly what happens while scanning.
+//!
+//! Debug directory entries other than CodeView are only decoded when asked
+//! for with `PeFile::decode_debug_dirs`, as scanning has no use for them.
+//! The version resource, the Authenticode signature, the import and export
+//! tables and the Rich header are likewise read with
+//! `PeFile::decode_version`, `PeFile::decode_signature`,
+//! `PeFile::decode_imports` and `PeFile::decode_rich`.
+
+use std::error::Error;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use version::{self, VersionInfo};
+use authenticode::{self, Signature};
+use imports::{self, Import, Exports};
+use rich::{self, RichHeader};
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct MZHeader {
+    pub signature:       [u8; 2],
+    pub last_page_bytes: u16,
+    pub num_pages:       u16,
+    pub num_relocations: u16,
+    pub header_size:     u16,
+    pub min_memory:      u16,
+    pub max_memory:      u16,
+    pub initial_ss:      u16,
+    pub initial_sp:      u16,
+    pub checksum:        u16,
+    pub entry:           u32,
+    pub ptr_relocation:  u16,
+    pub overlay:         u16,
+    pub reserved:        [u8; 32],
+    pub new_header:      u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct PEHeader {
+    pub signature:            [u8; 4],
+    pub machine:              u16,
+    pub num_sections:         u16,
+    pub timestamp:            u32,
+    pub ptr_symtable:         u32,
+    pub num_smtable:          u32,
+    pub optional_header_size: u16,
+    pub characteristics:      u16,
+}
+
+pub const IMAGE_FILE_MACHINE_I386:  u16 = 0x014c;
+pub const IMAGE_FILE_MACHINE_IA64:  u16 = 0x0200;
+pub const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct WindowsPEHeader32 {
+    pub magic:                      u16,
+    pub linker_major_version:       u8,
+    pub linker_minor_version:       u8,
+    pub size_of_code:               u32,
+    pub size_of_initialized_data:   u32,
+    pub size_of_uninitialized_data: u32,
+    pub entry:                      u32,
+    pub code_base:                  u32,
+    pub data_base:                  u32,
+    pub image_base:                 u32,
+    pub section_align:              u32,
+    pub file_align:                 u32,
+    pub major_os_version:           u16,
+    pub minor_os_version:           u16,
+    pub major_image_version:        u16,
+    pub minor_image_version:        u16,
+    pub major_subsystem_version:    u16,
+    pub minor_subsystem_version:    u16,
+    pub win32_version:              u32,
+    pub size_of_image:              u32,
+    pub size_of_headers:            u32,
+    pub checksum:                   u32,
+    pub subsystem:                  u16,
+    pub dll_characteristics:        u16,
+    pub size_of_stack_reserve:      u32,
+    pub size_of_stack_commit:       u32,
+    pub size_of_heap_reserve:       u32,
+    pub size_of_heap_commit:        u32,
+    pub loader_flags:               u32,
+    pub num_tables:                 u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct WindowsPEHeader64 {
+    pub magic:                      u16,
+    pub linker_major_version:       u8,
+    pub linker_minor_version:       u8,
+    pub size_of_code:               u32,
+    pub size_of_initialized_data:   u32,
+    pub size_of_uninitialized_data: u32,
+    pub entry:                      u32,
+    pub code_base:                  u32,
+    pub image_base:                 u64,
+    pub section_align:              u32,
+    pub file_align:                 u32,
+    pub major_os_version:           u16,
+    pub minor_os_version:           u16,
+    pub major_image_version:        u16,
+    pub minor_image_version:        u16,
+    pub major_subsystem_version:    u16,
+    pub minor_subsystem_version:    u16,
+    pub win32_version:              u32,
+    pub size_of_image:              u32,
+    pub size_of_headers:            u32,
+    pub checksum:                   u32,
+    pub subsystem:                  u16,
+    pub dll_characteristics:        u16,
+    pub size_of_stack_reserve:      u64,
+    pub size_of_stack_commit:       u64,
+    pub size_of_heap_reserve:       u64,
+    pub size_of_heap_commit:        u64,
+    pub loader_flags:               u32,
+    pub num_tables:                 u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ImageDataDirectory {
+    pub vaddr: u32,
+    pub size:  u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ImageSectionHeader {
+    pub name:                    [u8; 8],
+    pub vsize:                   u32,
+    pub vaddr:                   u32,
+    pub raw_data_size:           u32,
+    pub pointer_to_raw_data:     u32,
+    pub pointer_to_relocations:  u32,
+    pub pointer_to_line_numbers: u32,
+    pub number_of_relocations:   u16,
+    pub number_of_line_numbers:  u16,
+    pub characteristics:         u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ImageDebugDirectory {
+    pub characteristics:      u32,
+    pub timestamp:            u32,
+    pub major_version:        u16,
+    pub minor_version:        u16,
+    pub typ:                  u32,
+    pub size_of_data:         u32,
+    pub address_of_raw_data:  u32,
+    pub pointer_to_raw_data:  u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct CodeviewEntry {
+    pub signature: [u8; 4], // RSDS
+    pub guid_a:    u32,
+    pub guid_b:    u16,
+    pub guid_c:    u16,
+    pub guid_d:    [u8; 8],
+    pub age:       u32,
+}
+
+/// Read a structure from a file stream, directly interpreting the raw bytes
+/// of the file as T.
+///
+/// User must make sure the shape of the structure `T` is safe to use in this
+/// way, hence being unsafe.
+pub unsafe fn read_struct<T: Copy, R: Read>(fd: &mut R) -> io::Result<T>
+{
+    let mut ret: T = std::mem::zeroed();
+    fd.read_exact(std::slice::from_raw_parts_mut(
+            &mut ret as *mut _ as *mut u8,
+            std::mem::size_of_val(&ret)))?;
+    Ok(ret)
+}
+
+/// Implementation mimicing #![feature(range_contains)] for those stable rust
+/// users.
+fn contains(range: &std::ops::Range<u32>, item: u32) -> bool
+{
+    (range.start <= item) && (item < range.end)
+}
+
+const IMAGE_DEBUG_TYPE_CODEVIEW:              u32 = 2;
+const IMAGE_DEBUG_TYPE_FPO:                   u32 = 3;
+const IMAGE_DEBUG_TYPE_MISC:                  u32 = 4;
+const IMAGE_DEBUG_TYPE_VC_FEATURE:            u32 = 12;
+const IMAGE_DEBUG_TYPE_POGO:                  u32 = 13;
+const IMAGE_DEBUG_TYPE_ILTCG:                 u32 = 14;
+const IMAGE_DEBUG_TYPE_MPX:                   u32 = 15;
+const IMAGE_DEBUG_TYPE_REPRO:                 u32 = 16;
+const IMAGE_DEBUG_TYPE_EX_DLLCHARACTERISTICS: u32 = 20;
+
+/// MISC data type naming the `.dbg` file
+const IMAGE_DEBUG_MISC_EXENAME: u32 = 1;
+
+/// Index of the debug directory among the data directories
+const DEBUG_DIRECTORY: usize = 6;
+
+/// Names of the data directories, by index
+pub const DATA_DIRECTORY_NAMES: [&str; 16] = [
+    "EXPORT", "IMPORT", "RESOURCE", "EXCEPTION", "SECURITY", "BASERELOC",
+    "DEBUG", "ARCHITECTURE", "GLOBALPTR", "TLS", "LOAD_CONFIG",
+    "BOUND_IMPORT", "IAT", "DELAY_IMPORT", "COM_DESCRIPTOR", "RESERVED",
+];
+
+/// Get the name of a debug directory type
+pub fn debug_type_name(typ: u32) -> &'static str
+{
+    match typ {
+        0  => "UNKNOWN",
+        1  => "COFF",
+        2  => "CODEVIEW",
+        3  => "FPO",
+        4  => "MISC",
+        5  => "EXCEPTION",
+        6  => "FIXUP",
+        7  => "OMAP_TO_SRC",
+        8  => "OMAP_FROM_SRC",
+        9  => "BORLAND",
+        10 => "RESERVED10",
+        11 => "CLSID",
+        12 => "VC_FEATURE",
+        13 => "POGO",
+        14 => "ILTCG",
+        15 => "MPX",
+        16 => "REPRO",
+        17 => "EMBEDDED_PORTABLE_PDB",
+        18 => "SPGO",
+        19 => "PDBCHECKSUM",
+        20 => "EX_DLLCHARACTERISTICS",
+        _  => "?",
+    }
+}
+
+/// The optional header, which depends on the bitness of the image
+#[derive(Clone, Copy)]
+pub enum OptionalHeader {
+    Pe32(WindowsPEHeader32),
+    Pe64(WindowsPEHeader64),
+}
+
+impl OptionalHeader {
+    pub fn image_base(&self) -> u64
+    {
+        match self {
+            OptionalHeader::Pe32(hdr) => hdr.image_base as u64,
+            OptionalHeader::Pe64(hdr) => hdr.image_base,
+        }
+    }
+
+    pub fn size_of_image(&self) -> u32
+    {
+        match self {
+            OptionalHeader::Pe32(hdr) => hdr.size_of_image,
+            OptionalHeader::Pe64(hdr) => hdr.size_of_image,
+        }
+    }
+
+    pub fn num_tables(&self) -> u32
+    {
+        match self {
+            OptionalHeader::Pe32(hdr) => hdr.num_tables,
+            OptionalHeader::Pe64(hdr) => hdr.num_tables,
+        }
+    }
+
+    pub fn section_align(&self) -> u32
+    {
+        match self {
+            OptionalHeader::Pe32(hdr) => hdr.section_align,
+            OptionalHeader::Pe64(hdr) => hdr.section_align,
+        }
+    }
+
+    pub fn file_align(&self) -> u32
+    {
+        match self {
+            OptionalHeader::Pe32(hdr) => hdr.file_align,
+            OptionalHeader::Pe64(hdr) => hdr.file_align,
+        }
+    }
+
+    pub fn size_of_headers(&self) -> u32
+    {
+        match self {
+            OptionalHeader::Pe32(hdr) => hdr.size_of_headers,
+            OptionalHeader::Pe64(hdr) => hdr.size_of_headers,
+        }
+    }
+}
+
+/// Round `val` up to a multiple of `align`, which need not be a power of two
+fn align_up(val: u32, align: u32) -> u32
+{
+    if align == 0 {
+        return val;
+    }
+    val.div_ceil(align).saturating_mul(align)
+}
+
+/// A decoded RSDS CodeView record
+#[derive(Clone)]
+pub struct Codeview {
+    /// GUID of the PDB as 32 uppercase hex digits
+    pub guid: String,
+
+    pub age: u32,
+
+    /// Path of the PDB as recorded by the linker
+    pub path: String,
+
+    /// File name component of `path`
+    pub name: String,
+}
+
+/// An FPO_DATA record, describing the frame of a function which does not use
+/// a frame pointer
+#[derive(Clone, Copy)]
+pub struct Fpo {
+    /// RVA of the function
+    pub start: u32,
+
+    pub proc_size: u32,
+
+    /// Size of the locals, in dwords
+    pub locals: u32,
+
+    /// Size of the parameters, in dwords
+    pub params: u16,
+
+    /// Bytes of prologue code
+    pub prolog: u8,
+
+    /// Number of registers saved
+    pub regs: u8,
+
+    pub has_seh: bool,
+
+    /// Whether EBP has been allocated as a general register
+    pub use_bp: bool,
+
+    /// FRAME_FPO, FRAME_TRAP, FRAME_TSS or FRAME_NONFPO
+    pub frame: u8,
+}
+
+/// A POGO (profile guided optimization) record, one for every contribution
+/// of the linker to the image
+#[derive(Clone)]
+pub struct PogoEntry {
+    pub rva:  u32,
+    pub size: u32,
+    pub name: String,
+}
+
+/// The counters of a VC_FEATURE record
+#[derive(Clone, Copy)]
+pub struct VcFeature {
+    /// Objects built by compilers older than VC++ 11.00
+    pub pre_vc11: u32,
+
+    /// Objects built with C/C++
+    pub c_cpp: u32,
+
+    /// Functions protected with /GS
+    pub gs: u32,
+
+    /// Objects built with /sdl
+    pub sdl: u32,
+
+    /// Functions built with /guard:cf
+    pub guard_n: u32,
+}
+
+/// Decoded contents of a debug directory entry
+#[derive(Clone)]
+pub enum DebugData {
+    Codeview(Codeview),
+
+    /// IMAGE_DEBUG_MISC, naming the .DBG file symbols were split into
+    Misc {
+        data_type: u32,
+        name:      String,
+    },
+
+    Fpo(Vec<Fpo>),
+
+    Pogo {
+        /// `LTCG`, `PGI`, `PGO` or `PGU`, by how the image was built, or the
+        /// signature in hex if it is none of those
+        signature: String,
+        entries:   Vec<PogoEntry>,
+    },
+
+    /// The image was linked with incremental LTCG. There is no data.
+    Iltcg,
+
+    VcFeature(VcFeature),
+
+    /// Hash the image was built from with /Brepro, empty if the linker
+    /// recorded none. Identical sources and tools give identical hashes.
+    Repro(Vec<u8>),
+
+    /// IMAGE_DLLCHARACTERISTICS_EX_* flags
+    ExDllCharacteristics(u32),
+
+    /// Intel MPX bounds checking data, which has no public layout
+    Mpx(Vec<u8>),
+
+    /// Any other type, undecoded
+    Other(Vec<u8>),
+}
+
+/// Names of the IMAGE_DLLCHARACTERISTICS_EX_* flags
+pub const EX_DLLCHARACTERISTICS_NAMES: &[(u32, &str)] = &[
+    (0x01, "CET_COMPAT"),
+    (0x02, "CET_COMPAT_STRICT_MODE"),
+    (0x04, "CET_SET_CONTEXT_IP_VALIDATION_RELAXED_MODE"),
+    (0x08, "CET_DYNAMIC_APIS_ALLOW_IN_PROC"),
+    (0x10, "CET_RESERVED_1"),
+    (0x20, "CET_RESERVED_2"),
+    (0x40, "FORWARD_CFI_COMPAT"),
+    (0x80, "HOTPATCH_COMPATIBLE"),
+];
+
+/// Largest debug data we will read for a single entry
+const MAX_DEBUG_DATA: u32 = 16 * 1024 * 1024;
+
+/// Read the little endian `u16` at `offset` in `data`, if it is all there
+pub fn le16(data: &[u8], offset: usize) -> Option<u16>
+{
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+/// Read the little endian `u32` at `offset` in `data`, if it is all there
+pub fn le32(data: &[u8], offset: usize) -> Option<u32>
+{
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Decode an IMAGE_DEBUG_MISC record
+fn decode_misc(data: &[u8]) -> Result<DebugData, Box<dyn Error>>
+{
+    let (data_type, length) = match (le32(data, 0), le32(data, 4)) {
+        (Some(data_type), Some(length)) => (data_type, length as usize),
+        _ => return Err("MISC record too small".into()),
+    };
+    let unicode = data.get(8).cloned().unwrap_or(0) != 0;
+    let name = match data.get(12..length.min(data.len())) {
+        Some(name) => name,
+        None => return Err("MISC record length out of range".into()),
+    };
+
+    let name = if unicode {
+        let chars: Vec<u16> = name.chunks_exact(2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]]))
+            .take_while(|&x| x != 0)
+            .collect();
+        String::from_utf16_lossy(&chars)
+    } else {
+        let end = name.iter().position(|&x| x == 0).unwrap_or(name.len());
+        String::from_utf8_lossy(&name[..end]).into_owned()
+    };
+    Ok(DebugData::Misc { data_type, name })
+}
+
+/// Decode an array of FPO_DATA records
+fn decode_fpo(data: &[u8]) -> Result<DebugData, Box<dyn Error>>
+{
+    if !data.len().is_multiple_of(16) {
+        return Err("FPO data is not a multiple of FPO_DATA".into());
+    }
+    let records = data.chunks_exact(16).map(|x| {
+        let bits = le16(x, 14).unwrap();
+        Fpo {
+            start:     le32(x, 0).unwrap(),
+            proc_size: le32(x, 4).unwrap(),
+            locals:    le32(x, 8).unwrap(),
+            params:    le16(x, 12).unwrap(),
+            prolog:    bits as u8,
+            regs:      (bits >> 8) as u8 & 7,
+            has_seh:   bits & (1 << 11) != 0,
+            use_bp:    bits & (1 << 12) != 0,
+            frame:     (bits >> 14) as u8,
+        }
+    }).collect();
+    Ok(DebugData::Fpo(records))
+}
+
+/// Decode a POGO record: a signature followed by `<rva> <size> <name>`
+/// entries, with the names NUL terminated and padded to 4 bytes
+fn decode_pogo(data: &[u8]) -> Result<DebugData, Box<dyn Error>>
+{
+    let signature = le32(data, 0).ok_or("POGO record too small")?;
+    let name: String = signature.to_be_bytes().iter()
+        .take_while(|&&x| x != 0).map(|&x| x as char).collect();
+    let signature = if !name.is_empty() &&
+            name.chars().all(|x| x.is_ascii_alphanumeric()) {
+        name
+    } else {
+        format!("0x{:08x}", signature)
+    };
+
+    let mut entries = Vec::new();
+    let mut offset = 4;
+    while offset + 8 < data.len() {
+        let name = &data[offset + 8..];
+        let end = match name.iter().position(|&x| x == 0) {
+            Some(end) => end,
+            None => return Err("POGO entry name not terminated".into()),
+        };
+        entries.push(PogoEntry {
+            rva:  le32(data, offset).unwrap(),
+            size: le32(data, offset + 4).unwrap(),
+            name: String::from_utf8_lossy(&name[..end]).into_owned(),
+        });
+        offset += 8 + (end + 4) / 4 * 4;
+    }
+    Ok(DebugData::Pogo { signature, entries })
+}
+
+/// Decode a VC_FEATURE record
+fn decode_vc_feature(data: &[u8]) -> Result<DebugData, Box<dyn Error>>
+{
+    let counter = |ii: usize| {
+        le32(data, ii * 4).ok_or("VC_FEATURE record too small")
+    };
+    Ok(DebugData::VcFeature(VcFeature {
+        pre_vc11: counter(0)?,
+        c_cpp:    counter(1)?,
+        gs:       counter(2)?,
+        sdl:      counter(3)?,
+        guard_n:  counter(4)?,
+    }))
+}
+
+/// Decode a REPRO record, which is empty or a length prefixed hash
+fn decode_repro(data: &[u8]) -> Result<DebugData, Box<dyn Error>>
+{
+    if data.is_empty() {
+        return Ok(DebugData::Repro(Vec::new()));
+    }
+    let len = le32(data, 0).ok_or("REPRO record too small")? as usize;
+    match data[4..].get(..len) {
+        Some(hash) => Ok(DebugData::Repro(hash.to_vec())),
+        None => Err("REPRO hash length out of range".into()),
+    }
+}
+
+/// What was read out of an image
+#[derive(Default)]
+pub struct PeFile {
+    pub mz:         Option<MZHeader>,
+    pub pe:         Option<PEHeader>,
+    pub optional:   Option<OptionalHeader>,
+    pub data_dirs:  Vec<ImageDataDirectory>,
+    pub sections:   Vec<ImageSectionHeader>,
+    pub debug_dirs: Vec<ImageDebugDirectory>,
+    pub codeview:   Option<Codeview>,
+
+    /// Why parsing stopped before a CodeView record was found, if it did
+    pub stop: Option<String>,
+
+    /// Contents of each of `debug_dirs`, once `decode_debug_dirs` is called
+    pub debug_data: Vec<Result<DebugData, String>>,
+
+    /// The version resource, once `decode_version` is called and if the
+    /// image has one
+    pub version: Option<Result<VersionInfo, String>>,
+
+    /// The Authenticode signature, once `decode_signature` is called and if
+    /// the image is signed
+    pub signature: Option<Result<Signature, String>>,
+
+    /// Imports followed by delay-load imports, once `decode_imports` is
+    /// called
+    pub imports: Option<Result<Vec<Import>, String>>,
+
+    /// The export directory, once `decode_imports` is called and if the
+    /// image exports anything
+    pub exports: Option<Result<Exports, String>>,
+
+    /// The Rich header, once `decode_rich` is called and if the image has
+    /// one
+    pub rich: Option<Result<RichHeader, String>>,
+}
+
+/// Read a `T` from `fd` like `read_struct`, saying what was being read and
+/// where if the file is too short
+unsafe fn read<T: Copy, R: Read + Seek>(fd: &mut R, what: &str) ->
+    Result<T, Box<dyn Error>>
+{
+    let offset = fd.stream_position()?;
+    read_struct(fd).map_err(|err| {
+        format!("Failed to read {} at offset 0x{:x}: {}", what, offset, err)
+            .into()
+    })
+}
+
+impl PeFile {
+    /// Translate the `size` bytes at `rva` to an offset in the file, if they
+    /// are all backed by the file when the image is mapped.
+    ///
+    /// This follows how the Windows loader maps images rather than taking
+    /// the section table at face value:
+    ///
+    /// - Images with a section alignment below the page size are mapped
+    ///   flat, with every RVA being the same as its file offset.
+    /// - The headers are mapped as-is, up to `size_of_headers`.
+    /// - `pointer_to_raw_data` is rounded down to a 512 byte boundary.
+    /// - `raw_data_size` is rounded up to the file alignment, but never maps
+    ///   more than the virtual size (rounded up to the section alignment)
+    ///   of the section. A virtual size of zero means the raw size is used.
+    ///
+    /// The rest of a section is zero filled memory with nothing behind it
+    /// in the file.
+    pub fn rva_to_offset(&self, rva: u32, size: u32) -> Option<u64>
+    {
+        let optional = self.optional?;
+        let last = rva.checked_add(size.saturating_sub(1))?;
+
+        let section_align = optional.section_align();
+        let file_align = optional.file_align();
+        if section_align < 0x1000 && section_align == file_align {
+            return Some(rva as u64);
+        }
+
+        /* Sections can't overlap the headers, so they end where the first
+         * section starts at the latest */
+        let headers = self.sections.iter().map(|x| x.vaddr)
+            .fold(optional.size_of_headers(), |acc, x| acc.min(x));
+        if last < headers {
+            return Some(rva as u64);
+        }
+
+        for section in &self.sections {
+            let vsize = match section.vsize {
+                0 => section.raw_data_size,
+                vsize => vsize,
+            };
+            let mapped = align_up(section.raw_data_size, file_align)
+                .min(align_up(vsize, section_align));
+            let secrange = section.vaddr..
+                section.vaddr.saturating_add(mapped);
+
+            /* Check if the entire range is contained in this section */
+            if contains(&secrange, rva) && contains(&secrange, last) {
+                let raw_ptr = section.pointer_to_raw_data & !0x1ff;
+                return Some((rva - section.vaddr) as u64 + raw_ptr as u64);
+            }
+        }
+        None
+    }
+
+    /// Read the `size` bytes at `rva`
+    pub fn read_rva<R: Read + Seek>(&self, fd: &mut R, rva: u32, size: u32) ->
+        Result<Vec<u8>, Box<dyn Error>>
+    {
+        let offset = match self.rva_to_offset(rva, size) {
+            Some(offset) => offset,
+            None => return Err(format!("Unable to find RVA 0x{:x} in the file",
+                                       rva).into()),
+        };
+        fd.seek(SeekFrom::Start(offset))?;
+
+        let mut data = vec![0u8; size as usize];
+        fd.read_exact(&mut data).map_err(|err| {
+            format!("Failed to read RVA 0x{:x} at offset 0x{:x}: {}",
+                    rva, offset, err)
+        })?;
+        Ok(data)
+    }
+
+    /// Read the data of the debug directory entry `de`
+    fn read_debug_data<R: Read + Seek>(&self, fd: &mut R,
+                                       de: &ImageDebugDirectory) ->
+        Result<Vec<u8>, Box<dyn Error>>
+    {
+        if de.size_of_data == 0 {
+            return Ok(Vec::new());
+        }
+        if de.size_of_data > MAX_DEBUG_DATA {
+            return Err("Debug data too large".into());
+        }
+
+        /* The data may not be mapped, in which case only the file offset is
+         * set */
+        let offset = match de.pointer_to_raw_data {
+            0 => self.rva_to_offset(de.address_of_raw_data, de.size_of_data)
+                .ok_or("Unable to find debug data")?,
+            offset => offset as u64,
+        };
+        fd.seek(SeekFrom::Start(offset))?;
+
+        let mut data = vec![0u8; de.size_of_data as usize];
+        fd.read_exact(&mut data).map_err(|err| {
+            format!("Failed to read debug data at offset 0x{:x}: {}",
+                    offset, err)
+        })?;
+        Ok(data)
+    }
+
+    /// Decode the data of every debug directory entry which was read,
+    /// filling in `debug_data`
+    pub fn decode_debug_dirs<R: Read + Seek>(&mut self, fd: &mut R)
+    {
+        let mut decoded = Vec::new();
+        for de in &self.debug_dirs {
+            let data = match de.typ {
+                IMAGE_DEBUG_TYPE_CODEVIEW => {
+                    self.read_codeview(fd, de).map(DebugData::Codeview)
+                }
+                typ => self.read_debug_data(fd, de).and_then(|data| {
+                    match typ {
+                        IMAGE_DEBUG_TYPE_FPO        => decode_fpo(&data),
+                        IMAGE_DEBUG_TYPE_MISC       => decode_misc(&data),
+                        IMAGE_DEBUG_TYPE_VC_FEATURE => decode_vc_feature(&data),
+                        IMAGE_DEBUG_TYPE_POGO       => decode_pogo(&data),
+                        IMAGE_DEBUG_TYPE_ILTCG      => Ok(DebugData::Iltcg),
+                        IMAGE_DEBUG_TYPE_MPX        => Ok(DebugData::Mpx(data)),
+                        IMAGE_DEBUG_TYPE_REPRO      => decode_repro(&data),
+                        IMAGE_DEBUG_TYPE_EX_DLLCHARACTERISTICS => {
+                            le32(&data, 0)
+                                .map(DebugData::ExDllCharacteristics)
+                                .ok_or_else(|| {
+                                    "EX_DLLCHARACTERISTICS record too small"
+                                        .into()
+                                })
+                        }
+                        _ => Ok(DebugData::Other(data)),
+                    }
+                }),
+            };
+            decoded.push(data.map_err(|x| x.to_string()));
+        }
+        self.debug_data = decoded;
+    }
+
+    /// Read the version resource, filling in `version`
+    pub fn decode_version<R: Read + Seek>(&mut self, fd: &mut R)
+    {
+        self.version = version::read(self, fd)
+            .map_err(|x| x.to_string()).transpose();
+    }
+
+    /// Read the Authenticode signature and recompute the digest of the
+    /// image, filling in `signature`
+    pub fn decode_signature<R: Read + Seek>(&mut self, fd: &mut R)
+    {
+        self.signature = authenticode::read(self, fd)
+            .map_err(|x| x.to_string()).transpose();
+    }
+
+    /// Read the import, delay-load import and export tables, filling in
+    /// `imports` and `exports`
+    pub fn decode_imports<R: Read + Seek>(&mut self, fd: &mut R)
+    {
+        self.imports = Some(imports::read(self, fd)
+                            .map_err(|x| x.to_string()));
+        self.exports = imports::read_exports(self, fd)
+            .map_err(|x| x.to_string()).transpose();
+    }
+
+    /// Read the Rich header the linker left before the PE header, filling
+    /// in `rich`
+    pub fn decode_rich<R: Read + Seek>(&mut self, fd: &mut R)
+    {
+        self.rich = rich::read(self, fd)
+            .map_err(|x| x.to_string()).transpose();
+    }
+
+    /// Get the name the first MISC debug entry gives for the `.dbg` file
+    /// symbols were split into
+    pub fn misc_name<R: Read + Seek>(&self, fd: &mut R) -> Option<String>
+    {
+        let de = self.debug_dirs.iter()
+            .find(|x| x.typ == IMAGE_DEBUG_TYPE_MISC)?;
+        let data = self.read_debug_data(fd, de).ok()?;
+        match decode_misc(&data) {
+            Ok(DebugData::Misc { data_type: IMAGE_DEBUG_MISC_EXENAME, name })
+                if !name.is_empty() => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Read the image headers and tables from `fd` up to the CodeView record
+    fn walk<R: Read + Seek>(&mut self, fd: &mut R) ->
+        Result<(), Box<dyn Error>>
+    {
+        /* Check for an MZ header */
+        let mz_header: MZHeader = unsafe { read(fd, "MZ header")? };
+        if &mz_header.signature != b"MZ" {
+            return Err("No MZ header present".into());
+        }
+        self.mz = Some(mz_header);
+
+        /* Seek to where the PE header should be */
+        let new_header = mz_header.new_header as u64;
+        if fd.seek(SeekFrom::Start(new_header))? != new_header {
+            return Err("Failed to seek to PE header".into());
+        }
+
+        /* Check for a PE header */
+        let pe_header: PEHeader = unsafe { read(fd, "PE header")? };
+        if &pe_header.signature != b"PE\0\0" {
+            return Err("No PE header present".into());
+        }
+        self.pe = Some(pe_header);
+
+        let optional = match pe_header.machine {
+            IMAGE_FILE_MACHINE_I386 => {
+                OptionalHeader::Pe32(unsafe { read(fd, "optional header")? })
+            }
+            IMAGE_FILE_MACHINE_IA64 | IMAGE_FILE_MACHINE_AMD64 => {
+                OptionalHeader::Pe64(unsafe { read(fd, "optional header")? })
+            }
+            _ => return Err("Unsupported PE machine type".into())
+        };
+        self.optional = Some(optional);
+
+        /* Load all the data directories */
+        for _ in 0..optional.num_tables() {
+            let datadir: ImageDataDirectory =
+                unsafe { read(fd, "data directory")? };
+            self.data_dirs.push(datadir);
+        }
+
+        /* Seek to where the section table should be */
+        let section_headers = new_header + 0x18 +
+                              pe_header.optional_header_size as u64;
+        if fd.seek(SeekFrom::Start(section_headers))? != section_headers {
+            return Err("Failed to seek to section table".into());
+        }
+
+        /* Parse all the sections */
+        for _ in 0..pe_header.num_sections {
+            let sechdr: ImageSectionHeader =
+                unsafe { read(fd, "section header")? };
+            self.sections.push(sechdr);
+        }
+
+        /* Validate we have a debug directory */
+        if self.data_dirs.len() <= DEBUG_DIRECTORY {
+            return Err("No debug data directory".into());
+        }
+
+        /* Grab the debug table */
+        let debug_table = self.data_dirs[DEBUG_DIRECTORY];
+        if debug_table.vaddr == 0 || debug_table.size == 0 {
+            return Err("Debug directory not present or zero sized".into());
+        }
+
+        /* Validate debug table size is sane */
+        let iddlen = std::mem::size_of::<ImageDebugDirectory>() as u32;
+        let debug_table_ents = debug_table.size / iddlen;
+        if !debug_table.size.is_multiple_of(iddlen) || debug_table_ents == 0 {
+            return Err("No debug entries or not mod ImageDebugDirectory"
+                       .into());
+        }
+
+        /* Find the debug table in the file */
+        let debug_raw_ptr =
+            match self.rva_to_offset(debug_table.vaddr, debug_table.size) {
+                Some(offset) => offset,
+                None => return Err("Unable to find debug data".into()),
+            };
+
+        /* Seek to where the debug directories should be */
+        if fd.seek(SeekFrom::Start(debug_raw_ptr))? != debug_raw_ptr {
+            return Err("Failed to seek to debug directories".into());
+        }
+
+        /* Read all the debug table entries. A truncated table is only fatal
+         * if it cost us the codeview entry. */
+        for _ in 0..debug_table_ents {
+            let de: Result<ImageDebugDirectory, _> =
+                unsafe { read(fd, "debug directory") };
+            match de {
+                Ok(de) => self.debug_dirs.push(de),
+                Err(err) => {
+                    if self.debug_dirs.iter()
+                            .any(|x| x.typ == IMAGE_DEBUG_TYPE_CODEVIEW) {
+                        break;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        /* Decode the first codeview entry */
+        let de = match self.debug_dirs.iter()
+                .find(|x| x.typ == IMAGE_DEBUG_TYPE_CODEVIEW) {
+            Some(de) => *de,
+            None => return Err("Failed to find RSDS codeview directory".into()),
+        };
+        self.codeview = Some(self.read_codeview(fd, &de)?);
+        Ok(())
+    }
+
+    /// Read the RSDS CodeView record described by `de`
+    fn read_codeview<R: Read + Seek>(&self, fd: &mut R,
+                                     de: &ImageDebugDirectory) ->
+        Result<Codeview, Box<dyn Error>>
+    {
+        /* Like other debug data the record may only have an RVA */
+        let cvo = match de.pointer_to_raw_data {
+            0 => self.rva_to_offset(de.address_of_raw_data, de.size_of_data)
+                .ok_or("Unable to find codeview entry")?,
+            offset => offset as u64,
+        };
+
+        /* Seek to where the codeview entry should be */
+        if fd.seek(SeekFrom::Start(cvo))? != cvo {
+            return Err("Failed to seek to codeview entry".into());
+        }
+
+        let cv: CodeviewEntry = unsafe { read(fd, "codeview entry")? };
+        if &cv.signature != b"RSDS" {
+            return Err("No RSDS signature present in codeview ent".into());
+        }
+
+        /* Calculate theoretical string length based on the size of the
+         * section vs the size of the header */
+        let cv_strlen = match (de.size_of_data as usize)
+                .checked_sub(std::mem::size_of_val(&cv)) {
+            Some(len) => len,
+            None => return Err("Codeview entry too small for RSDS".into()),
+        };
+
+        /* Read in the debug path */
+        let mut dpath = vec![0u8; cv_strlen];
+        fd.read_exact(&mut dpath)
+            .map_err(|err| format!("Failed to read RSDS path: {}", err))?;
+
+        /* PDB strings are utf8 and null terminated, find the first null
+         * and we will split it there.
+         */
+        let null_strlen = match dpath.iter().position(|&x| x == 0) {
+            Some(len) => len,
+            None => {
+                return Err("Failed to find null terminiator in RSDS".into())
+            }
+        };
+        let dpath = std::str::from_utf8(&dpath[..null_strlen])?;
+
+        /* Further, since this path can be a full path, we get only
+         * the filename component of this path.
+         */
+        let name = Path::new(dpath).file_name().and_then(|x| x.to_str());
+        let name = match name {
+            Some(name) => name,
+            None => return Err("Could not parse file from RSDS path".into()),
+        };
+
+        Ok(Codeview {
+            guid: format!("{:08X}{:04X}{:04X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+                          {cv.guid_a}, {cv.guid_b}, {cv.guid_c},
+                          {cv.guid_d[0]}, {cv.guid_d[1]},
+                          {cv.guid_d[2]}, {cv.guid_d[3]},
+                          {cv.guid_d[4]}, {cv.guid_d[5]},
+                          {cv.guid_d[6]}, {cv.guid_d[7]}),
+            age:  cv.age,
+            path: dpath.into(),
+            name: name.into(),
+        })
+    }
+}
+
+/// Parse the image in `fd` as far as possible
+pub fn parse<R: Read + Seek>(fd: &mut R) -> PeFile
+{
+    let mut image = PeFile::default();
+    if let Err(err) = image.walk(fd) {
+        image.stop = Some(err.to_string());
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// An image with the given alignments, headers size and sections of
+    /// (vaddr, vsize, pointer_to_raw_data, raw_data_size)
+    fn mapped(section_align: u32, file_align: u32, size_of_headers: u32,
+              sections: &[(u32, u32, u32, u32)]) -> PeFile
+    {
+        let mut optional: WindowsPEHeader32 = unsafe { std::mem::zeroed() };
+        optional.section_align = section_align;
+        optional.file_align = file_align;
+        optional.size_of_headers = size_of_headers;
+
+        let sections = sections.iter().map(|&(vaddr, vsize, ptr, size)| {
+            let mut section: ImageSectionHeader =
+                unsafe { std::mem::zeroed() };
+            section.vaddr = vaddr;
+            section.vsize = vsize;
+            section.pointer_to_raw_data = ptr;
+            section.raw_data_size = size;
+            section
+        }).collect();
+
+        PeFile {
+            optional: Some(OptionalHeader::Pe32(optional)),
+            sections,
+            ..PeFile::default()
+        }
+    }
+
+    #[test]
+    fn flat_mapping()
+    {
+        let image = mapped(0x200, 0x200, 0x400, &[(0x400, 0x100, 0x400, 0)]);
+        assert_eq!(image.rva_to_offset(0x1234, 0x10), Some(0x1234));
+        assert_eq!(image.rva_to_offset(0xffff_fff0, 0x10), Some(0xffff_fff0));
+        assert_eq!(image.rva_to_offset(0xffff_fff0, 0x11), None);
+
+        /* Only when sections are aligned like the file */
+        let image = mapped(0x400, 0x200, 0x400, &[]);
+        assert_eq!(image.rva_to_offset(0x1234, 0x10), None);
+        assert_eq!(PeFile::default().rva_to_offset(0, 1), None);
+    }
+
+    #[test]
+    fn headers_mapping()
+    {
+        let image = mapped(0x1000, 0x200, 0x400,
+                           &[(0x1000, 0x1000, 0x400, 0x1000)]);
+        assert_eq!(image.rva_to_offset(0x10, 0x10), Some(0x10));
+        assert_eq!(image.rva_to_offset(0x3f0, 0x10), Some(0x3f0));
+        assert_eq!(image.rva_to_offset(0x3f8, 0x10), None);
+        assert_eq!(image.rva_to_offset(0x800, 0x10), None);
+
+        /* The headers end where the first section starts */
+        let image = mapped(0x1000, 0x200, 0x2000,
+                           &[(0x1000, 0x1000, 0x400, 0x1000)]);
+        assert_eq!(image.rva_to_offset(0xff0, 0x10), Some(0xff0));
+        assert_eq!(image.rva_to_offset(0x1000, 0x10), Some(0x400));
+    }
+
+    #[test]
+    fn section_mapping()
+    {
+        /* Raw data pointers are rounded down to 512 bytes */
+        let image = mapped(0x1000, 0x200, 0x400,
+                           &[(0x1000, 0x1000, 0x41f, 0x200)]);
+        assert_eq!(image.rva_to_offset(0x1010, 0x10), Some(0x410));
+
+        /* Raw sizes are rounded up to the file alignment */
+        let image = mapped(0x1000, 0x200, 0x400,
+                           &[(0x1000, 0x1000, 0x400, 0x10)]);
+        assert_eq!(image.rva_to_offset(0x11f0, 0x10), Some(0x5f0));
+        assert_eq!(image.rva_to_offset(0x1200, 0x10), None);
+
+        /* But never map more than the aligned virtual size */
+        let image = mapped(0x1000, 0x200, 0x400,
+                           &[(0x1000, 0x100, 0x400, 0x2000)]);
+        assert_eq!(image.rva_to_offset(0x1ff0, 0x10), Some(0x13f0));
+        assert_eq!(image.rva_to_offset(0x2000, 0x10), None);
+
+        /* Unless there is no virtual size */
+        let image = mapped(0x1000, 0x200, 0x400,
+                           &[(0x1000, 0, 0x400, 0x300)]);
+        assert_eq!(image.rva_to_offset(0x13f0, 0x10), Some(0x7f0));
+        assert_eq!(image.rva_to_offset(0x1400, 0x10), None);
+    }
+
+    #[test]
+    fn ranges_crossing_sections()
+    {
+        let image = mapped(0x1000, 0x200, 0x400,
+                           &[(0x1000, 0x1000, 0x400, 0x1000),
+                             (0x2000, 0x1000, 0x1400, 0x1000)]);
+        assert_eq!(image.rva_to_offset(0x1ff0, 0x10), Some(0x13f0));
+        assert_eq!(image.rva_to_offset(0x2000, 0x10), Some(0x1400));
+        assert_eq!(image.rva_to_offset(0x1ff0, 0x20), None);
+        assert_eq!(image.rva_to_offset(0x2ff0, 0x20), None);
+
+        /* An empty range is checked at its start */
+        assert_eq!(image.rva_to_offset(0x2ff0, 0), Some(0x23f0));
+        assert_eq!(image.rva_to_offset(0x3000, 0), None);
+    }
+
+    /// An RSDS record for `path`
+    fn rsds(path: &str) -> Vec<u8>
+    {
+        let mut data = b"RSDS".to_vec();
+        data.extend_from_slice(&0x0102_0304u32.to_le_bytes());
+        data.extend_from_slice(&0x0506u16.to_le_bytes());
+        data.extend_from_slice(&0x0708u16.to_le_bytes());
+        data.extend_from_slice(&[9, 10, 11, 12, 13, 14, 15, 16]);
+        data.extend_from_slice(&0x1fu32.to_le_bytes());
+        data.extend_from_slice(path.as_bytes());
+        data.push(0);
+        data
+    }
+
+    #[test]
+    fn codeview_by_rva()
+    {
+        let image = mapped(0x1000, 0x200, 0x400,
+                           &[(0x1000, 0x1000, 0x400, 0x200)]);
+        let record = rsds(r"d:\build\ntdll.pdb");
+        let mut data = vec![0u8; 0x600];
+        data[0x410..0x410 + record.len()].copy_from_slice(&record);
+
+        let mut de: ImageDebugDirectory = unsafe { std::mem::zeroed() };
+        de.typ = IMAGE_DEBUG_TYPE_CODEVIEW;
+        de.size_of_data = record.len() as u32;
+        de.address_of_raw_data = 0x1010;
+        let cv = image.read_codeview(&mut Cursor::new(&data), &de).unwrap();
+        assert_eq!(cv.guid, "0102030405060708090A0B0C0D0E0F10");
+        assert_eq!(cv.age, 0x1f);
+        assert_eq!(cv.path, r"d:\build\ntdll.pdb");
+        if cfg!(windows) {
+            assert_eq!(cv.name, "ntdll.pdb");
+        }
+
+        /* The file offset wins when there is one */
+        de.pointer_to_raw_data = 0x10;
+        assert!(image.read_codeview(&mut Cursor::new(&data), &de).is_err());
+
+        /* And the RVA has to be backed by the file */
+        de.pointer_to_raw_data = 0;
+        de.address_of_raw_data = 0x11f0;
+        let err = image.read_codeview(&mut Cursor::new(&data), &de);
+        assert_eq!(err.err().unwrap().to_string(),
+                   "Unable to find codeview entry");
+    }
+    fn misc(data: &[u8]) -> (u32, String)
+    {
+        match decode_misc(data).unwrap() {
+            DebugData::Misc { data_type, name } => (data_type, name),
+            _ => panic!("not a MISC record"),
+        }
+    }
+
+    #[test]
+    fn misc_records()
+    {
+        let mut data = vec![1, 0, 0, 0, 0x18, 0, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(b"ntdll.dbg\0\0\0");
+        assert_eq!(misc(&data), (1, "ntdll.dbg".to_string()));
+
+        /* The length bounds the name, and may be past the data */
+        data[4] = 0x10;
+        assert_eq!(misc(&data), (1, "ntdl".to_string()));
+        data[4] = 0xff;
+        assert_eq!(misc(&data), (1, "ntdll.dbg".to_string()));
+
+        let mut data = vec![1, 0, 0, 0, 0x20, 0, 0, 0, 1, 0, 0, 0];
+        data.extend("ñtdll.dbg\0x".encode_utf16()
+                    .flat_map(|x| x.to_le_bytes()));
+        assert_eq!(misc(&data), (1, "ñtdll.dbg".to_string()));
+
+        assert_eq!(decode_misc(&data[..7]).err().unwrap().to_string(),
+                   "MISC record too small");
+        data[4] = 11;
+        assert_eq!(decode_misc(&data).err().unwrap().to_string(),
+                   "MISC record length out of range");
+    }
+
+    #[test]
+    fn fpo_records()
+    {
+        let bits: u16 = 5 | 3 << 8 | 1 << 11 | 1 << 12 | 1 << 14;
+        let mut data: Vec<u8> = [0x1000u32, 0x80, 2].iter()
+            .flat_map(|x| x.to_le_bytes()).collect();
+        data.extend_from_slice(&3u16.to_le_bytes());
+        data.extend_from_slice(&bits.to_le_bytes());
+        data.extend_from_slice(&[0; 14]);
+        data.extend_from_slice(&(7u16 << 8 | 3 << 14).to_le_bytes());
+
+        let records = match decode_fpo(&data).unwrap() {
+            DebugData::Fpo(records) => records,
+            _ => panic!("not FPO data"),
+        };
+        let got: Vec<_> = records.iter().map(|x| {
+            (x.start, x.proc_size, x.locals, x.params, x.prolog, x.regs,
+             x.has_seh, x.use_bp, x.frame)
+        }).collect();
+        assert_eq!(got, [(0x1000, 0x80, 2, 3, 5, 3, true, true, 1),
+                         (0, 0, 0, 0, 0, 7, false, false, 3)]);
+
+        assert!(decode_fpo(&data[..31]).is_err());
+    }
+
+    fn pogo(data: &[u8]) -> (String, Vec<(u32, u32, String)>)
+    {
+        match decode_pogo(data).unwrap() {
+            DebugData::Pogo { signature, entries } => {
+                (signature, entries.into_iter()
+                    .map(|x| (x.rva, x.size, x.name)).collect())
+            }
+            _ => panic!("not a POGO record"),
+        }
+    }
+
+    #[test]
+    fn pogo_records()
+    {
+        /* Names are padded to 4 bytes, after their terminator */
+        let mut data = b"\0UGP".to_vec();
+        for &(rva, size, name) in &[(0x1000u32, 0x20u32, &b"abc\0"[..]),
+                                    (0x1020, 0x10, b".text\0\0\0"),
+                                    (0x2000, 0x8, b"abcd\0\0\0\0")] {
+            data.extend_from_slice(&rva.to_le_bytes());
+            data.extend_from_slice(&size.to_le_bytes());
+            data.extend_from_slice(name);
+        }
+        let entries = vec![(0x1000, 0x20, "abc".to_string()),
+                           (0x1020, 0x10, ".text".to_string()),
+                           (0x2000, 0x8, "abcd".to_string())];
+        assert_eq!(pogo(&data), ("PGU".to_string(), entries.clone()));
+
+        /* Trailing bytes too short for an entry are padding */
+        data.extend_from_slice(&[0; 8]);
+        assert_eq!(pogo(&data).1, entries);
+
+        assert_eq!(pogo(b"GCTL").0, "LTCG");
+        assert_eq!(pogo(&[0x78, 0x56, 0x34, 0x12]).0, "0x12345678");
+        assert_eq!(pogo(&[0, 0, 0, 0]).0, "0x00000000");
+
+        assert!(decode_pogo(&data[..3]).is_err());
+        assert_eq!(decode_pogo(&data[..26]).err().unwrap().to_string(),
+                   "POGO entry name not terminated");
+    }
+
+    #[test]
+    fn vc_feature_records()
+    {
+        let data: Vec<u8> = (1..6u32).flat_map(|x| x.to_le_bytes()).collect();
+        match decode_vc_feature(&data).unwrap() {
+            DebugData::VcFeature(x) => {
+                assert_eq!((x.pre_vc11, x.c_cpp, x.gs, x.sdl, x.guard_n),
+                           (1, 2, 3, 4, 5));
+            }
+            _ => panic!("not a VC_FEATURE record"),
+        }
+        assert_eq!(decode_vc_feature(&data[..19]).err().unwrap().to_string(),
+                   "VC_FEATURE record too small");
+    }
+
+    #[test]
+    fn repro_records()
+    {
+        let repro = |data: &[u8]| match decode_repro(data) {
+            Ok(DebugData::Repro(hash)) => Ok(hash),
+            Ok(_) => panic!("not a REPRO record"),
+            Err(err) => Err(err.to_string()),
+        };
+        assert_eq!(repro(&[]), Ok(vec![]));
+        assert_eq!(repro(&[4, 0, 0, 0, 1, 2, 3, 4, 5]), Ok(vec![1, 2, 3, 4]));
+        assert_eq!(repro(&[0, 0, 0, 0]), Ok(vec![]));
+        assert_eq!(repro(&[4, 0, 0]).unwrap_err(), "REPRO record too small");
+        for len in &[5u32, 0xffff_ffff] {
+            let mut data = len.to_le_bytes().to_vec();
+            data.extend_from_slice(&[1, 2, 3, 4]);
+            assert_eq!(repro(&data).unwrap_err(),
+                       "REPRO hash length out of range");
+        }
+    }
+    #[test]
+    fn little_endian()
+    {
+        let data = [1, 2, 3, 4, 5];
+        assert_eq!(le16(&data, 3), Some(0x0504));
+        assert_eq!(le16(&data, 4), None);
+        assert_eq!(le32(&data, 1), Some(0x0504_0302));
+        assert_eq!(le32(&data, 2), None);
+        assert_eq!(le32(&data, usize::MAX - 1), None);
+        assert_eq!(le16(&data, usize::MAX), None);
+    }
+}
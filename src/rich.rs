@@ -0,0 +1,453 @@
+//! The Rich header Microsoft linkers write between the MZ header and the PE
+//! header.
+//!
+//! It lists every tool which produced an object file linked into the image
+//! (compilers, assemblers, the resource converter, import libraries and the
+//! linker itself) by product ID and build number, with how many objects
+//! each produced. Everything after a `DanS` marker is XORed with a key,
+//! which follows the `Rich` marker ending the header in the clear. The key
+//! is a checksum of the MZ header, stub and entries.
+//!
+//! Product IDs are only documented by the community. They map to Visual
+//! Studio versions up to 2015, after which they stay the same and only the
+//! build number tells versions apart.
+
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+
+use json;
+use pe::{PeFile, le32};
+
+/// `DanS` and `Rich` as little endian dwords
+const DANS: u32 = 0x536e_6144;
+const RICH: u32 = 0x6863_6952;
+
+/// Offset of `e_lfanew` in the MZ header, which the checksum leaves out
+const E_LFANEW: usize = 0x3c;
+
+/// Most of the file read looking for the header. Real stubs are a few
+/// hundred bytes.
+const MAX_STUB_SIZE: u32 = 0x10000;
+
+/// Visual Studio versions, oldest first
+const VISUAL_STUDIO: [&str; 13] = [
+    "VS97", "VS6", "VS2002", "VS2003", "VS2005", "VS2008", "VS2010",
+    "VS2012", "VS2013", "VS2015", "VS2017", "VS2019", "VS2022",
+];
+
+/// Names of the tools by product ID
+const PRODUCTS: [&str; 0x10f] = [
+    "Unknown", "Import0", "Linker510", "Cvtomf510", "Linker600",
+    "Cvtomf600", "Cvtres500", "Utc11_Basic", "Utc11_C", "Utc12_Basic",
+    "Utc12_C", "Utc12_CPP", "AliasObj60", "VisualBasic60", "Masm613",
+    "Masm710", "Linker511", "Cvtomf511", "Masm614", "Linker512",
+    "Cvtomf512", "Utc12_C_Std", "Utc12_CPP_Std", "Utc12_C_Book",
+    "Utc12_CPP_Book", "Implib700", "Cvtomf700", "Utc13_Basic", "Utc13_C",
+    "Utc13_CPP", "Linker610", "Cvtomf610", "Linker601", "Cvtomf601",
+    "Utc12_1_Basic", "Utc12_1_C", "Utc12_1_CPP", "Linker620", "Cvtomf620",
+    "AliasObj70", "Linker621", "Cvtomf621", "Masm615", "Utc13_LTCG_C",
+    "Utc13_LTCG_CPP", "Masm620", "ILAsm100", "Utc12_2_Basic", "Utc12_2_C",
+    "Utc12_2_CPP", "Utc12_2_C_Std", "Utc12_2_CPP_Std", "Utc12_2_C_Book",
+    "Utc12_2_CPP_Book", "Implib622", "Cvtomf622", "Cvtres501",
+    "Utc13_C_Std", "Utc13_CPP_Std", "Cvtpgd1300", "Linker622", "Linker700",
+    "Export622", "Export700", "Masm700", "Utc13_POGO_I_C",
+    "Utc13_POGO_I_CPP", "Utc13_POGO_O_C", "Utc13_POGO_O_CPP", "Cvtres700",
+    "Cvtres710p", "Linker710p", "Cvtomf710p", "Export710p", "Implib710p",
+    "Masm710p", "Utc1310p_C", "Utc1310p_CPP", "Utc1310p_C_Std",
+    "Utc1310p_CPP_Std", "Utc1310p_LTCG_C", "Utc1310p_LTCG_CPP",
+    "Utc1310p_POGO_I_C", "Utc1310p_POGO_I_CPP", "Utc1310p_POGO_O_C",
+    "Utc1310p_POGO_O_CPP", "Linker624", "Cvtomf624", "Export624",
+    "Implib624", "Linker710", "Cvtomf710", "Export710", "Implib710",
+    "Cvtres710", "Utc1310_C", "Utc1310_CPP", "Utc1310_C_Std",
+    "Utc1310_CPP_Std", "Utc1310_LTCG_C", "Utc1310_LTCG_CPP",
+    "Utc1310_POGO_I_C", "Utc1310_POGO_I_CPP", "Utc1310_POGO_O_C",
+    "Utc1310_POGO_O_CPP", "AliasObj710", "AliasObj710p", "Cvtpgd1310",
+    "Cvtpgd1310p", "Utc1400_C", "Utc1400_CPP", "Utc1400_C_Std",
+    "Utc1400_CPP_Std", "Utc1400_LTCG_C", "Utc1400_LTCG_CPP",
+    "Utc1400_POGO_I_C", "Utc1400_POGO_I_CPP", "Utc1400_POGO_O_C",
+    "Utc1400_POGO_O_CPP", "Cvtpgd1400", "Linker800", "Cvtomf800",
+    "Export800", "Implib800", "Cvtres800", "Masm800", "AliasObj800",
+    "PhoenixPrerelease", "Utc1400_CVTCIL_C", "Utc1400_CVTCIL_CPP",
+    "Utc1400_LTCG_MSIL", "Utc1500_C", "Utc1500_CPP", "Utc1500_C_Std",
+    "Utc1500_CPP_Std", "Utc1500_CVTCIL_C", "Utc1500_CVTCIL_CPP",
+    "Utc1500_LTCG_C", "Utc1500_LTCG_CPP", "Utc1500_LTCG_MSIL",
+    "Utc1500_POGO_I_C", "Utc1500_POGO_I_CPP", "Utc1500_POGO_O_C",
+    "Utc1500_POGO_O_CPP", "Cvtpgd1500", "Linker900", "Export900",
+    "Implib900", "Cvtres900", "Masm900", "AliasObj900", "Resource",
+    "AliasObj1000", "Cvtpgd1600", "Cvtres1000", "Export1000", "Implib1000",
+    "Linker1000", "Masm1000", "Phx1600_C", "Phx1600_CPP",
+    "Phx1600_CVTCIL_C", "Phx1600_CVTCIL_CPP", "Phx1600_LTCG_C",
+    "Phx1600_LTCG_CPP", "Phx1600_LTCG_MSIL", "Phx1600_POGO_I_C",
+    "Phx1600_POGO_I_CPP", "Phx1600_POGO_O_C", "Phx1600_POGO_O_CPP",
+    "Utc1600_C", "Utc1600_CPP", "Utc1600_CVTCIL_C", "Utc1600_CVTCIL_CPP",
+    "Utc1600_LTCG_C", "Utc1600_LTCG_CPP", "Utc1600_LTCG_MSIL",
+    "Utc1600_POGO_I_C", "Utc1600_POGO_I_CPP", "Utc1600_POGO_O_C",
+    "Utc1600_POGO_O_CPP", "AliasObj1010", "Cvtpgd1610", "Cvtres1010",
+    "Export1010", "Implib1010", "Linker1010", "Masm1010", "Utc1610_C",
+    "Utc1610_CPP", "Utc1610_CVTCIL_C", "Utc1610_CVTCIL_CPP",
+    "Utc1610_LTCG_C", "Utc1610_LTCG_CPP", "Utc1610_LTCG_MSIL",
+    "Utc1610_POGO_I_C", "Utc1610_POGO_I_CPP", "Utc1610_POGO_O_C",
+    "Utc1610_POGO_O_CPP", "AliasObj1100", "Cvtpgd1700", "Cvtres1100",
+    "Export1100", "Implib1100", "Linker1100", "Masm1100", "Utc1700_C",
+    "Utc1700_CPP", "Utc1700_CVTCIL_C", "Utc1700_CVTCIL_CPP",
+    "Utc1700_LTCG_C", "Utc1700_LTCG_CPP", "Utc1700_LTCG_MSIL",
+    "Utc1700_POGO_I_C", "Utc1700_POGO_I_CPP", "Utc1700_POGO_O_C",
+    "Utc1700_POGO_O_CPP", "AliasObj1200", "Cvtpgd1800", "Cvtres1200",
+    "Export1200", "Implib1200", "Linker1200", "Masm1200", "Utc1800_C",
+    "Utc1800_CPP", "Utc1800_CVTCIL_C", "Utc1800_CVTCIL_CPP",
+    "Utc1800_LTCG_C", "Utc1800_LTCG_CPP", "Utc1800_LTCG_MSIL",
+    "Utc1800_POGO_I_C", "Utc1800_POGO_I_CPP", "Utc1800_POGO_O_C",
+    "Utc1800_POGO_O_CPP", "AliasObj1210", "Cvtpgd1810", "Cvtres1210",
+    "Export1210", "Implib1210", "Linker1210", "Masm1210", "Utc1810_C",
+    "Utc1810_CPP", "Utc1810_CVTCIL_C", "Utc1810_CVTCIL_CPP",
+    "Utc1810_LTCG_C", "Utc1810_LTCG_CPP", "Utc1810_LTCG_MSIL",
+    "Utc1810_POGO_I_C", "Utc1810_POGO_I_CPP", "Utc1810_POGO_O_C",
+    "Utc1810_POGO_O_CPP", "AliasObj1400", "Cvtpgd1900", "Cvtres1400",
+    "Export1400", "Implib1400", "Linker1400", "Masm1400", "Utc1900_C",
+    "Utc1900_CPP", "Utc1900_CVTCIL_C", "Utc1900_CVTCIL_CPP",
+    "Utc1900_LTCG_C", "Utc1900_LTCG_CPP", "Utc1900_LTCG_MSIL",
+    "Utc1900_POGO_I_C", "Utc1900_POGO_I_CPP", "Utc1900_POGO_O_C",
+    "Utc1900_POGO_O_CPP",
+];
+
+/// A tool listed in the Rich header
+#[derive(Clone, Copy)]
+pub struct Entry {
+    pub product: u16,
+    pub build:   u16,
+
+    /// Number of objects the tool produced
+    pub count: u32,
+}
+
+/// A decoded Rich header
+#[derive(Clone)]
+pub struct RichHeader {
+    /// File offset of the `DanS` marker
+    pub offset: u32,
+
+    /// The XOR key, which is also the checksum
+    pub key: u32,
+
+    /// Whether the checksum matches, which it doesn't for headers that were
+    /// edited or copied from another image
+    pub checksum_valid: bool,
+
+    pub entries: Vec<Entry>,
+}
+
+impl Entry {
+    /// Name of the tool, eg. `Utc1900_CPP` for the VS2015 and later C++
+    /// compiler
+    pub fn name(&self) -> String
+    {
+        match PRODUCTS.get(self.product as usize) {
+            Some(name) => name.to_string(),
+            None => format!("Unknown_{:04x}", self.product),
+        }
+    }
+
+    /// Index into `VISUAL_STUDIO` of the version the tool shipped with
+    fn version(&self) -> Option<usize>
+    {
+        Some(match self.product {
+            0x0002..=0x0003 | 0x0006..=0x0008 | 0x0010..=0x0011 |
+                0x0013..=0x0014 => 0,
+            0x0004..=0x0005 | 0x0009..=0x000e | 0x0012 | 0x0015..=0x0018 |
+                0x001e..=0x0026 | 0x0028..=0x002a | 0x002d |
+                0x002f..=0x0038 | 0x003c | 0x003e | 0x0056..=0x0059 => 1,
+            0x0019..=0x001d | 0x0027 | 0x002b..=0x002c | 0x002e |
+                0x0039..=0x003b | 0x003d | 0x003f..=0x0045 => 2,
+            0x000f | 0x0046..=0x0055 | 0x005a..=0x006c => 3,
+            0x006d..=0x0082 => 4,
+            0x0083..=0x0096 => 5,
+            0x0098..=0x00c6 => 6,
+            0x00c7..=0x00d8 => 7,
+            0x00d9..=0x00fc => 8,
+
+            /* VS2015 and later share product IDs. Builds are 23026 to
+             * 24215 for VS2015, 25017 to 27045 for VS2017, 27508 to 29337
+             * for VS2019 and 30133 on for VS2022. */
+            0x00fd..=0x010e => match self.build {
+                0..=24999     => 9,
+                25000..=27499 => 10,
+                27500..=29999 => 11,
+                _             => 12,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Visual Studio version the tool shipped with, eg. `VS2008`. Unknown
+    /// for imports counted by old linkers and resources.
+    pub fn visual_studio(&self) -> Option<&'static str>
+    {
+        self.version().map(|x| VISUAL_STUDIO[x])
+    }
+
+    /// Whether this is the linker which produced the image
+    pub fn is_linker(&self) -> bool
+    {
+        PRODUCTS.get(self.product as usize)
+            .is_some_and(|x| x.starts_with("Linker"))
+    }
+
+    /// Format this as a JSON object
+    pub fn json(&self) -> String
+    {
+        json::object(&[
+            ("product",       self.product.to_string()),
+            ("name",          json::quote(&self.name())),
+            ("build",         self.build.to_string()),
+            ("count",         self.count.to_string()),
+            ("visual_studio", self.visual_studio()
+                                  .map_or("null".into(), json::quote)),
+        ])
+    }
+}
+
+impl RichHeader {
+    /// Get the entry of the linker which produced the image, if listed
+    pub fn linker(&self) -> Option<&Entry>
+    {
+        self.entries.iter().rev().find(|x| x.is_linker())
+    }
+
+    /// Visual Studio version of the linker, or of the newest tool if no
+    /// linker is listed
+    pub fn toolchain(&self) -> Option<&'static str>
+    {
+        self.linker().and_then(|x| x.version())
+            .or_else(|| self.entries.iter().filter_map(|x| x.version()).max())
+            .map(|x| VISUAL_STUDIO[x])
+    }
+
+    /// Visual Studio version of the oldest tool, which is where objects
+    /// from old static libraries show up
+    pub fn oldest(&self) -> Option<&'static str>
+    {
+        self.entries.iter().filter_map(|x| x.version()).min()
+            .map(|x| VISUAL_STUDIO[x])
+    }
+
+    /// Format this as a JSON object
+    pub fn json(&self) -> String
+    {
+        let quoted = |val: Option<&str>| val.map_or("null".into(), json::quote);
+        let entries: Vec<String> = self.entries.iter().map(|x| x.json())
+            .collect();
+        json::object(&[
+            ("offset",         self.offset.to_string()),
+            ("key",            self.key.to_string()),
+            ("checksum_valid", self.checksum_valid.to_string()),
+            ("toolchain",      quoted(self.toolchain())),
+            ("oldest",         quoted(self.oldest())),
+            ("entries",        json::array(&entries)),
+        ])
+    }
+}
+
+/// Get the position in `VISUAL_STUDIO` of `name`, ignoring case, for
+/// comparing versions given on the command line
+pub fn version_index(name: &str) -> Option<usize>
+{
+    VISUAL_STUDIO.iter().position(|x| x.eq_ignore_ascii_case(name))
+}
+
+/// Compare the Visual Studio versions `a` and `b`, as given by `toolchain`
+pub fn older_than(a: &str, b: &str) -> bool
+{
+    match (version_index(a), version_index(b)) {
+        (Some(a), Some(b)) => a < b,
+        _ => false,
+    }
+}
+
+/// Decode the Rich header out of `stub`, the start of the file up to the PE
+/// header. Images without one give `None`.
+pub fn decode(stub: &[u8]) -> Result<Option<RichHeader>, Box<dyn Error>>
+{
+    /* The `Rich` marker is dword aligned and followed by the key, with the
+     * header growing backwards from it to `DanS` */
+    let rich = match (0x40..stub.len().saturating_sub(7)).step_by(4).rev()
+            .find(|&x| le32(stub, x) == Some(RICH)) {
+        Some(rich) => rich,
+        None => return Ok(None),
+    };
+    let key = le32(stub, rich + 4).ok_or("Rich header truncated")?;
+    let dans = (0x40..rich).step_by(4).rev()
+        .find(|&x| le32(stub, x).map(|x| x ^ key) == Some(DANS))
+        .ok_or("Rich header has no DanS marker")?;
+
+    /* `DanS` is followed by three padding dwords, then the entries */
+    let start = dans + 16;
+    if start > rich || (rich - start) % 8 != 0 {
+        return Err("Rich header entries truncated".into());
+    }
+    let mut entries = Vec::new();
+    for x in (start..rich).step_by(8) {
+        let (id, count) = match (le32(stub, x), le32(stub, x + 4)) {
+            (Some(id), Some(count)) => (id ^ key, count ^ key),
+            _ => return Err("Rich header entries truncated".into()),
+        };
+        entries.push(Entry {
+            product: (id >> 16) as u16,
+            build:   id as u16,
+            count,
+        });
+    }
+
+    /* The checksum adds up the bytes before `DanS`, other than e_lfanew,
+     * each rotated by its offset, then every entry rotated by its count */
+    let mut checksum = dans as u32;
+    for (ii, &byte) in stub[..dans].iter().enumerate() {
+        if (E_LFANEW..E_LFANEW + 4).contains(&ii) {
+            continue;
+        }
+        checksum = checksum.wrapping_add((byte as u32).rotate_left(ii as u32));
+    }
+    for entry in &entries {
+        let id = (entry.product as u32) << 16 | entry.build as u32;
+        checksum = checksum.wrapping_add(id.rotate_left(entry.count));
+    }
+
+    Ok(Some(RichHeader {
+        offset: dans as u32,
+        key,
+        checksum_valid: checksum == key,
+        entries,
+    }))
+}
+
+/// Read the Rich header of `image` from `fd`. Images without one give
+/// `None`.
+pub fn read<R: Read + Seek>(image: &PeFile, fd: &mut R) ->
+    Result<Option<RichHeader>, Box<dyn Error>>
+{
+    let end = match image.mz {
+        Some(mz) => {mz.new_header}.min(MAX_STUB_SIZE),
+        None => return Ok(None),
+    };
+
+    let mut stub = Vec::new();
+    fd.seek(SeekFrom::Start(0))?;
+    fd.by_ref().take(end as u64).read_to_end(&mut stub)?;
+    decode(&stub)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build an MZ header and stub holding a Rich header of `entries`
+    /// (product, build, count) at 0x80, keyed with its checksum
+    fn stub(entries: &[(u16, u16, u32)]) -> Vec<u8>
+    {
+        let mut stub: Vec<u8> = (0..0x80u32).map(|x| (x * 7) as u8).collect();
+        stub[..2].copy_from_slice(b"MZ");
+        stub[E_LFANEW..E_LFANEW + 4].copy_from_slice(&0x200u32.to_le_bytes());
+
+        let mut key = 0x80u32;
+        for (ii, &byte) in stub.iter().enumerate() {
+            if !(E_LFANEW..E_LFANEW + 4).contains(&ii) {
+                key = key.wrapping_add((byte as u32).rotate_left(ii as u32));
+            }
+        }
+        let ids: Vec<(u32, u32)> = entries.iter()
+            .map(|&(product, build, count)| {
+                ((product as u32) << 16 | build as u32, count)
+            }).collect();
+        for &(id, count) in &ids {
+            key = key.wrapping_add(id.rotate_left(count));
+        }
+
+        let mut dwords = vec![DANS, 0, 0, 0];
+        for &(id, count) in &ids {
+            dwords.extend_from_slice(&[id, count]);
+        }
+        for dword in dwords {
+            stub.extend_from_slice(&(dword ^ key).to_le_bytes());
+        }
+        stub.extend_from_slice(&RICH.to_le_bytes());
+        stub.extend_from_slice(&key.to_le_bytes());
+        stub.resize(0x200, 0);
+        stub
+    }
+
+    const ENTRIES: [(u16, u16, u32); 4] = [
+        (0x0001, 0, 5), (0x0093, 30729, 3), (0x0105, 24215, 40),
+        (0x0102, 30133, 1),
+    ];
+
+    #[test]
+    fn decode_entries()
+    {
+        let rich = decode(&stub(&ENTRIES)).unwrap().unwrap();
+        assert_eq!(rich.offset, 0x80);
+        assert!(rich.checksum_valid);
+        let got: Vec<_> = rich.entries.iter()
+            .map(|x| (x.name(), x.build, x.count, x.visual_studio()))
+            .collect();
+        assert_eq!(got, [
+            ("Import0".to_string(), 0, 5, None),
+            ("Implib900".to_string(), 30729, 3, Some("VS2008")),
+            ("Utc1900_CPP".to_string(), 24215, 40, Some("VS2015")),
+            ("Linker1400".to_string(), 30133, 1, Some("VS2022")),
+        ]);
+        assert_eq!(rich.linker().unwrap().product, 0x0102);
+        assert_eq!((rich.toolchain(), rich.oldest()),
+                   (Some("VS2022"), Some("VS2008")));
+        assert!(rich.json().contains(
+            "{\"product\":258,\"name\":\"Linker1400\",\"build\":30133,\
+             \"count\":1,\"visual_studio\":\"VS2022\"}"));
+
+        /* Without a linker the newest tool gives the toolchain */
+        let rich = decode(&stub(&ENTRIES[..3])).unwrap().unwrap();
+        assert!(rich.linker().is_none());
+        assert_eq!(rich.toolchain(), Some("VS2015"));
+        assert_eq!(Entry { product: 0x2000, build: 0, count: 0 }.name(),
+                   "Unknown_2000");
+
+        /* Products past the known ones can't be placed */
+        let entry = |product| Entry { product, build: 30133, count: 1 };
+        assert_eq!(entry(0x010e).visual_studio(), Some("VS2022"));
+        assert_eq!(entry(0x010f).visual_studio(), None);
+        assert_eq!(entry(0x2000).visual_studio(), None);
+    }
+
+    #[test]
+    fn checksum_and_bad_headers()
+    {
+        /* Edited entries no longer match the checksum */
+        let mut data = stub(&ENTRIES);
+        data[0x94] ^= 1;
+        let rich = decode(&data).unwrap().unwrap();
+        assert!(!rich.checksum_valid);
+        assert_eq!(rich.entries[0].count, 4);
+
+        assert!(decode(&stub(&[])[..0x80]).unwrap().is_none());
+        let mut data = stub(&ENTRIES);
+        data[0x80] ^= 1;
+        assert!(decode(&data).is_err());
+
+        /* An odd number of dwords between DanS and Rich */
+        let mut data = stub(&[]);
+        let key = le32(&data, 0x94).unwrap();
+        data.splice(0x90..0x90, (key ^ 0x1234).to_le_bytes());
+        assert!(decode(&data).is_err());
+
+        for len in 0..0x100 {
+            let _ = decode(&stub(&ENTRIES)[..len]);
+        }
+    }
+
+    #[test]
+    fn compare_versions()
+    {
+        assert_eq!(version_index("vs2015"), Some(9));
+        assert!(version_index("VS2016").is_none());
+        assert!(older_than("VS2008", "vs2010"));
+        assert!(!older_than("VS2010", "VS2010"));
+        assert!(!older_than("VS2008", "VS2016"));
+    }
+}
@@ -0,0 +1,447 @@
+//! Minimal HTTP server exposing a symbol store or filestore to debuggers.
+//!
+//! Requests are symsrv style `/<name>/<key>/<file>` paths, looked up without
+//! regard to case. A missing file is served by expanding its compressed
+//! `.pd_` style counterpart, or by following the `file.ptr` of the entry.
+//! Misses may be fetched from (and cached out of) an upstream server.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use cab;
+use download;
+use manifest::Kind;
+use store;
+
+/// Longest request line plus headers accepted
+const MAX_REQUEST_SIZE: u64 = 16 * 1024;
+
+/// How long a client may stall reading or writing before it is dropped
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of requests handled at once, further connections are refused
+const MAX_CONNECTIONS: usize = 64;
+
+/// Settings shared by every connection
+struct Server {
+    /// Root of the store being served, canonicalized
+    root: PathBuf,
+
+    /// Server to fetch and cache misses from, eg.
+    /// `https://msdl.microsoft.com/download/symbols`
+    upstream: Option<String>,
+
+    /// Case of files cached from upstream
+    case: store::Case,
+
+    /// Number of connections currently being handled
+    connections: AtomicUsize,
+}
+
+/// A connection being handled. Gives its slot back when the handling thread
+/// is done with it, even if the thread panics.
+struct Connection(Arc<Server>);
+
+impl Drop for Connection {
+    fn drop(&mut self)
+    {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// What a request resolved to
+enum Response {
+    /// Contents of a file on disk
+    File(PathBuf),
+
+    /// Contents expanded into memory
+    Data(Vec<u8>),
+
+    /// Redirect to another URL
+    Redirect(String),
+
+    NotFound,
+}
+
+/// Decode `%XX` escapes in a URL path
+fn percent_decode(path: &str) -> String
+{
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut ii = 0;
+    while ii < bytes.len() {
+        let hex = bytes.get(ii + 1..ii + 3)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (bytes[ii], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                ii += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                ii += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Split a request path into its `<name>/<key>/<file>` components. Anything
+/// which could escape the store is rejected.
+fn parse_path(path: &str) -> Option<(String, String, String)>
+{
+    let path = path.split('?').next().unwrap_or("");
+    let path = percent_decode(path).replace('\\', "/");
+
+    let parts: Vec<&str> = path.split('/').filter(|x| !x.is_empty()).collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    for part in &parts {
+        if *part == "." || *part == ".." || part.contains(':') ||
+                part.contains('\0') {
+            return None;
+        }
+    }
+
+    Some((parts[0].into(), parts[1].into(), parts[2].into()))
+}
+
+/// Follow the `file.ptr` at `ptr`, which holds either `PATH:<location>` or
+/// `MSG:<reason the file is unavailable>`. Local paths are only served if
+/// they lie inside the store at `root`, so a pointer can not be used to
+/// read arbitrary files off the machine.
+fn follow_ptr(root: &Path, ptr: &Path) -> Response
+{
+    let contents = match fs::read_to_string(ptr) {
+        Ok(contents) => contents,
+        Err(_) => return Response::NotFound,
+    };
+
+    match contents.trim().strip_prefix("PATH:") {
+        Some(url) if url.starts_with("http://") ||
+                     url.starts_with("https://") =>
+            Response::Redirect(url.into()),
+        Some(path) => match fs::canonicalize(path) {
+            Ok(path) if path.starts_with(root) && path.is_file() =>
+                Response::File(path),
+            _ => Response::NotFound,
+        },
+        None => Response::NotFound,
+    }
+}
+
+/// Look up `file` of the entry `name` with `key` in the store at `root`
+fn lookup(root: &Path, name: &str, key: &str, file: &str) -> Response
+{
+    let mut index = store::DirIndex::new();
+    let dir = match index.find_entry_dir(root, name, key) {
+        Some(dir) => dir,
+        None => return Response::NotFound,
+    };
+
+    if let Some(path) = index.find(&dir, file) {
+        return Response::File(path);
+    }
+
+    /* Expand the compressed file in place of the original */
+    if let Some(path) = index.find(&dir, &cab::compressed_name(file)) {
+        if let Ok(data) = File::open(&path)
+                .and_then(|mut fd| cab::extract(&mut fd, None)
+                          .map_err(|err| io::Error::other(err.to_string()))) {
+            return Response::Data(data.1);
+        }
+    }
+
+    match index.find(&dir, "file.ptr") {
+        Some(ptr) => follow_ptr(root, &ptr),
+        None => Response::NotFound,
+    }
+}
+
+/// Fetch `file` of the entry `name` with `key` from `upstream` into the
+/// store being served. Returns the path it was cached at.
+fn fetch(server: &Server, upstream: &str, name: &str, key: &str, file: &str)
+    -> Result<PathBuf, Box<dyn Error>>
+{
+    /* Only pass on requests which could be in a manifest, anything else
+     * would end up in the URL or the store unchecked */
+    if !download::valid_name(name) || !download::valid_name(file) {
+        return Err(format!("Not fetching invalid name {:?}", name).into());
+    }
+    Kind::from_name(name).check_key(key)?;
+
+    let root = &server.root;
+    let layout = store::detect_layout(root);
+    let dir = store::DirIndex::new()
+        .entry_dir(root, layout, server.case, name, key);
+    fs::create_dir_all(&dir)?;
+
+    let dest = dir.join(server.case.name(file));
+    let url = format!("{}/{}/{}/{}", upstream.trim_end_matches('/'),
+                      name, key, file);
+
+    match download::fetch(&url, &dest) {
+        Ok(true) => Ok(dest),
+        Ok(false) => {
+            store::remove_empty_dirs(root, &dir);
+            Err(format!("{} not found", url).into())
+        }
+        Err(err) => {
+            store::remove_empty_dirs(root, &dir);
+            Err(err)
+        }
+    }
+}
+
+/// Write the status line and headers of a response
+fn write_head(stream: &mut TcpStream, status: &str, headers: &[String]) ->
+    io::Result<()>
+{
+    write!(stream, "HTTP/1.1 {}\r\nConnection: close\r\n", status)?;
+    for header in headers {
+        write!(stream, "{}\r\n", header)?;
+    }
+    write!(stream, "\r\n")
+}
+
+/// Serve a single request on `stream`
+fn handle(mut stream: TcpStream, server: &Server) -> io::Result<()>
+{
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    /* Bound how much a client can make us buffer */
+    let mut reader =
+        BufReader::new(stream.try_clone()?.take(MAX_REQUEST_SIZE));
+
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    /* Skip the headers, nothing in them matters to us */
+    let mut complete = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            break;
+        }
+        if header.trim().is_empty() {
+            complete = true;
+            break;
+        }
+    }
+    if !complete {
+        return write_head(&mut stream, "400 Bad Request",
+                          &["Content-Length: 0".into()]);
+    }
+
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+    if method != "GET" && method != "HEAD" {
+        return write_head(&mut stream, "405 Method Not Allowed", &[]);
+    }
+
+    let mut response = match parse_path(path) {
+        Some((ref name, ref key, ref file)) => {
+            match lookup(&server.root, name, key, file) {
+                Response::NotFound if server.upstream.is_some() => {
+                    let upstream = server.upstream.as_ref().unwrap();
+                    match fetch(server, upstream, name, key, file) {
+                        Ok(path) => Response::File(path),
+                        Err(_) => Response::NotFound,
+                    }
+                }
+                response => response,
+            }
+        }
+        None => Response::NotFound,
+    };
+
+    let status = match response {
+        Response::File(_) | Response::Data(_) => "200",
+        Response::Redirect(_) => "302",
+        Response::NotFound => "404",
+    };
+    println!("{} {} {}", method, path, status);
+
+    let (len, mut body): (u64, Box<dyn Read>) = match response {
+        Response::File(ref path) => {
+            let fd = File::open(path)?;
+            (fd.metadata()?.len(), Box::new(fd))
+        }
+        Response::Data(ref mut data) => {
+            let data = std::mem::take(data);
+            (data.len() as u64, Box::new(io::Cursor::new(data)))
+        }
+        Response::Redirect(url) => {
+            return write_head(&mut stream, "302 Found",
+                              &[format!("Location: {}", url),
+                                "Content-Length: 0".into()]);
+        }
+        Response::NotFound => {
+            return write_head(&mut stream, "404 Not Found",
+                              &["Content-Length: 0".into()]);
+        }
+    };
+
+    write_head(&mut stream, "200 OK",
+               &[format!("Content-Length: {}", len),
+                 "Content-Type: application/octet-stream".into()])?;
+    if method == "GET" {
+        io::copy(&mut body, &mut stream)?;
+    }
+    Ok(())
+}
+
+/// Serve the store at `root` on `addr` forever
+pub fn serve(root: &Path, addr: &str, upstream: Option<String>,
+             case: store::Case) -> Result<(), Box<dyn Error>>
+{
+    let listener = TcpListener::bind(addr)?;
+    println!("Serving {:?} on http://{}/", root, listener.local_addr()?);
+
+    let server = Arc::new(Server {
+        root: fs::canonicalize(root)?,
+        upstream,
+        case,
+        connections: AtomicUsize::new(0),
+    });
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+
+        if server.connections.fetch_add(1, Ordering::SeqCst) >=
+                MAX_CONNECTIONS {
+            server.connections.fetch_sub(1, Ordering::SeqCst);
+            let _ = stream.set_write_timeout(Some(TIMEOUT));
+            let _ = write_head(&mut stream, "503 Service Unavailable",
+                               &["Content-Length: 0".into()]);
+            continue;
+        }
+
+        let conn = Connection(server.clone());
+        thread::spawn(move || {
+            let _ = handle(stream, &conn.0);
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty scratch directory for the test `name`
+    fn scratch(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("pdblister-{}-{}", name,
+                                                     std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(dir).unwrap()
+    }
+
+    #[test]
+    fn request_paths()
+    {
+        let entry = Some(("ntdll.pdb".to_string(), "AB1".to_string(),
+                          "ntdll.pdb".to_string()));
+        assert_eq!(parse_path("/ntdll.pdb/AB1/ntdll.pdb"), entry);
+        assert_eq!(parse_path("//ntdll.pdb/AB1/ntdll.pdb?x=/a/b"), entry);
+        assert_eq!(parse_path("/ntdll%2epdb/AB1\\ntdll.pdb"), entry);
+
+        for path in &["/ntdll.pdb/AB1", "/a/ntdll.pdb/AB1/ntdll.pdb",
+                      "/../AB1/ntdll.pdb", "/ntdll.pdb/%2e%2e/ntdll.pdb",
+                      "/ntdll.pdb/..\\..\\ntdll.pdb", "/ntdll.pdb/AB1/.",
+                      "/C:/Windows/win.ini", "/ntdll.pdb/AB1/a%00b",
+                      "/ntdll.pdb/AB1/..%5c..%5cwin.ini"] {
+            assert_eq!(parse_path(path), None, "{}", path);
+        }
+    }
+
+    fn describe(res: Response) -> String
+    {
+        match res {
+            Response::File(path) => format!("file {}", path.display()),
+            Response::Data(data) => format!("data {}", data.len()),
+            Response::Redirect(url) => format!("redirect {}", url),
+            Response::NotFound => "not found".into(),
+        }
+    }
+
+    #[test]
+    fn pointers_stay_in_the_store()
+    {
+        let dir = scratch("serve-ptr");
+        let root = dir.join("store");
+        let entry = root.join("ntdll.pdb").join("AB1");
+        fs::create_dir_all(&entry).unwrap();
+        fs::write(root.join("inside.pdb"), b"inside").unwrap();
+        fs::write(dir.join("outside.pdb"), b"outside").unwrap();
+
+        let follow = |contents: String| {
+            fs::write(entry.join("file.ptr"), contents).unwrap();
+            describe(lookup(&root, "NTDLL.PDB", "ab1", "ntdll.pdb"))
+        };
+        let inside = root.join("inside.pdb");
+        assert_eq!(follow(format!("PATH:{}", inside.display())),
+                   format!("file {}", inside.display()));
+        assert_eq!(follow(format!("PATH:{}\r\n", inside.display())),
+                   format!("file {}", inside.display()));
+        assert_eq!(follow(format!("PATH:{}", dir.join("outside.pdb")
+                                  .display())), "not found");
+        assert_eq!(follow(format!("PATH:{}", root.join("..")
+                                  .join("outside.pdb").display())),
+                   "not found");
+        assert_eq!(follow(format!("PATH:{}", root.display())), "not found");
+        assert_eq!(follow("PATH:https://example.com/ntdll.pdb".into()),
+                   "redirect https://example.com/ntdll.pdb");
+        assert_eq!(follow("MSG:Withdrawn".into()), "not found");
+
+        /* The file itself wins over the pointer */
+        fs::write(entry.join("ntdll.pdb"), b"pdb").unwrap();
+        assert_eq!(follow("MSG:Withdrawn".into()),
+                   format!("file {}", entry.join("ntdll.pdb").display()));
+        assert_eq!(describe(lookup(&root, "ntdll.pdb", "AB2", "ntdll.pdb")),
+                   "not found");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetches_are_validated()
+    {
+        let root = scratch("serve-fetch");
+        let server = Server {
+            root:        root.clone(),
+            upstream:    None,
+            case:        store::Case::Preserve,
+            connections: AtomicUsize::new(0),
+        };
+        let fetch = |name: &str, key: &str, file: &str| {
+            fetch(&server, "http://127.0.0.1:9", name, key, file)
+                .unwrap_err().to_string()
+        };
+        assert_eq!(fetch("..", "AB1", "ntdll.pdb"),
+                   "Not fetching invalid name \"..\"");
+        assert!(fetch("ntdll.pdb", "AB1", "a b").starts_with("Not fetching"));
+        assert!(!fetch("ntdll.pdb", "../AB1", "ntdll.pdb").is_empty());
+        assert!(!fetch("ntdll.dll", "XYZ", "ntdll.dll").is_empty());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
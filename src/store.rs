@@ -0,0 +1,576 @@
+//! Layout of symbol stores and filestores on disk.
+//!
+//! Every entry of a store lives at `<name>/<key>/<file>`, where `<file>` is
+//! either the file itself or its compressed `.dl_` style counterpart. Large
+//! stores use a two-tier layout, marked by an `index2.txt` file in the root
+//! of the store, which prefixes every entry with the first two characters
+//! of its name: `<na>/<name>/<key>/<file>`.
+//!
+//! Windows hosted stores are case insensitive, so names and keys found in
+//! the wild come in every mix of case. Lookups ignore case, and new entries
+//! are put in existing directories which only differ in case rather than
+//! growing duplicates on case sensitive file systems.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use rand::{thread_rng, Rng};
+
+use symstore::ADMIN_DIR;
+
+/// Marker file for a two-tier store
+const TWO_TIER_MARKER: &str = "index2.txt";
+
+/// Directory in the root of a store holding the `<name>` directories while
+/// it is converted between layouts
+const STAGING_DIR: &str = ".migrating";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Layout {
+    /// `<name>/<key>/<file>`
+    Flat,
+
+    /// `<na>/<name>/<key>/<file>`, marked by `index2.txt`
+    TwoTier,
+}
+
+/// How names and keys are cased when creating new store directories
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Case {
+    /// As they were given
+    Preserve,
+
+    /// As symsrv does, lowercase names and uppercase keys
+    Symsrv,
+}
+
+impl Case {
+    /// Apply this to the name of an entry
+    pub fn name(self, name: &str) -> String
+    {
+        match self {
+            Case::Preserve => name.to_string(),
+            Case::Symsrv   => name.to_lowercase(),
+        }
+    }
+
+    /// Apply this to the key of an entry
+    pub fn key(self, key: &str) -> String
+    {
+        match self {
+            Case::Preserve => key.to_string(),
+            Case::Symsrv   => key.to_uppercase(),
+        }
+    }
+}
+
+/// A single file stored in a store
+pub struct Entry {
+    /// Name of the entry, eg. `ntdll.dll`
+    pub name: String,
+
+    /// Key of the entry, eg. `62ee0d0121000`
+    pub key: String,
+
+    /// Full path to the file stored for this entry
+    pub path: PathBuf,
+}
+
+/// Detect the layout of the store at `root`
+pub fn detect_layout(root: &Path) -> Layout
+{
+    if root.join(TWO_TIER_MARKER).is_file() {
+        Layout::TwoTier
+    } else {
+        Layout::Flat
+    }
+}
+
+/// Get the two-tier prefix directory for an entry named `name`
+fn tier_prefix(name: &str) -> String
+{
+    name.chars().take(2).collect()
+}
+
+/// Children of directories by lowercased name. Every directory is only
+/// listed the first time something is looked up in it, so keep one of these
+/// around while working through many entries of a store.
+///
+/// Paths handed out for children which do not exist yet are remembered, so
+/// entries created through the index are found again whatever their case.
+#[derive(Default)]
+pub struct DirIndex {
+    dirs: HashMap<PathBuf, HashMap<String, Vec<String>>>,
+}
+
+impl DirIndex {
+    pub fn new() -> DirIndex
+    {
+        DirIndex::default()
+    }
+
+    /// Get the listing of `dir`, reading it if it has not been yet
+    fn listing(&mut self, dir: &Path) -> &mut HashMap<String, Vec<String>>
+    {
+        self.dirs.entry(dir.to_path_buf()).or_insert_with(|| {
+            let mut listing: HashMap<String, Vec<String>> = HashMap::new();
+            if let Ok(entries) = dir.read_dir() {
+                for entry in entries.flatten() {
+                    let name = entry.file_name().to_string_lossy()
+                        .into_owned();
+                    listing.entry(name.to_lowercase()).or_default()
+                        .push(name);
+                }
+            }
+            listing
+        })
+    }
+
+    /// Find the child of `dir` named `name`, ignoring case. An exact match
+    /// is preferred.
+    pub fn find(&mut self, dir: &Path, name: &str) -> Option<PathBuf>
+    {
+        let names = self.listing(dir).get(&name.to_lowercase())?;
+        let found = names.iter().find(|x| *x == name).unwrap_or(&names[0]);
+        Some(dir.join(found))
+    }
+
+    /// Find the child of `dir` named `name` ignoring case, or the path it
+    /// should be created at if there is none
+    fn find_or_join(&mut self, dir: &Path, name: &str) -> PathBuf
+    {
+        if let Some(path) = self.find(dir, name) {
+            return path;
+        }
+        self.listing(dir).insert(name.to_lowercase(), vec![name.to_string()]);
+        dir.join(name)
+    }
+
+    /// Get the directory to hold the entry for `name` with `key` in the
+    /// store at `root`. Existing directories are used whatever their case,
+    /// any missing ones are named according to `case`.
+    pub fn entry_dir(&mut self, root: &Path, layout: Layout, case: Case,
+                     name: &str, key: &str) -> PathBuf
+    {
+        let name = case.name(name);
+        let key = case.key(key);
+
+        let mut path = root.to_path_buf();
+        if layout == Layout::TwoTier {
+            path = self.find_or_join(&path, &tier_prefix(&name));
+        }
+        path = self.find_or_join(&path, &name);
+        self.find_or_join(&path, &key)
+    }
+
+    /// Get the path of the uncompressed file for `name` with `key` in the
+    /// store at `root`, following the same rules as `entry_dir`
+    pub fn entry_path(&mut self, root: &Path, layout: Layout, case: Case,
+                      name: &str, key: &str) -> PathBuf
+    {
+        let dir = self.entry_dir(root, layout, case, name, key);
+        self.find_or_join(&dir, &case.name(name))
+    }
+
+    /// Find the directory holding the entry for `name` with `key` in the
+    /// store at `root`, ignoring the case of every component
+    pub fn find_entry_dir(&mut self, root: &Path, name: &str, key: &str) ->
+        Option<PathBuf>
+    {
+        let mut dir = root.to_path_buf();
+        if detect_layout(root) == Layout::TwoTier {
+            dir = self.find(&dir, &tier_prefix(name))?;
+        }
+        dir = self.find(&dir, name)?;
+        self.find(&dir, key).filter(|x| x.is_dir())
+    }
+}
+
+/// List the sub-directories of `path`, ignoring errors
+fn subdirs(path: &Path) -> Vec<PathBuf>
+{
+    let mut dirs = Vec::new();
+    if let Ok(listing) = path.read_dir() {
+        for entry in listing.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            }
+        }
+    }
+    dirs
+}
+
+/// Get the final component of `path` as a string
+fn file_name(path: &Path) -> String
+{
+    path.file_name().map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Collect the entries under the `<name>` directory `name_dir`
+fn list_name_dir(name_dir: &Path, entries: &mut Vec<Entry>)
+{
+    let name = file_name(name_dir);
+    for key_dir in subdirs(name_dir) {
+        let key = file_name(&key_dir);
+        if let Ok(listing) = key_dir.read_dir() {
+            for file in listing.flatten() {
+                let path = file.path();
+                if path.is_file() {
+                    entries.push(Entry {
+                        name: name.clone(),
+                        key: key.clone(),
+                        path,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// List every entry of the store at `root`, in whichever layout it uses
+pub fn list_entries(root: &Path) -> Vec<Entry>
+{
+    let layout = detect_layout(root);
+
+    let mut entries = Vec::new();
+    for dir in subdirs(root) {
+        let name = file_name(&dir);
+        if name == ADMIN_DIR || name == STAGING_DIR {
+            continue;
+        }
+
+        match layout {
+            Layout::Flat => list_name_dir(&dir, &mut entries),
+            Layout::TwoTier => {
+                for name_dir in subdirs(&dir) {
+                    list_name_dir(&name_dir, &mut entries);
+                }
+            }
+        }
+    }
+
+    entries
+}
+
+/// Get the total size of the files directly inside `dir`
+pub fn dir_size(dir: &Path) -> u64
+{
+    let mut size = 0;
+    if let Ok(listing) = dir.read_dir() {
+        for entry in listing.flatten() {
+            if let Ok(meta) = entry.metadata() {
+                if meta.is_file() {
+                    size += meta.len();
+                }
+            }
+        }
+    }
+    size
+}
+
+/// Get a temporary name next to `path` to download or write it under
+pub fn temp_path(path: &Path) -> PathBuf
+{
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{:08x}.tmp", thread_rng().gen::<u32>()));
+    path.with_file_name(name)
+}
+
+/// Write `data` to `path` through a temporary file which is synced before
+/// being renamed into place, so that `path` is either absent or complete
+/// even if we are interrupted or the machine goes down
+pub fn write_file(path: &Path, data: &[u8]) -> Result<(), Box<dyn Error>>
+{
+    let tmp = temp_path(path);
+    let res = File::create(&tmp)
+        .and_then(|mut fd| fd.write_all(data).and_then(|_| fd.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok(res?)
+}
+
+/// Delete `dir` and its parents up to the store at `root` for as long as
+/// they are empty
+pub fn remove_empty_dirs(root: &Path, dir: &Path)
+{
+    let mut parent = Some(dir);
+    while let Some(path) = parent {
+        if path == root || fs::remove_dir(path).is_err() {
+            break;
+        }
+        parent = path.parent();
+    }
+}
+
+/// Delete the `<name>/<key>` directory `dir` of the store at `root`, along
+/// with any `<name>` or prefix directories this leaves empty
+pub fn remove_entry_dir(root: &Path, dir: &Path) -> Result<(), Box<dyn Error>>
+{
+    fs::remove_dir_all(dir)?;
+    if let Some(parent) = dir.parent() {
+        remove_empty_dirs(root, parent);
+    }
+    Ok(())
+}
+
+/// Check whether the files `a` and `b` have the same contents
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool>
+{
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    let mut buf_a = [0u8; 8192];
+    let mut buf_b = [0u8; 8192];
+    loop {
+        let len = a.read(&mut buf_a)?;
+        if len == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..len])?;
+        if buf_a[..len] != buf_b[..len] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Move the directory `from` to `to`, merging into `to` if it already
+/// exists. Files already present in `to` are only dropped from `from` if
+/// they are identical, anything else is an error and left in place.
+fn move_dir(from: &Path, to: &Path) -> Result<(), Box<dyn Error>>
+{
+    if !to.exists() {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(from, to)?;
+        return Ok(());
+    }
+
+    for entry in from.read_dir()? {
+        let entry = entry?.path();
+        let dest = to.join(entry.file_name().unwrap());
+        if entry.is_dir() {
+            move_dir(&entry, &dest)?;
+        } else if !dest.exists() {
+            fs::rename(&entry, &dest)?;
+        } else if same_contents(&entry, &dest)? {
+            fs::remove_file(&entry)?;
+        } else {
+            return Err(format!("{:?} differs from {:?}", entry, dest).into());
+        }
+    }
+    fs::remove_dir(from)?;
+    Ok(())
+}
+
+/// Convert the store at `root` to `layout`. Returns the number of `<name>`
+/// directories which were moved.
+///
+/// Every `<name>` directory is first moved into a staging directory, so
+/// prefix directories and short names (which are their own prefix) can
+/// never collide. Only then is the layout marker changed and the staged
+/// directories moved to where they belong. An interrupted conversion leaves
+/// the staging directory behind and is picked up by the next one.
+pub fn migrate(root: &Path, layout: Layout) -> Result<usize, Box<dyn Error>>
+{
+    let current = detect_layout(root);
+    let staging = root.join(STAGING_DIR);
+    if current == layout && !staging.exists() {
+        return Ok(0);
+    }
+
+    if current != layout {
+        fs::create_dir_all(&staging)?;
+        for dir in subdirs(root) {
+            let name = file_name(&dir);
+            if name == ADMIN_DIR || name == STAGING_DIR {
+                continue;
+            }
+
+            let name_dirs = match current {
+                Layout::Flat    => vec![dir.clone()],
+                Layout::TwoTier => subdirs(&dir),
+            };
+            for name_dir in name_dirs {
+                move_dir(&name_dir, &staging.join(file_name(&name_dir)))?;
+            }
+
+            /* Prefix directories are empty now */
+            if current == Layout::TwoTier {
+                fs::remove_dir(&dir)?;
+            }
+        }
+
+        match layout {
+            Layout::TwoTier => fs::write(root.join(TWO_TIER_MARKER), b"")?,
+            Layout::Flat => match fs::remove_file(root.join(TWO_TIER_MARKER)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound =>
+                    return Err(err.into()),
+                _ => {}
+            },
+        }
+    }
+
+    let mut moved = 0;
+    for tmp in subdirs(&staging) {
+        let name = file_name(&tmp);
+        let dest = match layout {
+            Layout::TwoTier => root.join(tier_prefix(&name)).join(&name),
+            Layout::Flat    => root.join(&name),
+        };
+        move_dir(&tmp, &dest)?;
+        moved += 1;
+    }
+    fs::remove_dir(&staging)?;
+
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty scratch directory for the test `name`
+    fn scratch(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("pdblister-{}-{}", name,
+                                                     std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn write_file_replaces_atomically()
+    {
+        let dir = scratch("write-file");
+        let path = dir.join("ntdll.pd_");
+        write_file(&path, b"old").unwrap();
+        write_file(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+
+        /* No temporary files are left behind, even on failure */
+        assert!(write_file(&dir.join("missing").join("x"), b"").is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Write `data` to `rel` under `root`, creating its directories
+    fn put(root: &Path, rel: &str, data: &[u8])
+    {
+        let path = root.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    /// Sorted `<name>/<key>` pairs of every entry of the store at `root`
+    fn keys(root: &Path) -> Vec<String>
+    {
+        let mut keys: Vec<String> = list_entries(root).iter()
+            .map(|x| format!("{}/{}", x.name, x.key))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn migrate_round_trip()
+    {
+        let root = scratch("migrate");
+        put(&root, "ntdll.dll/1234abcd5000/ntdll.dll", b"ntdll");
+        put(&root, "ntdll.pdb/ABCD1/ntdll.pd_", b"pdb");
+        put(&root, "a/1/a", b"short");
+        put(&root, "000Admin/history.txt", b"history");
+        let before = keys(&root);
+        assert_eq!(before.len(), 3);
+
+        assert_eq!(migrate(&root, Layout::TwoTier).unwrap(), 3);
+        assert_eq!(detect_layout(&root), Layout::TwoTier);
+        assert!(root.join("nt/ntdll.pdb/ABCD1/ntdll.pd_").is_file());
+        assert!(root.join("a/a/1/a").is_file());
+        assert_eq!(keys(&root), before);
+
+        /* Converting to the layout already in use does nothing */
+        assert_eq!(migrate(&root, Layout::TwoTier).unwrap(), 0);
+
+        assert_eq!(migrate(&root, Layout::Flat).unwrap(), 3);
+        assert_eq!(detect_layout(&root), Layout::Flat);
+        assert!(root.join("a/1/a").is_file());
+        assert_eq!(keys(&root), before);
+        assert_eq!(fs::read(root.join("000Admin/history.txt")).unwrap(),
+                   b"history");
+        assert!(!root.join(STAGING_DIR).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn migrate_resumes_and_merges()
+    {
+        /* Interrupted after the marker was written, with one directory
+         * moved and one still staged which overlaps it */
+        let root = scratch("migrate-resume");
+        put(&root, TWO_TIER_MARKER, b"");
+        put(&root, "nt/ntdll.dll/1/ntdll.dll", b"same");
+        put(&root, ".migrating/ntdll.dll/1/ntdll.dll", b"same");
+        put(&root, ".migrating/ntdll.dll/2/ntdll.dll", b"other");
+
+        assert_eq!(migrate(&root, Layout::TwoTier).unwrap(), 1);
+        assert_eq!(keys(&root), ["ntdll.dll/1", "ntdll.dll/2"]);
+        assert!(!root.join(STAGING_DIR).exists());
+
+        /* Differing files are never overwritten */
+        put(&root, ".migrating/ntdll.dll/1/ntdll.dll", b"changed");
+        assert!(migrate(&root, Layout::TwoTier).is_err());
+        assert_eq!(fs::read(root.join("nt/ntdll.dll/1/ntdll.dll")).unwrap(),
+                   b"same");
+        assert!(root.join(".migrating/ntdll.dll/1/ntdll.dll").is_file());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn entries_reuse_directories_of_any_case()
+    {
+        let root = scratch("case");
+        put(&root, "NTDLL.DLL/1234ABCD5000/NTDLL.DLL", b"ntdll");
+
+        let mut index = DirIndex::new();
+        let path = index.entry_path(&root, Layout::Flat, Case::Symsrv,
+                                    "ntdll.dll", "1234abcd5000");
+        assert_eq!(path, root.join("NTDLL.DLL/1234ABCD5000/NTDLL.DLL"));
+        assert_eq!(index.find_entry_dir(&root, "NtDll.dll", "1234AbCd5000"),
+                   Some(root.join("NTDLL.DLL/1234ABCD5000")));
+
+        /* New entries are cased as asked, and found again through the
+         * index before they exist */
+        let path = index.entry_path(&root, Layout::Flat, Case::Symsrv,
+                                    "Kernel32.dll", "abc");
+        assert_eq!(path, root.join("kernel32.dll/ABC/kernel32.dll"));
+        let again = index.entry_path(&root, Layout::Flat, Case::Preserve,
+                                     "KERNEL32.DLL", "Abc");
+        assert_eq!(again, path);
+
+        /* Exact matches win over other cases */
+        put(&root, "ntdll.dll/1/ntdll.dll", b"lower");
+        let mut index = DirIndex::new();
+        assert_eq!(index.find(&root, "ntdll.dll"),
+                   Some(root.join("ntdll.dll")));
+        assert_eq!(index.find(&root, "NTDLL.DLL"),
+                   Some(root.join("NTDLL.DLL")));
+        assert_eq!(index.find(&root, "missing"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+//! The `000Admin` transaction history symstore keeps in the root of a store.
+//!
+//! Every `symstore add` is a transaction with a ten digit ID. The files it
+//! added are listed in `000Admin/<ID>` as `"<name>\<key>","<source path>"`
+//! lines, and the transaction itself is recorded in `server.txt` (live
+//! transactions) and `history.txt` (everything ever done to the store).
+//! `lastid.txt` holds the most recently used ID.
+//!
+//! Deleting a transaction removes its line from `server.txt` and records a
+//! new `<ID>,del,<deleted ID>` transaction in `history.txt`. The listing of
+//! the deleted transaction is kept.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Directory symstore keeps its transaction history in
+pub const ADMIN_DIR: &str = "000Admin";
+
+/// Live transactions
+const SERVER_FILE: &str = "server.txt";
+
+/// Every transaction, including deletions
+const HISTORY_FILE: &str = "history.txt";
+
+/// Most recently used transaction ID
+const LASTID_FILE: &str = "lastid.txt";
+
+/// Created by symstore in the root of every store it writes to
+const PING_FILE: &str = "pingme.txt";
+
+/// An `add` transaction being built up
+pub struct Transaction {
+    /// ID this transaction will be committed under
+    pub id: u32,
+
+    product: String,
+    version: String,
+    comment: String,
+
+    /// `<name>\<key>` and source path of every file in the transaction
+    files: Vec<(String, PathBuf)>,
+}
+
+/// Format the current time the way symstore does, `MM/DD/YYYY` and
+/// `HH:MM:SS`. There is no timezone database to consult so this is UTC.
+fn timestamp() -> (String, String)
+{
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs()).unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let secs = secs % 86400;
+
+    /* Convert days since the epoch to a civil date */
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (format!("{:02}/{:02}/{:04}", month, day, year),
+     format!("{:02}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60))
+}
+
+/// Append `line` to the file at `path`, creating it if needed
+fn append_line(path: &Path, line: &str) -> Result<(), Box<dyn Error>>
+{
+    let mut fd = OpenOptions::new().create(true).append(true).open(path)?;
+    write!(fd, "{}\r\n", line)?;
+    Ok(())
+}
+
+/// Get the most recently used transaction ID of the store at `root`, or 0 if
+/// it has no history
+pub fn last_id(root: &Path) -> Result<u32, Box<dyn Error>>
+{
+    let path = root.join(ADMIN_DIR).join(LASTID_FILE);
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let id = fs::read_to_string(path)?;
+    Ok(id.trim().parse().map_err(|_| "Invalid lastid.txt")?)
+}
+
+/// Format a transaction ID the way it appears in file names and listings
+fn format_id(id: u32) -> String
+{
+    format!("{:010}", id)
+}
+
+/// Get the IDs of every live transaction of the store at `root`
+pub fn live_transactions(root: &Path) -> Result<Vec<u32>, Box<dyn Error>>
+{
+    let path = root.join(ADMIN_DIR).join(SERVER_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut ids = Vec::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let mut fields = line.split(',');
+        let id = fields.next().unwrap_or("").trim();
+        if id.is_empty() {
+            continue;
+        }
+        if fields.next() == Some("add") {
+            ids.push(id.parse().map_err(|_| "Invalid server.txt")?);
+        }
+    }
+    Ok(ids)
+}
+
+/// Get the `(name, key)` of every file listed in transaction `id` of the
+/// store at `root`
+pub fn transaction_files(root: &Path, id: u32) ->
+    Result<Vec<(String, String)>, Box<dyn Error>>
+{
+    let path = root.join(ADMIN_DIR).join(format_id(id));
+    let listing = fs::read_to_string(path)
+        .map_err(|_| format!("No such transaction {}", format_id(id)))?;
+
+    let mut files = Vec::new();
+    for line in listing.lines() {
+        /* First quoted field is `<name>\<key>` */
+        let entry = line.trim_start_matches('"').split('"').next()
+            .unwrap_or("");
+        if let Some((name, key)) = entry.split_once('\\') {
+            files.push((name.to_string(), key.to_string()));
+        }
+    }
+    Ok(files)
+}
+
+/// Get the files of transaction `id` which no other live transaction of the
+/// store at `root` references, ie. the files deleting it would remove
+pub fn unreferenced_files(root: &Path, id: u32) ->
+    Result<Vec<(String, String)>, Box<dyn Error>>
+{
+    if !live_transactions(root)?.contains(&id) {
+        return Err(format!("Transaction {} is not live",
+                           format_id(id)).into());
+    }
+
+    let mut referenced = HashSet::new();
+    for other in live_transactions(root)? {
+        if other == id {
+            continue;
+        }
+
+        /* Listings of other transactions may have been removed by hand */
+        if let Ok(refs) = transaction_files(root, other) {
+            referenced.extend(refs);
+        }
+    }
+
+    let mut files = transaction_files(root, id)?;
+    files.retain(|x| !referenced.contains(x));
+
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Record the deletion of transaction `id` in the history of the store at
+/// `root`. Returns the ID of the deletion transaction.
+pub fn record_delete(root: &Path, id: u32) -> Result<u32, Box<dyn Error>>
+{
+    let admin = root.join(ADMIN_DIR);
+    let server = admin.join(SERVER_FILE);
+
+    let deleted = format_id(id);
+    let mut remaining = String::new();
+    for line in fs::read_to_string(&server)?.lines() {
+        if line.split(',').next() != Some(deleted.as_str()) {
+            remaining.push_str(line);
+            remaining.push_str("\r\n");
+        }
+    }
+    fs::write(&server, remaining)?;
+
+    let del_id = last_id(root)? + 1;
+    append_line(&admin.join(HISTORY_FILE),
+                &format!("{},del,{}", format_id(del_id), deleted))?;
+    fs::write(admin.join(LASTID_FILE), format_id(del_id))?;
+
+    Ok(del_id)
+}
+
+impl Transaction {
+    /// Start a new transaction for the store at `root`. Nothing is written
+    /// until it is committed.
+    pub fn new(root: &Path, product: &str, version: &str, comment: &str) ->
+        Result<Transaction, Box<dyn Error>>
+    {
+        for field in &[product, version, comment] {
+            if field.contains('"') || field.contains('\n') {
+                return Err("Transaction fields may not contain quotes or \
+                            newlines".into());
+            }
+        }
+
+        Ok(Transaction {
+            id:      last_id(root)? + 1,
+            product: product.into(),
+            version: version.into(),
+            comment: comment.into(),
+            files:   Vec::new(),
+        })
+    }
+
+    /// Record that `source` was stored as `name` with `key`
+    pub fn add(&mut self, name: &str, key: &str, source: &Path)
+    {
+        let source = fs::canonicalize(source)
+            .unwrap_or_else(|_| source.to_path_buf());
+        self.files.push((format!("{}\\{}", name, key), source));
+    }
+
+    /// Write this transaction into the history of the store at `root`
+    pub fn commit(self, root: &Path) -> Result<(), Box<dyn Error>>
+    {
+        let admin = root.join(ADMIN_DIR);
+        fs::create_dir_all(&admin)?;
+
+        let id = format_id(self.id);
+
+        let mut listing = String::new();
+        for (entry, source) in &self.files {
+            listing.push_str(&format!("\"{}\",\"{}\"\r\n",
+                                      entry, source.display()));
+        }
+        fs::write(admin.join(&id), listing)?;
+
+        let (date, time) = timestamp();
+        let line = format!("{},add,file,{},{},\"{}\",\"{}\",\"{}\",",
+                           id, date, time, self.product, self.version,
+                           self.comment);
+        append_line(&admin.join(SERVER_FILE), &line)?;
+        append_line(&admin.join(HISTORY_FILE), &line)?;
+        fs::write(admin.join(LASTID_FILE), &id)?;
+
+        let ping = root.join(PING_FILE);
+        if !ping.exists() {
+            fs::write(ping, b"")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty scratch directory for the test `name`
+    fn scratch(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("pdblister-{}-{}", name,
+                                                     std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn admin(root: &Path, name: &str) -> String
+    {
+        fs::read_to_string(root.join(ADMIN_DIR).join(name)).unwrap()
+    }
+
+    /// Commit a transaction adding `files`, giving its ID
+    fn commit(root: &Path, files: &[(&str, &str)]) -> u32
+    {
+        let mut transaction =
+            Transaction::new(root, "Windows", "10", "test").unwrap();
+        for (name, key) in files {
+            transaction.add(name, key, &root.join(name));
+        }
+        let id = transaction.id;
+        transaction.commit(root).unwrap();
+        id
+    }
+
+    #[test]
+    fn add_transactions()
+    {
+        let root = scratch("symstore-add");
+        assert_eq!(last_id(&root).unwrap(), 0);
+        assert!(live_transactions(&root).unwrap().is_empty());
+
+        assert_eq!(commit(&root, &[("ntdll.pdb", "AB1"),
+                                   ("ntdll.dll", "5F00A000")]), 1);
+        assert_eq!(commit(&root, &[("ntdll.pdb", "AB1")]), 2);
+        assert_eq!(last_id(&root).unwrap(), 2);
+        assert_eq!(live_transactions(&root).unwrap(), [1, 2]);
+        assert_eq!(admin(&root, LASTID_FILE), "0000000002");
+        assert!(root.join(PING_FILE).is_file());
+
+        let listing = admin(&root, "0000000001");
+        assert!(listing.starts_with("\"ntdll.pdb\\AB1\",\""));
+        assert!(listing.ends_with("ntdll.dll\"\r\n"));
+        assert_eq!(transaction_files(&root, 1).unwrap(),
+                   [("ntdll.pdb".to_string(), "AB1".to_string()),
+                    ("ntdll.dll".to_string(), "5F00A000".to_string())]);
+        assert!(transaction_files(&root, 3).is_err());
+
+        /* server.txt and history.txt agree until something is deleted */
+        let server = admin(&root, SERVER_FILE);
+        assert_eq!(server, admin(&root, HISTORY_FILE));
+        let lines: Vec<&str> = server.split("\r\n").collect();
+        assert_eq!(lines.len(), 3);
+        let fields: Vec<&str> = lines[1].split(',').collect();
+        assert_eq!(fields[..3], ["0000000002", "add", "file"]);
+        assert_eq!(fields[5..], ["\"Windows\"", "\"10\"", "\"test\"", ""]);
+        assert_eq!((fields[3].len(), fields[4].len()), (10, 8));
+
+        assert!(Transaction::new(&root, "a\"b", "", "").is_err());
+        assert!(Transaction::new(&root, "", "", "a\nb").is_err());
+
+        fs::write(root.join(ADMIN_DIR).join(LASTID_FILE), "x").unwrap();
+        assert!(last_id(&root).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+    #[test]
+    fn delete_transactions()
+    {
+        let root = scratch("symstore-delete");
+        commit(&root, &[("ntdll.pdb", "AB1"), ("ntdll.dll", "5F00A000"),
+                        ("ntdll.dll", "5F00A000")]);
+        commit(&root, &[("ntdll.pdb", "AB1")]);
+        commit(&root, &[("kernel32.dll", "5F00B000")]);
+
+        /* Files still used by another transaction are kept */
+        assert_eq!(unreferenced_files(&root, 1).unwrap(),
+                   [("ntdll.dll".to_string(), "5F00A000".to_string())]);
+        assert!(unreferenced_files(&root, 2).unwrap().is_empty());
+
+        /* Unless that transaction's listing is gone */
+        fs::remove_file(root.join(ADMIN_DIR).join("0000000002")).unwrap();
+        assert_eq!(unreferenced_files(&root, 1).unwrap().len(), 2);
+
+        assert_eq!(record_delete(&root, 2).unwrap(), 4);
+        assert_eq!(live_transactions(&root).unwrap(), [1, 3]);
+        assert_eq!(last_id(&root).unwrap(), 4);
+        assert!(unreferenced_files(&root, 2).is_err());
+        assert_eq!(unreferenced_files(&root, 1).unwrap().len(), 2);
+
+        let history = admin(&root, HISTORY_FILE);
+        assert!(history.ends_with("\r\n0000000004,del,0000000002\r\n"));
+        assert_eq!(history.lines().count(), 4);
+        assert_eq!(admin(&root, SERVER_FILE).lines().count(), 2);
+
+        /* Listings of deleted transactions are kept */
+        assert_eq!(record_delete(&root, 1).unwrap(), 5);
+        assert_eq!(transaction_files(&root, 1).unwrap().len(), 3);
+        assert_eq!(live_transactions(&root).unwrap(), [3]);
+        assert_eq!(commit(&root, &[]), 6);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
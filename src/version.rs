@@ -0,0 +1,432 @@
+//! Version resources of PE images.
+//!
+//! The VS_VERSIONINFO resource holds the file and product versions both as
+//! numbers, in a VS_FIXEDFILEINFO, and as text in StringFileInfo tables next
+//! to the product and company names. It is found by walking the resource
+//! directory: type RT_VERSION, then the first name and the first language.
+//!
+//! VS_VERSIONINFO is a tree of blocks, each of which is
+//! `<length> <value length> <type> <UTF-16 key> <value> <children>` with the
+//! value and every child aligned to 4 bytes.
+
+use std::error::Error;
+use std::io::{Read, Seek};
+
+use json;
+use pe::{PeFile, le16, le32};
+
+/// Index of the resource directory among the data directories
+const RESOURCE_DIRECTORY: usize = 2;
+
+/// Resource type of version resources
+const RT_VERSION: u32 = 16;
+
+/// Set in the offset of resource directory entries pointing at another
+/// directory rather than at data
+const RESOURCE_SUBDIRECTORY: u32 = 0x8000_0000;
+
+/// Signature of VS_FIXEDFILEINFO
+const VS_FFI_SIGNATURE: u32 = 0xfeef_04bd;
+
+/// Block lengths are 16 bits, so no valid version resource is larger
+const MAX_VERSION_SIZE: u32 = 0x10000;
+
+/// The VS_FIXEDFILEINFO of a version resource
+#[derive(Clone, Copy, PartialEq)]
+pub struct FixedFileInfo {
+    /// Major, minor, build and revision of the file
+    pub file_version: [u16; 4],
+
+    /// Major, minor, build and revision of the product the file is part of
+    pub product_version: [u16; 4],
+
+    pub file_flags_mask: u32,
+
+    /// VS_FF_* flags, eg. VS_FF_DEBUG or VS_FF_PRERELEASE
+    pub file_flags: u32,
+
+    /// VOS_* operating system the file was built for
+    pub file_os: u32,
+
+    /// VFT_* type of the file, eg. VFT_APP or VFT_DLL
+    pub file_type: u32,
+
+    /// VFT2_* subtype, for drivers and fonts
+    pub file_subtype: u32,
+
+    /// Creation date, which linkers leave as zero
+    pub file_date: u64,
+}
+
+/// A decoded VS_VERSIONINFO
+#[derive(Clone, PartialEq)]
+pub struct VersionInfo {
+    pub fixed: Option<FixedFileInfo>,
+
+    /// Key of the string table, the language and code page in 8 hex digits
+    /// (eg. `040904B0` for US English in UTF-16), empty if there is none
+    pub language: String,
+
+    /// Strings of the first string table, eg. `FileVersion`, `ProductName`,
+    /// `CompanyName` and `OriginalFilename`, in the order they are stored
+    pub strings: Vec<(String, String)>,
+}
+
+/// Format a version as `major.minor.build.revision`
+fn version_string(version: [u16; 4]) -> String
+{
+    format!("{}.{}.{}.{}", version[0], version[1], version[2], version[3])
+}
+
+impl VersionInfo {
+    /// Numeric version of the file, eg. `10.0.19041.1`
+    pub fn file_version(&self) -> Option<String>
+    {
+        self.fixed.map(|x| version_string(x.file_version))
+    }
+
+    /// Numeric version of the product the file is part of
+    pub fn product_version(&self) -> Option<String>
+    {
+        self.fixed.map(|x| version_string(x.product_version))
+    }
+
+    /// Format this as a JSON object. The fixed information is `null` when
+    /// the resource has none.
+    pub fn json(&self) -> String
+    {
+        let null = || "null".to_string();
+        let quoted = |val: Option<String>| {
+            val.map_or_else(null, |x| json::quote(&x))
+        };
+
+        let fixed = self.fixed.map_or_else(null, |fixed| {
+            json::object(&[
+                ("file_flags_mask", fixed.file_flags_mask.to_string()),
+                ("file_flags",      fixed.file_flags.to_string()),
+                ("file_os",         fixed.file_os.to_string()),
+                ("file_type",       fixed.file_type.to_string()),
+                ("file_subtype",    fixed.file_subtype.to_string()),
+                ("file_date",       fixed.file_date.to_string()),
+            ])
+        });
+
+        let strings: Vec<(&str, String)> = self.strings.iter()
+            .map(|(key, val)| (key.as_str(), json::quote(val)))
+            .collect();
+
+        json::object(&[
+            ("file_version",    quoted(self.file_version())),
+            ("product_version", quoted(self.product_version())),
+            ("fixed",           fixed),
+            ("language",        json::quote(&self.language)),
+            ("strings",         json::object(&strings)),
+        ])
+    }
+}
+
+fn align4(val: usize) -> usize
+{
+    (val + 3) & !3
+}
+
+/// Decode NUL terminated (or unterminated) UTF-16 from `data`, returning it
+/// and the number of characters before the NUL
+fn utf16(data: &[u8]) -> (String, usize)
+{
+    let chars: Vec<u16> = data.chunks_exact(2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        .take_while(|&x| x != 0)
+        .collect();
+    (String::from_utf16_lossy(&chars), chars.len())
+}
+
+/// A block of a VS_VERSIONINFO
+struct Block<'a> {
+    key:      String,
+    value:    &'a [u8],
+    children: &'a [u8],
+}
+
+/// Split the block at the start of `data` off of it, returning the block and
+/// what follows it
+fn block(data: &[u8]) -> Result<(Block<'_>, &[u8]), Box<dyn Error>>
+{
+    let (len, value_len, typ) = match (le16(data, 0), le16(data, 2),
+                                       le16(data, 4)) {
+        (Some(len), Some(value_len), Some(typ)) =>
+            (len as usize, value_len as usize, typ),
+        _ => return Err("Version block truncated".into()),
+    };
+    if len < 6 || len > data.len() {
+        return Err("Version block length out of range".into());
+    }
+    let data_len = data.len();
+    let (block, _) = data.split_at(len);
+
+    let (key, key_chars) = utf16(&block[6..]);
+    let value_start = align4(6 + (key_chars + 1) * 2).min(len);
+
+    /* Text values are measured in characters. Some linkers got that wrong,
+     * which is why the value is clamped rather than rejected. */
+    let value_size = if typ == 1 { value_len * 2 } else { value_len };
+    let value_end = (value_start + value_size).min(len);
+
+    Ok((Block {
+        key,
+        value:    &block[value_start..value_end],
+        children: &block[align4(value_end).min(len)..],
+    }, &data[align4(len).min(data_len)..]))
+}
+
+/// Split `data` into the blocks it holds
+fn blocks(mut data: &[u8]) -> Result<Vec<Block<'_>>, Box<dyn Error>>
+{
+    let mut blocks = Vec::new();
+    while data.len() >= 6 {
+        let (child, rest) = block(data)?;
+        blocks.push(child);
+        data = rest;
+    }
+    Ok(blocks)
+}
+
+/// Decode a VS_FIXEDFILEINFO
+fn decode_fixed(data: &[u8]) -> Result<FixedFileInfo, Box<dyn Error>>
+{
+    if data.len() < 52 {
+        return Err("VS_FIXEDFILEINFO too small".into());
+    }
+    if le32(data, 0) != Some(VS_FFI_SIGNATURE) {
+        return Err("No VS_FIXEDFILEINFO signature present".into());
+    }
+    let dword = |ii: usize| le32(data, ii * 4).unwrap();
+    let version = |ms: u32, ls: u32| {
+        [(ms >> 16) as u16, ms as u16, (ls >> 16) as u16, ls as u16]
+    };
+    Ok(FixedFileInfo {
+        file_version:    version(dword(2), dword(3)),
+        product_version: version(dword(4), dword(5)),
+        file_flags_mask: dword(6),
+        file_flags:      dword(7),
+        file_os:         dword(8),
+        file_type:       dword(9),
+        file_subtype:    dword(10),
+        file_date:       (dword(11) as u64) << 32 | dword(12) as u64,
+    })
+}
+
+/// Decode a VS_VERSIONINFO resource
+pub fn decode(data: &[u8]) -> Result<VersionInfo, Box<dyn Error>>
+{
+    let (root, _) = block(data)?;
+    if root.key != "VS_VERSION_INFO" {
+        return Err("No VS_VERSION_INFO signature present".into());
+    }
+
+    let fixed = match root.value.len() {
+        0 => None,
+        _ => Some(decode_fixed(root.value)?),
+    };
+
+    let mut info = VersionInfo {
+        fixed,
+        language: String::new(),
+        strings:  Vec::new(),
+    };
+
+    /* Only the first string table is used, images rarely have more than
+     * one and they then hold the same versions in other languages */
+    for child in blocks(root.children)? {
+        if child.key != "StringFileInfo" {
+            continue;
+        }
+        if let Some(table) = blocks(child.children)?.into_iter().next() {
+            info.language = table.key;
+            for string in blocks(table.children)? {
+                info.strings.push((string.key, utf16(string.value).0));
+            }
+            break;
+        }
+    }
+    Ok(info)
+}
+
+/// Find an entry of the resource directory at `offset` (from the start of
+/// the resource directory at `base`), the one with `id` or the first one if
+/// `id` is `None`. Returns the offset the entry points at.
+fn find_entry<R: Read + Seek>(image: &PeFile, fd: &mut R, base: u32,
+                              offset: u32, id: Option<u32>) ->
+    Result<Option<u32>, Box<dyn Error>>
+{
+    let rva = base.checked_add(offset)
+        .ok_or("Resource directory out of range")?;
+    let header = image.read_rva(fd, rva, 16)?;
+    let count = le16(&header, 12).unwrap() as u32 +
+                le16(&header, 14).unwrap() as u32;
+
+    let entries = image.read_rva(fd, rva.checked_add(16)
+                                 .ok_or("Resource directory out of range")?,
+                                 count * 8)?;
+    Ok(entries.chunks_exact(8)
+        .find(|x| id.is_none_or(|id| le32(x, 0) == Some(id)))
+        .map(|x| le32(x, 4).unwrap()))
+}
+
+/// Read the version resource of `image` from `fd`. Images without one give
+/// `None`.
+pub fn read<R: Read + Seek>(image: &PeFile, fd: &mut R) ->
+    Result<Option<VersionInfo>, Box<dyn Error>>
+{
+    let base = match image.data_dirs.get(RESOURCE_DIRECTORY) {
+        Some(dir) if dir.vaddr != 0 && dir.size != 0 => dir.vaddr,
+        _ => return Ok(None),
+    };
+
+    /* Type, name and then language */
+    let mut offset = 0;
+    for (level, id) in [Some(RT_VERSION), None, None].iter().enumerate() {
+        if level > 0 && offset & RESOURCE_SUBDIRECTORY == 0 {
+            return Err("Resource directory entry is not a directory".into());
+        }
+        offset = match find_entry(image, fd, base,
+                                  offset & !RESOURCE_SUBDIRECTORY, *id)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+    }
+    if offset & RESOURCE_SUBDIRECTORY != 0 {
+        return Err("Version resource is a directory".into());
+    }
+
+    /* IMAGE_RESOURCE_DATA_ENTRY */
+    let entry = image.read_rva(fd, base.checked_add(offset)
+                               .ok_or("Resource data entry out of range")?,
+                               16)?;
+    let (rva, size) = (le32(&entry, 0).unwrap(), le32(&entry, 4).unwrap());
+    if size > MAX_VERSION_SIZE {
+        return Err("Version resource too large".into());
+    }
+
+    decode(&image.read_rva(fd, rva, size)?).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a version block. Text values are given in characters.
+    fn node(key: &str, text: bool, value: &[u8], value_len: usize,
+            children: &[u8]) -> Vec<u8>
+    {
+        let mut out = vec![0; 2];
+        out.extend_from_slice(&(value_len as u16).to_le_bytes());
+        out.extend_from_slice(&(text as u16).to_le_bytes());
+        for ch in key.encode_utf16().chain(Some(0)) {
+            out.extend_from_slice(&ch.to_le_bytes());
+        }
+        out.resize(align4(out.len()), 0);
+        out.extend_from_slice(value);
+        out.resize(align4(out.len()), 0);
+        out.extend_from_slice(children);
+        let len = out.len() as u16;
+        out[..2].copy_from_slice(&len.to_le_bytes());
+        out.resize(align4(out.len()), 0);
+        out
+    }
+
+    fn string(key: &str, val: &str) -> Vec<u8>
+    {
+        let mut value = Vec::new();
+        for ch in val.encode_utf16().chain(Some(0)) {
+            value.extend_from_slice(&ch.to_le_bytes());
+        }
+        node(key, true, &value, val.len() + 1, &[])
+    }
+
+    fn fixed() -> Vec<u8>
+    {
+        let dwords = [VS_FFI_SIGNATURE, 0x10000, 0xa0000, 0x4a610001,
+                      0xa0000, 0x4a610002, 0x3f, 1, 0x40004, 2, 0, 0, 3];
+        dwords.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    fn resource(fixed: &[u8], strings: &[u8]) -> Vec<u8>
+    {
+        let table = node("040904B0", true, &[], 0, strings);
+        let mut children = node("VarFileInfo", true, &[], 0, &[]);
+        children.extend(node("StringFileInfo", true, &[], 0, &table));
+        node("VS_VERSION_INFO", false, fixed, fixed.len(), &children)
+    }
+
+    #[test]
+    fn fixed_and_strings()
+    {
+        let mut strings = string("FileVersion", "10.0.19041.1");
+        strings.extend(string("ProductName", "Windows"));
+
+        /* Byte rather than character length, clamped to the block */
+        let mut company = node("CompanyName", true, &[], 0, &[]);
+        company[2] = 200;
+        company.extend("Co".encode_utf16().flat_map(|x| x.to_le_bytes()));
+        let len = company.len() as u16;
+        company[..2].copy_from_slice(&len.to_le_bytes());
+        strings.extend(company);
+
+        let info = decode(&resource(&fixed(), &strings)).unwrap();
+        assert_eq!(info.file_version().unwrap(), "10.0.19041.1");
+        assert_eq!(info.product_version().unwrap(), "10.0.19041.2");
+        let fixed = info.fixed.unwrap();
+        assert_eq!((fixed.file_flags, fixed.file_os, fixed.file_type),
+                   (1, 0x40004, 2));
+        assert_eq!(fixed.file_date, 3);
+        assert_eq!(info.language, "040904B0");
+        assert_eq!(info.strings, [
+            ("FileVersion".to_string(), "10.0.19041.1".to_string()),
+            ("ProductName".to_string(), "Windows".to_string()),
+            ("CompanyName".to_string(), "Co".to_string()),
+        ]);
+        assert!(info.json().starts_with(
+            "{\"file_version\":\"10.0.19041.1\",\
+             \"product_version\":\"10.0.19041.2\",\"fixed\":{"));
+    }
+
+    #[test]
+    fn optional_parts()
+    {
+        let info = decode(&node("VS_VERSION_INFO", false, &[], 0, &[]))
+            .unwrap();
+        assert!(info.fixed.is_none() && info.strings.is_empty());
+        assert!(info.language.is_empty());
+        assert!(info.json().contains("\"fixed\":null"));
+    }
+
+    #[test]
+    fn rejects_bad_resources()
+    {
+        assert!(decode(&node("VS_VERSION", false, &fixed(), 52, &[]))
+                .is_err());
+        let mut bad = fixed();
+        bad[0] ^= 1;
+        assert!(decode(&node("VS_VERSION_INFO", false, &bad, 52, &[]))
+                .is_err());
+        assert!(decode(&node("VS_VERSION_INFO", false, &bad[..48], 48, &[]))
+                .is_err());
+
+        /* Every truncation or corruption of a resource fails cleanly */
+        let data = resource(&fixed(), &string("FileVersion", "10.0"));
+        for len in 0..data.len() {
+            let _ = decode(&data[..len]);
+        }
+        let mut seed = 1u32;
+        for _ in 0..10000 {
+            let mut data = data.clone();
+            for _ in 0..4 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let pos = (seed >> 8) as usize % data.len();
+                data[pos] = (seed >> 24) as u8;
+            }
+            let _ = decode(&data);
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! Recursive directory watching with inotify.
+//!
+//! Every directory under the root is watched. Directories created later are
+//! watched as they appear, and files already in them when that happens are
+//! reported as well, as they may have been written before the watch existed.
+//! If the kernel drops events because too many queued up, the whole tree is
+//! scanned again and every file in it reported.
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Events meaning a file was (or is being) written or moved into place
+const FILE_EVENTS: u32 = libc::IN_MODIFY | libc::IN_CLOSE_WRITE |
+                         libc::IN_MOVED_TO;
+
+/// Events meaning a file or directory appeared. New directories get watched
+/// and new files are reported, which also covers hard links.
+const DIR_EVENTS: u32 = libc::IN_CREATE | libc::IN_MOVED_TO;
+
+pub struct Watcher {
+    fd: i32,
+
+    /// Directory being watched
+    root: PathBuf,
+
+    /// Directory each watch descriptor is for
+    dirs: HashMap<i32, PathBuf>,
+}
+
+impl Watcher {
+    /// Start watching `root` and every directory below it
+    pub fn new(root: &Path) -> io::Result<Watcher>
+    {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut watcher = Watcher {
+            fd,
+            root: root.to_path_buf(),
+            dirs: HashMap::new(),
+        };
+        watcher.add_tree(root, &mut Vec::new())?;
+        Ok(watcher)
+    }
+
+    /// Watch `dir` and every directory below it, collecting the files found
+    /// in them into `files`
+    fn add_tree(&mut self, dir: &Path, files: &mut Vec<PathBuf>) ->
+        io::Result<()>
+    {
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let wd = unsafe {
+            libc::inotify_add_watch(self.fd, path.as_ptr(),
+                                    FILE_EVENTS | DIR_EVENTS)
+        };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+
+        /* Like `recursive_listdir` errors in sub-directories are eaten */
+        if let Ok(listing) = dir.read_dir() {
+            for entry in listing.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    let _ = self.add_tree(&path, files);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` (forever if `None`) for changes. Returns the files
+    /// which were written to or appeared, possibly with duplicates.
+    pub fn wait(&mut self, timeout: Option<Duration>) ->
+        io::Result<Vec<PathBuf>>
+    {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.map_or(-1, |x| x.as_millis() as i32);
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::Interrupted => Ok(Vec::new()),
+                _ => Err(err),
+            };
+        }
+        if ready == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut buf = vec![0u8; 64 * 1024];
+        let len = unsafe {
+            libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void,
+                       buf.len())
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(self.events(&buf[..len as usize]))
+    }
+
+    /// Handle the inotify events in `buf`, returning the files they report
+    fn events(&mut self, buf: &[u8]) -> Vec<PathBuf>
+    {
+        let mut files = Vec::new();
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut offset = 0;
+        while offset + header <= buf.len() {
+            let event: libc::inotify_event = unsafe {
+                std::ptr::read_unaligned(
+                    buf[offset..].as_ptr() as *const libc::inotify_event)
+            };
+            let end = buf.len().min(offset + header + event.len as usize);
+            let name = &buf[offset + header..end];
+            offset = end;
+
+            /* Events were dropped, anything may have changed */
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                let root = self.root.clone();
+                let _ = self.add_tree(&root, &mut files);
+                continue;
+            }
+
+            /* Names are padded with NULs */
+            let name = match name.iter().position(|&x| x == 0) {
+                Some(end) => &name[..end],
+                None => name,
+            };
+            let dir = match self.dirs.get(&event.wd) {
+                Some(dir) => dir.clone(),
+                None => continue,
+            };
+            let path = dir.join(OsStr::from_bytes(name));
+
+            if event.mask & libc::IN_IGNORED != 0 {
+                self.dirs.remove(&event.wd);
+            } else if event.mask & libc::IN_ISDIR != 0 {
+                if event.mask & DIR_EVENTS != 0 {
+                    let _ = self.add_tree(&path, &mut files);
+                }
+            } else {
+                files.push(path);
+            }
+        }
+        files
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self)
+    {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+/// Files which were written to, held back until they have gone unwritten for
+/// a while so that files still being copied in aren't read half written
+pub struct Pending {
+    /// How long a file must go unwritten
+    settle: Duration,
+
+    /// Files and when they were last written to
+    files: HashMap<PathBuf, Instant>,
+}
+
+impl Pending {
+    pub fn new(settle: Duration) -> Pending
+    {
+        Pending { settle, files: HashMap::new() }
+    }
+
+    /// Note that `path` was just written to
+    pub fn touch(&mut self, path: PathBuf)
+    {
+        self.files.insert(path, Instant::now());
+    }
+
+    /// How long to wait for more changes before checking for settled files,
+    /// forever if there are none pending
+    pub fn timeout(&self) -> Option<Duration>
+    {
+        if self.files.is_empty() { None } else { Some(self.settle) }
+    }
+
+    /// Take the files which have settled
+    pub fn settled(&mut self) -> Vec<PathBuf>
+    {
+        let settle = self.settle;
+        let mut settled: Vec<PathBuf> = self.files.iter()
+            .filter(|x| x.1.elapsed() >= settle)
+            .map(|x| x.0.clone())
+            .collect();
+        settled.sort();
+        for path in &settled {
+            self.files.remove(path);
+        }
+        settled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    /// Create an empty scratch directory for the test `name`
+    fn scratch(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("pdblister-{}-{}", name,
+                                                     std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Wait until `watcher` reports `path`, failing after a few seconds
+    fn wait_for(watcher: &mut Watcher, path: &Path)
+    {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            let files = watcher.wait(Some(Duration::from_millis(100)))
+                .unwrap();
+            if files.iter().any(|x| x == path) {
+                return;
+            }
+        }
+        panic!("{:?} was not reported", path);
+    }
+
+    /// An inotify event for `wd` with the NUL padded `name`
+    fn event(wd: i32, mask: u32, name: &str) -> Vec<u8>
+    {
+        let len = if name.is_empty() { 0 } else { (name.len() + 16) & !15 };
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&wd.to_ne_bytes());
+        buf.extend_from_slice(&mask.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&(len as u32).to_ne_bytes());
+        buf.extend_from_slice(name.as_bytes());
+        buf.resize(buf.len() + len - name.len(), 0);
+        buf
+    }
+
+    #[test]
+    fn create_and_modify()
+    {
+        let dir = scratch("watch");
+        let mut watcher = Watcher::new(&dir).unwrap();
+
+        let file = dir.join("ntdll.dll");
+        fs::write(&file, b"MZ").unwrap();
+        wait_for(&mut watcher, &file);
+        while !watcher.wait(Some(Duration::from_millis(100))).unwrap()
+                .is_empty() {}
+
+        fs::OpenOptions::new().append(true).open(&file).unwrap()
+            .write_all(b"..").unwrap();
+        wait_for(&mut watcher, &file);
+
+        /* Files in new directories are reported once it is watched */
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let nested = dir.join("sub").join("kernel32.dll");
+        fs::write(&nested, b"MZ").unwrap();
+        wait_for(&mut watcher, &nested);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn overflow_rescans_the_tree()
+    {
+        let dir = scratch("watch-overflow");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.dll"), b"MZ").unwrap();
+        fs::write(dir.join("sub").join("b.dll"), b"MZ").unwrap();
+        let mut watcher = Watcher::new(&dir).unwrap();
+
+        let wd = *watcher.dirs.iter().find(|x| *x.1 == dir).unwrap().0;
+        let mut buf = event(wd, libc::IN_CLOSE_WRITE, "c.dll");
+        buf.extend(event(-1, libc::IN_Q_OVERFLOW, ""));
+        let mut files = watcher.events(&buf);
+        files.sort();
+        let mut want = vec![dir.join("a.dll"), dir.join("sub").join("b.dll"),
+                            dir.join("c.dll")];
+        want.sort();
+        assert_eq!(files, want);
+
+        /* Events cut short don't read past the buffer */
+        let buf = event(wd, libc::IN_CLOSE_WRITE, "c.dll");
+        assert_eq!(watcher.events(&buf[..buf.len() - 1]), [dir.join("c.dll")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_settle()
+    {
+        let settle = Duration::from_millis(200);
+        let mut pending = Pending::new(settle);
+        assert_eq!(pending.timeout(), None);
+
+        pending.touch(PathBuf::from("a"));
+        pending.touch(PathBuf::from("b"));
+        assert_eq!(pending.timeout(), Some(settle));
+        assert!(pending.settled().is_empty());
+
+        /* Writing to a file again restarts its wait */
+        std::thread::sleep(settle / 2);
+        pending.touch(PathBuf::from("b"));
+        std::thread::sleep(settle / 2 + Duration::from_millis(20));
+        assert_eq!(pending.settled(), [PathBuf::from("a")]);
+
+        std::thread::sleep(settle / 2);
+        assert_eq!(pending.settled(), [PathBuf::from("b")]);
+        assert_eq!(pending.timeout(), None);
+    }
+}
@@ -0,0 +1,1027 @@
+//! Reader for Windows Imaging Format (WIM) files such as the `install.wim`
+//! and `boot.wim` found on Windows installation media.
+//!
+//! A WIM holds one or more images (editions), each described by a metadata
+//! resource containing a directory tree. File contents are stored once as
+//! resources keyed by their SHA-1, so the same binary shared by several
+//! editions only appears once in the file.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use pe::read_struct;
+use lzms;
+use lzx;
+use xpress;
+
+/// Resource is not part of any image
+const RESHDR_FLAG_FREE:       u8 = 0x01;
+
+/// Resource is an image metadata resource
+const RESHDR_FLAG_METADATA:   u8 = 0x02;
+
+/// Resource is compressed in chunks
+const RESHDR_FLAG_COMPRESSED: u8 = 0x04;
+
+/// Resource is stored inside of a solid (multi-file) resource
+const RESHDR_FLAG_SOLID:      u8 = 0x10;
+
+/// Uncompressed size used in the blob table to mark a solid resource itself,
+/// rather than a blob stored within one.
+const SOLID_RESOURCE_MAGIC: u64 = 0x100000000;
+
+const WIM_FLAG_COMPRESSION:   u32 = 0x00000002;
+const WIM_FLAG_SPANNED:       u32 = 0x00000008;
+const WIM_FLAG_COMPRESS_XPRESS: u32 = 0x00020000;
+const WIM_FLAG_COMPRESS_LZX:    u32 = 0x00040000;
+const WIM_FLAG_COMPRESS_LZMS:   u32 = 0x00080000;
+
+const FILE_ATTRIBUTE_DIRECTORY:     u32 = 0x10;
+const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+
+/// Chunk size used by WIMs which do not specify one
+const DEFAULT_CHUNK_SIZE: u32 = 32768;
+
+/// Maximum directory depth we will descend, bounding the recursion
+const MAX_DEPTH: usize = 256;
+
+/// Largest chunk size accepted, that of the LZMS solid resources of ESDs
+const MAX_CHUNK_SIZE: u64 = 1 << 26;
+
+/// Largest resource or blob decompressed into memory, well past any PE or
+/// image metadata
+const MAX_RESOURCE_SIZE: u64 = 1 << 30;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct ResourceHeader {
+    size_in_wim: [u8; 7],
+    flags:       u8,
+    offset:      u64,
+    orig_size:   u64,
+}
+
+impl ResourceHeader {
+    /// Size of the (possibly compressed) resource in the WIM file
+    fn stored_size(&self) -> u64
+    {
+        let mut size = [0u8; 8];
+        size[..7].copy_from_slice(&self.size_in_wim);
+        u64::from_le_bytes(size)
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct WimHeader {
+    signature:     [u8; 8],
+    header_size:   u32,
+    version:       u32,
+    flags:         u32,
+    chunk_size:    u32,
+    guid:          [u8; 16],
+    part_number:   u16,
+    total_parts:   u16,
+    image_count:   u32,
+    blob_table:    ResourceHeader,
+    xml_data:      ResourceHeader,
+    boot_metadata: ResourceHeader,
+    boot_index:    u32,
+    integrity:     ResourceHeader,
+    unused:        [u8; 60],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct BlobTableEntry {
+    resource:    ResourceHeader,
+    part_number: u16,
+    ref_count:   u32,
+    hash:        [u8; 20],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SolidHeader {
+    orig_size:   u64,
+    chunk_size:  u32,
+    compression: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct DirEntry {
+    length:           u64,
+    attributes:       u32,
+    security_id:      u32,
+    subdir_offset:    u64,
+    unused:           [u64; 2],
+    creation_time:    u64,
+    last_access_time: u64,
+    last_write_time:  u64,
+    hash:             [u8; 20],
+    reparse_reserved: u32,
+    hard_link:        u64,
+    num_streams:      u16,
+    short_name_len:   u16,
+    name_len:         u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct StreamEntry {
+    length:   u64,
+    unused:   u64,
+    hash:     [u8; 20],
+    name_len: u16,
+}
+
+/// Compression formats a WIM resource may use
+#[derive(Clone, Copy, PartialEq)]
+enum Compression {
+    None,
+    Xpress,
+    Lzx,
+    Lzms,
+}
+
+/// Where the contents of a blob live
+#[derive(Clone, Copy)]
+enum BlobLocation {
+    /// A standalone resource
+    Resource(ResourceHeader),
+
+    /// A range of the uncompressed contents of a run of solid resources
+    Solid { run: usize, offset: u64, size: u64 },
+}
+
+/// A solid resource, many blobs compressed together as one stream
+struct SolidResource {
+    resource:    ResourceHeader,
+    header:      SolidHeader,
+
+    /// File offsets of every chunk and of the end of the last one, once
+    /// the chunk table is read
+    chunks: Option<Vec<u64>>,
+}
+
+/// A regular file found in a WIM image
+pub struct WimFile {
+    /// SHA-1 of the file contents, used to look up the contents in the WIM
+    pub hash: [u8; 20],
+
+    /// Name of the file, without its directory
+    pub name: String,
+}
+
+/// The parts of a directory entry we care about when walking an image
+struct Dentry {
+    attributes:    u32,
+    subdir_offset: u64,
+
+    /// Hash of the unnamed data stream
+    hash: [u8; 20],
+
+    /// Long name of the entry
+    name: String,
+
+    /// Offset of the next sibling entry
+    next: usize,
+}
+
+pub struct Wim {
+    fd: File,
+    header: WimHeader,
+
+    /// Size of the WIM file, which every resource has to fit in
+    file_size: u64,
+
+    /// All blobs in the WIM keyed by their SHA-1
+    blobs: HashMap<[u8; 20], BlobLocation>,
+
+    /// Metadata resources, one per image in image order
+    metadata: Vec<ResourceHeader>,
+
+    /// Runs of consecutive solid resources. Solid blobs address the
+    /// concatenation of the uncompressed contents of a run.
+    solid_runs: Vec<Vec<SolidResource>>,
+
+    /// Most recently decompressed solid chunk by run, resource and chunk
+    /// index, as blobs from the same chunk are commonly read back to back
+    solid_cache: Option<((usize, usize, usize), Vec<u8>)>,
+}
+
+impl Wim {
+    /// Open a WIM file and load its blob table
+    pub fn open(path: &Path) -> Result<Wim, Box<dyn Error>>
+    {
+        let mut fd = File::open(path)?;
+
+        let header: WimHeader = unsafe { read_struct(&mut fd)? };
+        if &header.signature != b"MSWIM\0\0\0" {
+            return Err("No WIM header present".into());
+        }
+        if header.flags & WIM_FLAG_SPANNED != 0 || header.total_parts > 1 {
+            return Err("Spanned WIMs are not supported".into());
+        }
+        let chunk_size = header.chunk_size as u64;
+        if chunk_size > MAX_CHUNK_SIZE ||
+                (chunk_size != 0 && !chunk_size.is_power_of_two()) {
+            return Err("Unsupported WIM chunk size".into());
+        }
+
+        let mut wim = Wim {
+            file_size: fd.metadata()?.len(),
+            fd,
+            header,
+            blobs: HashMap::new(),
+            metadata: Vec::new(),
+            solid_runs: Vec::new(),
+            solid_cache: None,
+        };
+
+        /* The blob table itself is stored as a resource */
+        let blob_table = wim.header.blob_table;
+        let table = wim.read_resource(&blob_table, None)?;
+        let entsize = std::mem::size_of::<BlobTableEntry>();
+
+        let mut last_was_solid_resource = false;
+        for raw in table.chunks(entsize) {
+            if raw.len() != entsize {
+                break;
+            }
+            let ent: BlobTableEntry = unsafe { read_struct(&mut &raw[..])? };
+            let res = ent.resource;
+
+            if res.flags & RESHDR_FLAG_FREE != 0 {
+                continue;
+            }
+
+            if res.flags & RESHDR_FLAG_SOLID != 0 {
+                if res.orig_size == SOLID_RESOURCE_MAGIC {
+                    /* Start a new run of solid resources if the previous
+                     * entry was not also a solid resource. */
+                    if !last_was_solid_resource {
+                        wim.solid_runs.push(Vec::new());
+                    }
+                    wim.check_range(&res)?;
+                    wim.fd.seek(SeekFrom::Start(res.offset))?;
+                    let header: SolidHeader =
+                        unsafe { read_struct(&mut wim.fd)? };
+                    if header.chunk_size == 0 ||
+                            header.chunk_size as u64 > MAX_CHUNK_SIZE {
+                        return Err("Unsupported solid resource chunk \
+                                    size".into());
+                    }
+                    wim.solid_runs.last_mut().unwrap().push(SolidResource {
+                        resource: res,
+                        header,
+                        chunks: None,
+                    });
+                    last_was_solid_resource = true;
+                    continue;
+                }
+
+                if wim.solid_runs.is_empty() {
+                    return Err("Solid blob before any solid resource".into());
+                }
+                wim.blobs.insert(ent.hash, BlobLocation::Solid {
+                    run:    wim.solid_runs.len() - 1,
+                    offset: res.offset,
+                    size:   res.orig_size,
+                });
+                last_was_solid_resource = false;
+                continue;
+            }
+            last_was_solid_resource = false;
+
+            if res.flags & RESHDR_FLAG_METADATA != 0 {
+                wim.metadata.push(res);
+            } else {
+                wim.blobs.insert(ent.hash, BlobLocation::Resource(res));
+            }
+        }
+
+        Ok(wim)
+    }
+
+    /// Number of images in this WIM
+    pub fn image_count(&self) -> u32
+    {
+        self.header.image_count
+    }
+
+    /// List all regular files in image `index` (1-based, as used by DISM and
+    /// the WIM XML data)
+    pub fn image_files(&mut self, index: u32) ->
+        Result<Vec<WimFile>, Box<dyn Error>>
+    {
+        if index == 0 || index as usize > self.metadata.len() {
+            return Err("Image index out of range".into());
+        }
+
+        let res = self.metadata[index as usize - 1];
+        let meta = self.read_resource(&res, None)?;
+
+        /* Metadata starts with the security data, which we skip. Its total
+         * length is rounded up to 8 bytes and is at least 8. */
+        if meta.len() < 8 {
+            return Err("WIM metadata resource too small".into());
+        }
+        let sd_len = u32::from_le_bytes(
+            [meta[0], meta[1], meta[2], meta[3]]) as usize;
+        let root = std::cmp::max((sd_len + 7) & !7, 8);
+
+        let root_ent = read_dentry(&meta, root)?
+            .ok_or("WIM image has no root directory")?;
+
+        let mut files = Vec::new();
+        walk_directory(&meta, root_ent.subdir_offset as usize, 0,
+                       &mut HashSet::new(), &mut files)?;
+        Ok(files)
+    }
+
+    /// Get the uncompressed size of the blob with SHA-1 `hash`
+    pub fn blob_size(&self, hash: &[u8; 20]) -> Option<u64>
+    {
+        self.blobs.get(hash).map(|loc| match *loc {
+            BlobLocation::Resource(res) => res.orig_size,
+            BlobLocation::Solid { size, .. } => size,
+        })
+    }
+
+    /// Read the contents of the blob with SHA-1 `hash`. If `limit` is given
+    /// the read may stop early once at least `limit` bytes are available,
+    /// which is used to cheaply check for file magics.
+    pub fn read_blob(&mut self, hash: &[u8; 20], limit: Option<u64>) ->
+        Result<Vec<u8>, Box<dyn Error>>
+    {
+        let loc = *self.blobs.get(hash).ok_or("Blob not present in WIM")?;
+
+        match loc {
+            BlobLocation::Resource(res) => self.read_resource(&res, limit),
+            BlobLocation::Solid { run, offset, size } => {
+                let size = std::cmp::min(size, limit.unwrap_or(size));
+                if size > MAX_RESOURCE_SIZE {
+                    return Err("WIM blob too large".into());
+                }
+                let end = offset.checked_add(size)
+                    .ok_or("Solid blob range overflow")?;
+
+                /* Only decompress the chunks of the run holding the blob */
+                let mut out = Vec::new();
+                let mut start = 0u64;
+                for index in 0..self.solid_runs[run].len() {
+                    let header = self.solid_runs[run][index].header;
+                    let chunk_size = header.chunk_size as u64;
+                    let res_end = start.saturating_add(header.orig_size);
+                    if res_end <= offset || start >= end {
+                        start = res_end;
+                        continue;
+                    }
+
+                    let first = offset.saturating_sub(start) / chunk_size;
+                    let last = (std::cmp::min(end, res_end) - start - 1) /
+                        chunk_size;
+                    for chunk in first..=last {
+                        let data = self.read_solid_chunk(run, index,
+                                                         chunk as usize)?;
+                        let chunk_start = start + chunk * chunk_size;
+                        let from = offset.saturating_sub(chunk_start) as usize;
+                        let to = (std::cmp::min(end, chunk_start +
+                                                data.len() as u64) -
+                                  chunk_start) as usize;
+                        out.extend_from_slice(&data[from..to]);
+                    }
+                    start = res_end;
+                }
+
+                if out.len() as u64 != size {
+                    return Err("Solid blob out of bounds".into());
+                }
+                Ok(out)
+            }
+        }
+    }
+
+    /// Check that `res` lies within the WIM file
+    fn check_range(&self, res: &ResourceHeader) -> Result<(), Box<dyn Error>>
+    {
+        match res.offset.checked_add(res.stored_size()) {
+            Some(end) if end <= self.file_size => Ok(()),
+            _ => Err("WIM resource out of bounds".into()),
+        }
+    }
+
+    /// Compression used by non-solid resources in this WIM
+    fn compression(&self) -> Compression
+    {
+        let flags = self.header.flags;
+        if flags & WIM_FLAG_COMPRESSION == 0 {
+            Compression::None
+        } else if flags & WIM_FLAG_COMPRESS_XPRESS != 0 {
+            Compression::Xpress
+        } else if flags & WIM_FLAG_COMPRESS_LZX != 0 {
+            Compression::Lzx
+        } else if flags & WIM_FLAG_COMPRESS_LZMS != 0 {
+            Compression::Lzms
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Read and decompress a standalone resource
+    fn read_resource(&mut self, res: &ResourceHeader, limit: Option<u64>) ->
+        Result<Vec<u8>, Box<dyn Error>>
+    {
+        let stored = res.stored_size();
+        let orig   = res.orig_size;
+
+        self.check_range(res)?;
+        if std::cmp::min(orig, limit.unwrap_or(orig)) > MAX_RESOURCE_SIZE {
+            return Err("WIM resource too large".into());
+        }
+        self.fd.seek(SeekFrom::Start(res.offset))?;
+
+        if res.flags & RESHDR_FLAG_COMPRESSED == 0 {
+            if stored != orig {
+                return Err("Uncompressed WIM resource size mismatch".into());
+            }
+            let size = std::cmp::min(orig, limit.unwrap_or(orig));
+            let mut data = vec![0u8; size as usize];
+            self.fd.read_exact(&mut data)?;
+            return Ok(data);
+        }
+
+        let chunk_size = match self.header.chunk_size {
+            0 => DEFAULT_CHUNK_SIZE,
+            x => x,
+        } as u64;
+        let num_chunks = orig.div_ceil(chunk_size);
+        let entsize = if orig > 0xffffffff { 8 } else { 4 };
+        let table_size = num_chunks.saturating_sub(1) * entsize;
+        if table_size > stored {
+            return Err("WIM chunk table larger than resource".into());
+        }
+
+        let mut raw_table = vec![0u8; table_size as usize];
+        self.fd.read_exact(&mut raw_table)?;
+
+        /* The chunk table holds the offsets of every chunk but the first,
+         * relative to the end of the table */
+        let mut offsets = vec![0u64];
+        for ent in raw_table.chunks(entsize as usize) {
+            let mut val = [0u8; 8];
+            val[..ent.len()].copy_from_slice(ent);
+            offsets.push(u64::from_le_bytes(val));
+        }
+        offsets.push(stored - table_size);
+
+        let compression = self.compression();
+        let mut out = Vec::new();
+        let mut input = Vec::new();
+        for chunk in 0..num_chunks as usize {
+            if let Some(limit) = limit {
+                if out.len() as u64 >= limit {
+                    break;
+                }
+            }
+
+            let (start, end) = (offsets[chunk], offsets[chunk + 1]);
+            if end < start || end > stored - table_size {
+                return Err("WIM chunk table is not sorted".into());
+            }
+            let out_size = std::cmp::min(
+                chunk_size, orig - chunk as u64 * chunk_size) as usize;
+
+            input.resize((end - start) as usize, 0);
+            self.fd.read_exact(&mut input)?;
+
+            out.append(&mut decompress_chunk(compression, &input, out_size,
+                                             chunk_size)?);
+        }
+
+        Ok(out)
+    }
+
+    /// Read the chunk table of resource `index` of solid run `run`, giving
+    /// the file offset of every chunk and of the end of the last one
+    fn solid_chunks(&mut self, run: usize, index: usize) ->
+        Result<Vec<u64>, Box<dyn Error>>
+    {
+        if let Some(chunks) = &self.solid_runs[run][index].chunks {
+            return Ok(chunks.clone());
+        }
+        let (res, header) = {
+            let solid = &self.solid_runs[run][index];
+            (solid.resource, solid.header)
+        };
+
+        let chunk_size = header.chunk_size as u64;
+        let num_chunks = header.orig_size.div_ceil(chunk_size);
+
+        /* Solid resources store the compressed size of every chunk after
+         * the header, which all have to fit in the resource */
+        let hdr_size = std::mem::size_of::<SolidHeader>() as u64;
+        let table_size = num_chunks.saturating_mul(4);
+        if hdr_size.saturating_add(table_size) > res.stored_size() {
+            return Err("Solid chunk table larger than resource".into());
+        }
+        self.fd.seek(SeekFrom::Start(res.offset + hdr_size))?;
+        let mut sizes = vec![0u8; table_size as usize];
+        self.fd.read_exact(&mut sizes)?;
+
+        let end = res.offset + res.stored_size();
+        let mut chunks = vec![res.offset + hdr_size + table_size];
+        for size in sizes.chunks(4) {
+            let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]);
+            let next = chunks[chunks.len() - 1] + size as u64;
+            if next > end {
+                return Err("Solid chunk out of bounds".into());
+            }
+            chunks.push(next);
+        }
+
+        self.solid_runs[run][index].chunks = Some(chunks.clone());
+        Ok(chunks)
+    }
+
+    /// Read and decompress chunk `chunk` of resource `index` of solid run
+    /// `run`
+    fn read_solid_chunk(&mut self, run: usize, index: usize, chunk: usize) ->
+        Result<&[u8], Box<dyn Error>>
+    {
+        let key = (run, index, chunk);
+        if self.solid_cache.as_ref().is_none_or(|x| x.0 != key) {
+            let header = self.solid_runs[run][index].header;
+            let compression = match header.compression {
+                0 => Compression::None,
+                1 => Compression::Xpress,
+                2 => Compression::Lzx,
+                3 => Compression::Lzms,
+                _ => return Err("Unknown solid resource compression".into()),
+            };
+
+            let chunks = self.solid_chunks(run, index)?;
+            if chunk + 1 >= chunks.len() {
+                return Err("Solid chunk out of bounds".into());
+            }
+            let chunk_size = header.chunk_size as u64;
+            let out_size = std::cmp::min(
+                chunk_size, header.orig_size - chunk as u64 * chunk_size);
+
+            let mut input = vec![0u8; (chunks[chunk + 1] - chunks[chunk])
+                                 as usize];
+            self.fd.seek(SeekFrom::Start(chunks[chunk]))?;
+            self.fd.read_exact(&mut input)?;
+
+            let data = decompress_chunk(compression, &input,
+                                        out_size as usize, chunk_size)?;
+            self.solid_cache = Some((key, data));
+        }
+        Ok(&self.solid_cache.as_ref().unwrap().1)
+    }
+}
+
+/// Decompress a single resource chunk
+fn decompress_chunk(compression: Compression, input: &[u8], out_size: usize,
+                    chunk_size: u64) -> Result<Vec<u8>, Box<dyn Error>>
+{
+    /* Chunks which did not compress are stored raw */
+    if input.len() == out_size || compression == Compression::None {
+        if input.len() != out_size {
+            return Err("Raw WIM chunk size mismatch".into());
+        }
+        return Ok(input.to_vec());
+    }
+
+    match compression {
+        Compression::Xpress => xpress::decompress(input, out_size),
+        Compression::Lzx => {
+            let order = 63 - chunk_size.leading_zeros();
+            lzx::decompress(input, out_size, order)
+        }
+        Compression::Lzms => lzms::decompress(input, out_size),
+        Compression::None => unreachable!(),
+    }
+}
+
+/// Read the directory entry at `offset` in the metadata resource `meta`.
+/// Returns `None` for the end of directory marker.
+fn read_dentry(meta: &[u8], offset: usize) ->
+    Result<Option<Dentry>, Box<dyn Error>>
+{
+    let entsize = std::mem::size_of::<DirEntry>();
+    if offset.checked_add(8).is_none_or(|end| end > meta.len()) {
+        return Err("WIM directory entry out of bounds".into());
+    }
+
+    let mut length = [0u8; 8];
+    length.copy_from_slice(&meta[offset..offset + 8]);
+    let length = u64::from_le_bytes(length) as usize;
+    if length < entsize {
+        return Ok(None);
+    }
+    if offset.checked_add(length).is_none_or(|end| end > meta.len()) {
+        return Err("WIM directory entry out of bounds".into());
+    }
+
+    let dentry: DirEntry = unsafe { read_struct(&mut &meta[offset..])? };
+
+    let names_len = dentry.name_len as usize + dentry.short_name_len as usize;
+    if entsize + names_len > length {
+        return Err("WIM directory entry name out of bounds".into());
+    }
+    let name = offset + entsize;
+    let name: Vec<u16> = meta[name..name + dentry.name_len as usize]
+        .chunks_exact(2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        .collect();
+
+    /* Extra streams follow the entry, each aligned to 8 bytes. An unnamed
+     * one holds the file data when the entry itself has no hash. */
+    let mut hash = dentry.hash;
+    let mut next = (offset + length + 7) & !7;
+    for _ in 0..dentry.num_streams {
+        if next + std::mem::size_of::<StreamEntry>() > meta.len() {
+            return Err("WIM stream entry out of bounds".into());
+        }
+        let stream: StreamEntry = unsafe { read_struct(&mut &meta[next..])? };
+        if stream.name_len == 0 && hash == [0u8; 20] {
+            hash = stream.hash;
+        }
+        if (stream.length as usize) < std::mem::size_of::<StreamEntry>() {
+            return Err("WIM stream entry too small".into());
+        }
+        next = (next + stream.length as usize + 7) & !7;
+    }
+
+    Ok(Some(Dentry {
+        attributes:    dentry.attributes,
+        subdir_offset: dentry.subdir_offset,
+        hash,
+        name: String::from_utf16_lossy(&name),
+        next,
+    }))
+}
+
+/// Recursively collect the regular files of the directory whose children
+/// start at `offset`. `seen` holds the offsets of the directories walked so
+/// far, which no other directory may share.
+fn walk_directory(meta: &[u8], offset: usize, depth: usize,
+                  seen: &mut HashSet<usize>, files: &mut Vec<WimFile>) ->
+    Result<(), Box<dyn Error>>
+{
+    if depth > MAX_DEPTH {
+        return Err("WIM directory tree too deep".into());
+    }
+
+    /* A subdirectory offset of zero means there are no children */
+    let mut offset = offset;
+    if offset == 0 {
+        return Ok(());
+    }
+
+    /* Directories sharing children would have them listed over and over,
+     * doubling with every level */
+    if !seen.insert(offset) {
+        return Err("WIM directory shares its children with another".into());
+    }
+
+    while let Some(dentry) = read_dentry(meta, offset)? {
+        if dentry.attributes & FILE_ATTRIBUTE_DIRECTORY != 0 {
+            walk_directory(meta, dentry.subdir_offset as usize, depth + 1,
+                           seen, files)?;
+        } else if dentry.attributes & FILE_ATTRIBUTE_REPARSE_POINT == 0 &&
+                dentry.hash != [0u8; 20] {
+            files.push(WimFile { hash: dentry.hash, name: dentry.name });
+        }
+
+        offset = dentry.next;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Create an empty scratch directory for the test `name`
+    fn scratch(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("pdblister-{}-{}", name,
+                                                     std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// XPRESS chunk of nothing but literals, each with a 9 bit codeword
+    fn xpress(data: &[u8]) -> Vec<u8>
+    {
+        let mut bits: Vec<u8> = data.iter()
+            .flat_map(|&x| (0..9).rev().map(move |bit| (x as u16 >> bit) as u8
+                                            & 1))
+            .collect();
+        bits.resize((bits.len() + 15) & !15, 0);
+        let mut out = vec![0x99; 256];
+        for word in bits.chunks(16) {
+            let word = word.iter().fold(0u16, |acc, &x| acc << 1 | x as u16);
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+
+    /// A WIM file being put together
+    struct Builder {
+        data: Vec<u8>,
+
+        /// Chunk size resources are XPRESS compressed with, if they are
+        chunk_size: Option<usize>,
+
+        /// Blob table entries
+        table: Vec<u8>,
+    }
+
+    impl Builder {
+        fn new(chunk_size: Option<usize>) -> Builder
+        {
+            Builder { data: vec![0; 208], chunk_size, table: Vec::new() }
+        }
+
+        /// Store `contents` as a resource, giving its header
+        fn resource(&mut self, contents: &[u8], flags: u8) -> Vec<u8>
+        {
+            let offset = self.data.len();
+            let flags = match self.chunk_size {
+                Some(chunk_size) => {
+                    let chunks: Vec<Vec<u8>> = contents.chunks(chunk_size)
+                        .map(xpress).collect();
+                    let mut start = 0;
+                    for chunk in &chunks[..chunks.len() - 1] {
+                        start += chunk.len() as u32;
+                        self.data.extend_from_slice(&start.to_le_bytes());
+                    }
+                    for chunk in &chunks {
+                        self.data.extend_from_slice(chunk);
+                    }
+                    flags | RESHDR_FLAG_COMPRESSED
+                }
+                None => {
+                    self.data.extend_from_slice(contents);
+                    flags
+                }
+            };
+
+            let stored = (self.data.len() - offset) as u64;
+            let mut header = stored.to_le_bytes()[..7].to_vec();
+            header.push(flags);
+            header.extend_from_slice(&(offset as u64).to_le_bytes());
+            header.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+            header
+        }
+
+        /// Store `contents` and add it to the blob table
+        fn blob(&mut self, hash: [u8; 20], contents: &[u8], flags: u8)
+        {
+            let header = self.resource(contents, flags);
+            self.table.extend_from_slice(&header);
+            self.table.extend_from_slice(&[1, 0, 1, 0, 0, 0]);
+            self.table.extend_from_slice(&hash);
+        }
+
+        /// Write out the blob table and the header
+        fn finish(mut self, image_count: u32) -> Vec<u8>
+        {
+            let table = std::mem::take(&mut self.table);
+            let table = self.resource(&table, 0);
+
+            let mut flags = 0;
+            let mut chunk_size = 0;
+            if let Some(size) = self.chunk_size {
+                flags = WIM_FLAG_COMPRESSION | WIM_FLAG_COMPRESS_XPRESS;
+                chunk_size = size as u32;
+            }
+            let header = &mut self.data[..208];
+            header[..8].copy_from_slice(b"MSWIM\0\0\0");
+            header[8..12].copy_from_slice(&208u32.to_le_bytes());
+            header[12..16].copy_from_slice(&0x10d00u32.to_le_bytes());
+            header[16..20].copy_from_slice(&flags.to_le_bytes());
+            header[20..24].copy_from_slice(&chunk_size.to_le_bytes());
+            header[40..44].copy_from_slice(&[1, 0, 1, 0]);
+            header[44..48].copy_from_slice(&image_count.to_le_bytes());
+            header[48..72].copy_from_slice(&table);
+            self.data
+        }
+    }
+
+    /// Directory entry padded to 8 bytes, followed by `streams`
+    fn dentry(attributes: u32, subdir: usize, hash: [u8; 20], name: &str,
+              streams: &[[u8; 20]]) -> Vec<u8>
+    {
+        let name: Vec<u8> = name.encode_utf16()
+            .flat_map(|x| x.to_le_bytes()).collect();
+        let length = 102 + name.len() + 2;
+
+        let mut ent = (length as u64).to_le_bytes().to_vec();
+        ent.extend_from_slice(&attributes.to_le_bytes());
+        ent.extend_from_slice(&[0xff; 4]);
+        ent.extend_from_slice(&(subdir as u64).to_le_bytes());
+        ent.resize(64, 0);
+        ent.extend_from_slice(&hash);
+        ent.resize(96, 0);
+        ent.extend_from_slice(&(streams.len() as u16).to_le_bytes());
+        ent.extend_from_slice(&[0, 0]);
+        ent.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        ent.extend_from_slice(&name);
+        ent.resize((length + 7) & !7, 0);
+
+        for hash in streams {
+            ent.extend_from_slice(&40u64.to_le_bytes());
+            ent.resize(ent.len() + 8, 0);
+            ent.extend_from_slice(hash);
+            ent.resize(ent.len() + 4, 0);
+        }
+        ent
+    }
+
+    /// Attributes, index of the directory holding its children, hash, name
+    /// and stream hashes of a directory entry
+    type Dentry<'a> = (u32, usize, [u8; 20], &'a str, &'a [[u8; 20]]);
+
+    /// Lay out directories in a metadata resource, the first directory
+    /// holding just the root
+    fn metadata(dirs: &[&[Dentry]]) -> Vec<u8>
+    {
+        /* Entries are the same size whatever they point to */
+        let mut offsets = vec![8];
+        for dir in dirs {
+            let len: usize = dir.iter()
+                .map(|x| dentry(x.0, 0, x.2, x.3, x.4).len()).sum();
+            offsets.push(offsets[offsets.len() - 1] + len + 8);
+        }
+
+        let mut meta = vec![8, 0, 0, 0, 0, 0, 0, 0];
+        for dir in dirs {
+            for x in dir.iter() {
+                let subdir = if x.1 == 0 { 0 } else { offsets[x.1] };
+                meta.extend(dentry(x.0, subdir, x.2, x.3, x.4));
+            }
+            meta.extend_from_slice(&[0; 8]);
+        }
+        meta
+    }
+
+    const DIR: u32 = FILE_ATTRIBUTE_DIRECTORY;
+
+    /// A WIM holding one image with a handful of files
+    fn build(chunk_size: Option<usize>) -> Vec<u8>
+    {
+        let none: &[[u8; 20]] = &[];
+        let meta = metadata(&[
+            &[(DIR, 1, [0; 20], "", none)],
+            &[(DIR, 2, [0; 20], "Windows", none),
+              (0x20, 0, [1; 20], "readme.txt", none)],
+            &[(0x20, 0, [2; 20], "ntdll.dll", none),
+              (0x20, 0, [0; 20], "kernel32.dll", &[[3; 20]]),
+              (FILE_ATTRIBUTE_REPARSE_POINT, 0, [4; 20], "link", none),
+              (0x20, 0, [0; 20], "empty.dll", none),
+              (DIR, 0, [0; 20], "System32", none)],
+        ]);
+
+        let mut wim = Builder::new(chunk_size);
+        wim.blob([1; 20], b"read me", 0);
+        wim.blob([2; 20], &(0..100u8).collect::<Vec<u8>>(), 0);
+        wim.blob([3; 20], b"kernel32", 0);
+        wim.blob([0xee; 20], b"freed", RESHDR_FLAG_FREE);
+        wim.blob([0; 20], &meta, RESHDR_FLAG_METADATA);
+        wim.finish(1)
+    }
+
+    /// Write `data` out and open it as a WIM
+    fn open(name: &str, data: &[u8]) -> Result<Wim, Box<dyn Error>>
+    {
+        let dir = scratch(name);
+        let path = dir.join("test.wim");
+        fs::write(&path, data).unwrap();
+        let wim = Wim::open(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        wim
+    }
+
+    #[test]
+    fn read_images()
+    {
+        assert_eq!(std::mem::size_of::<WimHeader>(), 208);
+        assert_eq!(std::mem::size_of::<DirEntry>(), 102);
+
+        for (name, chunk_size) in [("wim", None), ("wim-xpress", Some(32))] {
+            let mut wim = open(name, &build(chunk_size)).unwrap();
+            assert_eq!(wim.image_count(), 1);
+            assert_eq!(wim.blobs.len(), 3);
+
+            let files: Vec<(String, u8)> = wim.image_files(1).unwrap()
+                .into_iter().map(|x| (x.name, x.hash[0])).collect();
+            assert_eq!(files, [("ntdll.dll".to_string(), 2),
+                               ("kernel32.dll".to_string(), 3),
+                               ("readme.txt".to_string(), 1)]);
+            assert!(wim.image_files(0).is_err());
+            assert!(wim.image_files(2).is_err());
+
+            let data: Vec<u8> = (0..100).collect();
+            assert_eq!(wim.blob_size(&[2; 20]), Some(100));
+            assert_eq!(wim.read_blob(&[2; 20], None).unwrap(), data);
+            let head = wim.read_blob(&[2; 20], Some(2)).unwrap();
+            assert!(head.len() >= 2 && data.starts_with(&head));
+            assert_eq!(wim.read_blob(&[3; 20], None).unwrap(), b"kernel32");
+            assert!(wim.read_blob(&[0xee; 20], None).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_headers()
+    {
+        let data = build(Some(32));
+        assert!(open("wim-bad", &data[..100]).is_err());
+
+        let mut bad = data.clone();
+        bad[0] = b'X';
+        assert!(open("wim-bad", &bad).is_err());
+
+        /* Chunk sizes must be powers of two no larger than LZMS uses */
+        for size in [48u32, 1 << 27] {
+            let mut bad = data.clone();
+            bad[20..24].copy_from_slice(&size.to_le_bytes());
+            assert_eq!(open("wim-bad", &bad).err().unwrap().to_string(),
+                       "Unsupported WIM chunk size");
+        }
+
+        /* Spanned WIMs */
+        let mut bad = data.clone();
+        bad[42] = 2;
+        assert!(open("wim-bad", &bad).is_err());
+
+        /* The blob table runs past the end of the file */
+        let mut bad = data.clone();
+        bad[48] = 0xff;
+        bad[49] = 0xff;
+        assert!(open("wim-bad", &bad).is_err());
+
+        /* The chunk table of the blob table isn't sorted */
+        let mut bad = data.clone();
+        let table = data[56] as usize | (data[57] as usize) << 8;
+        bad[table + 3] = 0x7f;
+        assert!(open("wim-bad", &bad).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_metadata()
+    {
+        let none: &[[u8; 20]] = &[];
+        let image = |meta: &[u8]| {
+            let mut wim = Builder::new(None);
+            wim.blob([0; 20], meta, RESHDR_FLAG_METADATA);
+            let mut wim = open("wim-meta", &wim.finish(1)).unwrap();
+            wim.image_files(1).map(|x| x.len())
+        };
+
+        /* A subdirectory past the end of the metadata */
+        let mut meta = metadata(&[&[(DIR, 0, [0; 20], "", none)]]);
+        meta[24..32].copy_from_slice(&0x10000u64.to_le_bytes());
+        assert_eq!(image(&meta).err().unwrap().to_string(),
+                   "WIM directory entry out of bounds");
+
+        /* An entry whose name runs past its length */
+        let mut meta = metadata(&[&[(DIR, 1, [0; 20], "", none)],
+                                  &[(0x20, 0, [1; 20], "a.dll", none)]]);
+        meta[120..128].copy_from_slice(&103u64.to_le_bytes());
+        assert!(image(&meta).is_err());
+
+        /* Truncated metadata */
+        let meta = metadata(&[&[(DIR, 1, [0; 20], "", none)],
+                              &[(0x20, 0, [1; 20], "a.dll", none)]]);
+        assert_eq!(image(&meta).unwrap(), 1);
+        for len in 0..meta.len() - 8 {
+            assert!(image(&meta[..len]).is_err());
+        }
+
+        /* A directory holding itself */
+        let meta = metadata(&[&[(DIR, 1, [0; 20], "", none)],
+                              &[(DIR, 1, [0; 20], "loop", none)]]);
+        assert_eq!(image(&meta).err().unwrap().to_string(),
+                   "WIM directory shares its children with another");
+
+        /* Two directories listing the same children */
+        let meta = metadata(&[&[(DIR, 1, [0; 20], "", none)],
+                              &[(DIR, 2, [0; 20], "a", none),
+                                (DIR, 2, [0; 20], "b", none)],
+                              &[(0x20, 0, [1; 20], "a.dll", none)]]);
+        assert!(image(&meta).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! Decompressor for the XPRESS Huffman format (LZ77+Huffman in MS-XCA) as
+//! used for WIM resource chunks.
+
+use bitstream::BitReader;
+use huffman::Huffman;
+
+/// Number of symbols in the XPRESS Huffman alphabet
+const NUM_SYMBOLS: usize = 512;
+
+/// Longest codeword allowed
+const MAX_CODEWORD_LEN: u32 = 15;
+
+/// Shortest possible match
+const MIN_MATCH_LEN: usize = 3;
+
+/// Decompress a single XPRESS chunk `input` which expands to exactly
+/// `out_size` bytes.
+pub fn decompress(input: &[u8], out_size: usize) ->
+    Result<Vec<u8>, Box<dyn std::error::Error>>
+{
+    /* The chunk starts with 4-bit codeword lengths for all 512 symbols */
+    if input.len() < NUM_SYMBOLS / 2 {
+        return Err("XPRESS chunk too small for Huffman table".into());
+    }
+
+    let mut lengths = [0u8; NUM_SYMBOLS];
+    for (ii, &byte) in input[..NUM_SYMBOLS / 2].iter().enumerate() {
+        lengths[ii * 2]     = byte & 0xf;
+        lengths[ii * 2 + 1] = byte >> 4;
+    }
+    let code = Huffman::new(&lengths, MAX_CODEWORD_LEN)?;
+
+    let mut reader = BitReader::new(&input[NUM_SYMBOLS / 2..]);
+    let mut out = Vec::with_capacity(out_size);
+
+    while out.len() < out_size {
+        let sym = code.decode(&mut reader)? as usize;
+
+        if sym < 256 {
+            out.push(sym as u8);
+            continue;
+        }
+
+        let mut length = sym & 0xf;
+        let offset_bits = ((sym >> 4) & 0xf) as u32;
+
+        /* The offset bits come out of the bitstream before any extended
+         * length bytes are read from the raw byte stream. */
+        reader.ensure(16);
+        let offset = (1usize << offset_bits) |
+            reader.read_bits(offset_bits) as usize;
+
+        if length == 0xf {
+            length += reader.read_u8() as usize;
+            if length == 0xf + 0xff {
+                length = reader.read_u16() as usize;
+                if length == 0 {
+                    length = reader.read_u32() as usize;
+                }
+                if length < 0xf {
+                    return Err("Invalid XPRESS extended match length".into());
+                }
+            }
+        }
+        length += MIN_MATCH_LEN;
+
+        if offset > out.len() {
+            return Err("XPRESS match offset out of bounds".into());
+        }
+        if length > out_size - out.len() {
+            return Err("XPRESS match extends past end of chunk".into());
+        }
+
+        /* Matches may overlap the bytes they produce, so copy bytewise */
+        let start = out.len() - offset;
+        for ii in 0..length {
+            let byte = out[start + ii];
+            out.push(byte);
+        }
+
+        if reader.overrun() {
+            return Err("XPRESS input overrun".into());
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a chunk where every symbol has a 9 bit codeword, so symbols are
+    /// written as themselves. `bits` are (value, count) pairs, and `bytes`
+    /// are the raw bytes of an extended match length at the end of them.
+    fn chunk(bits: &[(u32, u32)], bytes: &[u8]) -> Vec<u8>
+    {
+        let mut stream = Vec::new();
+        for &(value, count) in bits {
+            for bit in (0..count).rev() {
+                stream.push((value >> bit) & 1);
+            }
+        }
+        while stream.len() % 16 != 0 {
+            stream.push(0);
+        }
+
+        let mut out = vec![0x99; NUM_SYMBOLS / 2];
+        for word in stream.chunks(16) {
+            let word = word.iter().fold(0u16, |acc, &x| acc << 1 | x as u16);
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+
+        /* Raw bytes come after the word the decoder refilled its bit
+         * buffer with */
+        if !bytes.is_empty() {
+            out.extend_from_slice(&[0, 0]);
+            out.extend_from_slice(bytes);
+        }
+        out
+    }
+
+    /// Bits of a match of `length` bytes at `offset`, which must be at least
+    /// 1 and with `length` between 3 and 17
+    fn match_bits(offset: u32, length: u32) -> Vec<(u32, u32)>
+    {
+        let offset_bits = 31 - offset.leading_zeros();
+        let sym = 256 | offset_bits << 4 | (length - 3);
+        vec![(sym, 9), (offset & !(1 << offset_bits), offset_bits)]
+    }
+
+    #[test]
+    fn literals_and_overlapping_match()
+    {
+        let mut bits: Vec<_> = b"abc".iter().map(|&x| (x as u32, 9)).collect();
+        bits.extend(match_bits(3, 9));
+        bits.extend(match_bits(1, 4));
+
+        let out = decompress(&chunk(&bits, &[]), 16).unwrap();
+        assert_eq!(out, b"abcabcabcabccccc");
+    }
+
+    #[test]
+    fn extended_match_length()
+    {
+        /* A length nibble of 15 continues in a byte, and a byte of 255 in
+         * a 16 bit length */
+        let out = decompress(&chunk(&[(b'x' as u32, 9), (0x10f, 9)], &[2]),
+                             21).unwrap();
+        assert_eq!(out, vec![b'x'; 21]);
+
+        let out = decompress(&chunk(&[(b'y' as u32, 9), (0x10f, 9)],
+                                    &[0xff, 0x2c, 0x01]), 304).unwrap();
+        assert_eq!(out, vec![b'y'; 304]);
+    }
+
+    #[test]
+    fn rejects_bad_matches()
+    {
+        /* Offset before the start of the output */
+        let mut bits = vec![(b'a' as u32, 9)];
+        bits.extend(match_bits(2, 3));
+        assert!(decompress(&chunk(&bits, &[]), 4).is_err());
+
+        /* Match running past the end of the chunk */
+        let mut bits = vec![(b'a' as u32, 9)];
+        bits.extend(match_bits(1, 10));
+        assert!(decompress(&chunk(&bits, &[]), 5).is_err());
+    }
+
+    #[test]
+    fn hostile_input_does_not_panic()
+    {
+        assert!(decompress(&[0x99; 255], 1).is_err());
+
+        /* A table which is not a valid prefix code */
+        assert!(decompress(&[0x11; 300], 1).is_err());
+
+        let mut bits: Vec<_> = b"abc".iter().map(|&x| (x as u32, 9)).collect();
+        bits.extend(match_bits(3, 9));
+        let valid = chunk(&bits, &[]);
+        for len in NUM_SYMBOLS / 2..valid.len() {
+            if let Ok(out) = decompress(&valid[..len], 12) {
+                assert_eq!(out.len(), 12);
+            }
+        }
+
+        let mut seed = 0x8765_4321u32;
+        for _ in 0..200 {
+            let mut input = vec![0x99; NUM_SYMBOLS / 2];
+            input.extend((0..32).map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            }));
+            if let Ok(out) = decompress(&input, 64) {
+                assert_eq!(out.len(), 64);
+            }
+        }
+    }
+}